@@ -1,9 +1,31 @@
+// 生成的类型都实现 serde，HTTP 网关用 JSON 收发 CommandRequest / CommandResponse
+const SERDE: &str = "#[derive(PartialOrd, serde::Serialize, serde::Deserialize)]";
+
 fn main() {
     let mut config = prost_build::Config::new();
-    config.bytes(&["."]);
-    config.type_attribute(".", "#[derive(PartialOrd)]");
-    config
+    config.bytes(["."]);
+    // 和 protobuf 一样，JSON 中缺少的字段使用缺省值
+    config.type_attribute(".", format!("{}\n#[serde(default)]", SERDE));
+    // prost-build 对一个类型只使用最先匹配到的 attribute，所以 oneof 要单独指定，不能带 serde(default)。
+    // 只包含一个 oneof 的 message 在 pb/mod.rs 中手工实现 serde，JSON 中直接是 oneof 的内容，比如 {"hget": {...}}
+    config.type_attribute(".abi.CommandRequest", "#[derive(PartialOrd)]");
+    config.type_attribute(".abi.Value", "#[derive(PartialOrd)]");
+    for oneof in [".abi.CommandRequest.request_data", ".abi.Value.value"] {
+        config.type_attribute(oneof, format!("{}\n#[serde(rename_all = \"snake_case\")]", SERDE));
+    }
+    // prost 生成的 enum 已经 derive 了 PartialOrd
+    for e in [".abi.KeyspaceOp", ".abi.TableKind"] {
+        config.type_attribute(e, "#[derive(serde::Serialize, serde::Deserialize)]");
+    }
+    // 只有 WATCH 的 response 才有 events，只有事务和批量命令的 response 才有 responses，只有 HSCAN 的 response 才有 cursor，
+    // 其它 response 的 JSON 中不出现这些字段
+    config.field_attribute(".abi.CommandResponse.events", "#[serde(skip_serializing_if = \"Vec::is_empty\")]");
+    config.field_attribute(".abi.CommandResponse.responses", "#[serde(skip_serializing_if = \"Vec::is_empty\")]");
+    config.field_attribute(".abi.CommandResponse.cursor", "#[serde(skip_serializing_if = \"String::is_empty\")]");
+    config.field_attribute(".abi.Value.value.binary", "#[serde(with = \"crate::pb::base64_bytes\")]");
+    // 同时生成 KvService 的 gRPC server 和 client
+    tonic_build::configure()
         .out_dir("src/pb")                              // 输出目录，这个目录要预先存在，否则报错
-        .compile_protos(&["abi.proto"], &["."]) // 生成文件的名字
+        .compile_with_config(config, &["abi.proto"], &["."]) // 生成文件的名字
         .unwrap();
-}
\ No newline at end of file
+}
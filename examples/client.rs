@@ -1,8 +1,5 @@
 use anyhow::Result;
-use async_prost::AsyncProstStream;
-use futures::prelude::*;
-use kv::{add, CommandRequest, CommandResponse};
-use tokio::net::TcpStream;
+use kv::client::Client;
 use tracing::info;
 
 #[tokio::main]
@@ -10,21 +7,12 @@ async fn main() -> Result<()> {
     tracing_subscriber::fmt::init();
 
     let addr = "127.0.0.1:9527";
-    // 连接服务器
-    let stream = TcpStream::connect(addr).await?;
+    // Client 在第一次请求时才会连接服务器
+    let client = Client::new(addr);
 
-    // 使用 AsyncProstStream 来处理 TCP Frame
-    let mut client = AsyncProstStream::<_, CommandResponse, CommandRequest, _>::from(stream).for_async();
-
-    // 生成一个 HSET 命令
-    let cmd = CommandRequest::new_hset("table1", "hello", "world".into());
-
-    // 发送 HSET 命令
-    client.send(cmd).await?;
-    if let Some(Ok(data)) = client.next().await {
-        info!("Got response {:?}", data);
-    }
+    // 发送 HSET 命令，返回 key 之前的 value
+    let old = client.hset("table1", "hello", "world".into()).await?;
+    info!("Got old value {:?}", old);
 
     Ok(())
-
 }
@@ -1,8 +1,5 @@
-
 use anyhow::Result;
-use async_prost::AsyncProstStream;
-use futures::prelude::*;
-use kv::{CommandRequest, CommandResponse, memory::MemTable, Service};
+use kv::{memory::MemTable, network::Server, Service};
 use tokio::net::TcpListener;
 use tracing::info;
 
@@ -13,18 +10,11 @@ async fn main() -> Result<()> {
     let addr = "127.0.0.1:9527";
     let listener = TcpListener::bind(addr).await?;
     info!("Start listening on {}", addr);
-    loop {
-        let (stream, addr) = listener.accept().await?;
-        info!("Client {:?} connected", addr);
-        let svc = service.clone();
-        tokio::spawn(async move {
-            let mut stream =
-                AsyncProstStream::<_, CommandRequest, CommandResponse, _>::from(stream).for_async();
-            while let Some(Ok(cmd)) = stream.next().await {
-                let res = svc.execute(cmd);
-                stream.send(res).await.unwrap();
-            }
-            info!("Client {:?} disconnected", addr);
-        });
-    }
-}
\ No newline at end of file
+    // Ctrl-C 之后等待已有的连接处理完再退出
+    Server::new(service)
+        .run(listener, async {
+            let _ = tokio::signal::ctrl_c().await;
+        })
+        .await?;
+    Ok(())
+}
@@ -1,3 +1,5 @@
+// 练习用的草稿代码，不用管 lint
+#![allow(dead_code, non_camel_case_types, clippy::to_string_trait_impl, clippy::init_numbered_fields)]
 
 #[derive(Debug)]
 struct Value(String);
@@ -0,0 +1,875 @@
+use std::fs;
+use std::path::PathBuf;
+use anyhow::Result;
+use clap::Parser;
+use futures::{Stream, StreamExt};
+use kv::client::Client;
+use kv::network::tls::TlsClientConnector;
+use kv::{value, CommandRequest, CommandResponse, Hrange, KeyspaceEvent, KeyspaceOp, KvError, KvPair, TableKind, Value};
+use rustyline::error::ReadlineError;
+use rustyline::Editor;
+
+/*
+    value 的写法：
+    - 带引号的参数总是 string，比如 "42"
+    - 可以用前缀指定类型：str:、int:、float:、bool:、hex:、base64:，比如 hex:deadbeef
+    - 没有前缀时自动推断：true/false 是 bool，能解析成整数的是 integer，能解析成小数的是 float，其它是 string
+*/
+const HELP: &str = r#"Commands:
+    hget <table> <key>
+    hgetall <table>
+    hmget <table> <key> [key ...]
+    hset <table> <key> <value>
+    hsetex <table> <key> <ttl_ms> <value>
+    hmset <table> <key> <value> [key value ...]
+    hmsetex <table> <ttl_ms> <key> <value> [key value ...]
+    hdel <table> <key>
+    hmdel <table> <key> [key ...]
+    hexist <table> <key>
+    hmexist <table> <key> [key ...]
+    expire <table> <key> <ttl_ms>
+    persist <table> <key>
+    ttl <table> <key>
+    hsetnx <table> <key> <value>
+    hcas <table> <key> <expected> <value>
+    hcasver <table> <key> <version> <value>
+    hincrby <table> <key> <delta>
+    hincrbyfloat <table> <key> <delta>
+    hlen <table>
+    hscan <table> [cursor] [count] [pattern]
+    hrange <table> <start> <end> [limit] [rev]
+    hprefix <table> <prefix> [limit] [rev]
+    listtables
+    createtable <table> [hash|ordered]
+    droptable <table>
+    renametable <table> <new_name>
+    truncate <table>
+    publish <topic> <value> [value ...]
+    subscribe <topic>
+    watch <table>
+    save
+    help
+    quit
+
+Interactive mode only:
+    multi                  start a transaction, the following commands are queued
+    batch [stop]           start a batch, with stop the batch stops at the first failed command
+    exec                   send the queued transaction or batch
+    discard                drop the queued commands
+
+Subscriptions:
+    subscribe and watch print messages until Ctrl-C, which also unsubscribes.
+    The server only lets the subscribing connection cancel a subscription,
+    so there is no separate unsubscribe / unwatch command.
+    Transactions can't watch keys for changes from the cli.
+
+Ranges:
+    start / end of hrange are like Redis ZRANGEBYLEX: [key includes key, (key excludes it,
+    - is the first key and + is the last key
+
+Values:
+    "text" or str:text     string
+    42 or int:42           integer
+    1.5 or float:1.5       float
+    true or bool:true      bool
+    hex:deadbeef           binary
+    base64:3q2+7w==        binary"#;
+
+/// KV client
+#[derive(Parser, Debug)]
+#[command(name = "kv-cli", version, after_help = HELP)]
+struct Args {
+    /// 服务器地址
+    #[arg(short, long, default_value = "127.0.0.1:9527")]
+    addr: String,
+    /// 使用 yamux 多路复用，服务器需要同样开启
+    #[arg(long)]
+    multiplex: bool,
+    /// 使用 TLS 连接，值是服务器证书中的域名
+    #[arg(long)]
+    domain: Option<String>,
+    /// 验证服务器证书的 CA，不指定时使用内置的根证书
+    #[arg(long, requires = "domain")]
+    ca: Option<PathBuf>,
+    /// 双向认证时客户端的证书
+    #[arg(long, requires_all = ["domain", "key"])]
+    cert: Option<PathBuf>,
+    /// 双向认证时客户端的私钥
+    #[arg(long, requires_all = ["domain", "cert"])]
+    key: Option<PathBuf>,
+    /// 要执行的命令，不指定时进入交互模式
+    #[arg(trailing_var_arg = true, allow_hyphen_values = true)]
+    command: Vec<String>,
+}
+
+#[tokio::main]
+async fn main() -> Result<()> {
+    let args = Args::parse();
+    let mut builder = Client::builder(args.addr.clone()).pool_size(1).multiplex(args.multiplex);
+    if let Some(domain) = &args.domain {
+        let ca = args.ca.as_ref().map(fs::read_to_string).transpose()?;
+        let identity = match (&args.cert, &args.key) {
+            (Some(cert), Some(key)) => Some((fs::read_to_string(cert)?, fs::read_to_string(key)?)),
+            _ => None,
+        };
+        let identity = identity.as_ref().map(|(cert, key)| (cert.as_str(), key.as_str()));
+        builder = builder.tls(TlsClientConnector::new(domain, identity, ca.as_deref())?);
+    }
+    let client = builder.build();
+
+    // one-shot 模式：参数已经被 shell 拆分好了，没有引号的信息
+    if !args.command.is_empty() {
+        let args: Vec<_> = args.command.into_iter().map(Arg::plain).collect();
+        if let Some(subscription) = parse_subscription(&args)? {
+            return stream_subscription(&client, subscription).await;
+        }
+        let cmd = parse_command(&args)?;
+        let res = client.execute(cmd).await?;
+        println!("{}", format_response(&res));
+        if res.status >= 400 {
+            std::process::exit(1);
+        }
+        return Ok(());
+    }
+
+    let mut editor = Editor::<()>::new()?;
+    let mut queue: Option<Queue> = None;
+    loop {
+        let prompt = match &queue {
+            Some(queue) => format!("{}({})> ", args.addr, queue.name()),
+            None => format!("{}> ", args.addr),
+        };
+        let line = match editor.readline(&prompt) {
+            Ok(line) => line,
+            Err(ReadlineError::Interrupted | ReadlineError::Eof) => break,
+            Err(e) => return Err(e.into()),
+        };
+        let args = match split_line(&line) {
+            Ok(args) if args.is_empty() => continue,
+            Ok(args) => args,
+            Err(e) => {
+                println!("(error) {}", e);
+                continue;
+            }
+        };
+        editor.add_history_entry(line.as_str());
+        match args[0].text.to_lowercase().as_str() {
+            "quit" | "exit" => break,
+            "help" => {
+                println!("{}", HELP);
+                continue;
+            }
+            _ => {}
+        }
+        let cmd = match parse_queue_command(&args, &mut queue) {
+            Ok(QueueCommand::Handled) => continue,
+            Ok(QueueCommand::Exec(cmd)) => cmd,
+            Ok(QueueCommand::None) => match parse_subscription(&args) {
+                Ok(Some(_)) if queue.is_some() => {
+                    println!("(error) subscribe and watch can't be queued");
+                    continue;
+                }
+                Ok(Some(subscription)) => {
+                    if let Err(e) = stream_subscription(&client, subscription).await {
+                        println!("(error) {}", e);
+                    }
+                    continue;
+                }
+                Ok(None) => match parse_command(&args) {
+                    Ok(cmd) => cmd,
+                    Err(e) => {
+                        println!("(error) {}", e);
+                        continue;
+                    }
+                },
+                Err(e) => {
+                    println!("(error) {}", e);
+                    continue;
+                }
+            },
+            Err(e) => {
+                println!("(error) {}", e);
+                continue;
+            }
+        };
+        match &mut queue {
+            Some(queue) => {
+                queue.commands.push(cmd);
+                println!("QUEUED");
+            }
+            None => match client.execute(cmd).await {
+                Ok(res) => println!("{}", format_response(&res)),
+                Err(e) => println!("(error) {}", e),
+            },
+        }
+    }
+    Ok(())
+}
+
+/// 交互模式下 multi / batch 之后排队的命令，exec 时作为一个事务或者批量命令发送
+struct Queue {
+    // None 表示事务，Some 表示批量命令以及它的 stop_on_error
+    batch: Option<bool>,
+    commands: Vec<CommandRequest>,
+}
+
+impl Queue {
+    fn name(&self) -> &'static str {
+        match self.batch {
+            None => "multi",
+            Some(_) => "batch",
+        }
+    }
+
+    fn into_request(self) -> CommandRequest {
+        match self.batch {
+            None => CommandRequest::new_transaction(vec![], self.commands),
+            Some(stop_on_error) => CommandRequest::new_batch(self.commands, stop_on_error),
+        }
+    }
+}
+
+/// parse_queue_command 的结果
+#[derive(Debug, PartialEq)]
+enum QueueCommand {
+    /// 不是 multi / batch / exec / discard
+    None,
+    /// 已经处理完了，不需要发送任何请求
+    Handled,
+    /// exec：发送排好队的事务或者批量命令
+    Exec(CommandRequest),
+}
+
+/// 处理 multi / batch / exec / discard，修改 queue
+fn parse_queue_command(args: &[Arg], queue: &mut Option<Queue>) -> Result<QueueCommand, KvError> {
+    let name = args[0].text.to_lowercase();
+    let batch = match (name.as_str(), args.len()) {
+        ("multi", 1) => None,
+        ("batch", 1) => Some(false),
+        ("batch", 2) if args[1].text.eq_ignore_ascii_case("stop") => Some(true),
+        ("exec", 1) => {
+            return match queue.take() {
+                Some(queue) => Ok(QueueCommand::Exec(queue.into_request())),
+                None => Err(KvError::InvalidCommand("exec without multi or batch".into())),
+            }
+        }
+        ("discard", 1) => {
+            return match queue.take() {
+                Some(_) => Ok(QueueCommand::Handled),
+                None => Err(KvError::InvalidCommand("discard without multi or batch".into())),
+            }
+        }
+        ("multi" | "batch" | "exec" | "discard", _) => {
+            return Err(KvError::InvalidCommand(format!("Wrong number of arguments for '{}'", name)))
+        }
+        _ => return Ok(QueueCommand::None),
+    };
+    if queue.is_some() {
+        return Err(KvError::InvalidCommand(format!("{} can't be nested", name)));
+    }
+    *queue = Some(Queue { batch, commands: Vec::new() });
+    Ok(QueueCommand::Handled)
+}
+
+/// subscribe / watch 的参数
+#[derive(Debug, PartialEq)]
+enum Subscription {
+    Topic(String),
+    Table(String),
+}
+
+/// 解析 subscribe / watch，其它命令返回 None
+fn parse_subscription(args: &[Arg]) -> Result<Option<Subscription>, KvError> {
+    let name = args[0].text.to_lowercase();
+    let subscription = match name.as_str() {
+        "subscribe" => Subscription::Topic,
+        "watch" => Subscription::Table,
+        "multi" | "batch" | "exec" | "discard" => {
+            return Err(KvError::InvalidCommand(format!("'{}' is only available in interactive mode", name)))
+        }
+        _ => return Ok(None),
+    };
+    match args.len() {
+        2 => Ok(Some(subscription(args[1].text.clone()))),
+        _ => Err(KvError::InvalidCommand(format!("Wrong number of arguments for '{}'", name))),
+    }
+}
+
+/// 订阅之后一直打印收到的消息，直到按下 Ctrl-C 或者连接断开。stream 被 drop 时服务器自动取消订阅
+async fn stream_subscription(client: &Client, subscription: Subscription) -> Result<()> {
+    match subscription {
+        Subscription::Topic(topic) => {
+            let (id, messages) = client.subscribe(topic).await?;
+            println!("(subscription {}) press Ctrl-C to stop", id);
+            print_messages(messages, |values| {
+                values.iter().map(format_value).collect::<Vec<_>>().join(" ")
+            })
+            .await
+        }
+        Subscription::Table(table) => {
+            let (id, events) = client.watch(table).await?;
+            println!("(watch {}) press Ctrl-C to stop", id);
+            print_messages(events, format_event).await
+        }
+    }
+}
+
+async fn print_messages<T>(messages: impl Stream<Item = Result<T, KvError>>, format: impl Fn(&T) -> String) -> Result<()> {
+    tokio::pin!(messages);
+    loop {
+        tokio::select! {
+            msg = messages.next() => match msg {
+                Some(Ok(msg)) => println!("{}", format(&msg)),
+                Some(Err(e)) => println!("(error) {}", e),
+                None => return Ok(()),
+            },
+            _ = tokio::signal::ctrl_c() => return Ok(()),
+        }
+    }
+}
+
+/// 命令行中的一个参数，quoted 表示它是用引号括起来的
+#[derive(Debug, PartialEq)]
+struct Arg {
+    text: String,
+    quoted: bool,
+}
+
+impl Arg {
+    fn plain(text: impl Into<String>) -> Self {
+        Self { text: text.into(), quoted: false }
+    }
+}
+
+/// 把一行输入拆分成参数，支持单引号、双引号和反斜杠转义
+fn split_line(line: &str) -> Result<Vec<Arg>, KvError> {
+    let mut args = Vec::new();
+    let mut chars = line.chars().peekable();
+    loop {
+        while chars.next_if(|c| c.is_whitespace()).is_some() {}
+        let first = match chars.peek() {
+            Some(c) => *c,
+            None => return Ok(args),
+        };
+        let mut text = String::new();
+        if first == '"' || first == '\'' {
+            chars.next();
+            loop {
+                match chars.next() {
+                    Some(c) if c == first => break,
+                    Some('\\') if first == '"' => match chars.next() {
+                        Some('n') => text.push('\n'),
+                        Some('t') => text.push('\t'),
+                        Some(c) => text.push(c),
+                        None => return Err(KvError::InvalidCommand("Unterminated quote".into())),
+                    },
+                    Some(c) => text.push(c),
+                    None => return Err(KvError::InvalidCommand("Unterminated quote".into())),
+                }
+            }
+            args.push(Arg { text, quoted: true });
+        } else {
+            while let Some(c) = chars.next_if(|c| !c.is_whitespace()) {
+                text.push(c);
+            }
+            args.push(Arg::plain(text));
+        }
+    }
+}
+
+/// 把参数解析成 CommandRequest，第一个参数是命令名
+fn parse_command(args: &[Arg]) -> Result<CommandRequest, KvError> {
+    let (name, args) = match args.split_first() {
+        Some((name, args)) => (name.text.to_lowercase(), args),
+        None => return Err(KvError::InvalidCommand("Empty command".into())),
+    };
+    let text = |i: usize| args[i].text.clone();
+    let keys = |from: usize| args[from..].iter().map(|arg| arg.text.clone()).collect::<Vec<_>>();
+    let arity = |min: usize, exact: bool| -> Result<(), KvError> {
+        if args.len() < min || (exact && args.len() != min) {
+            return Err(KvError::InvalidCommand(format!("Wrong number of arguments for '{}'", name)));
+        }
+        Ok(())
+    };
+
+    let cmd = match name.as_str() {
+        "hget" => {
+            arity(2, true)?;
+            CommandRequest::new_hget(text(0), text(1))
+        }
+        "hgetall" => {
+            arity(1, true)?;
+            CommandRequest::new_hgetall(text(0))
+        }
+        "hmget" => {
+            arity(2, false)?;
+            CommandRequest::new_hmget(text(0), keys(1))
+        }
+        "hset" => {
+            arity(3, true)?;
+            CommandRequest::new_hset(text(0), text(1), parse_value(&args[2])?)
+        }
+        "hsetex" => {
+            arity(4, true)?;
+            CommandRequest::new_hset_with_ttl(text(0), text(1), parse_value(&args[3])?, parse_ttl(&args[2])?)
+        }
+        "hmset" => {
+            arity(3, false)?;
+            CommandRequest::new_hmset(text(0), parse_pairs(&args[1..])?)
+        }
+        "hmsetex" => {
+            arity(4, false)?;
+            CommandRequest::new_hmset_with_ttl(text(0), parse_pairs(&args[2..])?, parse_ttl(&args[1])?)
+        }
+        "hdel" => {
+            arity(2, true)?;
+            CommandRequest::new_hdel(text(0), text(1))
+        }
+        "hmdel" => {
+            arity(2, false)?;
+            CommandRequest::new_hmdel(text(0), keys(1))
+        }
+        "hexist" => {
+            arity(2, true)?;
+            CommandRequest::new_hexist(text(0), text(1))
+        }
+        "hmexist" => {
+            arity(2, false)?;
+            CommandRequest::new_hmexist(text(0), keys(1))
+        }
+        "expire" => {
+            arity(3, true)?;
+            CommandRequest::new_expire(text(0), text(1), parse_ttl(&args[2])?)
+        }
+        "persist" => {
+            arity(2, true)?;
+            CommandRequest::new_persist(text(0), text(1))
+        }
+        "ttl" => {
+            arity(2, true)?;
+            CommandRequest::new_ttl(text(0), text(1))
+        }
+        "hsetnx" => {
+            arity(3, true)?;
+            CommandRequest::new_hsetnx(text(0), text(1), parse_value(&args[2])?)
+        }
+        "hcas" => {
+            arity(4, true)?;
+            CommandRequest::new_hcas(text(0), text(1), parse_value(&args[2])?, parse_value(&args[3])?)
+        }
+        "hcasver" => {
+            arity(4, true)?;
+            let version = args[2]
+                .text
+                .parse()
+                .map_err(|_| KvError::InvalidCommand(format!("Invalid version: {}", args[2].text)))?;
+            CommandRequest::new_hcas_version(text(0), text(1), version, parse_value(&args[3])?)
+        }
+        "hincrby" => {
+            arity(3, true)?;
+            CommandRequest::new_hincrby(text(0), text(1), parse_delta(&args[2])?)
+        }
+        "hincrbyfloat" => {
+            arity(3, true)?;
+            CommandRequest::new_hincrbyfloat(text(0), text(1), parse_delta(&args[2])?)
+        }
+        "hlen" => {
+            arity(1, true)?;
+            CommandRequest::new_hlen(text(0))
+        }
+        "hscan" => {
+            arity(1, false)?;
+            if args.len() > 4 {
+                return Err(KvError::InvalidCommand(format!("Wrong number of arguments for '{}'", name)));
+            }
+            let count = match args.get(2) {
+                Some(arg) => arg
+                    .text
+                    .parse()
+                    .map_err(|_| KvError::InvalidCommand(format!("Invalid count: {}", arg.text)))?,
+                None => 0,
+            };
+            let arg = |i: usize| args.get(i).map(|arg| arg.text.clone()).unwrap_or_default();
+            CommandRequest::new_hscan(text(0), arg(1), count, arg(3))
+        }
+        "hrange" => {
+            arity(3, false)?;
+            let (limit, reverse) = parse_limit(&args[3..])?;
+            let (start, start_exclusive) = parse_bound(&args[1], "-")?;
+            let (end, end_exclusive) = parse_bound(&args[2], "+")?;
+            let mut range = Hrange::new(text(0), start, end).limit(limit);
+            range.start_exclusive = start_exclusive;
+            range.end_exclusive = end_exclusive;
+            range.reverse = reverse;
+            range.into()
+        }
+        "hprefix" => {
+            arity(2, false)?;
+            let (limit, reverse) = parse_limit(&args[2..])?;
+            CommandRequest::new_hprefix(text(0), text(1), reverse, limit)
+        }
+        "createtable" => {
+            arity(1, false)?;
+            let kind = match args.get(1).map(|arg| arg.text.to_lowercase()).as_deref() {
+                None | Some("hash") => TableKind::Hash,
+                Some("ordered") => TableKind::Ordered,
+                Some(kind) => return Err(KvError::InvalidCommand(format!("Invalid table kind: {}", kind))),
+            };
+            if args.len() > 2 {
+                return Err(KvError::InvalidCommand(format!("Wrong number of arguments for '{}'", name)));
+            }
+            CommandRequest::new_createtable(text(0), kind)
+        }
+        "listtables" => {
+            arity(0, true)?;
+            CommandRequest::new_listtables()
+        }
+        "droptable" => {
+            arity(1, true)?;
+            CommandRequest::new_droptable(text(0))
+        }
+        "renametable" => {
+            arity(2, true)?;
+            CommandRequest::new_renametable(text(0), text(1))
+        }
+        "truncate" => {
+            arity(1, true)?;
+            CommandRequest::new_truncate(text(0))
+        }
+        "publish" => {
+            arity(2, false)?;
+            CommandRequest::new_publish(text(0), args[1..].iter().map(parse_value).collect::<Result<_, _>>()?)
+        }
+        "save" => {
+            arity(0, true)?;
+            CommandRequest::new_save()
+        }
+        _ => return Err(KvError::InvalidCommand(format!("Unknown command '{}', try 'help'", name))),
+    };
+    Ok(cmd)
+}
+
+fn parse_ttl(arg: &Arg) -> Result<u64, KvError> {
+    arg.text
+        .parse()
+        .map_err(|_| KvError::InvalidCommand(format!("Invalid ttl: {}", arg.text)))
+}
+
+/// 解析 hrange 的 start / end，返回 key 和是否不包含它。unbounded 是表示不限制的符号（- 或者 +）
+fn parse_bound(arg: &Arg, unbounded: &str) -> Result<(String, bool), KvError> {
+    if !arg.quoted && arg.text == unbounded {
+        return Ok((String::new(), false));
+    }
+    match arg.text.chars().next() {
+        Some('[') => Ok((arg.text[1..].to_string(), false)),
+        Some('(') => Ok((arg.text[1..].to_string(), true)),
+        _ => Err(KvError::InvalidCommand(format!("Invalid range: {}, try 'help'", arg.text))),
+    }
+}
+
+/// 解析 hrange / hprefix 最后可选的 [limit] [rev]
+fn parse_limit(args: &[Arg]) -> Result<(u32, bool), KvError> {
+    let (mut limit, mut reverse) = (0, false);
+    for arg in args {
+        match arg.text.as_str() {
+            "rev" if !reverse => reverse = true,
+            text => {
+                limit = text
+                    .parse()
+                    .map_err(|_| KvError::InvalidCommand(format!("Invalid limit: {}", text)))?
+            }
+        }
+    }
+    Ok((limit, reverse))
+}
+
+fn parse_delta<T: std::str::FromStr>(arg: &Arg) -> Result<T, KvError> {
+    arg.text
+        .parse()
+        .map_err(|_| KvError::InvalidCommand(format!("Invalid delta: {}", arg.text)))
+}
+
+fn parse_pairs(args: &[Arg]) -> Result<Vec<KvPair>, KvError> {
+    if !args.len().is_multiple_of(2) {
+        return Err(KvError::InvalidCommand("Keys and values must come in pairs".into()));
+    }
+    args.chunks(2)
+        .map(|pair| Ok(KvPair::new(pair[0].text.clone(), parse_value(&pair[1])?)))
+        .collect()
+}
+
+/// 把参数解析成 Value，规则见文件开头的说明
+fn parse_value(arg: &Arg) -> Result<Value, KvError> {
+    let text = arg.text.as_str();
+    if arg.quoted {
+        return Ok(text.into());
+    }
+    let invalid = |kind: &str| KvError::InvalidCommand(format!("Invalid {} value: {}", kind, text));
+    if let Some((prefix, rest)) = text.split_once(':') {
+        match prefix {
+            "str" => return Ok(rest.into()),
+            "int" => return rest.parse::<i64>().map(Into::into).map_err(|_| invalid("int")),
+            "float" => return rest.parse::<f64>().map(Into::into).map_err(|_| invalid("float")),
+            "bool" => return rest.parse::<bool>().map(Into::into).map_err(|_| invalid("bool")),
+            "hex" => return hex::decode(rest).map(binary).map_err(|_| invalid("hex")),
+            "base64" => return base64::decode(rest).map(binary).map_err(|_| invalid("base64")),
+            _ => {}
+        }
+    }
+    if let Ok(v) = text.parse::<bool>() {
+        return Ok(v.into());
+    }
+    if let Ok(v) = text.parse::<i64>() {
+        return Ok(v.into());
+    }
+    // 不把 inf、nan 这样的单词当成 float
+    if text.contains(|c: char| c.is_ascii_digit()) {
+        if let Ok(v) = text.parse::<f64>() {
+            return Ok(v.into());
+        }
+    }
+    Ok(text.into())
+}
+
+fn binary(data: Vec<u8>) -> Value {
+    Value {
+        value: Some(value::Value::Binary(data.into())),
+    }
+}
+
+/// 把 CommandResponse 格式化成便于阅读的文本
+fn format_response(res: &CommandResponse) -> String {
+    let mut lines = Vec::new();
+    if res.status >= 400 {
+        lines.push(format!("(error {}) {}", res.status, res.message));
+    } else if !res.message.is_empty() {
+        lines.push(format!("({}) {}", res.status, res.message));
+    }
+    for (i, v) in res.values.iter().enumerate() {
+        lines.push(format!("{}) {}", i + 1, format_value(v)));
+    }
+    for (i, pair) in res.pairs.iter().enumerate() {
+        let value = pair.value.as_ref().map(format_value).unwrap_or_else(|| "(nil)".into());
+        lines.push(format!("{}) {:?} => {}", i + 1, pair.key, value));
+    }
+    if !res.cursor.is_empty() {
+        lines.push(format!("(cursor) {:?}", res.cursor));
+    }
+    // 事务和批量命令中每个命令的结果
+    for (i, res) in res.responses.iter().enumerate() {
+        let formatted = format_response(res).replace('\n', "\n   ");
+        lines.push(format!("{}) {}", i + 1, formatted));
+    }
+    if lines.is_empty() {
+        lines.push(match res.status {
+            200 => "OK".into(),
+            status => format!("({})", status),
+        });
+    }
+    lines.join("\n")
+}
+
+/// 把 WATCH 收到的事件格式化成一行
+fn format_event(event: &KeyspaceEvent) -> String {
+    let value = |v: &Option<Value>| v.as_ref().map(format_value).unwrap_or_else(|| "(nil)".into());
+    let op = match KeyspaceOp::from_i32(event.op) {
+        Some(KeyspaceOp::Set) => "set",
+        Some(KeyspaceOp::Del) => "del",
+        Some(KeyspaceOp::TableDropped) => return format!("{:?} dropped", event.table),
+        Some(KeyspaceOp::TableTruncated) => return format!("{:?} truncated", event.table),
+        Some(KeyspaceOp::TableRenamed) => {
+            return format!("renamed {} -> {}", value(&event.old_value), value(&event.new_value))
+        }
+        None => "unknown",
+    };
+    format!("{:?} {:?} {}: {} -> {}", event.table, event.key, op, value(&event.old_value), value(&event.new_value))
+}
+
+fn format_value(v: &Value) -> String {
+    match &v.value {
+        Some(value::Value::String(s)) => format!("{:?}", s),
+        Some(value::Value::Binary(b)) => format!("(binary) hex:{}", hex::encode(b)),
+        Some(value::Value::Integer(i)) => format!("(integer) {}", i),
+        Some(value::Value::Float(f)) => format!("(float) {}", f),
+        Some(value::Value::Bool(b)) => format!("(bool) {}", b),
+        None => "(nil)".into(),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn args(line: &str) -> Vec<Arg> {
+        split_line(line).unwrap()
+    }
+
+    #[test]
+    fn split_line_should_handle_quotes() {
+        let parsed = args(r#"hset t1 'a key' "say \"hi\"" 42"#);
+        assert_eq!(
+            parsed,
+            vec![
+                Arg::plain("hset"),
+                Arg::plain("t1"),
+                Arg { text: "a key".into(), quoted: true },
+                Arg { text: "say \"hi\"".into(), quoted: true },
+                Arg::plain("42"),
+            ]
+        );
+        assert!(split_line("hget \"t1").is_err());
+    }
+
+    #[test]
+    fn parse_value_should_work() {
+        assert_eq!(parse_value(&Arg::plain("hello")), Ok("hello".into()));
+        assert_eq!(parse_value(&Arg::plain("42")), Ok(42.into()));
+        assert_eq!(parse_value(&Arg::plain("-1.5")), Ok((-1.5).into()));
+        assert_eq!(parse_value(&Arg::plain("true")), Ok(true.into()));
+        assert_eq!(parse_value(&Arg::plain("nan")), Ok("nan".into()));
+        assert_eq!(parse_value(&Arg { text: "42".into(), quoted: true }), Ok("42".into()));
+        assert_eq!(parse_value(&Arg::plain("str:42")), Ok("42".into()));
+        assert_eq!(parse_value(&Arg::plain("float:1")), Ok(1.0.into()));
+        assert_eq!(parse_value(&Arg::plain("hex:dead")), Ok(binary(vec![0xde, 0xad])));
+        assert_eq!(parse_value(&Arg::plain("base64:3q0=")), Ok(binary(vec![0xde, 0xad])));
+        assert_eq!(parse_value(&Arg::plain("user:123")), Ok("user:123".into()));
+        assert!(parse_value(&Arg::plain("int:abc")).is_err());
+        assert!(parse_value(&Arg::plain("hex:xyz")).is_err());
+    }
+
+    #[test]
+    fn parse_command_should_cover_all_commands() {
+        let cases = vec![
+            ("hget t1 k1", CommandRequest::new_hget("t1", "k1")),
+            ("HGETALL t1", CommandRequest::new_hgetall("t1")),
+            ("hmget t1 k1 k2", CommandRequest::new_hmget("t1", vec!["k1".into(), "k2".into()])),
+            ("hset t1 k1 10", CommandRequest::new_hset("t1", "k1", 10.into())),
+            ("hsetex t1 k1 1000 v1", CommandRequest::new_hset_with_ttl("t1", "k1", "v1".into(), 1000)),
+            (
+                "hmset t1 k1 v1 k2 true",
+                CommandRequest::new_hmset("t1", vec![KvPair::new("k1", "v1".into()), KvPair::new("k2", true.into())]),
+            ),
+            (
+                "hmsetex t1 1000 k1 v1",
+                CommandRequest::new_hmset_with_ttl("t1", vec![KvPair::new("k1", "v1".into())], 1000),
+            ),
+            ("hdel t1 k1", CommandRequest::new_hdel("t1", "k1")),
+            ("hmdel t1 k1 k2", CommandRequest::new_hmdel("t1", vec!["k1".into(), "k2".into()])),
+            ("hexist t1 k1", CommandRequest::new_hexist("t1", "k1")),
+            ("hmexist t1 k1 k2", CommandRequest::new_hmexist("t1", vec!["k1".into(), "k2".into()])),
+            ("expire t1 k1 500", CommandRequest::new_expire("t1", "k1", 500)),
+            ("persist t1 k1", CommandRequest::new_persist("t1", "k1")),
+            ("ttl t1 k1", CommandRequest::new_ttl("t1", "k1")),
+            ("hsetnx t1 k1 v1", CommandRequest::new_hsetnx("t1", "k1", "v1".into())),
+            ("hcas t1 k1 v1 v2", CommandRequest::new_hcas("t1", "k1", "v1".into(), "v2".into())),
+            ("hcasver t1 k1 7 v2", CommandRequest::new_hcas_version("t1", "k1", 7, "v2".into())),
+            ("hincrby t1 k1 -2", CommandRequest::new_hincrby("t1", "k1", -2)),
+            ("hincrbyfloat t1 k1 0.5", CommandRequest::new_hincrbyfloat("t1", "k1", 0.5)),
+            ("hlen t1", CommandRequest::new_hlen("t1")),
+            ("hscan t1", CommandRequest::new_hscan("t1", "", 0, "")),
+            ("hscan t1 3:k1 20 user:*", CommandRequest::new_hscan("t1", "3:k1", 20, "user:*")),
+            ("listtables", CommandRequest::new_listtables()),
+            ("createtable t1 ordered", CommandRequest::new_createtable("t1", TableKind::Ordered)),
+            ("createtable t1", CommandRequest::new_createtable("t1", TableKind::Hash)),
+            ("hrange t1 - +", CommandRequest::new_hrange("t1", "", "")),
+            (
+                "hrange t1 [a (b 10 rev",
+                Hrange::new("t1", "a", "b").end_exclusive().reverse().limit(10).into(),
+            ),
+            ("hprefix t1 user: 5", CommandRequest::new_hprefix("t1", "user:", false, 5)),
+            ("droptable t1", CommandRequest::new_droptable("t1")),
+            ("renametable t1 t2", CommandRequest::new_renametable("t1", "t2")),
+            ("truncate t1", CommandRequest::new_truncate("t1")),
+            ("save", CommandRequest::new_save()),
+        ];
+        for (line, expected) in cases {
+            assert_eq!(parse_command(&args(line)), Ok(expected), "{}", line);
+        }
+    }
+
+    #[test]
+    fn parse_command_should_reject_bad_input() {
+        assert!(parse_command(&args("hget t1")).is_err());
+        assert!(parse_command(&args("hmset t1 k1")).is_err());
+        assert!(parse_command(&args("hmset t1 k1 v1 k2")).is_err());
+        assert!(parse_command(&args("expire t1 k1 soon")).is_err());
+        assert!(parse_command(&args("hincrby t1 k1 0.5")).is_err());
+        assert!(parse_command(&args("hrange t1 a +")).is_err());
+        assert!(parse_command(&args("createtable t1 sorted")).is_err());
+        assert!(parse_command(&args("unknown t1")).is_err());
+    }
+
+    #[test]
+    fn queue_commands_should_build_transactions_and_batches() {
+        let mut queue = None;
+        assert!(parse_queue_command(&args("exec"), &mut queue).is_err());
+        assert_eq!(parse_queue_command(&args("hget t1 k1"), &mut queue), Ok(QueueCommand::None));
+
+        assert_eq!(parse_queue_command(&args("multi"), &mut queue), Ok(QueueCommand::Handled));
+        assert!(parse_queue_command(&args("batch"), &mut queue).is_err());
+        queue.as_mut().unwrap().commands.push(CommandRequest::new_hget("t1", "k1"));
+        let expected = CommandRequest::new_transaction(vec![], vec![CommandRequest::new_hget("t1", "k1")]);
+        assert_eq!(parse_queue_command(&args("exec"), &mut queue), Ok(QueueCommand::Exec(expected)));
+        assert!(queue.is_none());
+
+        assert_eq!(parse_queue_command(&args("batch stop"), &mut queue), Ok(QueueCommand::Handled));
+        let expected = CommandRequest::new_batch(vec![], true);
+        assert_eq!(parse_queue_command(&args("EXEC"), &mut queue), Ok(QueueCommand::Exec(expected)));
+
+        assert_eq!(parse_queue_command(&args("batch"), &mut queue), Ok(QueueCommand::Handled));
+        assert_eq!(parse_queue_command(&args("discard"), &mut queue), Ok(QueueCommand::Handled));
+        assert!(queue.is_none());
+        assert!(parse_queue_command(&args("batch now"), &mut queue).is_err());
+    }
+
+    #[test]
+    fn parse_subscription_should_work() {
+        assert_eq!(parse_subscription(&args("subscribe news")), Ok(Some(Subscription::Topic("news".into()))));
+        assert_eq!(parse_subscription(&args("WATCH t1")), Ok(Some(Subscription::Table("t1".into()))));
+        assert_eq!(parse_subscription(&args("hget t1 k1")), Ok(None));
+        assert!(parse_subscription(&args("subscribe")).is_err());
+        // one-shot 模式下不能使用事务和批量命令
+        assert!(parse_subscription(&args("multi")).is_err());
+    }
+
+    #[test]
+    fn format_response_should_work() {
+        let res: CommandResponse = vec![Value::from("v1"), 10.into(), Value::default()].into();
+        assert_eq!(format_response(&res), "1) \"v1\"\n2) (integer) 10\n3) (nil)");
+
+        let res: CommandResponse = vec![KvPair::new("k1", binary(vec![1, 2]))].into();
+        assert_eq!(format_response(&res), "1) \"k1\" => (binary) hex:0102");
+
+        let res: CommandResponse = KvError::NotFound("t1".into(), "k1".into()).into();
+        assert_eq!(format_response(&res), "(error 404) Not found for table: t1, key: k1");
+
+        assert_eq!(format_response(&CommandResponse { status: 200, ..Default::default() }), "OK");
+
+        let responses = vec![vec![Value::from(1), 2.into()].into(), KvError::NotFound("t1".into(), "k1".into()).into()];
+        let res = CommandResponse { status: 200, responses, ..Default::default() };
+        assert_eq!(
+            format_response(&res),
+            "1) 1) (integer) 1\n   2) (integer) 2\n2) (error 404) Not found for table: t1, key: k1"
+        );
+    }
+
+    #[test]
+    fn format_event_should_work() {
+        let event = KeyspaceEvent {
+            table: "t1".into(),
+            key: "k1".into(),
+            op: KeyspaceOp::Set as i32,
+            old_value: None,
+            new_value: Some("v1".into()),
+        };
+        assert_eq!(format_event(&event), "\"t1\" \"k1\" set: (nil) -> \"v1\"");
+        let event = KeyspaceEvent {
+            table: "t2".into(),
+            key: String::new(),
+            op: KeyspaceOp::TableRenamed as i32,
+            old_value: Some("t1".into()),
+            new_value: Some("t2".into()),
+        };
+        assert_eq!(format_event(&event), "renamed \"t1\" -> \"t2\"");
+    }
+}
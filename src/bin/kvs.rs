@@ -0,0 +1,135 @@
+use std::fs;
+use std::path::PathBuf;
+use std::time::Duration;
+use anyhow::Result;
+use clap::Parser;
+use kv::aof::{Aof, AofConfig};
+use kv::memory::MemTable;
+use kv::network::Server;
+use kv::network::tls::TlsServerAcceptor;
+use kv::sleddb::SledDb;
+use kv::{ServerConfig, Service, ServiceInner, Storage, StorageConfig};
+use tokio::net::TcpListener;
+use tracing::info;
+use tracing_subscriber::EnvFilter;
+
+// 后台清理过期 key 的间隔
+const EXPIRE_SWEEP_INTERVAL: Duration = Duration::from_secs(1);
+
+/// KV server
+#[derive(Parser, Debug)]
+#[command(name = "kvs", version)]
+struct Args {
+    /// 配置文件的路径，不指定时使用缺省配置
+    #[arg(short, long)]
+    config: Option<PathBuf>,
+    /// 覆盖配置文件中的监听地址
+    #[arg(short, long)]
+    addr: Option<String>,
+    /// 覆盖配置文件中的日志级别
+    #[arg(short, long)]
+    log_level: Option<String>,
+}
+
+#[tokio::main]
+async fn main() -> Result<()> {
+    let args = Args::parse();
+    let mut config = match &args.config {
+        Some(path) => ServerConfig::load(path)?,
+        None => ServerConfig::default(),
+    };
+    if let Some(addr) = args.addr {
+        config.general.addr = addr;
+    }
+    if let Some(level) = args.log_level {
+        config.log.level = level;
+    }
+
+    tracing_subscriber::fmt()
+        .with_env_filter(EnvFilter::new(&config.log.level))
+        .init();
+
+    match config.storage.clone() {
+        StorageConfig::Memory { snapshot_path, aof_path, fsync } => {
+            // 开启了 aof 时，由 aof 决定怎么从快照和日志中恢复数据，否则只从快照恢复
+            let aof = aof_path.map(|path| Aof::open(AofConfig { fsync, ..AofConfig::new(path) })).transpose()?;
+            let mut store = match (&aof, &snapshot_path) {
+                (Some(aof), _) => aof.recover(snapshot_path.as_deref())?,
+                (None, Some(path)) if path.exists() => MemTable::load_snapshot(path)?,
+                _ => MemTable::new(),
+            };
+            if let Some(path) = snapshot_path {
+                store = store.snapshot_path(path);
+            }
+            if let Some(bytes) = config.limits.max_memory {
+                store = store.max_memory(bytes);
+            }
+            store = store.eviction_policy(config.limits.eviction_policy);
+
+            let inner = match aof {
+                Some(aof) => ServiceInner::new(store).aof(aof),
+                None => ServiceInner::new(store),
+            };
+            let service: Service = inner.into();
+            service.spawn_expire_sweeper(EXPIRE_SWEEP_INTERVAL);
+            serve(&config, Server::new(service)).await
+        }
+        StorageConfig::Sled { path } => {
+            let service = ServiceInner::new(SledDb::new(path)?).into();
+            serve(&config, Server::new(service)).await
+        }
+    }
+}
+
+async fn serve<Store: Storage + Send + Sync + 'static>(config: &ServerConfig, server: Server<Store>) -> Result<()> {
+    let mut server = server
+        .shutdown_timeout(Duration::from_secs(config.general.shutdown_timeout))
+        .frame_config(config.frame)
+        .multiplex(config.general.multiplex);
+    if let Some(n) = config.limits.max_connections {
+        server = server.max_connections(n);
+    }
+    if let Some(tls) = &config.tls {
+        let cert = fs::read_to_string(&tls.cert)?;
+        let key = fs::read_to_string(&tls.key)?;
+        let ca = tls.ca.as_ref().map(fs::read_to_string).transpose()?;
+        server = server
+            .tls(TlsServerAcceptor::new(&cert, &key, ca.as_deref())?)
+            .handshake_timeout(Duration::from_secs(tls.handshake_timeout));
+    }
+    if let Some(addr) = &config.general.resp_addr {
+        server = server.resp(TcpListener::bind(addr).await?);
+        info!("Start listening on {} for RESP", addr);
+    }
+    if let Some(addr) = &config.general.http_addr {
+        server = server.http(TcpListener::bind(addr).await?);
+        info!("Start listening on {} for HTTP", addr);
+    }
+    if let Some(addr) = &config.general.grpc_addr {
+        server = server.grpc(TcpListener::bind(addr).await?);
+        info!("Start listening on {} for gRPC", addr);
+    }
+    let listener = TcpListener::bind(&config.general.addr).await?;
+    info!("Start listening on {}", config.general.addr);
+    server.run(listener, shutdown_signal()).await?;
+    info!("Server stopped");
+    Ok(())
+}
+
+/// 等待 SIGINT 或者 SIGTERM
+#[cfg(unix)]
+async fn shutdown_signal() {
+    use tokio::signal::unix::{signal, SignalKind};
+
+    let mut term = signal(SignalKind::terminate()).expect("failed to install SIGTERM handler");
+    tokio::select! {
+        _ = tokio::signal::ctrl_c() => info!("Received SIGINT"),
+        _ = term.recv() => info!("Received SIGTERM"),
+    }
+}
+
+#[cfg(not(unix))]
+async fn shutdown_signal() {
+    let _ = tokio::signal::ctrl_c().await;
+    info!("Received Ctrl-C");
+}
@@ -0,0 +1,842 @@
+use std::sync::{Arc, Mutex};
+use std::time::Duration;
+use futures::prelude::*;
+use tokio::net::TcpStream;
+use tokio::sync::{OwnedSemaphorePermit, Semaphore};
+use tokio::time;
+use tracing::warn;
+use crate::*;
+use crate::network::{AsyncStream, ProstClientStream};
+use crate::network::frame::{FrameConfig, ProstCodec};
+use crate::network::multiplex::YamuxCtrl;
+use crate::network::tls::TlsClientConnector;
+
+type Stream = ProstClientStream<Box<dyn AsyncStream>>;
+
+/*
+    Client 内部维护一个有上限的连接池：
+    - 同时最多有 pool_size 个请求在发送，超过的请求会等待空闲的连接
+    - 请求成功后连接放回池中复用；出错或者超时的连接状态未知，直接丢弃，下一次请求会重新连接
+    - 空闲的连接可能已经被服务器关闭了：从池中取出时先检查一下，已经关闭的直接丢弃。
+      发送之后才发现连接断了的话，只读的命令会在一个新的连接上重试一次，其它命令可能已经执行了，不能重试
+    - 连接失败时按指数退避重试
+    开启多路复用时，池中的"连接"是同一个 yamux 连接上的 stream，底层只有一个 TCP 连接，断开后自动重连。
+    Client 的 clone 是轻量级的，所有的 clone 共享同一个连接池。
+*/
+/// 异步的 KV client
+#[derive(Clone)]
+pub struct Client {
+    inner: Arc<ClientInner>,
+}
+
+struct ClientInner {
+    addr: String,
+    request_timeout: Duration,
+    connect_retries: usize,
+    backoff: Duration,
+    max_backoff: Duration,
+    frame_config: FrameConfig,
+    tls: Option<TlsClientConnector>,
+    multiplex: bool,
+    mux: tokio::sync::Mutex<Option<YamuxCtrl>>,
+    permits: Arc<Semaphore>,
+    idle: Mutex<Vec<Stream>>,
+}
+
+/// 创建 Client 的 builder
+pub struct ClientBuilder {
+    addr: String,
+    pool_size: usize,
+    request_timeout: Duration,
+    connect_retries: usize,
+    backoff: Duration,
+    max_backoff: Duration,
+    frame_config: FrameConfig,
+    tls: Option<TlsClientConnector>,
+    multiplex: bool,
+}
+
+impl ClientBuilder {
+    /// 连接池中最多有多少个连接，缺省是 8
+    pub fn pool_size(mut self, n: usize) -> Self {
+        self.pool_size = n.max(1);
+        self
+    }
+
+    /// 每个请求的超时时间（包括等待连接的时间），缺省是 5 秒
+    pub fn request_timeout(mut self, timeout: Duration) -> Self {
+        self.request_timeout = timeout;
+        self
+    }
+
+    /// 连接失败时最多重试几次，以及第一次和最长的重试间隔。间隔每次翻倍
+    pub fn backoff(mut self, retries: usize, initial: Duration, max: Duration) -> Self {
+        self.connect_retries = retries;
+        self.backoff = initial;
+        self.max_backoff = max;
+        self
+    }
+
+    /// 发送请求时使用的压缩配置
+    pub fn frame_config(mut self, config: FrameConfig) -> Self {
+        self.frame_config = config;
+        self
+    }
+
+    /// 使用 TLS 连接服务器
+    pub fn tls(mut self, connector: TlsClientConnector) -> Self {
+        self.tls = Some(connector);
+        self
+    }
+
+    /// 在一个连接上多路复用所有请求，服务器也需要开启
+    pub fn multiplex(mut self, enabled: bool) -> Self {
+        self.multiplex = enabled;
+        self
+    }
+
+    pub fn build(self) -> Client {
+        Client {
+            inner: Arc::new(ClientInner {
+                addr: self.addr,
+                request_timeout: self.request_timeout,
+                connect_retries: self.connect_retries,
+                backoff: self.backoff,
+                max_backoff: self.max_backoff,
+                frame_config: self.frame_config,
+                tls: self.tls,
+                multiplex: self.multiplex,
+                mux: tokio::sync::Mutex::new(None),
+                permits: Arc::new(Semaphore::new(self.pool_size)),
+                idle: Mutex::new(Vec::new()),
+            }),
+        }
+    }
+}
+
+impl Client {
+    /// 使用缺省配置创建 Client。连接是在第一次请求时才建立的
+    pub fn new(addr: impl Into<String>) -> Self {
+        Self::builder(addr).build()
+    }
+
+    pub fn builder(addr: impl Into<String>) -> ClientBuilder {
+        ClientBuilder {
+            addr: addr.into(),
+            pool_size: 8,
+            request_timeout: Duration::from_secs(5),
+            connect_retries: 3,
+            backoff: Duration::from_millis(100),
+            max_backoff: Duration::from_secs(2),
+            frame_config: FrameConfig::default(),
+            tls: None,
+            multiplex: false,
+        }
+    }
+
+    /// 发送任意的 CommandRequest，返回服务器的原始 response
+    pub async fn execute(&self, cmd: CommandRequest) -> Result<CommandResponse, KvError> {
+        let timeout = self.inner.request_timeout;
+        time::timeout(timeout, self.call(cmd)).await.map_err(|_| KvError::Timeout)?
+    }
+
+    pub async fn hget(&self, table: impl Into<String>, key: impl Into<String>) -> Result<Value, KvError> {
+        self.value(CommandRequest::new_hget(table, key)).await
+    }
+
+    pub async fn hgetall(&self, table: impl Into<String>) -> Result<Vec<KvPair>, KvError> {
+        Ok(self.request(CommandRequest::new_hgetall(table)).await?.pairs)
+    }
+
+    pub async fn hmget(&self, table: impl Into<String>, keys: Vec<String>) -> Result<Vec<Value>, KvError> {
+        Ok(self.request(CommandRequest::new_hmget(table, keys)).await?.values)
+    }
+
+    /// 返回 key 之前的 value
+    pub async fn hset(&self, table: impl Into<String>, key: impl Into<String>, value: Value) -> Result<Value, KvError> {
+        self.value(CommandRequest::new_hset(table, key, value)).await
+    }
+
+    pub async fn hset_with_ttl(
+        &self,
+        table: impl Into<String>,
+        key: impl Into<String>,
+        value: Value,
+        ttl: u64,
+    ) -> Result<Value, KvError> {
+        self.value(CommandRequest::new_hset_with_ttl(table, key, value, ttl)).await
+    }
+
+    pub async fn hmset(&self, table: impl Into<String>, pairs: Vec<KvPair>) -> Result<Vec<Value>, KvError> {
+        Ok(self.request(CommandRequest::new_hmset(table, pairs)).await?.values)
+    }
+
+    pub async fn hmset_with_ttl(
+        &self,
+        table: impl Into<String>,
+        pairs: Vec<KvPair>,
+        ttl: u64,
+    ) -> Result<Vec<Value>, KvError> {
+        Ok(self.request(CommandRequest::new_hmset_with_ttl(table, pairs, ttl)).await?.values)
+    }
+
+    /// 返回被删除的 value
+    pub async fn hdel(&self, table: impl Into<String>, key: impl Into<String>) -> Result<Value, KvError> {
+        self.value(CommandRequest::new_hdel(table, key)).await
+    }
+
+    pub async fn hmdel(&self, table: impl Into<String>, keys: Vec<String>) -> Result<Vec<Value>, KvError> {
+        Ok(self.request(CommandRequest::new_hmdel(table, keys)).await?.values)
+    }
+
+    pub async fn hexist(&self, table: impl Into<String>, key: impl Into<String>) -> Result<bool, KvError> {
+        to_bool(&self.value(CommandRequest::new_hexist(table, key)).await?)
+    }
+
+    pub async fn hmexist(&self, table: impl Into<String>, keys: Vec<String>) -> Result<Vec<bool>, KvError> {
+        let res = self.request(CommandRequest::new_hmexist(table, keys)).await?;
+        res.values.iter().map(to_bool).collect()
+    }
+
+    pub async fn save(&self) -> Result<(), KvError> {
+        self.request(CommandRequest::new_save()).await.map(|_| ())
+    }
+
+    /// key 不存在时返回 false
+    pub async fn expire(&self, table: impl Into<String>, key: impl Into<String>, ttl: u64) -> Result<bool, KvError> {
+        to_bool(&self.value(CommandRequest::new_expire(table, key, ttl)).await?)
+    }
+
+    pub async fn persist(&self, table: impl Into<String>, key: impl Into<String>) -> Result<bool, KvError> {
+        to_bool(&self.value(CommandRequest::new_persist(table, key)).await?)
+    }
+
+    /// 剩余的毫秒数，-1 表示没有过期时间，-2 表示 key 不存在
+    pub async fn ttl(&self, table: impl Into<String>, key: impl Into<String>) -> Result<i64, KvError> {
+        match self.value(CommandRequest::new_ttl(table, key)).await?.value {
+            Some(value::Value::Integer(i)) => Ok(i),
+            v => Err(unexpected(v)),
+        }
+    }
+
+    /// 返回加之后的值
+    pub async fn hincrby(&self, table: impl Into<String>, key: impl Into<String>, delta: i64) -> Result<i64, KvError> {
+        match self.value(CommandRequest::new_hincrby(table, key, delta)).await?.value {
+            Some(value::Value::Integer(i)) => Ok(i),
+            v => Err(unexpected(v)),
+        }
+    }
+
+    /// 返回加之后的值
+    pub async fn hincrbyfloat(&self, table: impl Into<String>, key: impl Into<String>, delta: f64) -> Result<f64, KvError> {
+        match self.value(CommandRequest::new_hincrbyfloat(table, key, delta)).await?.value {
+            Some(value::Value::Float(f)) => Ok(f),
+            v => Err(unexpected(v)),
+        }
+    }
+
+    /// key 不存在时才设置，返回是否设置了
+    pub async fn hsetnx(&self, table: impl Into<String>, key: impl Into<String>, value: Value) -> Result<bool, KvError> {
+        match self.request(CommandRequest::new_hsetnx(table, key, value)).await {
+            Ok(_) => Ok(true),
+            Err(KvError::ServerError(409, _)) => Ok(false),
+            Err(e) => Err(e),
+        }
+    }
+
+    /// key 当前的值等于 expected 时才设置，返回新的版本号。不满足时返回 412 的 ServerError
+    pub async fn hcas(
+        &self,
+        table: impl Into<String>,
+        key: impl Into<String>,
+        expected: Value,
+        value: Value,
+    ) -> Result<u64, KvError> {
+        to_version(self.value(CommandRequest::new_hcas(table, key, expected, value)).await?)
+    }
+
+    /// key 当前的版本号等于 version 时才设置，返回新的版本号。不满足时返回 412 的 ServerError
+    pub async fn hcas_version(
+        &self,
+        table: impl Into<String>,
+        key: impl Into<String>,
+        version: u64,
+        value: Value,
+    ) -> Result<u64, KvError> {
+        to_version(self.value(CommandRequest::new_hcas_version(table, key, version, value)).await?)
+    }
+
+    /// 执行事务，返回每个命令的 response。watched 中有 key 变化了返回 412 的 ServerError
+    pub async fn transaction(
+        &self,
+        watched: Vec<WatchedKey>,
+        commands: Vec<CommandRequest>,
+    ) -> Result<Vec<CommandResponse>, KvError> {
+        Ok(self.request(CommandRequest::new_transaction(watched, commands)).await?.responses)
+    }
+
+    /// 在一个请求中发送多个命令，返回每个执行了的命令的 response。
+    /// 单个命令失败不会让整个请求失败，需要检查每个 response 的 status
+    pub async fn batch(&self, requests: Vec<CommandRequest>, stop_on_error: bool) -> Result<BatchResponse, KvError> {
+        let res = self.request(CommandRequest::new_batch(requests, stop_on_error)).await?;
+        Ok(BatchResponse { responses: res.responses })
+    }
+
+    /// 从 cursor 开始遍历 table 的一页，返回这一页的 kv pair 和下一页的 cursor。
+    /// cursor 为空表示从头开始，返回的 cursor 为空表示遍历结束
+    pub async fn hscan(
+        &self,
+        table: impl Into<String>,
+        cursor: impl Into<String>,
+        count: u32,
+        pattern: impl Into<String>,
+    ) -> Result<(Vec<KvPair>, String), KvError> {
+        let res = self.request(CommandRequest::new_hscan(table, cursor, count, pattern)).await?;
+        Ok((res.pairs, res.cursor))
+    }
+
+    /// 创建指定类型的 table，table 已经存在时返回 409 的 ServerError
+    pub async fn create_table(&self, table: impl Into<String>, kind: TableKind) -> Result<(), KvError> {
+        self.request(CommandRequest::new_createtable(table, kind)).await.map(|_| ())
+    }
+
+    /// 按 key 的顺序返回有序 table 中的一个范围，用 Hrange::new 创建查询
+    pub async fn hrange(&self, range: Hrange) -> Result<Vec<KvPair>, KvError> {
+        Ok(self.request(range.into()).await?.pairs)
+    }
+
+    /// 按 key 的顺序返回有序 table 中以 prefix 开头的 kv pair，limit 为 0 时不限制
+    pub async fn hprefix(
+        &self,
+        table: impl Into<String>,
+        prefix: impl Into<String>,
+        reverse: bool,
+        limit: u32,
+    ) -> Result<Vec<KvPair>, KvError> {
+        Ok(self.request(CommandRequest::new_hprefix(table, prefix, reverse, limit)).await?.pairs)
+    }
+
+    /// 返回所有 table 的名字，按名字排序
+    pub async fn list_tables(&self) -> Result<Vec<String>, KvError> {
+        let res = self.request(CommandRequest::new_listtables()).await?;
+        res.values
+            .into_iter()
+            .map(|v| match v.value {
+                Some(value::Value::String(s)) => Ok(s),
+                v => Err(unexpected(v)),
+            })
+            .collect()
+    }
+
+    /// 删除整个 table，返回 table 之前是否存在
+    pub async fn drop_table(&self, table: impl Into<String>) -> Result<bool, KvError> {
+        to_bool(&self.value(CommandRequest::new_droptable(table)).await?)
+    }
+
+    /// 重命名 table。table 不存在时返回 404，new_name 已经存在时返回 409 的 ServerError
+    pub async fn rename_table(&self, table: impl Into<String>, new_name: impl Into<String>) -> Result<(), KvError> {
+        self.request(CommandRequest::new_renametable(table, new_name)).await.map(|_| ())
+    }
+
+    /// 删除 table 中所有的 key，返回删除的 key 的个数
+    pub async fn truncate(&self, table: impl Into<String>) -> Result<usize, KvError> {
+        to_count(self.value(CommandRequest::new_truncate(table)).await?)
+    }
+
+    /// 返回 table 中 key 的个数
+    pub async fn hlen(&self, table: impl Into<String>) -> Result<usize, KvError> {
+        to_count(self.value(CommandRequest::new_hlen(table)).await?)
+    }
+
+    /// 返回收到消息的订阅者数量
+    pub async fn publish(&self, topic: impl Into<String>, data: Vec<Value>) -> Result<i64, KvError> {
+        match self.value(CommandRequest::new_publish(topic, data)).await?.value {
+            Some(value::Value::Integer(i)) => Ok(i),
+            v => Err(unexpected(v)),
+        }
+    }
+
+    /// 订阅 topic，返回订阅的 id 和收到的消息组成的 stream。
+    /// 订阅独占一个不放回连接池的连接，stream 被 drop 时连接断开，服务器自动取消订阅。
+    /// 服务器只允许在创建订阅的连接上 UNSUBSCRIBE，所以 drop stream 是取消订阅的唯一方式
+    pub async fn subscribe(
+        &self,
+        topic: impl Into<String>,
+    ) -> Result<(u32, impl futures::Stream<Item = Result<Vec<Value>, KvError>>), KvError> {
+        let (id, stream) = self.open_subscription(CommandRequest::new_subscribe(topic)).await?;
+        Ok((id, stream.map_ok(|res| res.values)))
+    }
+
+    /// 监听 table 中 key 的变化，返回订阅的 id 和事件组成的 stream。连接的使用方式和 subscribe 一样
+    pub async fn watch(
+        &self,
+        table: impl Into<String>,
+    ) -> Result<(u32, impl futures::Stream<Item = Result<KeyspaceEvent, KvError>>), KvError> {
+        let (id, stream) = self.open_subscription(CommandRequest::new_watch(table)).await?;
+        // 一个 response 中可能有多个事件，展开成一个个的事件
+        let events = stream.flat_map(|res| {
+            let events = match res {
+                Ok(res) => res.events.into_iter().map(Ok).collect(),
+                Err(e) => vec![Err(e)],
+            };
+            stream::iter(events)
+        });
+        Ok((id, events))
+    }
+
+    /// 在一个新的连接上发送 SUBSCRIBE 或者 WATCH，返回订阅的 id 和之后收到的 response 组成的 stream
+    async fn open_subscription(
+        &self,
+        cmd: CommandRequest,
+    ) -> Result<(u32, impl futures::Stream<Item = Result<CommandResponse, KvError>>), KvError> {
+        let mut stream = self.connect().await?;
+        let timeout = self.inner.request_timeout;
+        let res = time::timeout(timeout, async {
+            stream.send(cmd).await?;
+            stream.next().await.unwrap_or_else(|| Err(KvError::IoError("Connection closed by server".into())))
+        })
+        .await
+        .map_err(|_| KvError::Timeout)??;
+        let id = match res.status {
+            200 => match res.values.into_iter().next().and_then(|v| v.value) {
+                Some(value::Value::Integer(id)) => id as u32,
+                v => return Err(unexpected(v)),
+            },
+            status => return Err(KvError::ServerError(status, res.message)),
+        };
+        // 被服务器踢掉时会先收到一个错误，然后连接上不会再有消息
+        let messages = stream.map(|res| match res {
+            Ok(res) if res.status == 200 => Ok(res),
+            Ok(res) => Err(KvError::ServerError(res.status, res.message)),
+            Err(e) => Err(e),
+        });
+        Ok((id, messages))
+    }
+
+    /// 发送请求，把非 200 的 response 转换成错误
+    async fn request(&self, cmd: CommandRequest) -> Result<CommandResponse, KvError> {
+        let res = self.execute(cmd).await?;
+        match res.status {
+            200 => Ok(res),
+            status => Err(KvError::ServerError(status, res.message)),
+        }
+    }
+
+    /// 发送请求，返回 response 中的第一个 value
+    async fn value(&self, cmd: CommandRequest) -> Result<Value, KvError> {
+        let res = self.request(cmd).await?;
+        Ok(res.values.into_iter().next().unwrap_or_default())
+    }
+
+    async fn call(&self, cmd: CommandRequest) -> Result<CommandResponse, KvError> {
+        let mut conn = self.checkout().await?;
+        let retry = (conn.reused && cmd.is_read_only()).then(|| cmd.clone());
+        match (conn.request(cmd).await, retry) {
+            (Err(e), Some(cmd)) => {
+                warn!("Pooled connection to {} failed: {:?}, retry on a new connection", self.inner.addr, e);
+                conn.stream = Some(self.connect().await?);
+                conn.request(cmd).await
+            }
+            (res, _) => res,
+        }
+    }
+
+    /// 从连接池中拿一个连接，没有可用的空闲连接就新建一个
+    async fn checkout(&self) -> Result<PooledStream, KvError> {
+        let permit = Arc::clone(&self.inner.permits)
+            .acquire_owned()
+            .await
+            .map_err(|e| KvError::Internal(e.to_string()))?;
+        let (stream, reused) = loop {
+            let idle = self.inner.idle.lock().unwrap().pop();
+            match idle {
+                Some(mut stream) => match is_alive(&mut stream) {
+                    true => break (stream, true),
+                    false => warn!("Pooled connection to {} was closed, dropped", self.inner.addr),
+                },
+                None => break (self.connect().await?, false),
+            }
+        };
+        Ok(PooledStream {
+            stream: Some(stream),
+            reused,
+            reusable: false,
+            inner: Arc::clone(&self.inner),
+            _permit: permit,
+        })
+    }
+
+    async fn connect(&self) -> Result<Stream, KvError> {
+        let stream: Box<dyn AsyncStream> = match self.inner.multiplex {
+            true => Box::new(self.open_stream().await?),
+            false => self.dial().await?,
+        };
+        Ok(Stream::new(stream, ProstCodec::new(self.inner.frame_config)))
+    }
+
+    /// 在 yamux 连接上打开一个 stream，连接不存在或者已经断开时重新建立
+    async fn open_stream(&self) -> Result<impl AsyncStream, KvError> {
+        let mut mux = self.inner.mux.lock().await;
+        let ctrl = match mux.as_ref() {
+            Some(ctrl) if !ctrl.is_closed() => ctrl.clone(),
+            _ => {
+                let ctrl = YamuxCtrl::new_client(self.dial().await?);
+                *mux = Some(ctrl.clone());
+                ctrl
+            }
+        };
+        drop(mux);
+        ctrl.open_stream().await
+    }
+
+    /// 建立到服务器的连接
+    async fn dial(&self) -> Result<Box<dyn AsyncStream>, KvError> {
+        let inner = &self.inner;
+        let mut backoff = inner.backoff;
+        let mut retries = 0;
+        let stream = loop {
+            match TcpStream::connect(&inner.addr).await {
+                Ok(stream) => break stream,
+                Err(e) if retries < inner.connect_retries => {
+                    warn!("Failed to connect to {}: {:?}, retry in {:?}", inner.addr, e, backoff);
+                    time::sleep(backoff).await;
+                    backoff = (backoff * 2).min(inner.max_backoff);
+                    retries += 1;
+                }
+                Err(e) => return Err(e.into()),
+            }
+        };
+        // TLS 握手失败一般是证书的问题，重试也没有用
+        Ok(match &inner.tls {
+            Some(connector) => Box::new(connector.connect(stream).await?),
+            None => Box::new(stream),
+        })
+    }
+}
+
+/// 从连接池中借出的连接，drop 时如果连接还能用就放回池中
+struct PooledStream {
+    stream: Option<Stream>,
+    // 是不是从池中取出的空闲连接
+    reused: bool,
+    reusable: bool,
+    inner: Arc<ClientInner>,
+    _permit: OwnedSemaphorePermit,
+}
+
+impl PooledStream {
+    /// 发送请求并等待 response，收到 response 之后连接才能放回池中
+    async fn request(&mut self, cmd: CommandRequest) -> Result<CommandResponse, KvError> {
+        let stream = self.stream.as_mut().unwrap();
+        stream.send(cmd).await?;
+        match stream.next().await {
+            Some(Ok(res)) => {
+                self.reusable = true;
+                Ok(res)
+            }
+            Some(Err(e)) => Err(e),
+            None => Err(KvError::IoError("Connection closed by server".into())),
+        }
+    }
+}
+
+/// 空闲的连接上不应该有任何数据，能读到东西（包括连接已经关闭）说明它不能再用了
+fn is_alive(stream: &mut Stream) -> bool {
+    stream.next().now_or_never().is_none()
+}
+
+impl Drop for PooledStream {
+    fn drop(&mut self) {
+        if let (true, Some(stream)) = (self.reusable, self.stream.take()) {
+            self.inner.idle.lock().unwrap().push(stream);
+        }
+    }
+}
+
+fn to_bool(v: &Value) -> Result<bool, KvError> {
+    match v.value {
+        Some(value::Value::Bool(b)) => Ok(b),
+        ref v => Err(unexpected(v.clone())),
+    }
+}
+
+fn to_version(v: Value) -> Result<u64, KvError> {
+    match v.value {
+        Some(value::Value::Integer(i)) => Ok(i as u64),
+        v => Err(unexpected(v)),
+    }
+}
+
+fn to_count(v: Value) -> Result<usize, KvError> {
+    match v.value {
+        Some(value::Value::Integer(i)) => Ok(i as usize),
+        v => Err(unexpected(v)),
+    }
+}
+
+fn unexpected(v: Option<value::Value>) -> KvError {
+    KvError::Internal(format!("Unexpected value in response: {:?}", v))
+}
+
+#[cfg(test)]
+mod tests {
+    use std::net::SocketAddr;
+    use std::time::Instant;
+    use tokio::net::TcpListener;
+    use crate::command_request::RequestData;
+    use crate::memory::MemTable;
+    use crate::network::{ProstServerStream, Server};
+    use crate::network::tls::TlsServerAcceptor;
+    use crate::network::tls::tests::{TestCerts, DOMAIN};
+    use super::*;
+
+    async fn start_server() -> SocketAddr {
+        let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let addr = listener.local_addr().unwrap();
+        let service: Service = Service::new(MemTable::new());
+        tokio::spawn(Server::new(service).run(listener, future::pending()));
+        addr
+    }
+
+    #[tokio::test]
+    async fn client_typed_methods_should_work() {
+        let client = Client::new(start_server().await.to_string());
+
+        assert_eq!(client.hset("t1", "k1", "v1".into()).await, Ok(Value::default()));
+        assert_eq!(client.hset("t1", "k1", "v2".into()).await, Ok("v1".into()));
+        assert_eq!(client.hget("t1", "k1").await, Ok("v2".into()));
+        let pairs = vec![KvPair::new("k2", 10.into()), KvPair::new("k3", true.into())];
+        assert_eq!(client.hmset("t1", pairs).await.unwrap().len(), 2);
+        let values = client.hmget("t1", vec!["k2".into(), "k4".into()]).await;
+        assert_eq!(values, Ok(vec![10.into(), Value::default()]));
+        assert_eq!(client.hgetall("t1").await.unwrap().len(), 3);
+        assert_eq!(client.hexist("t1", "k3").await, Ok(true));
+        assert_eq!(client.hmexist("t1", vec!["k3".into(), "k4".into()]).await, Ok(vec![true, false]));
+        assert_eq!(client.hdel("t1", "k3").await, Ok(true.into()));
+        assert_eq!(client.ttl("t1", "k1").await, Ok(-1));
+        assert_eq!(client.expire("t1", "k1", 60_000).await, Ok(true));
+        assert!(client.ttl("t1", "k1").await.unwrap() > 0);
+        assert_eq!(client.persist("t1", "k1").await, Ok(true));
+        assert_eq!(client.hincrby("t1", "k2", 5).await, Ok(15));
+        assert_eq!(client.hincrbyfloat("t1", "k2", 0.5).await, Ok(15.5));
+        assert_eq!(client.hsetnx("t1", "lock", "a".into()).await, Ok(true));
+        assert_eq!(client.hsetnx("t1", "lock", "b".into()).await, Ok(false));
+        let version = client.hcas("t1", "lock", "a".into(), "b".into()).await.unwrap();
+        let err = client.hcas("t1", "lock", "a".into(), "c".into()).await.unwrap_err();
+        assert!(matches!(err, KvError::ServerError(412, _)));
+        assert!(client.hcas_version("t1", "lock", version, "c".into()).await.is_ok());
+        assert_eq!(client.hget("t1", "lock").await, Ok("c".into()));
+        let commands = vec![CommandRequest::new_hset("t1", "lock", "d".into()), CommandRequest::new_hget("t1", "lock")];
+        let responses = client.transaction(vec![WatchedKey::new("t1", "lock", "c".into())], commands.clone()).await;
+        assert_eq!(responses.unwrap()[1].values, vec!["d".into()]);
+        let err = client.transaction(vec![WatchedKey::new("t1", "lock", "c".into())], commands).await.unwrap_err();
+        assert!(matches!(err, KvError::ServerError(412, _)));
+
+        let err = client.hget("t1", "k3").await.unwrap_err();
+        assert_eq!(err, KvError::ServerError(404, "Not found for table: t1, key: k3".into()));
+        let err = client.save().await.unwrap_err();
+        assert!(matches!(err, KvError::ServerError(400, _)));
+    }
+
+    #[tokio::test]
+    async fn client_batch_should_work() {
+        let client = Client::new(start_server().await.to_string());
+        let requests: Vec<_> = (0..1000)
+            .map(|i| CommandRequest::new_hset(format!("t{}", i % 10), format!("k{}", i), i.into()))
+            .collect();
+        let res = client.batch(requests, false).await.unwrap();
+        assert_eq!(res.responses.len(), 1000);
+        assert!(res.responses.iter().all(|res| res.status == 200));
+        assert_eq!(client.hget("t9", "k999").await, Ok(999.into()));
+
+        // 遇到第一个失败的命令就停止
+        let requests = vec![
+            CommandRequest::new_hget("t0", "k0"),
+            CommandRequest::new_hget("t0", "missing"),
+            CommandRequest::new_hset("t0", "k0", "changed".into()),
+        ];
+        let res = client.batch(requests, true).await.unwrap();
+        let statuses: Vec<_> = res.responses.iter().map(|res| res.status).collect();
+        assert_eq!(statuses, vec![200, 404]);
+        assert_eq!(client.hget("t0", "k0").await, Ok(0.into()));
+    }
+
+    #[tokio::test]
+    async fn client_pool_should_limit_connections() {
+        let client = Client::builder(start_server().await.to_string()).pool_size(2).build();
+        let tasks: Vec<_> = (0..20)
+            .map(|i| {
+                let client = client.clone();
+                tokio::spawn(async move { client.hset("t1", format!("k{}", i), i.into()).await })
+            })
+            .collect();
+        for task in tasks {
+            task.await.unwrap().unwrap();
+        }
+        assert!(client.inner.idle.lock().unwrap().len() <= 2);
+        assert_eq!(client.hgetall("t1").await.unwrap().len(), 20);
+    }
+
+    #[tokio::test]
+    async fn client_pubsub_should_work() {
+        let client = Client::new(start_server().await.to_string());
+        let (id, messages) = client.subscribe("news").await.unwrap();
+        tokio::pin!(messages);
+        // 订阅的连接不影响其它请求
+        assert_eq!(client.publish("news", vec!["hello".into(), 1.into()]).await, Ok(1));
+        assert_eq!(messages.next().await.unwrap(), Ok(vec!["hello".into(), 1.into()]));
+
+        // 其它连接不能取消这个订阅
+        let res = client.execute(CommandRequest::new_unsubscribe("news", id)).await.unwrap();
+        assert_eq!(res.status, 404);
+        assert_eq!(client.publish("news", vec!["again".into()]).await, Ok(1));
+        assert_eq!(messages.next().await.unwrap(), Ok(vec!["again".into()]));
+    }
+
+    #[tokio::test]
+    async fn client_watch_should_receive_events() {
+        let client = Client::new(start_server().await.to_string());
+        let (id, events) = client.watch("t1").await.unwrap();
+        tokio::pin!(events);
+        client.hset("t1", "k1", "v1".into()).await.unwrap();
+        client.hset("t1", "k1", "v2".into()).await.unwrap();
+
+        let event = events.next().await.unwrap().unwrap();
+        assert_eq!((event.table.as_str(), event.key.as_str()), ("t1", "k1"));
+        assert_eq!((event.old_value, event.new_value), (None, Some("v1".into())));
+        let event = events.next().await.unwrap().unwrap();
+        assert_eq!((event.old_value, event.new_value), (Some("v1".into()), Some("v2".into())));
+
+        let res = client.execute(CommandRequest::new_unwatch("t1", id)).await.unwrap();
+        assert_eq!(res.status, 404);
+    }
+
+    #[tokio::test]
+    async fn client_should_time_out() {
+        // 只 accept 不回复的服务器
+        let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let addr = listener.local_addr().unwrap();
+        tokio::spawn(async move {
+            let mut conns = Vec::new();
+            while let Ok((stream, _)) = listener.accept().await {
+                conns.push(stream);
+            }
+        });
+
+        let client = Client::builder(addr.to_string())
+            .request_timeout(Duration::from_millis(100))
+            .build();
+        assert_eq!(client.hget("t1", "k1").await, Err(KvError::Timeout));
+        // 超时的连接不会放回池中
+        assert!(client.inner.idle.lock().unwrap().is_empty());
+    }
+
+    /// 每个连接只回复第一个请求的服务器。wait 为 true 时等收到下一个请求再关闭连接，否则回复之后马上关闭
+    async fn start_one_shot_server(wait: bool) -> SocketAddr {
+        let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let addr = listener.local_addr().unwrap();
+        tokio::spawn(async move {
+            while let Ok((stream, _)) = listener.accept().await {
+                tokio::spawn(async move {
+                    let mut stream = ProstServerStream::new(stream, ProstCodec::default());
+                    if let Some(Ok(_)) = stream.next().await {
+                        stream.send(Value::from("v1").into()).await.unwrap();
+                    }
+                    if wait {
+                        let _ = stream.next().await;
+                    }
+                });
+            }
+        });
+        addr
+    }
+
+    #[tokio::test]
+    async fn client_should_drop_closed_pooled_connections() {
+        let client = Client::builder(start_one_shot_server(false).await.to_string()).pool_size(1).build();
+        assert_eq!(client.hget("t1", "k1").await, Ok("v1".into()));
+        time::sleep(Duration::from_millis(50)).await;
+        // 池中的连接已经被服务器关闭了，写操作也会用一个新的连接发送
+        assert_eq!(client.hset("t1", "k1", "v2".into()).await, Ok("v1".into()));
+    }
+
+    #[tokio::test]
+    async fn client_should_retry_reads_on_broken_pooled_connection() {
+        let client = Client::builder(start_one_shot_server(true).await.to_string()).pool_size(1).build();
+        assert_eq!(client.hget("t1", "k1").await, Ok("v1".into()));
+        // 发送之后连接才断开，读操作在新的连接上重试一次
+        assert_eq!(client.hget("t1", "k1").await, Ok("v1".into()));
+        // 写操作可能已经执行了，不能重试
+        assert!(matches!(client.hset("t1", "k1", "v2".into()).await, Err(KvError::IoError(_))));
+    }
+
+    #[tokio::test]
+    async fn client_should_reconnect_with_backoff() {
+        // 先拿到一个空闲的端口，稍后才在上面启动服务器
+        let addr = TcpListener::bind("127.0.0.1:0").await.unwrap().local_addr().unwrap();
+        tokio::spawn(async move {
+            time::sleep(Duration::from_millis(150)).await;
+            let listener = TcpListener::bind(addr).await.unwrap();
+            let service: Service = Service::new(MemTable::new());
+            Server::new(service).run(listener, future::pending()).await
+        });
+
+        let client = Client::builder(addr.to_string())
+            .backoff(5, Duration::from_millis(50), Duration::from_millis(200))
+            .build();
+        assert_eq!(client.hset("t1", "k1", "v1".into()).await, Ok(Value::default()));
+    }
+
+    #[tokio::test]
+    async fn client_should_work_with_mutual_tls() {
+        let certs = TestCerts::generate();
+        let acceptor = TlsServerAcceptor::new(&certs.server.0, &certs.server.1, Some(&certs.ca)).unwrap();
+        let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let addr = listener.local_addr().unwrap();
+        let service: Service = Service::new(MemTable::new());
+        tokio::spawn(Server::new(service).tls(acceptor).run(listener, future::pending()));
+
+        let identity = (certs.client.0.as_str(), certs.client.1.as_str());
+        let connector = TlsClientConnector::new(DOMAIN, Some(identity), Some(&certs.ca)).unwrap();
+        let client = Client::builder(addr.to_string()).tls(connector).build();
+        assert_eq!(client.hset("t1", "k1", "v1".into()).await, Ok(Value::default()));
+        assert_eq!(client.hget("t1", "k1").await, Ok("v1".into()));
+
+        // 没有 TLS 的客户端连不上
+        let client = Client::builder(addr.to_string())
+            .request_timeout(Duration::from_millis(500))
+            .build();
+        assert!(client.hget("t1", "k1").await.is_err());
+    }
+
+    #[tokio::test(flavor = "multi_thread", worker_threads = 4)]
+    async fn slow_request_should_not_block_multiplexed_client() {
+        // HGETALL 要执行 500ms
+        let service: Service = ServiceInner::new(MemTable::new())
+            .fn_received(|cmd: &CommandRequest| {
+                if let Some(RequestData::Hgetall(_)) = cmd.request_data {
+                    std::thread::sleep(Duration::from_millis(500));
+                }
+                Ok(())
+            })
+            .into();
+        let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let addr = listener.local_addr().unwrap();
+        tokio::spawn(Server::new(service).multiplex(true).run(listener, future::pending()));
+
+        let client = Client::builder(addr.to_string()).multiplex(true).build();
+        client.hset("t1", "k1", "v1".into()).await.unwrap();
+        let slow = {
+            let client = client.clone();
+            tokio::spawn(async move { client.hgetall("t1").await })
+        };
+        time::sleep(Duration::from_millis(50)).await;
+
+        let start = Instant::now();
+        assert_eq!(client.hget("t1", "k1").await, Ok("v1".into()));
+        assert!(start.elapsed() < Duration::from_millis(400));
+        assert_eq!(slow.await.unwrap().unwrap().len(), 1);
+        // 所有请求都在同一个连接上
+        assert!(client.inner.mux.lock().await.is_some());
+    }
+}
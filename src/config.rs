@@ -0,0 +1,201 @@
+use std::fs;
+use std::path::{Path, PathBuf};
+use std::str::FromStr;
+use serde::{Deserialize, Serialize};
+use crate::aof::FsyncPolicy;
+use crate::errors::KvError;
+use crate::memory::EvictionPolicy;
+use crate::network::frame::FrameConfig;
+
+/// kvs 的配置，从 TOML 文件中加载
+#[derive(Clone, Debug, Default, PartialEq, Serialize, Deserialize)]
+pub struct ServerConfig {
+    #[serde(default)]
+    pub general: GeneralConfig,
+    #[serde(default)]
+    pub storage: StorageConfig,
+    #[serde(default)]
+    pub log: LogConfig,
+    #[serde(default)]
+    pub limits: LimitsConfig,
+    #[serde(default)]
+    pub frame: FrameConfig,
+    /// 没有这一段时不使用 TLS
+    pub tls: Option<TlsConfig>,
+}
+
+#[derive(Clone, Debug, PartialEq, Serialize, Deserialize)]
+pub struct GeneralConfig {
+    /// 监听的地址
+    pub addr: String,
+    /// 收到退出信号后，最多等待多少秒让已有的连接处理完
+    #[serde(default = "default_shutdown_timeout")]
+    pub shutdown_timeout: u64,
+    /// 是否在每个连接上使用 yamux 多路复用，客户端需要同样开启
+    #[serde(default)]
+    pub multiplex: bool,
+    /// 兼容 Redis 的 RESP 协议的监听地址，不指定时不提供 RESP 协议
+    pub resp_addr: Option<String>,
+    /// HTTP/JSON 网关的监听地址，不指定时不提供 HTTP 网关
+    pub http_addr: Option<String>,
+    /// gRPC 服务的监听地址，不指定时不提供 gRPC 服务
+    pub grpc_addr: Option<String>,
+}
+
+/// 使用哪种 Storage
+#[derive(Clone, Debug, PartialEq, Serialize, Deserialize)]
+#[serde(tag = "type", rename_all = "lowercase")]
+pub enum StorageConfig {
+    /// 使用 MemTable，可以配置快照和 aof 来持久化
+    Memory {
+        snapshot_path: Option<PathBuf>,
+        aof_path: Option<PathBuf>,
+        #[serde(default = "default_fsync")]
+        fsync: FsyncPolicy,
+    },
+    /// 使用 sled 存储在 path 目录下
+    Sled { path: PathBuf },
+}
+
+/// TLS 证书和私钥的路径，都是 PEM 格式
+#[derive(Clone, Debug, PartialEq, Serialize, Deserialize)]
+pub struct TlsConfig {
+    pub cert: PathBuf,
+    pub key: PathBuf,
+    /// 指定了 CA 时，客户端必须提供由这个 CA 签发的证书
+    pub ca: Option<PathBuf>,
+    /// TLS 握手最多等待多少秒
+    #[serde(default = "default_handshake_timeout")]
+    pub handshake_timeout: u64,
+}
+
+#[derive(Clone, Debug, PartialEq, Serialize, Deserialize)]
+pub struct LogConfig {
+    /// 日志级别，格式和 RUST_LOG 环境变量一样，比如 info 或者 kv=debug
+    pub level: String,
+}
+
+#[derive(Clone, Debug, Default, PartialEq, Serialize, Deserialize)]
+pub struct LimitsConfig {
+    /// 最多同时处理多少个连接，超过之后新连接要等待
+    pub max_connections: Option<usize>,
+    /// MemTable 最多使用多少字节
+    pub max_memory: Option<u64>,
+    /// MemTable 超过 max_memory 时的淘汰策略
+    #[serde(default)]
+    pub eviction_policy: EvictionPolicy,
+}
+
+impl ServerConfig {
+    /// 从 path 指向的 TOML 文件中加载配置
+    pub fn load(path: impl AsRef<Path>) -> Result<Self, KvError> {
+        fs::read_to_string(path)?.parse()
+    }
+}
+
+impl FromStr for ServerConfig {
+    type Err = KvError;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        toml::from_str(s).map_err(|e| KvError::InvalidConfig(e.to_string()))
+    }
+}
+
+impl Default for GeneralConfig {
+    fn default() -> Self {
+        Self {
+            addr: "127.0.0.1:9527".into(),
+            shutdown_timeout: default_shutdown_timeout(),
+            multiplex: false,
+            resp_addr: None,
+            http_addr: None,
+            grpc_addr: None,
+        }
+    }
+}
+
+impl Default for StorageConfig {
+    fn default() -> Self {
+        Self::Memory {
+            snapshot_path: None,
+            aof_path: None,
+            fsync: default_fsync(),
+        }
+    }
+}
+
+impl Default for LogConfig {
+    fn default() -> Self {
+        Self { level: "info".into() }
+    }
+}
+
+fn default_shutdown_timeout() -> u64 {
+    30
+}
+
+fn default_handshake_timeout() -> u64 {
+    10
+}
+
+fn default_fsync() -> FsyncPolicy {
+    FsyncPolicy::EverySec
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::network::frame::Compression;
+    use super::*;
+
+    #[test]
+    fn server_config_should_be_loaded() {
+        let config = ServerConfig::load("fixtures/kvs.toml").unwrap();
+        assert_eq!(config.general.addr, "127.0.0.1:9527");
+        assert_eq!(config.general.shutdown_timeout, 10);
+        assert!(config.general.multiplex);
+        assert_eq!(config.general.resp_addr.as_deref(), Some("127.0.0.1:6379"));
+        assert_eq!(config.general.http_addr.as_deref(), Some("127.0.0.1:8080"));
+        assert_eq!(config.general.grpc_addr.as_deref(), Some("127.0.0.1:50051"));
+        assert_eq!(
+            config.storage,
+            StorageConfig::Memory {
+                snapshot_path: Some("/tmp/kvs/dump.kvs".into()),
+                aof_path: Some("/tmp/kvs/appendonly.aof".into()),
+                fsync: FsyncPolicy::EverySec,
+            }
+        );
+        assert_eq!(config.log.level, "info");
+        assert_eq!(config.limits.max_connections, Some(1024));
+        assert_eq!(config.limits.max_memory, Some(256 * 1024 * 1024));
+        assert_eq!(config.limits.eviction_policy, EvictionPolicy::AllKeysLru);
+        assert_eq!(config.frame.compression, Compression::Zstd);
+        assert_eq!(config.frame.compression_threshold, 4096);
+        assert_eq!(config.frame.max_frame_size, 4 * 1024 * 1024);
+        assert_eq!(
+            config.tls,
+            Some(TlsConfig {
+                cert: "/etc/kvs/server.crt".into(),
+                key: "/etc/kvs/server.key".into(),
+                ca: Some("/etc/kvs/ca.crt".into()),
+                handshake_timeout: 5,
+            })
+        );
+    }
+
+    #[test]
+    fn missing_sections_should_use_default() {
+        let config: ServerConfig = "[storage]\ntype = \"sled\"\npath = \"/tmp/kvs\"".parse().unwrap();
+        assert_eq!(config.general, GeneralConfig::default());
+        assert_eq!(config.storage, StorageConfig::Sled { path: "/tmp/kvs".into() });
+        assert_eq!(config.log, LogConfig::default());
+        assert_eq!(config.limits, LimitsConfig::default());
+        assert_eq!(config.frame, FrameConfig::default());
+        assert_eq!(config.tls, None);
+    }
+
+    #[test]
+    fn invalid_config_should_be_rejected() {
+        let err = "[storage]\ntype = \"rocksdb\"".parse::<ServerConfig>().unwrap_err();
+        assert!(matches!(err, KvError::InvalidConfig(_)));
+    }
+}
@@ -13,5 +13,66 @@ pub enum KvError {
     Internal(String),
 
     #[error("Cannot process command {0} with table: {1}, key: {2}. Error: {3}")]
-    StorageError(&'static str, String, String, String)
-}
\ No newline at end of file
+    StorageError(&'static str, String, String, String),
+
+    #[error("Failed to encode protobuf message")]
+    EncodeError(#[from] prost::EncodeError),
+
+    #[error("Failed to decode protobuf message")]
+    DecodeError(#[from] prost::DecodeError),
+
+    #[error("Failed to access sled db")]
+    SledError(#[from] sled::Error),
+
+    #[error("I/O error: {0}")]
+    IoError(String),
+
+    #[error("Table not found: {0}")]
+    TableNotFound(String),
+
+    #[error("Table already exists: {0}")]
+    TableExists(String),
+
+    #[error("Key already exists in table: {0}, key: {1}")]
+    KeyExists(String, String),
+
+    #[error("Precondition failed for table: {0}, key: {1}")]
+    PreconditionFailed(String, String),
+
+    #[error("Invalid snapshot: {0}")]
+    InvalidSnapshot(String),
+
+    #[error("Out of memory: {0}")]
+    OutOfMemory(String),
+
+    #[error("Invalid config: {0}")]
+    InvalidConfig(String),
+
+    #[error("Server returned {0}: {1}")]
+    ServerError(u32, String),
+
+    #[error("Request timed out")]
+    Timeout,
+
+    #[error("Frame error: {0}")]
+    FrameError(String),
+
+    #[error("Frame is too large: {0} bytes")]
+    FrameTooLarge(usize),
+
+    #[error("TLS error: {0}")]
+    TlsError(String),
+
+    #[error("Subscription not found for topic: {0}, id: {1}")]
+    SubscriptionNotFound(String, u32),
+
+    #[error("Subscriber of topic {0} is too slow, dropped after {1} pending messages")]
+    SlowSubscriber(String, usize),
+}
+
+// std::io::Error 没有实现 PartialEq，所以只保留它的描述
+impl From<std::io::Error> for KvError {
+    fn from(e: std::io::Error) -> Self {
+        KvError::IoError(e.to_string())
+    }
+}
@@ -2,8 +2,13 @@ mod service;
 mod pb;
 mod errors;
 mod storage;
+mod config;
+pub mod client;
+pub mod network;
 
 
+pub use config::*;
+pub use errors::KvError;
 pub use pb::{*, abi::*};
 pub use service::*;
 pub use storage::*;
@@ -0,0 +1,378 @@
+use std::io::{Read, Write};
+use std::marker::PhantomData;
+use bytes::{Buf, BufMut, BytesMut};
+use flate2::{read::GzDecoder, write::GzEncoder};
+use prost::Message;
+use serde::{Deserialize, Serialize};
+use tokio_util::codec::{Decoder, Encoder};
+use tracing::debug;
+use crate::{CommandRequest, CommandResponse, KvError};
+
+/*
+    frame 的格式：| header: u32（大端） | payload |
+
+    header 的最高两位表示 payload 使用的压缩算法，剩下的 30 位是 payload 的长度：
+
+    | 31..30: 压缩算法 | 29..0: payload 长度 |
+
+    payload 是 protobuf 编码后的消息，压缩时是压缩后的数据。
+    编码时只有消息大小超过阈值才压缩，解码时根据 header 自动解压。
+*/
+/// header 的长度
+pub const LEN_LEN: usize = 4;
+/// frame 的最大长度，header 中只有 30 位来存长度
+pub const MAX_FRAME: usize = (1 << 30) - 1;
+/// 缺省允许的消息大小。header 里的长度不可信，不能按它直接分配内存
+pub const DEFAULT_MAX_FRAME_SIZE: usize = 8 * 1024 * 1024;
+/// 消息超过这个大小才压缩。以太网的 MTU 是 1500，去掉 IP 头 20 字节、TCP 头 20 字节，再去掉 header 4 字节
+pub const COMPRESSION_THRESHOLD: usize = 1436;
+
+const COMPRESSION_SHIFT: u32 = 30;
+const LEN_MASK: u32 = (1 << COMPRESSION_SHIFT) - 1;
+
+/// payload 使用的压缩算法
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum Compression {
+    /// 不压缩
+    None = 0,
+    Gzip = 1,
+    /// 压缩率不如 gzip，但快得多，作为缺省的压缩算法
+    #[default]
+    Lz4 = 2,
+    Zstd = 3,
+}
+
+/// frame 编解码的配置
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Serialize, Deserialize)]
+pub struct FrameConfig {
+    /// 压缩算法，None 表示从不压缩。只影响编码，解码时根据 header 自动解压
+    #[serde(default)]
+    pub compression: Compression,
+    /// 消息超过这个大小才压缩
+    #[serde(default = "default_threshold")]
+    pub compression_threshold: usize,
+    /// 单个消息（压缩前）允许的最大字节数，收到超过的 frame 直接拒绝，要发出的 response 超过时改为发出 413
+    #[serde(default = "default_max_frame_size")]
+    pub max_frame_size: usize,
+}
+
+impl Default for FrameConfig {
+    fn default() -> Self {
+        Self {
+            compression: Compression::default(),
+            compression_threshold: COMPRESSION_THRESHOLD,
+            max_frame_size: DEFAULT_MAX_FRAME_SIZE,
+        }
+    }
+}
+
+impl FrameConfig {
+    /// 实际生效的上限，不会超过 header 能表示的长度
+    fn max_size(&self) -> usize {
+        self.max_frame_size.min(MAX_FRAME)
+    }
+}
+
+fn default_threshold() -> usize {
+    COMPRESSION_THRESHOLD
+}
+
+fn default_max_frame_size() -> usize {
+    DEFAULT_MAX_FRAME_SIZE
+}
+
+/// 把 protobuf 消息编码成 frame，或者从 frame 解码出消息
+pub trait FrameCoder
+where
+    Self: Message + Sized + Default,
+{
+    /// 把消息编码成一个 frame 写入 buf
+    fn encode_frame(&self, buf: &mut BytesMut, config: &FrameConfig) -> Result<(), KvError> {
+        let size = self.encoded_len();
+        if size > config.max_size() {
+            return Err(frame_too_large(size));
+        }
+        let compression = match config.compression {
+            Compression::None => Compression::None,
+            _ if size <= config.compression_threshold => Compression::None,
+            c => c,
+        };
+
+        let payload = match compression {
+            Compression::None => self.encode_to_vec(),
+            c => {
+                let data = compress(c, &self.encode_to_vec())?;
+                debug!("Compress a frame with {:?}: {} -> {}", c, size, data.len());
+                data
+            }
+        };
+        if payload.len() > config.max_size() {
+            return Err(frame_too_large(payload.len()));
+        }
+
+        buf.reserve(LEN_LEN + payload.len());
+        buf.put_u32(((compression as u32) << COMPRESSION_SHIFT) | payload.len() as u32);
+        buf.put_slice(&payload);
+        Ok(())
+    }
+
+    /// 消息太大、编码失败时用来代替它发出去的消息，None 表示直接返回错误
+    fn too_large(_size: usize) -> Option<Self> {
+        None
+    }
+
+    /// 从 buf 中取出一个完整的 frame 解码。调用者需要保证 buf 中有完整的 frame
+    fn decode_frame(buf: &mut BytesMut, config: &FrameConfig) -> Result<Self, KvError> {
+        let (compression, len) = decode_header(buf.get_u32());
+        let payload = buf.split_to(len);
+        match compression {
+            Compression::None => Ok(Self::decode(payload)?),
+            c => Ok(Self::decode(decompress(c, &payload, config.max_size())?.as_slice())?),
+        }
+    }
+}
+
+impl FrameCoder for CommandRequest {}
+
+// response 太大时给客户端返回 413，连接还能继续使用
+impl FrameCoder for CommandResponse {
+    fn too_large(size: usize) -> Option<Self> {
+        Some(frame_too_large(size).into())
+    }
+}
+
+fn frame_too_large(size: usize) -> KvError {
+    KvError::FrameTooLarge(size)
+}
+
+/// 解析 header，返回压缩算法和 payload 的长度
+fn decode_header(header: u32) -> (Compression, usize) {
+    let compression = match header >> COMPRESSION_SHIFT {
+        0 => Compression::None,
+        1 => Compression::Gzip,
+        2 => Compression::Lz4,
+        _ => Compression::Zstd,
+    };
+    (compression, (header & LEN_MASK) as usize)
+}
+
+fn compress(compression: Compression, data: &[u8]) -> Result<Vec<u8>, KvError> {
+    match compression {
+        Compression::None => Ok(data.to_vec()),
+        Compression::Gzip => {
+            let mut encoder = GzEncoder::new(Vec::with_capacity(data.len() / 2), flate2::Compression::default());
+            encoder.write_all(data)?;
+            Ok(encoder.finish()?)
+        }
+        Compression::Lz4 => Ok(lz4_flex::compress_prepend_size(data)),
+        Compression::Zstd => Ok(zstd::encode_all(data, 0)?),
+    }
+}
+
+fn decompress(compression: Compression, data: &[u8], max_size: usize) -> Result<Vec<u8>, KvError> {
+    // 限制解压后的大小，避免恶意构造的数据把内存耗尽
+    let limit = max_size as u64 + 1;
+    let mut buf = Vec::with_capacity((data.len() * 2).min(max_size));
+    match compression {
+        Compression::None => buf.extend_from_slice(data),
+        Compression::Gzip => {
+            GzDecoder::new(data).take(limit).read_to_end(&mut buf)?;
+        }
+        Compression::Lz4 => {
+            if data.len() >= 4 && u32::from_le_bytes([data[0], data[1], data[2], data[3]]) as u64 >= limit {
+                return Err(KvError::FrameError("Decompressed frame is too large".into()));
+            }
+            buf = lz4_flex::decompress_size_prepended(data).map_err(|e| KvError::FrameError(e.to_string()))?;
+        }
+        Compression::Zstd => {
+            zstd::Decoder::new(data)?.take(limit).read_to_end(&mut buf)?;
+        }
+    }
+    if buf.len() > max_size {
+        return Err(KvError::FrameError("Decompressed frame is too large".into()));
+    }
+    Ok(buf)
+}
+
+/*
+    ProstCodec 把 FrameCoder 接入 tokio-util 的 Framed，这样一个 TcpStream 就能当作 Stream<Item = In> + Sink<Out> 使用。
+    服务器端 In 是 CommandRequest、Out 是 CommandResponse，客户端正好相反。
+*/
+/// 读取 In 类型的消息，写出 Out 类型的消息
+pub struct ProstCodec<In, Out> {
+    config: FrameConfig,
+    _msg: PhantomData<fn(Out) -> In>,
+}
+
+impl<In, Out> ProstCodec<In, Out> {
+    pub fn new(config: FrameConfig) -> Self {
+        Self { config, _msg: PhantomData }
+    }
+}
+
+impl<In, Out> Default for ProstCodec<In, Out> {
+    fn default() -> Self {
+        Self::new(FrameConfig::default())
+    }
+}
+
+impl<In: FrameCoder, Out> Decoder for ProstCodec<In, Out> {
+    type Item = In;
+    type Error = KvError;
+
+    fn decode(&mut self, src: &mut BytesMut) -> Result<Option<Self::Item>, Self::Error> {
+        if src.len() < LEN_LEN {
+            return Ok(None);
+        }
+        let header = u32::from_be_bytes([src[0], src[1], src[2], src[3]]);
+        let (_, len) = decode_header(header);
+        // 先检查长度再分配内存，否则一个伪造的 header 就能让我们预留上 GB 的空间
+        if len > self.config.max_size() {
+            return Err(frame_too_large(len));
+        }
+        if src.len() < LEN_LEN + len {
+            src.reserve(LEN_LEN + len - src.len());
+            return Ok(None);
+        }
+        In::decode_frame(src, &self.config).map(Some)
+    }
+}
+
+impl<In, Out: FrameCoder> Encoder<Out> for ProstCodec<In, Out> {
+    type Error = KvError;
+
+    fn encode(&mut self, item: Out, dst: &mut BytesMut) -> Result<(), Self::Error> {
+        match item.encode_frame(dst, &self.config) {
+            Err(KvError::FrameTooLarge(size)) => match Out::too_large(size) {
+                Some(item) => item.encode_frame(dst, &self.config),
+                None => Err(KvError::FrameTooLarge(size)),
+            },
+            result => result,
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use futures::prelude::*;
+    use tokio::io::duplex;
+    use tokio_util::codec::Framed;
+    use crate::{KvPair, Value};
+    use super::*;
+
+    fn big_response() -> CommandResponse {
+        let pairs: Vec<_> = (0..1000)
+            .map(|i| KvPair::new(format!("key{}", i), Value::from(format!("value{}", i))))
+            .collect();
+        pairs.into()
+    }
+
+    #[test]
+    fn command_request_encode_decode_should_work() {
+        let mut buf = BytesMut::new();
+        let cmd = CommandRequest::new_hset("t1", "k1", "v1".into());
+        cmd.encode_frame(&mut buf, &FrameConfig::default()).unwrap();
+        // 小消息不压缩
+        assert_eq!(buf.len(), LEN_LEN + cmd.encoded_len());
+        assert_eq!(buf[0] >> 6, 0);
+
+        let cmd1 = CommandRequest::decode_frame(&mut buf, &FrameConfig::default()).unwrap();
+        assert_eq!(cmd, cmd1);
+        assert!(buf.is_empty());
+    }
+
+    #[test]
+    fn large_frame_should_be_compressed_with_every_algorithm() {
+        let res = big_response();
+        for compression in [Compression::Gzip, Compression::Lz4, Compression::Zstd] {
+            let mut buf = BytesMut::new();
+            let config = FrameConfig { compression, ..Default::default() };
+            res.encode_frame(&mut buf, &config).unwrap();
+            assert_eq!((buf[0] >> 6) as u32, compression as u32);
+            assert!(buf.len() < res.encoded_len());
+
+            let res1 = CommandResponse::decode_frame(&mut buf, &config).unwrap();
+            assert_eq!(res, res1);
+        }
+    }
+
+    #[test]
+    fn compression_none_should_never_compress() {
+        let res = big_response();
+        let mut buf = BytesMut::new();
+        let config = FrameConfig { compression: Compression::None, ..Default::default() };
+        res.encode_frame(&mut buf, &config).unwrap();
+        assert_eq!(buf.len(), LEN_LEN + res.encoded_len());
+    }
+
+    #[test]
+    fn codec_should_wait_for_complete_frame() {
+        let mut codec = ProstCodec::<CommandResponse, CommandRequest>::default();
+        let mut buf = BytesMut::new();
+        big_response().encode_frame(&mut buf, &FrameConfig::default()).unwrap();
+
+        let mut partial = buf.split_to(buf.len() / 2);
+        assert_eq!(codec.decode(&mut partial).unwrap(), None);
+        partial.unsplit(buf);
+        assert_eq!(codec.decode(&mut partial).unwrap(), Some(big_response()));
+    }
+
+    #[test]
+    fn oversized_frame_should_be_rejected_before_reserving() {
+        let mut codec = ProstCodec::<CommandRequest, CommandResponse>::default();
+        let mut buf = BytesMut::new();
+        // 只有一个声称 payload 接近 1 GiB 的 header
+        buf.put_u32(LEN_MASK);
+        let err = codec.decode(&mut buf).unwrap_err();
+        assert!(matches!(err, KvError::FrameTooLarge(_)));
+        assert!(buf.capacity() < DEFAULT_MAX_FRAME_SIZE);
+    }
+
+    #[test]
+    fn frame_over_max_size_should_fail() {
+        let res = big_response();
+        let mut buf = BytesMut::new();
+        res.encode_frame(&mut buf, &FrameConfig::default()).unwrap();
+        let compressed = buf.len() - LEN_LEN;
+        assert!(compressed < res.encoded_len());
+
+        // 压缩后没有超过上限，但解压后超过了
+        let config = FrameConfig { max_frame_size: compressed, ..Default::default() };
+        let err = CommandResponse::decode_frame(&mut buf.clone(), &config).unwrap_err();
+        assert!(matches!(err, KvError::FrameError(_)));
+
+        let err = res.encode_frame(&mut BytesMut::new(), &config).unwrap_err();
+        assert!(matches!(err, KvError::FrameTooLarge(_)));
+    }
+
+    #[test]
+    fn codec_should_encode_oversized_response_as_error() {
+        let config = FrameConfig { max_frame_size: 1024, ..Default::default() };
+        let mut codec = ProstCodec::<CommandRequest, CommandResponse>::new(config);
+        let mut buf = BytesMut::new();
+        codec.encode(big_response(), &mut buf).unwrap();
+        let res = CommandResponse::decode_frame(&mut buf, &config).unwrap();
+        assert_eq!(res.status, 413);
+        assert!(res.pairs.is_empty());
+
+        // 请求太大时没有可以代替的消息，直接返回错误
+        let mut codec = ProstCodec::<CommandResponse, CommandRequest>::new(config);
+        let cmd = CommandRequest::new_hset("t1", "k1", "v".repeat(2048).into());
+        assert!(matches!(codec.encode(cmd, &mut BytesMut::new()), Err(KvError::FrameTooLarge(_))));
+    }
+
+    #[tokio::test]
+    async fn framed_stream_should_work() {
+        let (client, server) = duplex(1024 * 1024);
+        let mut client = Framed::new(client, ProstCodec::<CommandResponse, CommandRequest>::default());
+        let mut server = Framed::new(server, ProstCodec::<CommandRequest, CommandResponse>::default());
+
+        let cmd = CommandRequest::new_hgetall("t1");
+        client.send(cmd.clone()).await.unwrap();
+        assert_eq!(server.next().await.unwrap().unwrap(), cmd);
+
+        server.send(big_response()).await.unwrap();
+        assert_eq!(client.next().await.unwrap().unwrap(), big_response());
+    }
+}
@@ -0,0 +1,275 @@
+use std::convert::Infallible;
+use bytes::{Bytes, BytesMut};
+use http::{header, Method, StatusCode};
+use hyper::body::HttpBody;
+use hyper::server::conn::Http;
+use hyper::service::service_fn;
+use hyper::{Body, Request, Response};
+use tokio::sync::watch;
+use crate::*;
+use super::AsyncStream;
+
+/*
+    HTTP/JSON 网关，不方便使用 protobuf 的客户端可以直接用 JSON 访问 KV server：
+
+    - POST /cmd：body 是 JSON 格式的 CommandRequest，比如 {"hget": {"table": "t", "key": "k"}}
+    - GET /tables/{table}：HGETALL
+    - GET /tables/{table}/keys/{key}：HGET
+    - PUT /tables/{table}/keys/{key}：HSET，body 是 JSON 格式的 Value，比如 {"string": "hello"}，可以用 ?ttl= 指定过期的毫秒数
+    - DELETE /tables/{table}/keys/{key}：HDEL
+
+    response 的 body 都是 JSON 格式的 CommandResponse，HTTP 的状态码就是 CommandResponse.status。
+*/
+/// 请求的 body 最大 16MB
+const MAX_BODY_LEN: usize = 16 * 1024 * 1024;
+
+/// 处理一个 HTTP 连接上的所有请求。收到退出通知时，处理完正在进行的请求后关闭连接
+pub(crate) async fn handle_http_connection<S, Store>(
+    stream: S,
+    service: Service<Store>,
+    mut shutdown: watch::Receiver<()>,
+) -> Result<(), KvError>
+where
+    S: AsyncStream + 'static,
+    Store: Storage + Send + Sync + 'static,
+{
+    let handler = service_fn(move |req| {
+        let service = service.clone();
+        async move { Ok::<_, Infallible>(handle_request(req, &service).await) }
+    });
+    // 同时支持 HTTP/1.1 和 HTTP/2，由 hyper 根据客户端发来的数据判断
+    let conn = Http::new().serve_connection(stream, handler);
+    tokio::pin!(conn);
+    tokio::select! {
+        res = conn.as_mut() => return res.map_err(http_error),
+        _ = shutdown.changed() => conn.as_mut().graceful_shutdown(),
+    }
+    conn.await.map_err(http_error)
+}
+
+/// 把 HTTP 请求翻译成 CommandRequest 执行，再把 CommandResponse 翻译成 HTTP response
+pub async fn handle_request<Store: Storage + Send + Sync + 'static>(req: Request<Body>, service: &Service<Store>) -> Response<Body> {
+    let mut res = match parse_request(req).await {
+        Ok(cmd) => service.execute_async(cmd).await,
+        Err(res) => res,
+    };
+    service.before_send(&mut res);
+    let response = to_http_response(&res);
+    service.after_send();
+    response
+}
+
+async fn parse_request(req: Request<Body>) -> Result<CommandRequest, CommandResponse> {
+    let (parts, body) = req.into_parts();
+    let segments = parts
+        .uri
+        .path()
+        .split('/')
+        .filter(|s| !s.is_empty())
+        .map(percent_decode)
+        .collect::<Result<Vec<_>, _>>()
+        .map_err(CommandResponse::from)?;
+    let segments: Vec<_> = segments.iter().map(|s| s.as_str()).collect();
+
+    match (&parts.method, segments.as_slice()) {
+        (&Method::POST, ["cmd"]) => {
+            let body = read_body(body).await?;
+            serde_json::from_slice(&body).map_err(|e| invalid(format!("Invalid CommandRequest: {}", e)))
+        }
+        (&Method::GET, ["tables", table]) => Ok(CommandRequest::new_hgetall(*table)),
+        (&Method::GET, ["tables", table, "keys", key]) => Ok(CommandRequest::new_hget(*table, *key)),
+        (&Method::PUT, ["tables", table, "keys", key]) => {
+            let ttl = match query_param(parts.uri.query(), "ttl") {
+                Some(ttl) => ttl.parse().map_err(|_| invalid(format!("Invalid ttl: {}", ttl)))?,
+                None => 0,
+            };
+            let body = read_body(body).await?;
+            let value: Value = serde_json::from_slice(&body).map_err(|e| invalid(format!("Invalid Value: {}", e)))?;
+            Ok(CommandRequest::new_hset_with_ttl(*table, *key, value, ttl))
+        }
+        (&Method::DELETE, ["tables", table, "keys", key]) => Ok(CommandRequest::new_hdel(*table, *key)),
+        (_, ["cmd"]) | (_, ["tables", _]) | (_, ["tables", _, "keys", _]) => Err(error_response(
+            StatusCode::METHOD_NOT_ALLOWED,
+            format!("Method {} is not allowed", parts.method),
+        )),
+        _ => Err(error_response(
+            StatusCode::NOT_FOUND,
+            format!("No route for {}", parts.uri.path()),
+        )),
+    }
+}
+
+fn to_http_response(res: &CommandResponse) -> Response<Body> {
+    let status = StatusCode::from_u16(res.status as u16).unwrap_or(StatusCode::INTERNAL_SERVER_ERROR);
+    // CommandResponse 只包含 string、数字和 base64 编码的 binary，序列化不会失败
+    let body = serde_json::to_vec(res).unwrap_or_default();
+    Response::builder()
+        .status(status)
+        .header(header::CONTENT_TYPE, "application/json")
+        .body(Body::from(body))
+        .unwrap()
+}
+
+async fn read_body(mut body: Body) -> Result<Bytes, CommandResponse> {
+    let mut buf = BytesMut::new();
+    while let Some(chunk) = body.data().await {
+        let chunk = chunk.map_err(http_error)?;
+        if buf.len() + chunk.len() > MAX_BODY_LEN {
+            return Err(error_response(
+                StatusCode::PAYLOAD_TOO_LARGE,
+                format!("Request body is larger than {} bytes", MAX_BODY_LEN),
+            ));
+        }
+        buf.extend_from_slice(&chunk);
+    }
+    Ok(buf.freeze())
+}
+
+fn query_param<'a>(query: Option<&'a str>, name: &str) -> Option<&'a str> {
+    query?
+        .split('&')
+        .filter_map(|kv| kv.split_once('='))
+        .find(|(k, _)| *k == name)
+        .map(|(_, v)| v)
+}
+
+/// 路径中的 table 和 key 可能经过了 URL 编码，比如空格是 %20
+fn percent_decode(s: &str) -> Result<String, KvError> {
+    let bytes = s.as_bytes();
+    let mut decoded = Vec::with_capacity(bytes.len());
+    let mut i = 0;
+    while i < bytes.len() {
+        match bytes[i] {
+            b'%' => {
+                let hex = bytes
+                    .get(i + 1..i + 3)
+                    .and_then(|h| std::str::from_utf8(h).ok())
+                    .and_then(|h| u8::from_str_radix(h, 16).ok())
+                    .ok_or_else(|| KvError::InvalidCommand(format!("Invalid percent-encoding in {}", s)))?;
+                decoded.push(hex);
+                i += 3;
+            }
+            c => {
+                decoded.push(c);
+                i += 1;
+            }
+        }
+    }
+    String::from_utf8(decoded).map_err(|_| KvError::InvalidCommand(format!("Path is not valid UTF-8: {}", s)))
+}
+
+fn invalid(msg: String) -> CommandResponse {
+    KvError::InvalidCommand(msg).into()
+}
+
+fn error_response(status: StatusCode, message: String) -> CommandResponse {
+    CommandResponse {
+        status: status.as_u16() as _,
+        message,
+        ..Default::default()
+    }
+}
+
+fn http_error(e: hyper::Error) -> KvError {
+    KvError::IoError(format!("HTTP error: {}", e))
+}
+
+#[cfg(test)]
+mod tests {
+    use tokio::io::{duplex, AsyncReadExt, AsyncWriteExt};
+    use crate::memory::MemTable;
+    use super::*;
+
+    async fn call(service: &Service, method: Method, uri: &str, body: &str) -> (StatusCode, CommandResponse) {
+        let req = Request::builder()
+            .method(method)
+            .uri(uri)
+            .body(Body::from(body.to_string()))
+            .unwrap();
+        let res = handle_request(req, service).await;
+        let status = res.status();
+        let body = hyper::body::to_bytes(res.into_body()).await.unwrap();
+        (status, serde_json::from_slice(&body).unwrap())
+    }
+
+    #[test]
+    fn command_request_json_should_use_oneof_directly() {
+        let cmd: CommandRequest = serde_json::from_str(r#"{"hget": {"table": "t", "key": "k"}}"#).unwrap();
+        assert_eq!(cmd, CommandRequest::new_hget("t", "k"));
+
+        // 缺少的字段使用缺省值，binary 使用 base64
+        let cmd: CommandRequest =
+            serde_json::from_str(r#"{"hset": {"table": "t", "pair": {"key": "k", "value": {"binary": "aGVsbG8="}}}}"#)
+                .unwrap();
+        let value = Value {
+            value: Some(value::Value::Binary(Bytes::from_static(b"hello"))),
+        };
+        assert_eq!(cmd, CommandRequest::new_hset("t", "k", value.clone()));
+        assert_eq!(serde_json::to_string(&value).unwrap(), r#"{"binary":"aGVsbG8="}"#);
+        assert_eq!(serde_json::to_string(&Value::from(1)).unwrap(), r#"{"integer":1}"#);
+        assert_eq!(serde_json::to_string(&Value::default()).unwrap(), "null");
+    }
+
+    #[tokio::test]
+    async fn rest_routes_should_work() {
+        let service: Service = Service::new(MemTable::new());
+        let (status, res) = call(&service, Method::PUT, "/tables/t1/keys/user%3A1", r#"{"string": "v1"}"#).await;
+        assert_eq!(status, StatusCode::OK);
+        assert_eq!(res.values, vec![Value::default()]);
+
+        let (status, res) = call(&service, Method::GET, "/tables/t1/keys/user:1", "").await;
+        assert_eq!(status, StatusCode::OK);
+        assert_eq!(res.values, vec![Value::from("v1")]);
+
+        let (_, res) = call(&service, Method::GET, "/tables/t1", "").await;
+        assert_eq!(res.pairs, vec![KvPair::new("user:1", "v1".into())]);
+
+        let (status, _) = call(&service, Method::DELETE, "/tables/t1/keys/user:1", "").await;
+        assert_eq!(status, StatusCode::OK);
+        let (status, res) = call(&service, Method::GET, "/tables/t1/keys/user:1", "").await;
+        assert_eq!(status, StatusCode::NOT_FOUND);
+        assert_eq!(res.status, 404);
+    }
+
+    #[tokio::test]
+    async fn put_with_ttl_should_expire() {
+        let service: Service = Service::new(MemTable::new());
+        call(&service, Method::PUT, "/tables/t1/keys/k1?ttl=60000", r#"{"integer": 42}"#).await;
+        let (_, res) = call(&service, Method::POST, "/cmd", r#"{"ttl": {"table": "t1", "key": "k1"}}"#).await;
+        assert!(matches!(res.values[0].value, Some(value::Value::Integer(ttl)) if ttl > 0 && ttl <= 60000));
+    }
+
+    #[tokio::test]
+    async fn invalid_requests_should_be_rejected() {
+        let service: Service = Service::new(MemTable::new());
+        let (status, res) = call(&service, Method::POST, "/cmd", "not json").await;
+        assert_eq!(status, StatusCode::BAD_REQUEST);
+        assert!(res.message.contains("Invalid CommandRequest"));
+
+        let (status, _) = call(&service, Method::POST, "/cmd", "{}").await;
+        assert_eq!(status, StatusCode::BAD_REQUEST);
+        let (status, _) = call(&service, Method::GET, "/cmd", "").await;
+        assert_eq!(status, StatusCode::METHOD_NOT_ALLOWED);
+        let (status, _) = call(&service, Method::GET, "/unknown", "").await;
+        assert_eq!(status, StatusCode::NOT_FOUND);
+    }
+
+    #[tokio::test]
+    async fn http_connection_should_work() {
+        let (mut client, server) = duplex(64 * 1024);
+        let service: Service = Service::new(MemTable::new());
+        service.execute(CommandRequest::new_hset("t1", "k1", "v1".into()));
+        let (_shutdown_tx, shutdown_rx) = watch::channel(());
+        tokio::spawn(handle_http_connection(server, service, shutdown_rx));
+
+        client
+            .write_all(b"GET /tables/t1/keys/k1 HTTP/1.1\r\nHost: localhost\r\nConnection: close\r\n\r\n")
+            .await
+            .unwrap();
+        let mut buf = String::new();
+        client.read_to_string(&mut buf).await.unwrap();
+        assert!(buf.starts_with("HTTP/1.1 200 OK\r\n"));
+        assert!(buf.contains("content-type: application/json"));
+        assert!(buf.ends_with(r#""values":[{"string":"v1"}],"pairs":[]}"#));
+    }
+}
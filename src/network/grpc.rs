@@ -0,0 +1,196 @@
+use std::pin::Pin;
+use std::task::{Context, Poll};
+use futures::Stream;
+use hyper::server::conn::Http;
+use tokio::sync::{mpsc, watch};
+use tonic::{Code, Request, Response, Status};
+use crate::*;
+use crate::kv_service_server::{KvService, KvServiceServer};
+use super::AsyncStream;
+
+/*
+    abi.proto 中定义的 KvService，让其它语言可以直接用 gRPC 访问 KV server，不需要实现我们自己的 frame 格式。
+
+    gRPC 连接和其它协议一样由 Server::run 接受，经过同样的连接数限制和 TLS 处理，然后交给 hyper 按 HTTP/2 处理。
+    Execute 的结果和其它协议一样放在 CommandResponse.status 中，只有 Scan 出错时才使用 gRPC 的状态码。
+*/
+/// Scan 最多缓存多少个还没发给客户端的 kv pair
+const SCAN_BUFFER: usize = 128;
+
+/// 把 Service 包装成 gRPC 的 KvService
+pub struct GrpcService<Store = memory::MemTable> {
+    service: Service<Store>,
+}
+
+impl<Store> GrpcService<Store> {
+    pub fn new(service: Service<Store>) -> Self {
+        Self { service }
+    }
+}
+
+#[tonic::async_trait]
+impl<Store: Storage + Send + Sync + 'static> KvService for GrpcService<Store> {
+    async fn execute(&self, request: Request<CommandRequest>) -> Result<Response<CommandResponse>, Status> {
+        let mut res = self.service.execute_async(request.into_inner()).await;
+        self.service.before_send(&mut res);
+        let response = Response::new(res);
+        self.service.after_send();
+        Ok(response)
+    }
+
+    type ScanStream = ScanStream;
+
+    async fn scan(&self, request: Request<ScanRequest>) -> Result<Response<Self::ScanStream>, Status> {
+        let ScanRequest { table, prefix } = request.into_inner();
+        let (tx, rx) = mpsc::channel(SCAN_BUFFER);
+        let service = self.service.clone();
+        // 遍历 table 是阻塞的操作，放在单独的线程中进行。客户端读得慢时 channel 会满，遍历也就停下来等待
+        tokio::task::spawn_blocking(move || match service.scan(&table, &prefix) {
+            Ok(iter) => {
+                for pair in iter {
+                    // 客户端断开了，不用再遍历
+                    if tx.blocking_send(Ok(pair)).is_err() {
+                        break;
+                    }
+                }
+            }
+            Err(e) => {
+                let _ = tx.blocking_send(Err(to_status(e)));
+            }
+        });
+        Ok(Response::new(ScanStream { rx }))
+    }
+}
+
+/// Scan 返回的 kv pair stream
+pub struct ScanStream {
+    rx: mpsc::Receiver<Result<KvPair, Status>>,
+}
+
+impl Stream for ScanStream {
+    type Item = Result<KvPair, Status>;
+
+    fn poll_next(mut self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<Option<Self::Item>> {
+        self.rx.poll_recv(cx)
+    }
+}
+
+/// 使用和 CommandResponse 一样的规则把 KvError 转换成 gRPC 的状态码
+fn to_status(e: KvError) -> Status {
+    let res: CommandResponse = e.into();
+    let code = match res.status {
+        400 => Code::InvalidArgument,
+        404 => Code::NotFound,
+        409 => Code::Aborted,
+        412 => Code::FailedPrecondition,
+        504 => Code::DeadlineExceeded,
+        507 => Code::ResourceExhausted,
+        _ => Code::Internal,
+    };
+    Status::new(code, res.message)
+}
+
+/// 处理一个 gRPC 连接。收到退出通知时，处理完正在进行的请求后关闭连接
+pub(crate) async fn handle_grpc_connection<S, Store>(
+    stream: S,
+    service: Service<Store>,
+    mut shutdown: watch::Receiver<()>,
+) -> Result<(), KvError>
+where
+    S: AsyncStream + 'static,
+    Store: Storage + Send + Sync + 'static,
+{
+    let svc = KvServiceServer::new(GrpcService::new(service));
+    let conn = Http::new().http2_only(true).serve_connection(stream, svc);
+    tokio::pin!(conn);
+    tokio::select! {
+        res = conn.as_mut() => return res.map_err(grpc_error),
+        _ = shutdown.changed() => conn.as_mut().graceful_shutdown(),
+    }
+    conn.await.map_err(grpc_error)
+}
+
+fn grpc_error(e: hyper::Error) -> KvError {
+    KvError::IoError(format!("gRPC error: {}", e))
+}
+
+#[cfg(test)]
+mod tests {
+    use std::net::SocketAddr;
+    use tokio::net::TcpListener;
+    use tokio::sync::oneshot;
+    use crate::kv_service_client::KvServiceClient;
+    use crate::memory::MemTable;
+    use crate::network::Server;
+    use super::*;
+
+    async fn start_server(service: Service) -> (SocketAddr, oneshot::Sender<()>) {
+        // 主 listener 不会用到，只用来满足 Server::run 的参数
+        let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let grpc = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let addr = grpc.local_addr().unwrap();
+        let (tx, rx) = oneshot::channel::<()>();
+        tokio::spawn(Server::new(service).grpc(grpc).run(listener, async move {
+            let _ = rx.await;
+        }));
+        (addr, tx)
+    }
+
+    #[test]
+    fn conflict_should_map_to_aborted() {
+        let status = to_status(KvError::KeyExists("t1".into(), "k1".into()));
+        assert_eq!(status.code(), Code::Aborted);
+        assert_eq!(status.message(), "Key already exists in table: t1, key: k1");
+    }
+
+    #[test]
+    fn precondition_failed_should_map_to_failed_precondition() {
+        let status = to_status(KvError::PreconditionFailed("t1".into(), "k1".into()));
+        assert_eq!(status.code(), Code::FailedPrecondition);
+        assert_eq!(status.message(), "Precondition failed for table: t1, key: k1");
+    }
+
+    #[tokio::test]
+    async fn grpc_execute_and_scan_should_work() {
+        let (addr, _shutdown) = start_server(Service::new(MemTable::new())).await;
+        let mut client = KvServiceClient::connect(format!("http://{}", addr)).await.unwrap();
+
+        let pairs = vec![
+            KvPair::new("user:1", "alice".into()),
+            KvPair::new("user:2", "bob".into()),
+            KvPair::new("order:1", 42.into()),
+        ];
+        let res = client.execute(CommandRequest::new_hmset("t1", pairs)).await.unwrap().into_inner();
+        assert_eq!(res.status, 200);
+        let res = client.execute(CommandRequest::new_hget("t1", "nope")).await.unwrap().into_inner();
+        assert_eq!(res.status, 404);
+
+        let request = ScanRequest { table: "t1".into(), prefix: "user:".into() };
+        let mut stream = client.scan(request).await.unwrap().into_inner();
+        let mut pairs = Vec::new();
+        while let Some(pair) = stream.message().await.unwrap() {
+            pairs.push(pair);
+        }
+        pairs.sort_by(|a, b| a.key.cmp(&b.key));
+        assert_eq!(
+            pairs,
+            vec![KvPair::new("user:1", "alice".into()), KvPair::new("user:2", "bob".into())]
+        );
+    }
+
+    #[tokio::test]
+    async fn grpc_scan_should_respect_on_received() {
+        let inner = ServiceInner::new(MemTable::new()).fn_received(|cmd| match &cmd.request_data {
+            Some(command_request::RequestData::Hgetall(_)) => Err(KvError::InvalidCommand("forbidden".into())),
+            _ => Ok(()),
+        });
+        let (addr, _shutdown) = start_server(inner.into()).await;
+        let mut client = KvServiceClient::connect(format!("http://{}", addr)).await.unwrap();
+
+        let request = ScanRequest { table: "t1".into(), ..Default::default() };
+        let mut stream = client.scan(request).await.unwrap().into_inner();
+        let status = stream.message().await.unwrap_err();
+        assert_eq!(status.code(), Code::InvalidArgument);
+        assert!(status.message().contains("forbidden"));
+    }
+}
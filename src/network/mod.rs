@@ -0,0 +1,449 @@
+pub mod frame;
+pub mod gateway;
+pub mod grpc;
+pub mod multiplex;
+pub mod resp;
+pub mod tls;
+
+use std::collections::HashSet;
+use std::future::Future;
+use std::io;
+use std::net::SocketAddr;
+use std::sync::Arc;
+use std::time::Duration;
+use futures::prelude::*;
+use futures::stream::SelectAll;
+use tokio::io::{AsyncRead, AsyncWrite};
+use tokio::net::{TcpListener, TcpStream};
+use tokio::sync::{mpsc, watch, OwnedSemaphorePermit, Semaphore};
+use tokio::time;
+use tokio_util::codec::Framed;
+use tracing::{info, warn};
+use crate::*;
+use crate::command_request::RequestData;
+use crate::memory::MemTable;
+use crate::topic::Subscription;
+use frame::{FrameConfig, ProstCodec};
+use tls::TlsServerAcceptor;
+
+/// 服务器端的 frame stream：读取 CommandRequest，写出 CommandResponse
+pub type ProstServerStream<S> = Framed<S, ProstCodec<CommandRequest, CommandResponse>>;
+/// 客户端的 frame stream：读取 CommandResponse，写出 CommandRequest
+pub type ProstClientStream<S> = Framed<S, ProstCodec<CommandResponse, CommandRequest>>;
+
+/// 可以读写的异步连接，比如 TcpStream 或者 TLS stream
+pub trait AsyncStream: AsyncRead + AsyncWrite + Unpin + Send {}
+
+impl<S: AsyncRead + AsyncWrite + Unpin + Send> AsyncStream for S {}
+
+/// TLS 握手缺省的超时时间
+pub const DEFAULT_HANDSHAKE_TIMEOUT: Duration = Duration::from_secs(10);
+
+/// 处理 TCP 连接的服务器，收到 shutdown 信号后会等待已有的连接处理完，再把数据落盘
+pub struct Server<Store = MemTable> {
+    service: Service<Store>,
+    max_connections: Option<usize>,
+    shutdown_timeout: Duration,
+    frame_config: FrameConfig,
+    tls: Option<TlsServerAcceptor>,
+    handshake_timeout: Duration,
+    multiplex: bool,
+    resp: Option<TcpListener>,
+    http: Option<TcpListener>,
+    grpc: Option<TcpListener>,
+}
+
+/// 连接使用的协议
+#[derive(Clone, Copy, Debug)]
+enum Protocol {
+    /// 我们自己的 protobuf frame
+    Prost,
+    /// 兼容 Redis 的 RESP
+    Resp,
+    /// HTTP/JSON 网关
+    Http,
+    /// gRPC 的 KvService
+    Grpc,
+}
+
+impl<Store: Storage + Send + Sync + 'static> Server<Store> {
+    pub fn new(service: Service<Store>) -> Self {
+        Self {
+            service,
+            max_connections: None,
+            shutdown_timeout: Duration::from_secs(30),
+            frame_config: FrameConfig::default(),
+            tls: None,
+            handshake_timeout: DEFAULT_HANDSHAKE_TIMEOUT,
+            multiplex: false,
+            resp: None,
+            http: None,
+            grpc: None,
+        }
+    }
+
+    /// 同时在另一个 listener 上提供兼容 Redis 的 RESP 协议，和主 listener 共享连接数限制和 TLS 配置
+    pub fn resp(mut self, listener: TcpListener) -> Self {
+        self.resp = Some(listener);
+        self
+    }
+
+    /// 同时在另一个 listener 上提供 HTTP/JSON 网关，和主 listener 共享连接数限制和 TLS 配置
+    pub fn http(mut self, listener: TcpListener) -> Self {
+        self.http = Some(listener);
+        self
+    }
+
+    /// 同时在另一个 listener 上提供 gRPC 的 KvService，和主 listener 共享连接数限制和 TLS 配置
+    pub fn grpc(mut self, listener: TcpListener) -> Self {
+        self.grpc = Some(listener);
+        self
+    }
+
+    /// 每个连接上使用 yamux 多路复用，客户端也需要开启
+    pub fn multiplex(mut self, enabled: bool) -> Self {
+        self.multiplex = enabled;
+        self
+    }
+
+    /// 使用 TLS 加密连接
+    pub fn tls(mut self, acceptor: TlsServerAcceptor) -> Self {
+        self.tls = Some(acceptor);
+        self
+    }
+
+    /// TLS 握手最多等待多久，超时的连接直接关闭，不会一直占着连接数的名额
+    pub fn handshake_timeout(mut self, timeout: Duration) -> Self {
+        self.handshake_timeout = timeout;
+        self
+    }
+
+    /// 发送 response 时使用的压缩配置
+    pub fn frame_config(mut self, config: FrameConfig) -> Self {
+        self.frame_config = config;
+        self
+    }
+
+    /// 最多同时处理多少个连接，超过之后新连接要等到有连接断开才会被 accept
+    pub fn max_connections(mut self, n: usize) -> Self {
+        self.max_connections = Some(n);
+        self
+    }
+
+    /// 退出时最多等待已有的连接多久
+    pub fn shutdown_timeout(mut self, timeout: Duration) -> Self {
+        self.shutdown_timeout = timeout;
+        self
+    }
+
+    /// 在 listener 上处理连接，直到 shutdown 完成。
+    /// 退出时不再接受新连接，每个连接处理完手上的请求后关闭，最后调用 Service::flush 把数据落盘
+    pub async fn run(self, listener: TcpListener, shutdown: impl Future<Output = ()>) -> Result<(), KvError> {
+        let limit = self.max_connections.map(|n| Arc::new(Semaphore::new(n)));
+        // 通知所有连接退出
+        let (notify_tx, notify_rx) = watch::channel(());
+        // 每个连接都持有一个 done_tx，全部连接结束后 done_rx 会收到 None
+        let (done_tx, mut done_rx) = mpsc::channel::<()>(1);
+
+        tokio::pin!(shutdown);
+        loop {
+            // 先拿到连接数的名额再 accept，这样两个 listener 不会互相占用名额
+            let permit = tokio::select! {
+                permit = acquire(&limit) => permit?,
+                _ = &mut shutdown => break,
+            };
+            let (res, protocol) = tokio::select! {
+                res = listener.accept() => (res, Protocol::Prost),
+                res = accept_optional(&self.resp) => (res, Protocol::Resp),
+                res = accept_optional(&self.http) => (res, Protocol::Http),
+                res = accept_optional(&self.grpc) => (res, Protocol::Grpc),
+                _ = &mut shutdown => break,
+            };
+            let (stream, addr) = match res {
+                Ok(conn) => conn,
+                Err(e) => {
+                    warn!("Failed to accept connection: {:?}", e);
+                    continue;
+                }
+            };
+            info!("Client {:?} connected with {:?}", addr, protocol);
+            let svc = self.service.clone();
+            let notify_rx = notify_rx.clone();
+            let done_tx = done_tx.clone();
+            let tls = self.tls.clone();
+            let (frame_config, multiplex, handshake_timeout) = (self.frame_config, self.multiplex, self.handshake_timeout);
+            tokio::spawn(async move {
+                // TLS 握手在每个连接自己的 task 中完成，不会阻塞 accept
+                let result = match tls {
+                    Some(acceptor) => match time::timeout(handshake_timeout, acceptor.accept(stream)).await {
+                        Ok(Ok(stream)) => serve(stream, protocol, frame_config, multiplex, svc, notify_rx).await,
+                        Ok(Err(e)) => Err(e),
+                        Err(_) => Err(KvError::Timeout),
+                    },
+                    None => serve(stream, protocol, frame_config, multiplex, svc, notify_rx).await,
+                };
+                if let Err(e) = result {
+                    warn!("Client {:?} error: {:?}", addr, e);
+                }
+                info!("Client {:?} disconnected", addr);
+                drop(permit);
+                drop(done_tx);
+            });
+        }
+
+        info!("Shutting down, waiting for connections to finish");
+        drop(listener);
+        drop(self.resp);
+        drop(self.http);
+        drop(self.grpc);
+        let _ = notify_tx.send(());
+        drop(done_tx);
+        if time::timeout(self.shutdown_timeout, done_rx.recv()).await.is_err() {
+            warn!("Timed out waiting for connections after {:?}", self.shutdown_timeout);
+        }
+        self.service.flush()
+    }
+}
+
+/// 有连接数限制时，先拿到一个名额
+async fn acquire(limit: &Option<Arc<Semaphore>>) -> Result<Option<OwnedSemaphorePermit>, KvError> {
+    match limit {
+        Some(limit) => Ok(Some(
+            Arc::clone(limit)
+                .acquire_owned()
+                .await
+                .map_err(|e| KvError::Internal(e.to_string()))?,
+        )),
+        None => Ok(None),
+    }
+}
+
+/// 没有配置的 listener 永远不会返回
+async fn accept_optional(listener: &Option<TcpListener>) -> io::Result<(TcpStream, SocketAddr)> {
+    match listener {
+        Some(listener) => listener.accept().await,
+        None => future::pending().await,
+    }
+}
+
+async fn serve<S, Store>(
+    stream: S,
+    protocol: Protocol,
+    frame_config: FrameConfig,
+    multiplex: bool,
+    service: Service<Store>,
+    shutdown: watch::Receiver<()>,
+) -> Result<(), KvError>
+where
+    S: AsyncStream + 'static,
+    Store: Storage + Send + Sync + 'static,
+{
+    match (protocol, multiplex) {
+        (Protocol::Resp, _) => resp::handle_resp_connection(stream, service, shutdown).await,
+        (Protocol::Http, _) => gateway::handle_http_connection(stream, service, shutdown).await,
+        (Protocol::Grpc, _) => grpc::handle_grpc_connection(stream, service, shutdown).await,
+        (Protocol::Prost, true) => multiplex::serve_multiplexed(stream, frame_config, service, shutdown).await,
+        (Protocol::Prost, false) => {
+            handle_connection(Framed::new(stream, ProstCodec::new(frame_config)), service, shutdown).await
+        }
+    }
+}
+
+/// 处理一个连接上的所有请求。收到退出通知时，正在处理的请求会处理完并发出 response。
+/// 连接上 SUBSCRIBE 或者 WATCH 之后，订阅收到的消息会和其它请求的 response 一起发给客户端，直到取消订阅或者连接断开。
+/// UNSUBSCRIBE / UNWATCH 只能取消这个连接自己的订阅
+async fn handle_connection<S: AsyncStream, Store: Storage + Send + Sync + 'static>(
+    mut stream: ProstServerStream<S>,
+    service: Service<Store>,
+    mut shutdown: watch::Receiver<()>,
+) -> Result<(), KvError> {
+    let mut subscriptions = Subscriptions::default();
+    loop {
+        let cmd = tokio::select! {
+            cmd = stream.next() => cmd,
+            Some(mut msg) = subscriptions.streams.next(), if !subscriptions.streams.is_empty() => {
+                service.before_send(&mut msg);
+                stream.send(msg).await?;
+                service.after_send();
+                continue;
+            }
+            _ = shutdown.changed() => break,
+        };
+        let cmd = match cmd {
+            Some(cmd) => cmd?,
+            None => break,
+        };
+        let mut res = match cmd.request_data {
+            Some(RequestData::Subscribe(param)) => subscriptions.add(service.subscribe(&param.topic), false),
+            Some(RequestData::Watch(param)) => subscriptions.add(service.watch(&param.table), true),
+            Some(RequestData::Unsubscribe(param)) => {
+                subscriptions.remove(param.topic, param.id, false, |topic, id| service.unsubscribe(topic, id))
+            }
+            Some(RequestData::Unwatch(param)) => {
+                subscriptions.remove(param.table, param.id, true, |table, id| service.unwatch(table, id))
+            }
+            request_data => service.execute_async(CommandRequest { request_data }).await,
+        };
+        service.before_send(&mut res);
+        stream.send(res).await?;
+        service.after_send();
+    }
+    Ok(())
+}
+
+/*
+    SUBSCRIBE 和 WATCH 的 id 是依次分配的，很容易猜到，所以连接要记下自己创建了哪些订阅，
+    不是这个连接的订阅和不存在的订阅一样返回 404。SUBSCRIBE 和 WATCH 各自分配 id，所以要记下是哪一种。
+*/
+/// 一个连接上的订阅
+#[derive(Default)]
+struct Subscriptions {
+    streams: SelectAll<Subscription>,
+    // (topic 或者 table, id, 是否是 WATCH)
+    owned: HashSet<(String, u32, bool)>,
+}
+
+impl Subscriptions {
+    /// 订阅成功时把它加到连接的订阅中，返回订阅的 id
+    fn add(&mut self, result: Result<Subscription, KvError>, watch: bool) -> CommandResponse {
+        match result {
+            Ok(subscription) => {
+                let res = Value::from(subscription.id() as i64).into();
+                self.owned.insert((subscription.topic().to_string(), subscription.id(), watch));
+                self.streams.push(subscription);
+                res
+            }
+            Err(e) => e.into(),
+        }
+    }
+
+    /// 取消这个连接自己的订阅
+    fn remove(
+        &mut self,
+        topic: String,
+        id: u32,
+        watch: bool,
+        cancel: impl FnOnce(&str, u32) -> Result<(), KvError>,
+    ) -> CommandResponse {
+        let key = (topic, id, watch);
+        if !self.owned.contains(&key) {
+            return KvError::SubscriptionNotFound(key.0, id).into();
+        }
+        match cancel(&key.0, id) {
+            Ok(()) => {
+                self.owned.remove(&key);
+                Value::from(true).into()
+            }
+            Err(e) => e.into(),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use tempfile::tempdir;
+    use tokio::sync::oneshot;
+    use super::*;
+
+    #[tokio::test]
+    async fn server_should_serve_and_flush_on_shutdown() {
+        let dir = tempdir().unwrap();
+        let path = dir.path().join("dump.kvs");
+        let service = Service::new(MemTable::new().snapshot_path(&path));
+        let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let addr = listener.local_addr().unwrap();
+        let (shutdown_tx, shutdown_rx) = oneshot::channel::<()>();
+        let server = tokio::spawn(
+            Server::new(service)
+                .max_connections(1)
+                .run(listener, async move {
+                    let _ = shutdown_rx.await;
+                }),
+        );
+
+        let stream = TcpStream::connect(addr).await.unwrap();
+        let mut client = ProstClientStream::new(stream, ProstCodec::default());
+        client.send(CommandRequest::new_hset("t1", "k1", "v1".into())).await.unwrap();
+        let res = client.next().await.unwrap().unwrap();
+        assert_eq!(res.status, 200);
+
+        // 退出时连接被关闭，数据写入快照
+        shutdown_tx.send(()).unwrap();
+        assert!(client.next().await.is_none());
+        server.await.unwrap().unwrap();
+        let store = MemTable::load_snapshot(&path).unwrap();
+        assert_eq!(store.get("t1", "k1"), Ok(Some("v1".into())));
+    }
+
+    #[tokio::test]
+    async fn oversized_response_should_not_close_connection() {
+        let service = Service::new(MemTable::new());
+        for i in 0..100 {
+            service.execute(CommandRequest::new_hset("t1", format!("key{}", i), "v".repeat(100).into()));
+        }
+        let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let addr = listener.local_addr().unwrap();
+        let config = FrameConfig { max_frame_size: 4096, ..Default::default() };
+        tokio::spawn(Server::new(service).frame_config(config).run(listener, future::pending()));
+
+        let stream = TcpStream::connect(addr).await.unwrap();
+        let mut client = ProstClientStream::new(stream, ProstCodec::new(config));
+        client.send(CommandRequest::new_hgetall("t1")).await.unwrap();
+        let res = client.next().await.unwrap().unwrap();
+        assert_eq!(res.status, 413);
+
+        // 连接还能继续使用
+        client.send(CommandRequest::new_hget("t1", "key1")).await.unwrap();
+        let res = client.next().await.unwrap().unwrap();
+        assert_eq!(res.values, vec!["v".repeat(100).into()]);
+    }
+
+    #[tokio::test]
+    async fn stalled_tls_handshake_should_time_out() {
+        use tokio::io::AsyncReadExt;
+        use crate::network::tls::tests::TestCerts;
+
+        let certs = TestCerts::generate();
+        let acceptor = TlsServerAcceptor::new(&certs.server.0, &certs.server.1, None).unwrap();
+        let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let addr = listener.local_addr().unwrap();
+        let server = Server::new(Service::new(MemTable::new()))
+            .tls(acceptor)
+            .handshake_timeout(Duration::from_millis(100));
+        tokio::spawn(server.run(listener, future::pending()));
+
+        // 连上之后什么都不发，服务器在超时之后关闭连接
+        let mut stream = TcpStream::connect(addr).await.unwrap();
+        let mut buf = [0u8; 16];
+        let n = time::timeout(Duration::from_secs(5), stream.read(&mut buf)).await.unwrap();
+        assert!(matches!(n, Ok(0) | Err(_)));
+    }
+
+    #[tokio::test]
+    async fn unsubscribe_should_only_cancel_own_subscriptions() {
+        let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let addr = listener.local_addr().unwrap();
+        tokio::spawn(Server::new(Service::new(MemTable::new())).run(listener, future::pending()));
+        let connect = || async { ProstClientStream::new(TcpStream::connect(addr).await.unwrap(), ProstCodec::default()) };
+        let mut owner = connect().await;
+        let mut other = connect().await;
+
+        owner.send(CommandRequest::new_subscribe("news")).await.unwrap();
+        let id = match owner.next().await.unwrap().unwrap().values[0].value {
+            Some(value::Value::Integer(id)) => id as u32,
+            ref v => panic!("unexpected subscription id: {:?}", v),
+        };
+
+        // 其它连接取消不了，和订阅不存在一样返回 404
+        other.send(CommandRequest::new_unsubscribe("news", id)).await.unwrap();
+        assert_eq!(other.next().await.unwrap().unwrap().status, 404);
+        other.send(CommandRequest::new_unwatch("news", id)).await.unwrap();
+        assert_eq!(other.next().await.unwrap().unwrap().status, 404);
+
+        // 创建订阅的连接可以取消
+        owner.send(CommandRequest::new_unsubscribe("news", id)).await.unwrap();
+        assert_eq!(owner.next().await.unwrap().unwrap().values, vec![true.into()]);
+        other.send(CommandRequest::new_publish("news", vec!["hello".into()])).await.unwrap();
+        assert_eq!(other.next().await.unwrap().unwrap().values, vec![0.into()]);
+    }
+}
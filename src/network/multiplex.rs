@@ -0,0 +1,225 @@
+use std::task::Poll;
+use futures::future;
+use tokio::sync::{mpsc, oneshot, watch};
+use tokio_util::codec::Framed;
+use tokio_util::compat::{Compat, FuturesAsyncReadCompatExt, TokioAsyncReadCompatExt};
+use tracing::warn;
+use yamux::{Config, Connection, ConnectionError, Mode};
+use crate::*;
+use super::frame::{FrameConfig, ProstCodec};
+use super::{handle_connection, AsyncStream};
+
+/*
+    使用 yamux 在一个连接上多路复用多个 stream。每个 stream 都是一个独立的 frame stream，
+    服务器端为每个 stream 启动一个 task，用 Service::execute 处理它上面的请求，所以一个很慢的 HGETALL 不会挡住其它 stream 上的 HGET。
+
+    yamux 的 Connection 需要一直被 poll 才能收发数据，所以客户端和服务器端都由一个单独的 task 驱动它。
+*/
+/// 打开新 stream 的请求，结果通过 oneshot 返回
+type OpenRequest = oneshot::Sender<Result<yamux::Stream, KvError>>;
+
+/// 多路复用的 stream，可以直接当作 AsyncRead / AsyncWrite 使用
+pub type MuxStream = Compat<yamux::Stream>;
+
+/// 客户端的 yamux 连接控制器，用来打开新的 stream。clone 是轻量级的
+#[derive(Clone)]
+pub struct YamuxCtrl {
+    tx: mpsc::Sender<OpenRequest>,
+}
+
+impl YamuxCtrl {
+    /// 在 stream 上建立 yamux 连接，并启动一个 task 驱动它
+    pub fn new_client<S: AsyncStream + 'static>(stream: S) -> Self {
+        let (tx, rx) = mpsc::channel(32);
+        let conn = Connection::new(stream.compat(), Config::default(), Mode::Client);
+        tokio::spawn(drive_client(conn, rx));
+        Self { tx }
+    }
+
+    /// 打开一个新的 stream
+    pub async fn open_stream(&self) -> Result<MuxStream, KvError> {
+        let (tx, rx) = oneshot::channel();
+        self.tx.send(tx).await.map_err(|_| closed())?;
+        Ok(rx.await.map_err(|_| closed())??.compat())
+    }
+
+    /// 底层连接是否已经断开
+    pub fn is_closed(&self) -> bool {
+        self.tx.is_closed()
+    }
+}
+
+async fn drive_client<S: AsyncStream>(mut conn: Connection<Compat<S>>, mut rx: mpsc::Receiver<OpenRequest>) {
+    let mut pending: Option<OpenRequest> = None;
+    let result = future::poll_fn(|cx| loop {
+        // 先处理打开新 stream 的请求，同一时间只处理一个
+        match pending.take() {
+            Some(req) => match conn.poll_new_outbound(cx) {
+                Poll::Ready(res) => {
+                    let _ = req.send(res.map_err(mux_error));
+                    continue;
+                }
+                Poll::Pending => pending = Some(req),
+            },
+            None => match rx.poll_recv(cx) {
+                Poll::Ready(Some(req)) => {
+                    pending = Some(req);
+                    continue;
+                }
+                // 所有的 YamuxCtrl 都被 drop 了
+                Poll::Ready(None) => return Poll::Ready(Ok(())),
+                Poll::Pending => {}
+            },
+        }
+        // 再驱动连接上的读写
+        match conn.poll_next_inbound(cx) {
+            // 客户端不接受服务器打开的 stream
+            Poll::Ready(Some(Ok(_))) => continue,
+            Poll::Ready(Some(Err(e))) => return Poll::Ready(Err(e)),
+            Poll::Ready(None) => return Poll::Ready(Ok(())),
+            Poll::Pending => return Poll::Pending,
+        }
+    })
+    .await;
+    // 让 is_closed 返回 true，之后的请求会重新建立连接
+    drop(rx);
+
+    if let Err(e) = result {
+        warn!("Multiplexed connection error: {:?}", e);
+    }
+    let _ = future::poll_fn(|cx| conn.poll_close(cx)).await;
+}
+
+/// 服务器端处理一个多路复用的连接：每个 stream 由一个单独的 task 处理。
+/// 收到退出通知后不再接受新的 stream，等已有的 stream 处理完再关闭连接
+pub(crate) async fn serve_multiplexed<S, Store>(
+    stream: S,
+    frame_config: FrameConfig,
+    service: Service<Store>,
+    mut shutdown: watch::Receiver<()>,
+) -> Result<(), KvError>
+where
+    S: AsyncStream,
+    Store: Storage + Send + Sync + 'static,
+{
+    let mut conn = Connection::new(stream.compat(), Config::default(), Mode::Server);
+    // 和 Server::run 一样，每个 stream 的 task 持有一个 done_tx
+    let (done_tx, mut done_rx) = mpsc::channel::<()>(1);
+    let mut done_tx = Some(done_tx);
+    loop {
+        tokio::select! {
+            inbound = future::poll_fn(|cx| conn.poll_next_inbound(cx)) => match inbound {
+                Some(Ok(stream)) => {
+                    // 正在退出时，新打开的 stream 直接丢弃
+                    if let Some(done_tx) = done_tx.clone() {
+                        let stream = Framed::new(stream.compat(), ProstCodec::new(frame_config));
+                        let svc = service.clone();
+                        let shutdown = shutdown.clone();
+                        tokio::spawn(async move {
+                            if let Err(e) = handle_connection(stream, svc, shutdown).await {
+                                warn!("Stream error: {:?}", e);
+                            }
+                            drop(done_tx);
+                        });
+                    }
+                }
+                Some(Err(e)) => return Err(mux_error(e)),
+                None => return Ok(()),
+            },
+            _ = shutdown.changed(), if done_tx.is_some() => done_tx = None,
+            _ = done_rx.recv(), if done_tx.is_none() => break,
+        }
+    }
+    future::poll_fn(|cx| conn.poll_close(cx)).await.map_err(mux_error)
+}
+
+fn mux_error(e: ConnectionError) -> KvError {
+    KvError::IoError(format!("Multiplexed connection error: {}", e))
+}
+
+fn closed() -> KvError {
+    KvError::IoError("Multiplexed connection closed".into())
+}
+
+#[cfg(test)]
+mod tests {
+    use futures::prelude::*;
+    use tokio::io::duplex;
+    use crate::memory::MemTable;
+    use crate::network::ProstClientStream;
+    use super::*;
+
+    #[tokio::test]
+    async fn yamux_streams_should_be_served_independently() {
+        let (client, server) = duplex(64 * 1024);
+        let service: Service = Service::new(MemTable::new());
+        let (_shutdown_tx, shutdown_rx) = watch::channel(());
+        tokio::spawn(serve_multiplexed(server, FrameConfig::default(), service, shutdown_rx));
+
+        let ctrl = YamuxCtrl::new_client(client);
+        let mut s1 = ProstClientStream::new(ctrl.open_stream().await.unwrap(), ProstCodec::default());
+        let mut s2 = ProstClientStream::new(ctrl.open_stream().await.unwrap(), ProstCodec::default());
+
+        s1.send(CommandRequest::new_hset("t1", "k1", "v1".into())).await.unwrap();
+        s2.send(CommandRequest::new_hset("t1", "k2", "v2".into())).await.unwrap();
+        assert_eq!(s2.next().await.unwrap().unwrap().status, 200);
+        assert_eq!(s1.next().await.unwrap().unwrap().status, 200);
+
+        s1.send(CommandRequest::new_hgetall("t1")).await.unwrap();
+        assert_eq!(s1.next().await.unwrap().unwrap().pairs.len(), 2);
+    }
+
+    // #[tokio::test] 使用单线程的 runtime，SAVE 如果在 runtime 的线程上执行，就会挡住所有 stream
+    #[tokio::test]
+    async fn slow_save_should_not_block_other_streams() {
+        use std::sync::{mpsc as std_mpsc, Arc, Mutex};
+        use std::sync::atomic::{AtomicBool, Ordering};
+        use std::time::Duration;
+
+        // SAVE 一直等到 HGET 返回之后才放行，等太久说明 HGET 被挡住了
+        let (release_tx, release_rx) = std_mpsc::channel::<()>();
+        let release_rx = Mutex::new(release_rx);
+        let released = Arc::new(AtomicBool::new(false));
+        let flag = released.clone();
+        let service: Service = ServiceInner::new(MemTable::new())
+            .fn_received(move |cmd: &CommandRequest| {
+                if matches!(cmd.request_data, Some(command_request::RequestData::Save(_))) {
+                    let ok = release_rx.lock().unwrap().recv_timeout(Duration::from_secs(5)).is_ok();
+                    flag.store(ok, Ordering::SeqCst);
+                }
+                Ok(())
+            })
+            .into();
+        service.execute(CommandRequest::new_hset("t1", "k1", "v1".into()));
+        let (client, server) = duplex(64 * 1024);
+        let (_shutdown_tx, shutdown_rx) = watch::channel(());
+        tokio::spawn(serve_multiplexed(server, FrameConfig::default(), service, shutdown_rx));
+
+        let ctrl = YamuxCtrl::new_client(client);
+        let mut s1 = ProstClientStream::new(ctrl.open_stream().await.unwrap(), ProstCodec::default());
+        let mut s2 = ProstClientStream::new(ctrl.open_stream().await.unwrap(), ProstCodec::default());
+
+        s1.send(CommandRequest::new_save()).await.unwrap();
+        s2.send(CommandRequest::new_hget("t1", "k1")).await.unwrap();
+        assert_eq!(s2.next().await.unwrap().unwrap().values, vec!["v1".into()]);
+        release_tx.send(()).unwrap();
+        // 没有配置快照文件，SAVE 返回 400，但它是在 HGET 返回之后才结束的
+        assert_eq!(s1.next().await.unwrap().unwrap().status, 400);
+        assert!(released.load(Ordering::SeqCst));
+    }
+
+    #[tokio::test]
+    async fn yamux_ctrl_should_be_closed_when_connection_drops() {
+        let (client, server) = duplex(64 * 1024);
+        let ctrl = YamuxCtrl::new_client(client);
+        drop(server);
+        // 驱动 task 发现连接断开后退出，is_closed 返回 true
+        tokio::time::timeout(std::time::Duration::from_secs(1), async {
+            while !ctrl.is_closed() {
+                tokio::task::yield_now().await;
+            }
+        })
+        .await
+        .unwrap();
+    }
+}
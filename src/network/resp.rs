@@ -0,0 +1,887 @@
+use bytes::{Buf, BufMut, Bytes, BytesMut};
+use futures::prelude::*;
+use tokio::sync::watch;
+use tokio_util::codec::{Decoder, Encoder, Framed};
+use crate::*;
+use super::AsyncStream;
+
+/*
+    兼容 Redis 的 RESP 协议，这样 redis-cli 和现有的 Redis 客户端库可以直接访问 KV server。
+
+    Redis 的 hash 正好对应我们的 table：HSET key field value 中的 key 是 table，field 是 table 中的 key。
+    支持 HGET、HSET、HMGET、HMSET、HDEL、HEXISTS、HGETALL、HLEN、HSCAN、HINCRBY、HINCRBYFLOAT 和 HSETNX，把它们翻译成 CommandRequest，
+    再把 CommandResponse 翻译成 Redis 对应命令的返回格式。
+    MULTI 之后的命令在连接上排队，EXEC 时作为一个 Transaction 执行；不支持 Redis 的 WATCH。
+
+    连接建立后使用 RESP2，客户端发送 HELLO 3 之后切换到 RESP3。
+    RESP3 新增的类型（null、double、boolean、map）在 RESP2 下会编码成对应的 RESP2 类型。
+*/
+/// 一个 bulk string 最大 512MB，和 Redis 一样
+const MAX_BULK_LEN: usize = 512 * 1024 * 1024;
+/// 一个 array 或者 map 最多多少个元素
+const MAX_ELEMENTS: usize = 1024 * 1024;
+/// inline 命令（比如 telnet 中输入的 PING）最大 64KB
+const MAX_INLINE_LEN: usize = 64 * 1024;
+
+/// 使用的 RESP 版本
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq)]
+pub enum RespVersion {
+    #[default]
+    Resp2,
+    Resp3,
+}
+
+/// RESP 的数据
+#[derive(Clone, Debug, PartialEq)]
+pub enum RespFrame {
+    Simple(String),
+    Error(String),
+    Integer(i64),
+    Bulk(Bytes),
+    Array(Vec<RespFrame>),
+    /// RESP2 下编码成 null bulk string
+    Null,
+    /// RESP2 下编码成 bulk string
+    Double(f64),
+    /// RESP2 下编码成 1 或者 0
+    Boolean(bool),
+    /// RESP2 下编码成 key 和 value 交替出现的 array
+    Map(Vec<(RespFrame, RespFrame)>),
+}
+
+impl RespFrame {
+    /// 用 version 对应的格式编码
+    pub fn encode(&self, buf: &mut BytesMut, version: RespVersion) {
+        match self {
+            RespFrame::Simple(s) => put_line(buf, b'+', s.as_bytes()),
+            RespFrame::Error(s) => put_line(buf, b'-', s.as_bytes()),
+            RespFrame::Integer(i) => put_line(buf, b':', i.to_string().as_bytes()),
+            RespFrame::Bulk(data) => {
+                put_line(buf, b'$', data.len().to_string().as_bytes());
+                buf.put_slice(data);
+                buf.put_slice(b"\r\n");
+            }
+            RespFrame::Array(items) => {
+                put_line(buf, b'*', items.len().to_string().as_bytes());
+                items.iter().for_each(|item| item.encode(buf, version));
+            }
+            RespFrame::Null => match version {
+                RespVersion::Resp2 => buf.put_slice(b"$-1\r\n"),
+                RespVersion::Resp3 => buf.put_slice(b"_\r\n"),
+            },
+            RespFrame::Double(f) => match version {
+                RespVersion::Resp2 => RespFrame::Bulk(format_double(*f).into()).encode(buf, version),
+                RespVersion::Resp3 => put_line(buf, b',', format_double(*f).as_bytes()),
+            },
+            RespFrame::Boolean(b) => match version {
+                RespVersion::Resp2 => RespFrame::Integer(*b as i64).encode(buf, version),
+                RespVersion::Resp3 => buf.put_slice(if *b { b"#t\r\n" } else { b"#f\r\n" }),
+            },
+            RespFrame::Map(pairs) => {
+                match version {
+                    RespVersion::Resp2 => put_line(buf, b'*', (pairs.len() * 2).to_string().as_bytes()),
+                    RespVersion::Resp3 => put_line(buf, b'%', pairs.len().to_string().as_bytes()),
+                }
+                for (k, v) in pairs {
+                    k.encode(buf, version);
+                    v.encode(buf, version);
+                }
+            }
+        }
+    }
+
+    /// 从 buf 中解析一个完整的 frame，返回 frame 和它占用的字节数。数据不完整时返回 None
+    pub fn parse(buf: &[u8]) -> Result<Option<(Self, usize)>, KvError> {
+        let mut pos = 0;
+        Ok(parse_frame(buf, &mut pos, 0)?.map(|frame| (frame, pos)))
+    }
+}
+
+fn put_line(buf: &mut BytesMut, prefix: u8, data: &[u8]) {
+    buf.reserve(data.len() + 3);
+    buf.put_u8(prefix);
+    buf.put_slice(data);
+    buf.put_slice(b"\r\n");
+}
+
+fn format_double(f: f64) -> String {
+    match f {
+        f if f.is_nan() => "nan".into(),
+        f if f.is_infinite() && f > 0.0 => "inf".into(),
+        f if f.is_infinite() => "-inf".into(),
+        f => f.to_string(),
+    }
+}
+
+fn protocol_error(msg: impl Into<String>) -> KvError {
+    KvError::FrameError(format!("Protocol error: {}", msg.into()))
+}
+
+/// 读到 \r\n 为止，返回这一行的内容（不含 \r\n）
+fn read_line<'a>(buf: &'a [u8], pos: &mut usize) -> Option<&'a [u8]> {
+    let start = *pos;
+    let end = buf[start..].windows(2).position(|w| w == b"\r\n")? + start;
+    *pos = end + 2;
+    Some(&buf[start..end])
+}
+
+fn parse_number<T: std::str::FromStr>(line: &[u8]) -> Result<T, KvError> {
+    std::str::from_utf8(line)
+        .ok()
+        .and_then(|s| s.parse().ok())
+        .ok_or_else(|| protocol_error(format!("invalid number {:?}", String::from_utf8_lossy(line))))
+}
+
+/// 解析长度，-1 表示 null
+fn parse_len(line: &[u8], max: usize) -> Result<Option<usize>, KvError> {
+    match parse_number::<i64>(line)? {
+        -1 => Ok(None),
+        n if n < 0 || n as usize > max => Err(protocol_error(format!("invalid length {}", n))),
+        n => Ok(Some(n as usize)),
+    }
+}
+
+fn parse_frame(buf: &[u8], pos: &mut usize, depth: usize) -> Result<Option<RespFrame>, KvError> {
+    // 嵌套太深的数据一般是恶意构造的，避免递归把栈用完
+    if depth > 32 {
+        return Err(protocol_error("too deeply nested"));
+    }
+    if *pos >= buf.len() {
+        return Ok(None);
+    }
+    let prefix = buf[*pos];
+    *pos += 1;
+    let line = match read_line(buf, pos) {
+        Some(line) => line,
+        None => return Ok(None),
+    };
+    let text = || String::from_utf8_lossy(line).into_owned();
+
+    let frame = match prefix {
+        b'+' => RespFrame::Simple(text()),
+        b'-' => RespFrame::Error(text()),
+        b':' => RespFrame::Integer(parse_number(line)?),
+        b'_' => RespFrame::Null,
+        b',' => RespFrame::Double(match line {
+            b"inf" => f64::INFINITY,
+            b"-inf" => f64::NEG_INFINITY,
+            _ => parse_number(line)?,
+        }),
+        b'#' => match line {
+            b"t" => RespFrame::Boolean(true),
+            b"f" => RespFrame::Boolean(false),
+            _ => return Err(protocol_error("invalid boolean")),
+        },
+        b'$' => match parse_len(line, MAX_BULK_LEN)? {
+            None => RespFrame::Null,
+            Some(len) => {
+                if buf.len() < *pos + len + 2 {
+                    return Ok(None);
+                }
+                if &buf[*pos + len..*pos + len + 2] != b"\r\n" {
+                    return Err(protocol_error("bulk string is not terminated by CRLF"));
+                }
+                let data = Bytes::copy_from_slice(&buf[*pos..*pos + len]);
+                *pos += len + 2;
+                RespFrame::Bulk(data)
+            }
+        },
+        b'*' => match parse_len(line, MAX_ELEMENTS)? {
+            None => RespFrame::Null,
+            Some(len) => {
+                let mut items = Vec::with_capacity(len.min(64));
+                for _ in 0..len {
+                    match parse_frame(buf, pos, depth + 1)? {
+                        Some(item) => items.push(item),
+                        None => return Ok(None),
+                    }
+                }
+                RespFrame::Array(items)
+            }
+        },
+        b'%' => {
+            let len = parse_len(line, MAX_ELEMENTS)?.ok_or_else(|| protocol_error("invalid map length"))?;
+            let mut pairs = Vec::with_capacity(len.min(64));
+            for _ in 0..len {
+                let k = match parse_frame(buf, pos, depth + 1)? {
+                    Some(k) => k,
+                    None => return Ok(None),
+                };
+                match parse_frame(buf, pos, depth + 1)? {
+                    Some(v) => pairs.push((k, v)),
+                    None => return Ok(None),
+                }
+            }
+            RespFrame::Map(pairs)
+        }
+        c => return Err(protocol_error(format!("unexpected byte {:?}", c as char))),
+    };
+    Ok(Some(frame))
+}
+
+/// 服务器端的 RESP 编解码：读取客户端发来的命令，写出 RespFrame
+#[derive(Debug, Default)]
+pub struct RespCodec {
+    /// 编码 response 时使用的版本，由 HELLO 命令切换
+    pub version: RespVersion,
+}
+
+impl Decoder for RespCodec {
+    /// 命令的各个参数
+    type Item = Vec<Bytes>;
+    type Error = KvError;
+
+    fn decode(&mut self, src: &mut BytesMut) -> Result<Option<Self::Item>, Self::Error> {
+        loop {
+            if src.is_empty() {
+                return Ok(None);
+            }
+            // 不是 array 开头的是 inline 命令，用空格分隔参数
+            if src[0] != b'*' {
+                let end = match src.iter().position(|&c| c == b'\n') {
+                    Some(end) => end,
+                    None if src.len() > MAX_INLINE_LEN => return Err(protocol_error("too big inline request")),
+                    None => return Ok(None),
+                };
+                let line = src.split_to(end + 1);
+                let args: Vec<_> = line[..]
+                    .split(|c: &u8| c.is_ascii_whitespace())
+                    .filter(|arg| !arg.is_empty())
+                    .map(Bytes::copy_from_slice)
+                    .collect();
+                // 空行直接忽略
+                if args.is_empty() {
+                    continue;
+                }
+                return Ok(Some(args));
+            }
+
+            let (frame, len) = match RespFrame::parse(src)? {
+                Some(v) => v,
+                None => return Ok(None),
+            };
+            src.advance(len);
+            let args = match frame {
+                RespFrame::Array(items) => items
+                    .into_iter()
+                    .map(|item| match item {
+                        RespFrame::Bulk(arg) => Ok(arg),
+                        _ => Err(protocol_error("expected bulk string")),
+                    })
+                    .collect::<Result<Vec<_>, _>>()?,
+                _ => return Err(protocol_error("expected array")),
+            };
+            if args.is_empty() {
+                continue;
+            }
+            return Ok(Some(args));
+        }
+    }
+}
+
+impl Encoder<RespFrame> for RespCodec {
+    type Error = KvError;
+
+    fn encode(&mut self, item: RespFrame, dst: &mut BytesMut) -> Result<(), Self::Error> {
+        item.encode(dst, self.version);
+        Ok(())
+    }
+}
+
+/// 从 Redis 命令翻译来的请求
+#[derive(Debug, PartialEq)]
+pub enum RespCommand {
+    /// 交给 Service 执行的命令，reply 说明怎么把 CommandResponse 翻译成 RESP
+    Kv { cmd: CommandRequest, reply: Reply },
+    /// 连接层面的命令（PING、HELLO 等），直接返回结果
+    Local(RespFrame),
+    /// HELLO 切换协议版本
+    Hello(RespVersion),
+    /// 返回 OK 后关闭连接
+    Quit,
+    /// 开始事务，之后的命令先排队，EXEC 时作为一个事务执行
+    Multi,
+    /// 执行排队的命令
+    Exec,
+    /// 丢弃排队的命令
+    Discard,
+}
+
+/// 不同的 Redis 命令，返回的格式不同
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum Reply {
+    /// HGET：value 或者 null
+    Value,
+    /// HMGET：value 的 array，不存在的是 null
+    Values,
+    /// HSET：新增了多少个 field
+    Added,
+    /// HDEL：删除了多少个 field
+    Removed,
+    /// HMSET：OK
+    Ok,
+    /// HEXISTS：1 或者 0
+    Exists,
+    /// HGETALL：field 和 value 组成的 map
+    Pairs,
+    /// HINCRBY：加之后的整数；HLEN：field 的个数
+    Integer,
+    /// HSETNX：设置了返回 1，key 已经存在返回 0
+    SetNx,
+    /// HSCAN：下一页的 cursor 和 field、value 交替组成的 array
+    Scan,
+}
+
+impl RespCommand {
+    /// 把 Redis 命令的参数翻译成 RespCommand，出错时返回要发给客户端的错误
+    pub fn parse(args: Vec<Bytes>) -> Result<Self, RespFrame> {
+        let name = String::from_utf8_lossy(&args[0]).to_ascii_lowercase();
+        let args = &args[1..];
+        let arity = |ok: bool| match ok {
+            true => Ok(()),
+            false => Err(RespFrame::Error(format!(
+                "ERR wrong number of arguments for '{}' command",
+                name
+            ))),
+        };
+
+        let (cmd, reply) = match name.as_str() {
+            "hget" => {
+                arity(args.len() == 2)?;
+                (CommandRequest::new_hget(string(&args[0])?, string(&args[1])?), Reply::Value)
+            }
+            "hmget" => {
+                arity(args.len() >= 2)?;
+                (CommandRequest::new_hmget(string(&args[0])?, strings(&args[1..])?), Reply::Values)
+            }
+            "hgetall" => {
+                arity(args.len() == 1)?;
+                (CommandRequest::new_hgetall(string(&args[0])?), Reply::Pairs)
+            }
+            "hset" | "hmset" => {
+                arity(args.len() >= 3 && args.len() % 2 == 1)?;
+                let table = string(&args[0])?;
+                let mut pairs = args[1..]
+                    .chunks(2)
+                    .map(|kv| Ok(KvPair::new(string(&kv[0])?, value(&kv[1]))))
+                    .collect::<Result<Vec<_>, RespFrame>>()?;
+                let cmd = match pairs.len() {
+                    1 => {
+                        let pair = pairs.remove(0);
+                        CommandRequest::new_hset(table, pair.key, pair.value.unwrap_or_default())
+                    }
+                    _ => CommandRequest::new_hmset(table, pairs),
+                };
+                (cmd, if name == "hset" { Reply::Added } else { Reply::Ok })
+            }
+            "hdel" => {
+                arity(args.len() >= 2)?;
+                let table = string(&args[0])?;
+                let cmd = match args.len() {
+                    2 => CommandRequest::new_hdel(table, string(&args[1])?),
+                    _ => CommandRequest::new_hmdel(table, strings(&args[1..])?),
+                };
+                (cmd, Reply::Removed)
+            }
+            "hsetnx" => {
+                arity(args.len() == 3)?;
+                (CommandRequest::new_hsetnx(string(&args[0])?, string(&args[1])?, value(&args[2])), Reply::SetNx)
+            }
+            "hlen" => {
+                arity(args.len() == 1)?;
+                (CommandRequest::new_hlen(string(&args[0])?), Reply::Integer)
+            }
+            "hscan" => {
+                // HSCAN key cursor [MATCH pattern] [COUNT count]
+                arity(args.len() >= 2 && args.len().is_multiple_of(2))?;
+                // Redis 的 cursor 用 0 表示开始和结束，我们用空字符串
+                let cursor = match string(&args[1])? {
+                    c if c == "0" => String::new(),
+                    c => c,
+                };
+                let (mut pattern, mut count) = (String::new(), 0);
+                for option in args[2..].chunks(2) {
+                    match option[0].to_ascii_lowercase().as_slice() {
+                        b"match" => pattern = string(&option[1])?,
+                        b"count" => count = number(&option[1], "ERR value is not an integer or out of range")?,
+                        _ => return Err(RespFrame::Error("ERR syntax error".into())),
+                    }
+                }
+                (CommandRequest::new_hscan(string(&args[0])?, cursor, count, pattern), Reply::Scan)
+            }
+            "hexists" => {
+                arity(args.len() == 2)?;
+                (CommandRequest::new_hexist(string(&args[0])?, string(&args[1])?), Reply::Exists)
+            }
+            "hincrby" => {
+                arity(args.len() == 3)?;
+                let delta = number(&args[2], "ERR value is not an integer or out of range")?;
+                (CommandRequest::new_hincrby(string(&args[0])?, string(&args[1])?, delta), Reply::Integer)
+            }
+            "hincrbyfloat" => {
+                arity(args.len() == 3)?;
+                let delta = number(&args[2], "ERR value is not a valid float")?;
+                (CommandRequest::new_hincrbyfloat(string(&args[0])?, string(&args[1])?, delta), Reply::Value)
+            }
+            "ping" => {
+                arity(args.len() <= 1)?;
+                return Ok(RespCommand::Local(match args.first() {
+                    Some(msg) => RespFrame::Bulk(msg.clone()),
+                    None => RespFrame::Simple("PONG".into()),
+                }));
+            }
+            "echo" => {
+                arity(args.len() == 1)?;
+                return Ok(RespCommand::Local(RespFrame::Bulk(args[0].clone())));
+            }
+            "hello" => {
+                // 只支持 HELLO [protover]，不支持 AUTH 和 SETNAME
+                arity(args.len() <= 1)?;
+                let version = match args.first().map(|v| v.as_ref()) {
+                    None | Some(b"2") => RespVersion::Resp2,
+                    Some(b"3") => RespVersion::Resp3,
+                    Some(_) => {
+                        return Err(RespFrame::Error("NOPROTO unsupported protocol version".into()))
+                    }
+                };
+                return Ok(RespCommand::Hello(version));
+            }
+            // redis-cli 启动时会发送 COMMAND DOCS 来获取命令的帮助，返回空的结果即可
+            "command" => return Ok(RespCommand::Local(RespFrame::Array(vec![]))),
+            // 客户端库连接时可能会发送 CLIENT SETNAME / SETINFO，直接返回 OK
+            "client" => return Ok(RespCommand::Local(RespFrame::Simple("OK".into()))),
+            "quit" => return Ok(RespCommand::Quit),
+            "multi" | "exec" | "discard" => {
+                arity(args.is_empty())?;
+                return Ok(match name.as_str() {
+                    "multi" => RespCommand::Multi,
+                    "exec" => RespCommand::Exec,
+                    _ => RespCommand::Discard,
+                });
+            }
+            _ => return Err(RespFrame::Error(format!("ERR unknown command '{}'", name))),
+        };
+        Ok(RespCommand::Kv { cmd, reply })
+    }
+}
+
+fn string(arg: &Bytes) -> Result<String, RespFrame> {
+    String::from_utf8(arg.to_vec()).map_err(|_| RespFrame::Error("ERR table and key must be valid UTF-8".into()))
+}
+
+fn strings(args: &[Bytes]) -> Result<Vec<String>, RespFrame> {
+    args.iter().map(string).collect()
+}
+
+fn number<T: std::str::FromStr>(arg: &Bytes, err: &str) -> Result<T, RespFrame> {
+    std::str::from_utf8(arg)
+        .ok()
+        .and_then(|s| s.parse().ok())
+        .ok_or_else(|| RespFrame::Error(err.into()))
+}
+
+/// Redis 的 value 都是字节串，合法的 UTF-8 存成 string，否则存成 binary
+fn value(arg: &Bytes) -> Value {
+    match std::str::from_utf8(arg) {
+        Ok(s) => s.into(),
+        Err(_) => Value {
+            value: Some(value::Value::Binary(arg.clone())),
+        },
+    }
+}
+
+/// 把 Value 转换成 bulk string，不存在的值转换成 null
+fn value_to_frame(v: &Value) -> RespFrame {
+    match &v.value {
+        None => RespFrame::Null,
+        Some(value::Value::String(s)) => RespFrame::Bulk(Bytes::copy_from_slice(s.as_bytes())),
+        Some(value::Value::Binary(b)) => RespFrame::Bulk(b.clone()),
+        Some(value::Value::Integer(i)) => RespFrame::Bulk(i.to_string().into()),
+        Some(value::Value::Float(f)) => RespFrame::Bulk(format_double(*f).into()),
+        Some(value::Value::Bool(b)) => RespFrame::Bulk(if *b { "1" } else { "0" }.into()),
+    }
+}
+
+impl Reply {
+    /// 把 CommandResponse 翻译成 Redis 对应命令的返回格式
+    pub fn to_frame(self, res: &CommandResponse) -> RespFrame {
+        if res.status == 404 && self == Reply::Value {
+            return RespFrame::Null;
+        }
+        if res.status == 409 && self == Reply::SetNx {
+            return RespFrame::Integer(0);
+        }
+        if res.status >= 400 {
+            return RespFrame::Error(format!("ERR {}", res.message));
+        }
+        match self {
+            Reply::Value => res.values.first().map(value_to_frame).unwrap_or(RespFrame::Null),
+            Reply::Values => RespFrame::Array(res.values.iter().map(value_to_frame).collect()),
+            // HSET 返回之前的值，之前没有值说明是新增的 field
+            Reply::Added => RespFrame::Integer(res.values.iter().filter(|v| v.value.is_none()).count() as i64),
+            Reply::Removed => RespFrame::Integer(res.values.iter().filter(|v| v.value.is_some()).count() as i64),
+            Reply::Ok => RespFrame::Simple("OK".into()),
+            Reply::Exists => {
+                let exists = matches!(res.values.first(), Some(Value { value: Some(value::Value::Bool(true)) }));
+                RespFrame::Integer(exists as i64)
+            }
+            Reply::SetNx => RespFrame::Integer(1),
+            Reply::Integer => match res.values.first() {
+                Some(Value { value: Some(value::Value::Integer(i)) }) => RespFrame::Integer(*i),
+                _ => RespFrame::Error("ERR value is not an integer".into()),
+            },
+            Reply::Scan => {
+                let cursor = match res.cursor.as_str() {
+                    "" => "0",
+                    c => c,
+                };
+                let items = res
+                    .pairs
+                    .iter()
+                    .flat_map(|pair| {
+                        let key = RespFrame::Bulk(Bytes::copy_from_slice(pair.key.as_bytes()));
+                        [key, pair.value.as_ref().map(value_to_frame).unwrap_or(RespFrame::Null)]
+                    })
+                    .collect();
+                RespFrame::Array(vec![RespFrame::Bulk(Bytes::copy_from_slice(cursor.as_bytes())), RespFrame::Array(items)])
+            }
+            Reply::Pairs => RespFrame::Map(
+                res.pairs
+                    .iter()
+                    .map(|pair| {
+                        let key = RespFrame::Bulk(Bytes::copy_from_slice(pair.key.as_bytes()));
+                        (key, pair.value.as_ref().map(value_to_frame).unwrap_or(RespFrame::Null))
+                    })
+                    .collect(),
+            ),
+        }
+    }
+}
+
+/// HELLO 返回的服务器信息
+fn hello_reply() -> RespFrame {
+    let field = |k: &str, v: RespFrame| (RespFrame::Bulk(Bytes::copy_from_slice(k.as_bytes())), v);
+    RespFrame::Map(vec![
+        field("server", RespFrame::Bulk("kv".into())),
+        field("version", RespFrame::Bulk(env!("CARGO_PKG_VERSION").into())),
+        field("proto", RespFrame::Integer(3)),
+        field("mode", RespFrame::Bulk("standalone".into())),
+        field("role", RespFrame::Bulk("master".into())),
+        field("modules", RespFrame::Array(vec![])),
+    ])
+}
+
+/// 处理一个 RESP 连接上的所有请求。和 handle_connection 一样，收到退出通知时处理完当前的请求再关闭
+pub(crate) async fn handle_resp_connection<S: AsyncStream, Store: Storage + Send + Sync + 'static>(
+    stream: S,
+    service: Service<Store>,
+    mut shutdown: watch::Receiver<()>,
+) -> Result<(), KvError> {
+    let mut stream = Framed::new(stream, RespCodec::default());
+    // MULTI 之后排队的命令；排队时有命令出错，EXEC 会放弃整个事务
+    let mut queued: Option<Vec<(CommandRequest, Reply)>> = None;
+    let mut aborted = false;
+    loop {
+        let args = tokio::select! {
+            args = stream.next() => args,
+            _ = shutdown.changed() => break,
+        };
+        let args = match args {
+            Some(Ok(args)) => args,
+            Some(Err(e)) => {
+                // 和 Redis 一样，协议错误时返回错误后关闭连接
+                let _ = stream.send(RespFrame::Error(format!("ERR {}", e))).await;
+                return Err(e);
+            }
+            None => break,
+        };
+        match (RespCommand::parse(args), &mut queued) {
+            (Ok(RespCommand::Kv { cmd, reply }), Some(commands)) => {
+                commands.push((cmd, reply));
+                stream.send(RespFrame::Simple("QUEUED".into())).await?;
+            }
+            (Ok(RespCommand::Kv { cmd, reply }), None) => {
+                let mut res = service.execute_async(cmd).await;
+                service.before_send(&mut res);
+                stream.send(reply.to_frame(&res)).await?;
+                service.after_send();
+            }
+            (Ok(RespCommand::Multi), Some(_)) => {
+                stream.send(RespFrame::Error("ERR MULTI calls can not be nested".into())).await?
+            }
+            (Ok(RespCommand::Multi), None) => {
+                queued = Some(Vec::new());
+                aborted = false;
+                stream.send(RespFrame::Simple("OK".into())).await?;
+            }
+            (Ok(RespCommand::Exec), None) => stream.send(RespFrame::Error("ERR EXEC without MULTI".into())).await?,
+            (Ok(RespCommand::Exec), Some(_)) if aborted => {
+                queued = None;
+                let msg = "EXECABORT Transaction discarded because of previous errors.";
+                stream.send(RespFrame::Error(msg.into())).await?;
+            }
+            (Ok(RespCommand::Exec), Some(_)) => {
+                let (commands, replies): (Vec<_>, Vec<_>) = queued.take().unwrap_or_default().into_iter().unzip();
+                let mut res = service.execute_async(CommandRequest::new_transaction(vec![], commands)).await;
+                service.before_send(&mut res);
+                let frame = match res.status {
+                    200 => RespFrame::Array(
+                        replies.iter().zip(res.responses.iter()).map(|(reply, res)| reply.to_frame(res)).collect(),
+                    ),
+                    _ => RespFrame::Error(format!("ERR {}", res.message)),
+                };
+                stream.send(frame).await?;
+                service.after_send();
+            }
+            (Ok(RespCommand::Discard), None) => {
+                stream.send(RespFrame::Error("ERR DISCARD without MULTI".into())).await?
+            }
+            (Ok(RespCommand::Discard), Some(_)) => {
+                queued = None;
+                stream.send(RespFrame::Simple("OK".into())).await?;
+            }
+            (Ok(RespCommand::Local(frame)), _) => stream.send(frame).await?,
+            (Ok(RespCommand::Hello(version)), _) => {
+                stream.codec_mut().version = version;
+                stream.send(hello_reply()).await?;
+            }
+            (Ok(RespCommand::Quit), _) => {
+                stream.send(RespFrame::Simple("OK".into())).await?;
+                break;
+            }
+            (Err(frame), commands) => {
+                aborted = commands.is_some();
+                stream.send(frame).await?
+            }
+        }
+    }
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use tokio::io::{duplex, AsyncReadExt, AsyncWriteExt, DuplexStream};
+    use crate::memory::MemTable;
+    use super::*;
+
+    fn encode(frame: &RespFrame, version: RespVersion) -> Vec<u8> {
+        let mut buf = BytesMut::new();
+        frame.encode(&mut buf, version);
+        buf.to_vec()
+    }
+
+    fn args(args: &[&str]) -> Vec<Bytes> {
+        args.iter().map(|arg| Bytes::copy_from_slice(arg.as_bytes())).collect()
+    }
+
+    #[test]
+    fn resp_frame_encode_parse_should_work() {
+        let frame = RespFrame::Array(vec![
+            RespFrame::Simple("OK".into()),
+            RespFrame::Error("ERR oops".into()),
+            RespFrame::Integer(-42),
+            RespFrame::Bulk("hello\r\nworld".into()),
+            RespFrame::Null,
+            RespFrame::Double(1.5),
+            RespFrame::Boolean(true),
+            RespFrame::Map(vec![(RespFrame::Bulk("k".into()), RespFrame::Integer(1))]),
+        ]);
+        let data = encode(&frame, RespVersion::Resp3);
+        assert_eq!(RespFrame::parse(&data).unwrap(), Some((frame, data.len())));
+        // 数据不完整时返回 None
+        assert_eq!(RespFrame::parse(&data[..data.len() - 1]).unwrap(), None);
+        assert!(RespFrame::parse(b"?oops\r\n").is_err());
+    }
+
+    #[test]
+    fn resp3_types_should_be_downgraded_in_resp2() {
+        let v2 = RespVersion::Resp2;
+        assert_eq!(encode(&RespFrame::Null, v2), b"$-1\r\n");
+        assert_eq!(encode(&RespFrame::Boolean(true), v2), b":1\r\n");
+        assert_eq!(encode(&RespFrame::Double(2.5), v2), b"$3\r\n2.5\r\n");
+        let map = RespFrame::Map(vec![(RespFrame::Bulk("k".into()), RespFrame::Bulk("v".into()))]);
+        assert_eq!(encode(&map, v2), b"*2\r\n$1\r\nk\r\n$1\r\nv\r\n");
+        assert_eq!(encode(&map, RespVersion::Resp3), b"%1\r\n$1\r\nk\r\n$1\r\nv\r\n");
+    }
+
+    #[test]
+    fn codec_should_decode_arrays_and_inline_commands() {
+        let mut codec = RespCodec::default();
+        let mut buf = BytesMut::from(&b"*2\r\n$4\r\nPING\r\n$2\r\nhi\r\n\r\nPING  there\r\n*1\r\n$4\r\nPI"[..]);
+        assert_eq!(codec.decode(&mut buf).unwrap(), Some(args(&["PING", "hi"])));
+        // 空行被忽略
+        assert_eq!(codec.decode(&mut buf).unwrap(), Some(args(&["PING", "there"])));
+        assert_eq!(codec.decode(&mut buf).unwrap(), None);
+        buf.extend_from_slice(b"NG\r\n");
+        assert_eq!(codec.decode(&mut buf).unwrap(), Some(args(&["PING"])));
+        assert!(buf.is_empty());
+
+        let mut buf = BytesMut::from(&b"*1\r\n:1\r\n"[..]);
+        assert!(codec.decode(&mut buf).is_err());
+    }
+
+    #[test]
+    fn hash_commands_should_be_translated() {
+        let cmd = RespCommand::parse(args(&["HGET", "t1", "k1"])).unwrap();
+        assert_eq!(cmd, RespCommand::Kv { cmd: CommandRequest::new_hget("t1", "k1"), reply: Reply::Value });
+
+        let cmd = RespCommand::parse(args(&["hset", "t1", "k1", "v1", "k2", "v2"])).unwrap();
+        let pairs = vec![KvPair::new("k1", "v1".into()), KvPair::new("k2", "v2".into())];
+        assert_eq!(cmd, RespCommand::Kv { cmd: CommandRequest::new_hmset("t1", pairs), reply: Reply::Added });
+
+        let cmd = RespCommand::parse(args(&["HDEL", "t1", "k1", "k2"])).unwrap();
+        let keys = vec!["k1".to_string(), "k2".to_string()];
+        assert_eq!(cmd, RespCommand::Kv { cmd: CommandRequest::new_hmdel("t1", keys), reply: Reply::Removed });
+
+        assert_eq!(
+            RespCommand::parse(args(&["HSET", "t1", "k1"])),
+            Err(RespFrame::Error("ERR wrong number of arguments for 'hset' command".into()))
+        );
+        let cmd = RespCommand::parse(args(&["HINCRBY", "t1", "k1", "-3"])).unwrap();
+        assert_eq!(cmd, RespCommand::Kv { cmd: CommandRequest::new_hincrby("t1", "k1", -3), reply: Reply::Integer });
+        assert_eq!(
+            RespCommand::parse(args(&["HINCRBY", "t1", "k1", "1.5"])),
+            Err(RespFrame::Error("ERR value is not an integer or out of range".into()))
+        );
+        let cmd = RespCommand::parse(args(&["HINCRBYFLOAT", "t1", "k1", "1.5"])).unwrap();
+        assert_eq!(cmd, RespCommand::Kv { cmd: CommandRequest::new_hincrbyfloat("t1", "k1", 1.5), reply: Reply::Value });
+        let cmd = RespCommand::parse(args(&["HSCAN", "t1", "0", "MATCH", "k*", "COUNT", "5"])).unwrap();
+        assert_eq!(cmd, RespCommand::Kv { cmd: CommandRequest::new_hscan("t1", "", 5, "k*"), reply: Reply::Scan });
+        assert_eq!(
+            RespCommand::parse(args(&["HSCAN", "t1", "0", "LIMIT", "5"])),
+            Err(RespFrame::Error("ERR syntax error".into()))
+        );
+        let cmd = RespCommand::parse(args(&["HLEN", "t1"])).unwrap();
+        assert_eq!(cmd, RespCommand::Kv { cmd: CommandRequest::new_hlen("t1"), reply: Reply::Integer });
+
+        assert_eq!(
+            RespCommand::parse(args(&["SET", "k1", "v1"])),
+            Err(RespFrame::Error("ERR unknown command 'set'".into()))
+        );
+    }
+
+    #[test]
+    fn responses_should_be_translated() {
+        let not_found: CommandResponse = KvError::NotFound("t1".into(), "k1".into()).into();
+        assert_eq!(Reply::Value.to_frame(&not_found), RespFrame::Null);
+        assert!(matches!(Reply::Values.to_frame(&not_found), RespFrame::Error(_)));
+
+        let res: CommandResponse = vec![Value::default(), Value::from("old")].into();
+        assert_eq!(Reply::Added.to_frame(&res), RespFrame::Integer(1));
+        assert_eq!(Reply::Removed.to_frame(&res), RespFrame::Integer(1));
+        assert_eq!(
+            Reply::Values.to_frame(&res),
+            RespFrame::Array(vec![RespFrame::Null, RespFrame::Bulk("old".into())])
+        );
+
+        let res: CommandResponse = Value::from(10).into();
+        assert_eq!(Reply::Value.to_frame(&res), RespFrame::Bulk("10".into()));
+        let res: CommandResponse = Value::from(true).into();
+        assert_eq!(Reply::Exists.to_frame(&res), RespFrame::Integer(1));
+    }
+
+    async fn request(client: &mut DuplexStream, cmd: &[&str]) -> RespFrame {
+        let mut buf = BytesMut::new();
+        RespFrame::Array(cmd.iter().map(|arg| RespFrame::Bulk(arg.to_string().into())).collect())
+            .encode(&mut buf, RespVersion::Resp2);
+        client.write_all(&buf).await.unwrap();
+
+        let mut data = Vec::new();
+        loop {
+            if let Some((frame, _)) = RespFrame::parse(&data).unwrap() {
+                return frame;
+            }
+            let mut chunk = [0u8; 1024];
+            let n = client.read(&mut chunk).await.unwrap();
+            assert!(n > 0);
+            data.extend_from_slice(&chunk[..n]);
+        }
+    }
+
+    #[tokio::test]
+    async fn resp_multi_exec_should_work() {
+        let (mut client, server) = duplex(64 * 1024);
+        let service: Service = Service::new(MemTable::new());
+        let (_shutdown_tx, shutdown_rx) = watch::channel(());
+        tokio::spawn(handle_resp_connection(server, service, shutdown_rx));
+
+        assert!(matches!(request(&mut client, &["EXEC"]).await, RespFrame::Error(_)));
+        assert_eq!(request(&mut client, &["MULTI"]).await, RespFrame::Simple("OK".into()));
+        assert_eq!(request(&mut client, &["HSET", "t1", "k1", "v1"]).await, RespFrame::Simple("QUEUED".into()));
+        assert_eq!(request(&mut client, &["HINCRBY", "t1", "n", "2"]).await, RespFrame::Simple("QUEUED".into()));
+        assert_eq!(request(&mut client, &["HGET", "t1", "k1"]).await, RespFrame::Simple("QUEUED".into()));
+        assert_eq!(
+            request(&mut client, &["EXEC"]).await,
+            RespFrame::Array(vec![RespFrame::Integer(1), RespFrame::Integer(2), RespFrame::Bulk("v1".into())])
+        );
+
+        // DISCARD 之后排队的命令不会执行
+        assert_eq!(request(&mut client, &["MULTI"]).await, RespFrame::Simple("OK".into()));
+        assert_eq!(request(&mut client, &["HSET", "t1", "k2", "v2"]).await, RespFrame::Simple("QUEUED".into()));
+        assert_eq!(request(&mut client, &["DISCARD"]).await, RespFrame::Simple("OK".into()));
+        assert_eq!(request(&mut client, &["HGET", "t1", "k2"]).await, RespFrame::Null);
+
+        // 排队时出错，EXEC 放弃整个事务
+        assert_eq!(request(&mut client, &["MULTI"]).await, RespFrame::Simple("OK".into()));
+        assert_eq!(request(&mut client, &["HSET", "t1", "k3", "v3"]).await, RespFrame::Simple("QUEUED".into()));
+        assert!(matches!(request(&mut client, &["HSET", "t1"]).await, RespFrame::Error(_)));
+        match request(&mut client, &["EXEC"]).await {
+            RespFrame::Error(msg) => assert!(msg.starts_with("EXECABORT")),
+            frame => panic!("unexpected frame: {:?}", frame),
+        }
+        assert_eq!(request(&mut client, &["HGET", "t1", "k3"]).await, RespFrame::Null);
+    }
+
+    #[tokio::test]
+    async fn resp_connection_should_work() {
+        let (mut client, server) = duplex(64 * 1024);
+        let service: Service = Service::new(MemTable::new());
+        let (_shutdown_tx, shutdown_rx) = watch::channel(());
+        tokio::spawn(handle_resp_connection(server, service, shutdown_rx));
+
+        assert_eq!(request(&mut client, &["PING"]).await, RespFrame::Simple("PONG".into()));
+        assert_eq!(request(&mut client, &["HSET", "t1", "k1", "v1", "k2", "v2"]).await, RespFrame::Integer(2));
+        assert_eq!(request(&mut client, &["HSET", "t1", "k1", "v3"]).await, RespFrame::Integer(0));
+        assert_eq!(request(&mut client, &["HMSET", "t1", "k3", "v4"]).await, RespFrame::Simple("OK".into()));
+        assert_eq!(request(&mut client, &["HGET", "t1", "k1"]).await, RespFrame::Bulk("v3".into()));
+        assert_eq!(request(&mut client, &["HGET", "t1", "k9"]).await, RespFrame::Null);
+        assert_eq!(request(&mut client, &["HEXISTS", "t1", "k2"]).await, RespFrame::Integer(1));
+        assert_eq!(request(&mut client, &["HSET", "t2", "n", "10"]).await, RespFrame::Integer(1));
+        assert_eq!(request(&mut client, &["HINCRBY", "t2", "n", "5"]).await, RespFrame::Integer(15));
+        assert_eq!(request(&mut client, &["HINCRBYFLOAT", "t2", "n", "0.5"]).await, RespFrame::Bulk("15.5".into()));
+        assert!(matches!(request(&mut client, &["HINCRBY", "t1", "k1", "1"]).await, RespFrame::Error(_)));
+        assert_eq!(request(&mut client, &["HSETNX", "t2", "m", "1"]).await, RespFrame::Integer(1));
+        assert_eq!(request(&mut client, &["HSETNX", "t2", "m", "2"]).await, RespFrame::Integer(0));
+        assert_eq!(request(&mut client, &["HDEL", "t1", "k2", "k9"]).await, RespFrame::Integer(1));
+        assert_eq!(request(&mut client, &["HLEN", "t1"]).await, RespFrame::Integer(2));
+        assert_eq!(request(&mut client, &["HLEN", "t9"]).await, RespFrame::Integer(0));
+        assert_eq!(
+            request(&mut client, &["HSCAN", "t1", "0", "MATCH", "k3"]).await,
+            RespFrame::Array(vec![
+                RespFrame::Bulk("0".into()),
+                RespFrame::Array(vec![RespFrame::Bulk("k3".into()), RespFrame::Bulk("v4".into())])
+            ])
+        );
+        assert_eq!(
+            request(&mut client, &["HMGET", "t1", "k1", "k2"]).await,
+            RespFrame::Array(vec![RespFrame::Bulk("v3".into()), RespFrame::Null])
+        );
+
+        // 切换到 RESP3 之后，HGETALL 返回 map
+        assert!(matches!(request(&mut client, &["HELLO", "3"]).await, RespFrame::Map(_)));
+        match request(&mut client, &["HGETALL", "t1"]).await {
+            RespFrame::Map(mut pairs) => {
+                pairs.sort_by_key(|(k, _)| format!("{:?}", k));
+                assert_eq!(
+                    pairs,
+                    vec![
+                        (RespFrame::Bulk("k1".into()), RespFrame::Bulk("v3".into())),
+                        (RespFrame::Bulk("k3".into()), RespFrame::Bulk("v4".into())),
+                    ]
+                );
+            }
+            frame => panic!("expect map, got {:?}", frame),
+        }
+        assert_eq!(request(&mut client, &["QUIT"]).await, RespFrame::Simple("OK".into()));
+    }
+}
@@ -0,0 +1,241 @@
+use std::sync::Arc;
+use tokio::io::{AsyncRead, AsyncWrite};
+use tokio_rustls::rustls::crypto::{ring, CryptoProvider};
+use tokio_rustls::rustls::pki_types::pem::PemObject;
+use tokio_rustls::rustls::pki_types::{CertificateDer, PrivateKeyDer, ServerName};
+use tokio_rustls::rustls::server::WebPkiClientVerifier;
+use tokio_rustls::rustls::{ClientConfig, RootCertStore, ServerConfig};
+use tokio_rustls::{client, server, TlsAcceptor, TlsConnector};
+use crate::KvError;
+
+/// KV server 自己的 ALPN，我们的客户端只使用它
+const ALPN_KV: &str = "kv";
+/// 服务器还接受 HTTP 网关和 gRPC 使用的 ALPN
+const ALPN_HTTP: [&str; 2] = ["h2", "http/1.1"];
+
+/*
+    证书和私钥都是 PEM 格式的字符串，从文件读取由调用者负责。
+    服务器端指定了 client_ca 时开启双向认证：客户端必须提供由这个 CA 签发的证书。
+    客户端没有指定 server_ca 时，使用 webpki-roots 中的根证书验证服务器。
+*/
+/// 服务器端的 TLS 处理
+#[derive(Clone)]
+pub struct TlsServerAcceptor {
+    inner: Arc<ServerConfig>,
+}
+
+/// 客户端的 TLS 处理
+#[derive(Clone)]
+pub struct TlsClientConnector {
+    inner: Arc<ClientConfig>,
+    domain: ServerName<'static>,
+}
+
+impl TlsServerAcceptor {
+    /// 加载服务器的证书和私钥，client_ca 不为空时验证客户端证书
+    pub fn new(cert: &str, key: &str, client_ca: Option<&str>) -> Result<Self, KvError> {
+        let provider = provider();
+        let builder = ServerConfig::builder_with_provider(provider.clone())
+            .with_safe_default_protocol_versions()
+            .map_err(tls_error)?;
+        let builder = match client_ca {
+            Some(ca) => {
+                let verifier = WebPkiClientVerifier::builder_with_provider(Arc::new(load_roots(ca)?), provider)
+                    .build()
+                    .map_err(tls_error)?;
+                builder.with_client_cert_verifier(verifier)
+            }
+            None => builder.with_no_client_auth(),
+        };
+        let mut config = builder
+            .with_single_cert(load_certs(cert)?, load_key(key)?)
+            .map_err(tls_error)?;
+        config.alpn_protocols = std::iter::once(ALPN_KV).chain(ALPN_HTTP).map(|p| p.into()).collect();
+
+        Ok(Self { inner: Arc::new(config) })
+    }
+
+    /// 在 stream 上完成 TLS 握手
+    pub async fn accept<S>(&self, stream: S) -> Result<server::TlsStream<S>, KvError>
+    where
+        S: AsyncRead + AsyncWrite + Unpin + Send,
+    {
+        let acceptor = TlsAcceptor::from(Arc::clone(&self.inner));
+        Ok(acceptor.accept(stream).await?)
+    }
+}
+
+impl TlsClientConnector {
+    /// domain 是服务器证书中的域名，identity 是双向认证时客户端的证书和私钥
+    pub fn new(
+        domain: impl Into<String>,
+        identity: Option<(&str, &str)>,
+        server_ca: Option<&str>,
+    ) -> Result<Self, KvError> {
+        let roots = match server_ca {
+            Some(ca) => load_roots(ca)?,
+            None => RootCertStore {
+                roots: webpki_roots::TLS_SERVER_ROOTS.to_vec(),
+            },
+        };
+        let builder = ClientConfig::builder_with_provider(provider())
+            .with_safe_default_protocol_versions()
+            .map_err(tls_error)?
+            .with_root_certificates(roots);
+        let mut config = match identity {
+            Some((cert, key)) => builder
+                .with_client_auth_cert(load_certs(cert)?, load_key(key)?)
+                .map_err(tls_error)?,
+            None => builder.with_no_client_auth(),
+        };
+        config.alpn_protocols = vec![ALPN_KV.into()];
+
+        let domain = ServerName::try_from(domain.into()).map_err(tls_error)?;
+        Ok(Self { inner: Arc::new(config), domain })
+    }
+
+    /// 在 stream 上完成 TLS 握手
+    pub async fn connect<S>(&self, stream: S) -> Result<client::TlsStream<S>, KvError>
+    where
+        S: AsyncRead + AsyncWrite + Unpin + Send,
+    {
+        let connector = TlsConnector::from(Arc::clone(&self.inner));
+        Ok(connector.connect(self.domain.clone(), stream).await?)
+    }
+}
+
+fn provider() -> Arc<CryptoProvider> {
+    Arc::new(ring::default_provider())
+}
+
+fn tls_error(e: impl ToString) -> KvError {
+    KvError::TlsError(e.to_string())
+}
+
+fn load_certs(pem: &str) -> Result<Vec<CertificateDer<'static>>, KvError> {
+    let certs = CertificateDer::pem_slice_iter(pem.as_bytes())
+        .collect::<Result<Vec<_>, _>>()
+        .map_err(tls_error)?;
+    if certs.is_empty() {
+        return Err(KvError::TlsError("No certificate found".into()));
+    }
+    Ok(certs)
+}
+
+fn load_key(pem: &str) -> Result<PrivateKeyDer<'static>, KvError> {
+    PrivateKeyDer::from_pem_slice(pem.as_bytes()).map_err(tls_error)
+}
+
+fn load_roots(pem: &str) -> Result<RootCertStore, KvError> {
+    let mut roots = RootCertStore::empty();
+    for cert in load_certs(pem)? {
+        roots.add(cert).map_err(tls_error)?;
+    }
+    Ok(roots)
+}
+
+#[cfg(test)]
+pub(crate) mod tests {
+    use rcgen::{
+        BasicConstraints, Certificate, CertificateParams, DnType, ExtendedKeyUsagePurpose, IsCa, KeyPair,
+    };
+    use tokio::io::{duplex, AsyncReadExt, AsyncWriteExt};
+    use super::*;
+
+    pub(crate) const DOMAIN: &str = "kvserver.acme.inc";
+
+    /// 测试时生成的一套证书，都是 PEM 格式
+    pub(crate) struct TestCerts {
+        pub(crate) ca: String,
+        pub(crate) server: (String, String),
+        pub(crate) client: (String, String),
+    }
+
+    impl TestCerts {
+        pub(crate) fn generate() -> Self {
+            let ca_key = KeyPair::generate().unwrap();
+            let mut params = CertificateParams::new(Vec::<String>::new()).unwrap();
+            params.is_ca = IsCa::Ca(BasicConstraints::Unconstrained);
+            params.distinguished_name.push(DnType::CommonName, "Acme CA");
+            let ca = params.self_signed(&ca_key).unwrap();
+
+            let server = issue(&ca, &ca_key, DOMAIN, ExtendedKeyUsagePurpose::ServerAuth);
+            let client = issue(&ca, &ca_key, "awesome-device-id", ExtendedKeyUsagePurpose::ClientAuth);
+            Self { ca: ca.pem(), server, client }
+        }
+    }
+
+    fn issue(ca: &Certificate, ca_key: &KeyPair, name: &str, usage: ExtendedKeyUsagePurpose) -> (String, String) {
+        let key = KeyPair::generate().unwrap();
+        let mut params = CertificateParams::new(vec![name.to_string()]).unwrap();
+        params.distinguished_name.push(DnType::CommonName, name);
+        params.extended_key_usages = vec![usage];
+        let cert = params.signed_by(&key, ca, ca_key).unwrap();
+        (cert.pem(), key.serialize_pem())
+    }
+
+    async fn echo(acceptor: TlsServerAcceptor, connector: TlsClientConnector) -> Result<Vec<u8>, KvError> {
+        let (client, server) = duplex(4096);
+        let server = tokio::spawn(async move {
+            let mut stream = acceptor.accept(server).await?;
+            let mut buf = [0u8; 12];
+            stream.read_exact(&mut buf).await?;
+            stream.write_all(&buf).await?;
+            stream.flush().await?;
+            Ok::<_, KvError>(())
+        });
+
+        let mut stream = connector.connect(client).await?;
+        stream.write_all(b"hello world!").await?;
+        let mut buf = vec![0u8; 12];
+        stream.read_exact(&mut buf).await?;
+        server.await.unwrap()?;
+        Ok(buf)
+    }
+
+    #[tokio::test]
+    async fn tls_should_work() {
+        let certs = TestCerts::generate();
+        let acceptor = TlsServerAcceptor::new(&certs.server.0, &certs.server.1, None).unwrap();
+        let connector = TlsClientConnector::new(DOMAIN, None, Some(&certs.ca)).unwrap();
+        assert_eq!(echo(acceptor, connector).await.unwrap(), b"hello world!");
+    }
+
+    #[tokio::test]
+    async fn tls_with_client_cert_should_work() {
+        let certs = TestCerts::generate();
+        let acceptor = TlsServerAcceptor::new(&certs.server.0, &certs.server.1, Some(&certs.ca)).unwrap();
+        let identity = (certs.client.0.as_str(), certs.client.1.as_str());
+        let connector = TlsClientConnector::new(DOMAIN, Some(identity), Some(&certs.ca)).unwrap();
+        assert_eq!(echo(acceptor, connector).await.unwrap(), b"hello world!");
+    }
+
+    #[tokio::test]
+    async fn tls_without_client_cert_should_be_rejected_by_mtls_server() {
+        let certs = TestCerts::generate();
+        let acceptor = TlsServerAcceptor::new(&certs.server.0, &certs.server.1, Some(&certs.ca)).unwrap();
+        let connector = TlsClientConnector::new(DOMAIN, None, Some(&certs.ca)).unwrap();
+        assert!(echo(acceptor, connector).await.is_err());
+    }
+
+    #[tokio::test]
+    async fn tls_with_untrusted_server_should_fail() {
+        let certs = TestCerts::generate();
+        let other = TestCerts::generate();
+        let acceptor = TlsServerAcceptor::new(&certs.server.0, &certs.server.1, None).unwrap();
+        let connector = TlsClientConnector::new(DOMAIN, None, Some(&other.ca)).unwrap();
+        assert!(echo(acceptor, connector).await.is_err());
+
+        let acceptor = TlsServerAcceptor::new(&certs.server.0, &certs.server.1, None).unwrap();
+        let connector = TlsClientConnector::new("wrong.domain", None, Some(&certs.ca)).unwrap();
+        assert!(echo(acceptor, connector).await.is_err());
+    }
+
+    #[test]
+    fn invalid_pem_should_be_rejected() {
+        assert!(matches!(
+            TlsServerAcceptor::new("not a cert", "not a key", None),
+            Err(KvError::TlsError(_))
+        ));
+    }
+}
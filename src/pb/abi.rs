@@ -1,163 +1,875 @@
 /// 来自客户端的命令请求
-#[derive(PartialOrd)]
-#[derive(Clone, PartialEq, ::prost::Message)]
+#[derive(PartialOrd, Clone, PartialEq, ::prost::Message)]
 pub struct CommandRequest {
-    #[prost(oneof="command_request::RequestData", tags="1, 2, 3, 4, 5, 6, 7, 8, 9")]
+    #[prost(
+        oneof = "command_request::RequestData",
+        tags = "1, 2, 3, 4, 5, 6, 7, 8, 9, 10, 11, 12, 13, 14, 15, 16, 17, 18, 19, 20, 21, 22, 23, 24, 25, 26, 27, 28, 29, 30, 31, 32, 33"
+    )]
     pub request_data: ::core::option::Option<command_request::RequestData>,
 }
 /// Nested message and enum types in `CommandRequest`.
 pub mod command_request {
-    #[derive(PartialOrd)]
+    #[derive(PartialOrd, serde::Serialize, serde::Deserialize)]
+    #[serde(rename_all = "snake_case")]
     #[derive(Clone, PartialEq, ::prost::Oneof)]
     pub enum RequestData {
-        #[prost(message, tag="1")]
+        #[prost(message, tag = "1")]
         Hget(super::Hget),
-        #[prost(message, tag="2")]
+        #[prost(message, tag = "2")]
         Hgetall(super::Hgetall),
-        #[prost(message, tag="3")]
+        #[prost(message, tag = "3")]
         Hmget(super::Hmget),
-        #[prost(message, tag="4")]
+        #[prost(message, tag = "4")]
         Hset(super::Hset),
-        #[prost(message, tag="5")]
+        #[prost(message, tag = "5")]
         Hmset(super::Hmset),
-        #[prost(message, tag="6")]
+        #[prost(message, tag = "6")]
         Hdel(super::Hdel),
-        #[prost(message, tag="7")]
+        #[prost(message, tag = "7")]
         Hmdel(super::Hmdel),
-        #[prost(message, tag="8")]
+        #[prost(message, tag = "8")]
         Hexist(super::Hexist),
-        #[prost(message, tag="9")]
+        #[prost(message, tag = "9")]
         Hmexist(super::Hmexist),
+        #[prost(message, tag = "10")]
+        Save(super::Save),
+        #[prost(message, tag = "11")]
+        Expire(super::Expire),
+        #[prost(message, tag = "12")]
+        Persist(super::Persist),
+        #[prost(message, tag = "13")]
+        Ttl(super::Ttl),
+        #[prost(message, tag = "14")]
+        Subscribe(super::Subscribe),
+        #[prost(message, tag = "15")]
+        Unsubscribe(super::Unsubscribe),
+        #[prost(message, tag = "16")]
+        Publish(super::Publish),
+        #[prost(message, tag = "17")]
+        Watch(super::Watch),
+        #[prost(message, tag = "18")]
+        Unwatch(super::Unwatch),
+        #[prost(message, tag = "19")]
+        Hincrby(super::Hincrby),
+        #[prost(message, tag = "20")]
+        Hincrbyfloat(super::Hincrbyfloat),
+        #[prost(message, tag = "21")]
+        Hsetnx(super::Hsetnx),
+        #[prost(message, tag = "22")]
+        Hcas(super::Hcas),
+        #[prost(message, tag = "23")]
+        Transaction(super::Transaction),
+        #[prost(message, tag = "24")]
+        Batch(super::BatchRequest),
+        #[prost(message, tag = "25")]
+        Listtables(super::Listtables),
+        #[prost(message, tag = "26")]
+        Droptable(super::Droptable),
+        #[prost(message, tag = "27")]
+        Renametable(super::Renametable),
+        #[prost(message, tag = "28")]
+        Truncate(super::Truncate),
+        #[prost(message, tag = "29")]
+        Hlen(super::Hlen),
+        #[prost(message, tag = "30")]
+        Hscan(super::Hscan),
+        #[prost(message, tag = "31")]
+        Createtable(super::Createtable),
+        #[prost(message, tag = "32")]
+        Hrange(super::Hrange),
+        #[prost(message, tag = "33")]
+        Hprefix(super::Hprefix),
     }
 }
 /// 返回的 kvpair
-#[derive(PartialOrd)]
+#[derive(PartialOrd, serde::Serialize, serde::Deserialize)]
+#[serde(default)]
 #[derive(Clone, PartialEq, ::prost::Message)]
 pub struct KvPair {
-    #[prost(string, tag="1")]
+    #[prost(string, tag = "1")]
     pub key: ::prost::alloc::string::String,
-    #[prost(message, optional, tag="2")]
+    #[prost(message, optional, tag = "2")]
     pub value: ::core::option::Option<Value>,
 }
 /// 返回的值（有不同的类型）
-#[derive(PartialOrd)]
-#[derive(Clone, PartialEq, ::prost::Message)]
+#[derive(PartialOrd, Clone, PartialEq, ::prost::Message)]
 pub struct Value {
-    #[prost(oneof="value::Value", tags="1, 2, 3, 4, 5")]
+    #[prost(oneof = "value::Value", tags = "1, 2, 3, 4, 5")]
     pub value: ::core::option::Option<value::Value>,
 }
 /// Nested message and enum types in `Value`.
 pub mod value {
-    #[derive(PartialOrd)]
+    #[derive(PartialOrd, serde::Serialize, serde::Deserialize)]
+    #[serde(rename_all = "snake_case")]
     #[derive(Clone, PartialEq, ::prost::Oneof)]
     pub enum Value {
-        #[prost(string, tag="1")]
+        #[prost(string, tag = "1")]
         String(::prost::alloc::string::String),
-        #[prost(bytes, tag="2")]
+        #[prost(bytes, tag = "2")]
+        #[serde(with = "crate::pb::base64_bytes")]
         Binary(::prost::bytes::Bytes),
-        #[prost(int64, tag="3")]
+        #[prost(int64, tag = "3")]
         Integer(i64),
-        #[prost(double, tag="4")]
+        #[prost(double, tag = "4")]
         Float(f64),
-        #[prost(bool, tag="5")]
+        #[prost(bool, tag = "5")]
         Bool(bool),
     }
 }
 /// 从 table 中获取一个 key，返回 value
-#[derive(PartialOrd)]
+#[derive(PartialOrd, serde::Serialize, serde::Deserialize)]
+#[serde(default)]
 #[derive(Clone, PartialEq, ::prost::Message)]
 pub struct Hget {
-    #[prost(string, tag="1")]
+    #[prost(string, tag = "1")]
     pub table: ::prost::alloc::string::String,
-    #[prost(string, tag="2")]
+    #[prost(string, tag = "2")]
     pub key: ::prost::alloc::string::String,
 }
 /// 从 table 中获取所有的 Kvpair
-#[derive(PartialOrd)]
+#[derive(PartialOrd, serde::Serialize, serde::Deserialize)]
+#[serde(default)]
 #[derive(Clone, PartialEq, ::prost::Message)]
 pub struct Hgetall {
-    #[prost(string, tag="1")]
+    #[prost(string, tag = "1")]
     pub table: ::prost::alloc::string::String,
 }
 /// 从 table 中获取一组 key，返回它们的 value
-#[derive(PartialOrd)]
+#[derive(PartialOrd, serde::Serialize, serde::Deserialize)]
+#[serde(default)]
 #[derive(Clone, PartialEq, ::prost::Message)]
 pub struct Hmget {
-    #[prost(string, tag="1")]
+    #[prost(string, tag = "1")]
     pub table: ::prost::alloc::string::String,
-    #[prost(string, repeated, tag="2")]
+    #[prost(string, repeated, tag = "2")]
     pub keys: ::prost::alloc::vec::Vec<::prost::alloc::string::String>,
 }
 /// 往 table 里存一个 kvpair，
 /// 如果 table 不存在就创建这个 table
-#[derive(PartialOrd)]
+#[derive(PartialOrd, serde::Serialize, serde::Deserialize)]
+#[serde(default)]
 #[derive(Clone, PartialEq, ::prost::Message)]
 pub struct Hset {
-    #[prost(string, tag="1")]
+    #[prost(string, tag = "1")]
     pub table: ::prost::alloc::string::String,
-    #[prost(message, optional, tag="2")]
+    #[prost(message, optional, tag = "2")]
     pub pair: ::core::option::Option<KvPair>,
+    /// 多少毫秒后过期，0 表示不过期
+    #[prost(uint64, tag = "3")]
+    pub ttl: u64,
+    /// 过期的时间点（unix 时间戳，毫秒），不为 0 时优先于 ttl
+    #[prost(uint64, tag = "4")]
+    pub expire_at: u64,
 }
 /// 往 table 中存一组 kvpair，
 /// 如果 table 不存在就创建这个 table
-#[derive(PartialOrd)]
+#[derive(PartialOrd, serde::Serialize, serde::Deserialize)]
+#[serde(default)]
 #[derive(Clone, PartialEq, ::prost::Message)]
 pub struct Hmset {
-    #[prost(string, tag="1")]
+    #[prost(string, tag = "1")]
     pub table: ::prost::alloc::string::String,
-    #[prost(message, repeated, tag="2")]
+    #[prost(message, repeated, tag = "2")]
     pub pairs: ::prost::alloc::vec::Vec<KvPair>,
+    /// 多少毫秒后过期，0 表示不过期
+    #[prost(uint64, tag = "3")]
+    pub ttl: u64,
+    /// 过期的时间点（unix 时间戳，毫秒），不为 0 时优先于 ttl
+    #[prost(uint64, tag = "4")]
+    pub expire_at: u64,
 }
 /// 从 table 中删除一个 key，返回它之前的值
-#[derive(PartialOrd)]
+#[derive(PartialOrd, serde::Serialize, serde::Deserialize)]
+#[serde(default)]
 #[derive(Clone, PartialEq, ::prost::Message)]
 pub struct Hdel {
-    #[prost(string, tag="1")]
+    #[prost(string, tag = "1")]
     pub table: ::prost::alloc::string::String,
-    #[prost(string, tag="2")]
+    #[prost(string, tag = "2")]
     pub key: ::prost::alloc::string::String,
 }
 /// 从 table 中删除一组 key，返回它们之前的值
-#[derive(PartialOrd)]
+#[derive(PartialOrd, serde::Serialize, serde::Deserialize)]
+#[serde(default)]
 #[derive(Clone, PartialEq, ::prost::Message)]
 pub struct Hmdel {
-    #[prost(string, tag="1")]
+    #[prost(string, tag = "1")]
     pub table: ::prost::alloc::string::String,
-    #[prost(string, repeated, tag="2")]
+    #[prost(string, repeated, tag = "2")]
     pub keys: ::prost::alloc::vec::Vec<::prost::alloc::string::String>,
 }
 /// 查看 key 是否存在
-#[derive(PartialOrd)]
+#[derive(PartialOrd, serde::Serialize, serde::Deserialize)]
+#[serde(default)]
 #[derive(Clone, PartialEq, ::prost::Message)]
 pub struct Hexist {
-    #[prost(string, tag="1")]
+    #[prost(string, tag = "1")]
     pub table: ::prost::alloc::string::String,
-    #[prost(string, tag="2")]
+    #[prost(string, tag = "2")]
     pub key: ::prost::alloc::string::String,
 }
 /// 查看一组 key 是否存在
-#[derive(PartialOrd)]
+#[derive(PartialOrd, serde::Serialize, serde::Deserialize)]
+#[serde(default)]
 #[derive(Clone, PartialEq, ::prost::Message)]
 pub struct Hmexist {
-    #[prost(string, tag="1")]
+    #[prost(string, tag = "1")]
     pub table: ::prost::alloc::string::String,
-    #[prost(string, repeated, tag="2")]
+    #[prost(string, repeated, tag = "2")]
     pub keys: ::prost::alloc::vec::Vec<::prost::alloc::string::String>,
 }
+/// 把数据保存到磁盘上（MemTable 会写一个快照文件）
+#[derive(PartialOrd, serde::Serialize, serde::Deserialize)]
+#[serde(default)]
+#[derive(Clone, PartialEq, ::prost::Message)]
+pub struct Save {}
+/// 设置 key 的过期时间，返回 key 是否存在
+#[derive(PartialOrd, serde::Serialize, serde::Deserialize)]
+#[serde(default)]
+#[derive(Clone, PartialEq, ::prost::Message)]
+pub struct Expire {
+    #[prost(string, tag = "1")]
+    pub table: ::prost::alloc::string::String,
+    #[prost(string, tag = "2")]
+    pub key: ::prost::alloc::string::String,
+    /// 多少毫秒后过期
+    #[prost(uint64, tag = "3")]
+    pub ttl: u64,
+    /// 过期的时间点（unix 时间戳，毫秒），不为 0 时优先于 ttl
+    #[prost(uint64, tag = "4")]
+    pub expire_at: u64,
+}
+/// 去掉 key 的过期时间，返回 key 是否存在
+#[derive(PartialOrd, serde::Serialize, serde::Deserialize)]
+#[serde(default)]
+#[derive(Clone, PartialEq, ::prost::Message)]
+pub struct Persist {
+    #[prost(string, tag = "1")]
+    pub table: ::prost::alloc::string::String,
+    #[prost(string, tag = "2")]
+    pub key: ::prost::alloc::string::String,
+}
+/// 查看 key 还有多少毫秒过期，key 不存在返回 -2，没有过期时间返回 -1
+#[derive(PartialOrd, serde::Serialize, serde::Deserialize)]
+#[serde(default)]
+#[derive(Clone, PartialEq, ::prost::Message)]
+pub struct Ttl {
+    #[prost(string, tag = "1")]
+    pub table: ::prost::alloc::string::String,
+    #[prost(string, tag = "2")]
+    pub key: ::prost::alloc::string::String,
+}
+/// 订阅一个 topic。服务器先返回订阅的 id，之后 topic 上的每条消息都是一个单独的 response，
+/// 消息的内容放在 values 中
+#[derive(PartialOrd, serde::Serialize, serde::Deserialize)]
+#[serde(default)]
+#[derive(Clone, PartialEq, ::prost::Message)]
+pub struct Subscribe {
+    #[prost(string, tag = "1")]
+    pub topic: ::prost::alloc::string::String,
+}
+/// 取消订阅，id 是 Subscribe 返回的订阅 id
+#[derive(PartialOrd, serde::Serialize, serde::Deserialize)]
+#[serde(default)]
+#[derive(Clone, PartialEq, ::prost::Message)]
+pub struct Unsubscribe {
+    #[prost(string, tag = "1")]
+    pub topic: ::prost::alloc::string::String,
+    #[prost(uint32, tag = "2")]
+    pub id: u32,
+}
+/// 向 topic 发布一条消息，返回收到这条消息的订阅者数量
+#[derive(PartialOrd, serde::Serialize, serde::Deserialize)]
+#[serde(default)]
+#[derive(Clone, PartialEq, ::prost::Message)]
+pub struct Publish {
+    #[prost(string, tag = "1")]
+    pub topic: ::prost::alloc::string::String,
+    #[prost(message, repeated, tag = "2")]
+    pub data: ::prost::alloc::vec::Vec<Value>,
+}
+/// 监听 table 中 key 的变化。服务器先返回订阅的 id，
+/// 之后每个修改了这个 table 的命令都会产生一个带 events 的 response
+#[derive(PartialOrd, serde::Serialize, serde::Deserialize)]
+#[serde(default)]
+#[derive(Clone, PartialEq, ::prost::Message)]
+pub struct Watch {
+    #[prost(string, tag = "1")]
+    pub table: ::prost::alloc::string::String,
+}
+/// 取消监听，id 是 Watch 返回的订阅 id
+#[derive(PartialOrd, serde::Serialize, serde::Deserialize)]
+#[serde(default)]
+#[derive(Clone, PartialEq, ::prost::Message)]
+pub struct Unwatch {
+    #[prost(string, tag = "1")]
+    pub table: ::prost::alloc::string::String,
+    #[prost(uint32, tag = "2")]
+    pub id: u32,
+}
+/// 把 key 的整数值加上 delta，返回加之后的值。key 不存在时当作 0
+#[derive(PartialOrd, serde::Serialize, serde::Deserialize)]
+#[serde(default)]
+#[derive(Clone, PartialEq, ::prost::Message)]
+pub struct Hincrby {
+    #[prost(string, tag = "1")]
+    pub table: ::prost::alloc::string::String,
+    #[prost(string, tag = "2")]
+    pub key: ::prost::alloc::string::String,
+    #[prost(int64, tag = "3")]
+    pub delta: i64,
+}
+/// 把 key 的数值加上浮点数 delta，返回加之后的值。key 不存在时当作 0
+#[derive(PartialOrd, serde::Serialize, serde::Deserialize)]
+#[serde(default)]
+#[derive(Clone, PartialEq, ::prost::Message)]
+pub struct Hincrbyfloat {
+    #[prost(string, tag = "1")]
+    pub table: ::prost::alloc::string::String,
+    #[prost(string, tag = "2")]
+    pub key: ::prost::alloc::string::String,
+    #[prost(double, tag = "3")]
+    pub delta: f64,
+}
+/// key 不存在时才设置 value，返回新的版本号。key 已经存在时返回 409
+#[derive(PartialOrd, serde::Serialize, serde::Deserialize)]
+#[serde(default)]
+#[derive(Clone, PartialEq, ::prost::Message)]
+pub struct Hsetnx {
+    #[prost(string, tag = "1")]
+    pub table: ::prost::alloc::string::String,
+    #[prost(message, optional, tag = "2")]
+    pub pair: ::core::option::Option<KvPair>,
+}
+/// key 当前的值等于 expected（version 不为 0 时改为比较版本号）时才设置 value，
+/// 返回新的版本号，不满足时返回 412。expected 为空表示 key 不存在
+#[derive(PartialOrd, serde::Serialize, serde::Deserialize)]
+#[serde(default)]
+#[derive(Clone, PartialEq, ::prost::Message)]
+pub struct Hcas {
+    #[prost(string, tag = "1")]
+    pub table: ::prost::alloc::string::String,
+    #[prost(string, tag = "2")]
+    pub key: ::prost::alloc::string::String,
+    #[prost(message, optional, tag = "3")]
+    pub value: ::core::option::Option<Value>,
+    #[prost(message, optional, tag = "4")]
+    pub expected: ::core::option::Option<Value>,
+    #[prost(uint64, tag = "5")]
+    pub version: u64,
+}
+/// 事务开始前要检查的 key：当前的值等于 expected（version 不为 0 时改为比较版本号），
+/// expected 为空表示 key 不存在。和 HCAS 的规则一样
+#[derive(PartialOrd, serde::Serialize, serde::Deserialize)]
+#[serde(default)]
+#[derive(Clone, PartialEq, ::prost::Message)]
+pub struct WatchedKey {
+    #[prost(string, tag = "1")]
+    pub table: ::prost::alloc::string::String,
+    #[prost(string, tag = "2")]
+    pub key: ::prost::alloc::string::String,
+    #[prost(message, optional, tag = "3")]
+    pub expected: ::core::option::Option<Value>,
+    #[prost(uint64, tag = "4")]
+    pub version: u64,
+}
+/// 事务：watched 中的 key 都没有变化时，依次执行 commands，执行期间不会有其它写操作插进来。
+/// 任何一个 key 变化了就放弃整个事务，返回 412。
+/// 和 Redis 一样，某个命令失败不会回滚之前的命令，每个命令的结果依次放在 response 的 responses 中
+#[derive(PartialOrd, serde::Serialize, serde::Deserialize)]
+#[serde(default)]
+#[derive(Clone, PartialEq, ::prost::Message)]
+pub struct Transaction {
+    #[prost(message, repeated, tag = "1")]
+    pub watched: ::prost::alloc::vec::Vec<WatchedKey>,
+    #[prost(message, repeated, tag = "2")]
+    pub commands: ::prost::alloc::vec::Vec<CommandRequest>,
+}
+/// 在一个请求中发送多个命令，减少往返的次数。命令依次执行，但不是事务，执行期间可能有其它写操作。
+/// stop_on_error 为 true 时，遇到第一个失败（status >= 400）的命令就停止，后面的命令不再执行
+#[derive(PartialOrd, serde::Serialize, serde::Deserialize)]
+#[serde(default)]
+#[derive(Clone, PartialEq, ::prost::Message)]
+pub struct BatchRequest {
+    #[prost(message, repeated, tag = "1")]
+    pub requests: ::prost::alloc::vec::Vec<CommandRequest>,
+    #[prost(bool, tag = "2")]
+    pub stop_on_error: bool,
+}
+/// 批量命令的结果，依次对应 BatchRequest 中执行了的命令。
+/// 作为 CommandRequest 发送时，这些结果放在 CommandResponse 的 responses 中
+#[derive(PartialOrd, serde::Serialize, serde::Deserialize)]
+#[serde(default)]
+#[derive(Clone, PartialEq, ::prost::Message)]
+pub struct BatchResponse {
+    #[prost(message, repeated, tag = "1")]
+    pub responses: ::prost::alloc::vec::Vec<CommandResponse>,
+}
+/// 返回所有 table 的名字，按名字排序，每个名字是一个 string value
+#[derive(PartialOrd, serde::Serialize, serde::Deserialize)]
+#[serde(default)]
+#[derive(Clone, PartialEq, ::prost::Message)]
+pub struct Listtables {}
+/// 按 cursor 分页遍历 table，返回的 pairs 是这一页的数据，CommandResponse 的 cursor 是下一页的 cursor。
+/// cursor 为空表示从头开始。count 是这一页最多检查多少个 key，为 0 时是 10；
+/// pattern 是 glob 格式的 key 过滤条件，只过滤检查过的 key，所以一页的数据可能是空的，需要继续遍历直到 cursor 为空
+#[derive(PartialOrd, serde::Serialize, serde::Deserialize)]
+#[serde(default)]
+#[derive(Clone, PartialEq, ::prost::Message)]
+pub struct Hscan {
+    #[prost(string, tag = "1")]
+    pub table: ::prost::alloc::string::String,
+    #[prost(string, tag = "2")]
+    pub cursor: ::prost::alloc::string::String,
+    #[prost(uint32, tag = "3")]
+    pub count: u32,
+    #[prost(string, tag = "4")]
+    pub pattern: ::prost::alloc::string::String,
+}
+/// 创建指定类型的 table，成功时返回 true，table 已经存在时返回 409。
+/// 写入一个不存在的 table 时会自动创建 HASH 类型的 table，所以 ORDERED 的 table 要在写入之前创建
+#[derive(PartialOrd, serde::Serialize, serde::Deserialize)]
+#[serde(default)]
+#[derive(Clone, PartialEq, ::prost::Message)]
+pub struct Createtable {
+    #[prost(string, tag = "1")]
+    pub table: ::prost::alloc::string::String,
+    #[prost(enumeration = "TableKind", tag = "2")]
+    pub kind: i32,
+}
+/// 按 key 的顺序返回 ORDERED table 中 [start, end] 范围内的 kv pair，对 HASH table 返回 400。
+/// start 为空表示从第一个 key 开始，end 为空表示到最后一个 key 为止；
+/// 缺省包含 start 和 end，start_exclusive / end_exclusive 为 true 时不包含。
+/// reverse 为 true 时从大到小返回；limit 是最多返回多少个，为 0 时不限制
+#[derive(PartialOrd, serde::Serialize, serde::Deserialize)]
+#[serde(default)]
+#[derive(Clone, PartialEq, ::prost::Message)]
+pub struct Hrange {
+    #[prost(string, tag = "1")]
+    pub table: ::prost::alloc::string::String,
+    #[prost(string, tag = "2")]
+    pub start: ::prost::alloc::string::String,
+    #[prost(string, tag = "3")]
+    pub end: ::prost::alloc::string::String,
+    #[prost(bool, tag = "4")]
+    pub start_exclusive: bool,
+    #[prost(bool, tag = "5")]
+    pub end_exclusive: bool,
+    #[prost(bool, tag = "6")]
+    pub reverse: bool,
+    #[prost(uint32, tag = "7")]
+    pub limit: u32,
+}
+/// 按 key 的顺序返回 ORDERED table 中以 prefix 开头的 kv pair，reverse 和 limit 和 HRANGE 一样
+#[derive(PartialOrd, serde::Serialize, serde::Deserialize)]
+#[serde(default)]
+#[derive(Clone, PartialEq, ::prost::Message)]
+pub struct Hprefix {
+    #[prost(string, tag = "1")]
+    pub table: ::prost::alloc::string::String,
+    #[prost(string, tag = "2")]
+    pub prefix: ::prost::alloc::string::String,
+    #[prost(bool, tag = "3")]
+    pub reverse: bool,
+    #[prost(uint32, tag = "4")]
+    pub limit: u32,
+}
+// DROPTABLE / RENAMETABLE / TRUNCATE 修改整个 table，不产生 WATCH 的 keyspace 事件
+
+/// 删除整个 table，返回 table 之前是否存在
+#[derive(PartialOrd, serde::Serialize, serde::Deserialize)]
+#[serde(default)]
+#[derive(Clone, PartialEq, ::prost::Message)]
+pub struct Droptable {
+    #[prost(string, tag = "1")]
+    pub table: ::prost::alloc::string::String,
+}
+/// 重命名 table，成功时返回 true。table 不存在时返回 404，new_name 已经存在时返回 409
+#[derive(PartialOrd, serde::Serialize, serde::Deserialize)]
+#[serde(default)]
+#[derive(Clone, PartialEq, ::prost::Message)]
+pub struct Renametable {
+    #[prost(string, tag = "1")]
+    pub table: ::prost::alloc::string::String,
+    #[prost(string, tag = "2")]
+    pub new_name: ::prost::alloc::string::String,
+}
+/// 删除 table 中所有的 key，但保留 table，返回删除的 key 的个数
+#[derive(PartialOrd, serde::Serialize, serde::Deserialize)]
+#[serde(default)]
+#[derive(Clone, PartialEq, ::prost::Message)]
+pub struct Truncate {
+    #[prost(string, tag = "1")]
+    pub table: ::prost::alloc::string::String,
+}
+/// 返回 table 中 key 的个数，table 不存在时返回 0
+#[derive(PartialOrd, serde::Serialize, serde::Deserialize)]
+#[serde(default)]
+#[derive(Clone, PartialEq, ::prost::Message)]
+pub struct Hlen {
+    #[prost(string, tag = "1")]
+    pub table: ::prost::alloc::string::String,
+}
+/// table 中一个 key 的变化
+#[derive(PartialOrd, serde::Serialize, serde::Deserialize)]
+#[serde(default)]
+#[derive(Clone, PartialEq, ::prost::Message)]
+pub struct KeyspaceEvent {
+    #[prost(string, tag = "1")]
+    pub table: ::prost::alloc::string::String,
+    #[prost(string, tag = "2")]
+    pub key: ::prost::alloc::string::String,
+    #[prost(enumeration = "KeyspaceOp", tag = "3")]
+    pub op: i32,
+    /// 修改之前的值，之前没有这个 key 时为空。HINCRBY / HINCRBYFLOAT 不返回之前的值，也为空。
+    /// TABLE_RENAMED 时是原来的 table 名
+    #[prost(message, optional, tag = "4")]
+    pub old_value: ::core::option::Option<Value>,
+    /// 修改之后的值，DEL 时为空。TABLE_RENAMED 时是新的 table 名
+    #[prost(message, optional, tag = "5")]
+    pub new_value: ::core::option::Option<Value>,
+}
 /// 服务器的响应
-#[derive(PartialOrd)]
+#[derive(PartialOrd, serde::Serialize, serde::Deserialize)]
+#[serde(default)]
 #[derive(Clone, PartialEq, ::prost::Message)]
 pub struct CommandResponse {
     /// 状态码；复用 HTTP 2xx/4xx/5xx 状态码
-    #[prost(uint32, tag="1")]
+    #[prost(uint32, tag = "1")]
     pub status: u32,
     /// 如果不是 2xx，message 里包含详细的信息
-    #[prost(string, tag="2")]
+    #[prost(string, tag = "2")]
     pub message: ::prost::alloc::string::String,
     /// 成功返回的 values
-    #[prost(message, repeated, tag="3")]
+    #[prost(message, repeated, tag = "3")]
     pub values: ::prost::alloc::vec::Vec<Value>,
     /// 成功返回的 kv pairs
-    #[prost(message, repeated, tag="4")]
+    #[prost(message, repeated, tag = "4")]
     pub pairs: ::prost::alloc::vec::Vec<KvPair>,
+    /// WATCH 收到的 key 的变化，一个命令修改的多个 key 放在同一个 response 中
+    #[prost(message, repeated, tag = "5")]
+    #[serde(skip_serializing_if = "Vec::is_empty")]
+    pub events: ::prost::alloc::vec::Vec<KeyspaceEvent>,
+    /// 事务或者批量命令中每个命令的 response
+    #[prost(message, repeated, tag = "6")]
+    #[serde(skip_serializing_if = "Vec::is_empty")]
+    pub responses: ::prost::alloc::vec::Vec<CommandResponse>,
+    /// HSCAN 下一页的 cursor，为空表示遍历结束
+    #[prost(string, tag = "7")]
+    #[serde(skip_serializing_if = "String::is_empty")]
+    pub cursor: ::prost::alloc::string::String,
+}
+/// 遍历一个 table，以 stream 的方式返回其中的 kv pair
+#[derive(PartialOrd, serde::Serialize, serde::Deserialize)]
+#[serde(default)]
+#[derive(Clone, PartialEq, ::prost::Message)]
+pub struct ScanRequest {
+    #[prost(string, tag = "1")]
+    pub table: ::prost::alloc::string::String,
+    /// 只返回以 prefix 开头的 key，为空时返回所有的 key
+    #[prost(string, tag = "2")]
+    pub prefix: ::prost::alloc::string::String,
+}
+/// table 的类型。HASH 不保证 key 的顺序；ORDERED 按 key 排序，支持 HRANGE / HPREFIX
+#[derive(
+    serde::Serialize,
+    serde::Deserialize,
+    Clone,
+    Copy,
+    Debug,
+    PartialEq,
+    Eq,
+    Hash,
+    PartialOrd,
+    Ord,
+    ::prost::Enumeration,
+)]
+#[repr(i32)]
+pub enum TableKind {
+    Hash = 0,
+    Ordered = 1,
+}
+/// key 发生了什么变化
+#[derive(
+    serde::Serialize,
+    serde::Deserialize,
+    Clone,
+    Copy,
+    Debug,
+    PartialEq,
+    Eq,
+    Hash,
+    PartialOrd,
+    Ord,
+    ::prost::Enumeration,
+)]
+#[repr(i32)]
+pub enum KeyspaceOp {
+    Set = 0,
+    Del = 1,
+    /// 下面是整个 table 的变化，事件中的 key 为空，WATCH 的一方需要重新读取整个 table
+    /// table 被 DROPTABLE 删除了
+    TableDropped = 2,
+    /// table 中所有的 key 被 TRUNCATE 删除了
+    TableTruncated = 3,
+    /// table 被 RENAMETABLE 重命名，原来的 table 和新的 table 都会收到这个事件
+    TableRenamed = 4,
+}
+#[doc = r" Generated client implementations."]
+pub mod kv_service_client {
+    #![allow(unused_variables, dead_code, missing_docs, clippy::let_unit_value)]
+    use tonic::codegen::*;
+    #[doc = " KV server 的 gRPC 服务，其它语言可以直接用 abi.proto 生成客户端"]
+    #[derive(Debug, Clone)]
+    pub struct KvServiceClient<T> {
+        inner: tonic::client::Grpc<T>,
+    }
+    impl KvServiceClient<tonic::transport::Channel> {
+        #[doc = r" Attempt to create a new client by connecting to a given endpoint."]
+        pub async fn connect<D>(dst: D) -> Result<Self, tonic::transport::Error>
+        where
+            D: std::convert::TryInto<tonic::transport::Endpoint>,
+            D::Error: Into<StdError>,
+        {
+            let conn = tonic::transport::Endpoint::new(dst)?.connect().await?;
+            Ok(Self::new(conn))
+        }
+    }
+    impl<T> KvServiceClient<T>
+    where
+        T: tonic::client::GrpcService<tonic::body::BoxBody>,
+        T::ResponseBody: Body + Send + Sync + 'static,
+        T::Error: Into<StdError>,
+        <T::ResponseBody as Body>::Error: Into<StdError> + Send,
+    {
+        pub fn new(inner: T) -> Self {
+            let inner = tonic::client::Grpc::new(inner);
+            Self { inner }
+        }
+        pub fn with_interceptor<F>(
+            inner: T,
+            interceptor: F,
+        ) -> KvServiceClient<InterceptedService<T, F>>
+        where
+            F: tonic::service::Interceptor,
+            T: tonic::codegen::Service<
+                http::Request<tonic::body::BoxBody>,
+                Response = http::Response<
+                    <T as tonic::client::GrpcService<tonic::body::BoxBody>>::ResponseBody,
+                >,
+            >,
+            <T as tonic::codegen::Service<http::Request<tonic::body::BoxBody>>>::Error:
+                Into<StdError> + Send + Sync,
+        {
+            KvServiceClient::new(InterceptedService::new(inner, interceptor))
+        }
+        #[doc = r" Compress requests with `gzip`."]
+        #[doc = r""]
+        #[doc = r" This requires the server to support it otherwise it might respond with an"]
+        #[doc = r" error."]
+        pub fn send_gzip(mut self) -> Self {
+            self.inner = self.inner.send_gzip();
+            self
+        }
+        #[doc = r" Enable decompressing responses with `gzip`."]
+        pub fn accept_gzip(mut self) -> Self {
+            self.inner = self.inner.accept_gzip();
+            self
+        }
+        #[doc = " 执行一个命令"]
+        pub async fn execute(
+            &mut self,
+            request: impl tonic::IntoRequest<super::CommandRequest>,
+        ) -> Result<tonic::Response<super::CommandResponse>, tonic::Status> {
+            self.inner.ready().await.map_err(|e| {
+                tonic::Status::new(
+                    tonic::Code::Unknown,
+                    format!("Service was not ready: {}", e.into()),
+                )
+            })?;
+            let codec = tonic::codec::ProstCodec::default();
+            let path = http::uri::PathAndQuery::from_static("/abi.KvService/Execute");
+            self.inner.unary(request.into_request(), path, codec).await
+        }
+        #[doc = " 遍历 table，每个 kv pair 是 stream 中的一个消息，不需要一次把整个 table 放进一个 response"]
+        pub async fn scan(
+            &mut self,
+            request: impl tonic::IntoRequest<super::ScanRequest>,
+        ) -> Result<tonic::Response<tonic::codec::Streaming<super::KvPair>>, tonic::Status>
+        {
+            self.inner.ready().await.map_err(|e| {
+                tonic::Status::new(
+                    tonic::Code::Unknown,
+                    format!("Service was not ready: {}", e.into()),
+                )
+            })?;
+            let codec = tonic::codec::ProstCodec::default();
+            let path = http::uri::PathAndQuery::from_static("/abi.KvService/Scan");
+            self.inner
+                .server_streaming(request.into_request(), path, codec)
+                .await
+        }
+    }
+}
+#[doc = r" Generated server implementations."]
+pub mod kv_service_server {
+    #![allow(unused_variables, dead_code, missing_docs, clippy::let_unit_value)]
+    use tonic::codegen::*;
+    #[doc = "Generated trait containing gRPC methods that should be implemented for use with KvServiceServer."]
+    #[async_trait]
+    pub trait KvService: Send + Sync + 'static {
+        #[doc = " 执行一个命令"]
+        async fn execute(
+            &self,
+            request: tonic::Request<super::CommandRequest>,
+        ) -> Result<tonic::Response<super::CommandResponse>, tonic::Status>;
+        #[doc = "Server streaming response type for the Scan method."]
+        type ScanStream: futures_core::Stream<Item = Result<super::KvPair, tonic::Status>>
+            + Send
+            + Sync
+            + 'static;
+        #[doc = " 遍历 table，每个 kv pair 是 stream 中的一个消息，不需要一次把整个 table 放进一个 response"]
+        async fn scan(
+            &self,
+            request: tonic::Request<super::ScanRequest>,
+        ) -> Result<tonic::Response<Self::ScanStream>, tonic::Status>;
+    }
+    #[doc = " KV server 的 gRPC 服务，其它语言可以直接用 abi.proto 生成客户端"]
+    #[derive(Debug)]
+    pub struct KvServiceServer<T: KvService> {
+        inner: _Inner<T>,
+        accept_compression_encodings: (),
+        send_compression_encodings: (),
+    }
+    struct _Inner<T>(Arc<T>);
+    impl<T: KvService> KvServiceServer<T> {
+        pub fn new(inner: T) -> Self {
+            let inner = Arc::new(inner);
+            let inner = _Inner(inner);
+            Self {
+                inner,
+                accept_compression_encodings: Default::default(),
+                send_compression_encodings: Default::default(),
+            }
+        }
+        pub fn with_interceptor<F>(inner: T, interceptor: F) -> InterceptedService<Self, F>
+        where
+            F: tonic::service::Interceptor,
+        {
+            InterceptedService::new(Self::new(inner), interceptor)
+        }
+    }
+    impl<T, B> tonic::codegen::Service<http::Request<B>> for KvServiceServer<T>
+    where
+        T: KvService,
+        B: Body + Send + Sync + 'static,
+        B::Error: Into<StdError> + Send + 'static,
+    {
+        type Response = http::Response<tonic::body::BoxBody>;
+        type Error = Never;
+        type Future = BoxFuture<Self::Response, Self::Error>;
+        fn poll_ready(&mut self, _cx: &mut Context<'_>) -> Poll<Result<(), Self::Error>> {
+            Poll::Ready(Ok(()))
+        }
+        fn call(&mut self, req: http::Request<B>) -> Self::Future {
+            let inner = self.inner.clone();
+            match req.uri().path() {
+                "/abi.KvService/Execute" => {
+                    #[allow(non_camel_case_types)]
+                    struct ExecuteSvc<T: KvService>(pub Arc<T>);
+                    impl<T: KvService> tonic::server::UnaryService<super::CommandRequest> for ExecuteSvc<T> {
+                        type Response = super::CommandResponse;
+                        type Future = BoxFuture<tonic::Response<Self::Response>, tonic::Status>;
+                        fn call(
+                            &mut self,
+                            request: tonic::Request<super::CommandRequest>,
+                        ) -> Self::Future {
+                            let inner = self.0.clone();
+                            let fut = async move { (*inner).execute(request).await };
+                            Box::pin(fut)
+                        }
+                    }
+                    let accept_compression_encodings = self.accept_compression_encodings;
+                    let send_compression_encodings = self.send_compression_encodings;
+                    let inner = self.inner.clone();
+                    let fut = async move {
+                        let inner = inner.0;
+                        let method = ExecuteSvc(inner);
+                        let codec = tonic::codec::ProstCodec::default();
+                        let mut grpc = tonic::server::Grpc::new(codec).apply_compression_config(
+                            accept_compression_encodings,
+                            send_compression_encodings,
+                        );
+                        let res = grpc.unary(method, req).await;
+                        Ok(res)
+                    };
+                    Box::pin(fut)
+                }
+                "/abi.KvService/Scan" => {
+                    #[allow(non_camel_case_types)]
+                    struct ScanSvc<T: KvService>(pub Arc<T>);
+                    impl<T: KvService> tonic::server::ServerStreamingService<super::ScanRequest> for ScanSvc<T> {
+                        type Response = super::KvPair;
+                        type ResponseStream = T::ScanStream;
+                        type Future =
+                            BoxFuture<tonic::Response<Self::ResponseStream>, tonic::Status>;
+                        fn call(
+                            &mut self,
+                            request: tonic::Request<super::ScanRequest>,
+                        ) -> Self::Future {
+                            let inner = self.0.clone();
+                            let fut = async move { (*inner).scan(request).await };
+                            Box::pin(fut)
+                        }
+                    }
+                    let accept_compression_encodings = self.accept_compression_encodings;
+                    let send_compression_encodings = self.send_compression_encodings;
+                    let inner = self.inner.clone();
+                    let fut = async move {
+                        let inner = inner.0;
+                        let method = ScanSvc(inner);
+                        let codec = tonic::codec::ProstCodec::default();
+                        let mut grpc = tonic::server::Grpc::new(codec).apply_compression_config(
+                            accept_compression_encodings,
+                            send_compression_encodings,
+                        );
+                        let res = grpc.server_streaming(method, req).await;
+                        Ok(res)
+                    };
+                    Box::pin(fut)
+                }
+                _ => Box::pin(async move {
+                    Ok(http::Response::builder()
+                        .status(200)
+                        .header("grpc-status", "12")
+                        .header("content-type", "application/grpc")
+                        .body(empty_body())
+                        .unwrap())
+                }),
+            }
+        }
+    }
+    impl<T: KvService> Clone for KvServiceServer<T> {
+        fn clone(&self) -> Self {
+            let inner = self.inner.clone();
+            Self {
+                inner,
+                accept_compression_encodings: self.accept_compression_encodings,
+                send_compression_encodings: self.send_compression_encodings,
+            }
+        }
+    }
+    impl<T: KvService> Clone for _Inner<T> {
+        fn clone(&self) -> Self {
+            Self(self.0.clone())
+        }
+    }
+    impl<T: std::fmt::Debug> std::fmt::Debug for _Inner<T> {
+        fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+            write!(f, "{:?}", self.0)
+        }
+    }
+    impl<T: KvService> tonic::transport::NamedService for KvServiceServer<T> {
+        const NAME: &'static str = "abi.KvService";
+    }
 }
@@ -1,9 +1,12 @@
 pub mod abi;
 
-use http::StatusCode;
-use abi::*;
 use crate::command_request::RequestData;
 use crate::errors::KvError;
+use abi::*;
+use http::StatusCode;
+use prost::Message;
+use serde::{Deserialize, Deserializer, Serialize, Serializer};
+use std::convert::TryFrom;
 
 impl CommandRequest {
     /// 创建 HSET 命令
@@ -11,8 +14,26 @@ impl CommandRequest {
         Self {
             request_data: Some(RequestData::Hset(Hset {
                 table: table.into(),
-                pair: Some(KvPair::new(key, value))
-            }))
+                pair: Some(KvPair::new(key, value)),
+                ..Default::default()
+            })),
+        }
+    }
+
+    /// 创建带过期时间的 HSET 命令，ttl 的单位是毫秒
+    pub fn new_hset_with_ttl(
+        table: impl Into<String>,
+        key: impl Into<String>,
+        value: Value,
+        ttl: u64,
+    ) -> Self {
+        Self {
+            request_data: Some(RequestData::Hset(Hset {
+                table: table.into(),
+                pair: Some(KvPair::new(key, value)),
+                ttl,
+                ..Default::default()
+            })),
         }
     }
 
@@ -21,8 +42,8 @@ impl CommandRequest {
         Self {
             request_data: Some(RequestData::Hget(Hget {
                 table: table.into(),
-                key: key.into()
-            }))
+                key: key.into(),
+            })),
         }
     }
 
@@ -31,7 +52,7 @@ impl CommandRequest {
         Self {
             request_data: Some(RequestData::Hgetall(Hgetall {
                 table: table.into(),
-            }))
+            })),
         }
     }
 
@@ -41,7 +62,7 @@ impl CommandRequest {
             request_data: Some(RequestData::Hmget(Hmget {
                 table: table.into(),
                 keys,
-            }))
+            })),
         }
     }
 
@@ -49,8 +70,21 @@ impl CommandRequest {
         Self {
             request_data: Some(RequestData::Hmset(Hmset {
                 table: table.into(),
-                pairs
-            }))
+                pairs,
+                ..Default::default()
+            })),
+        }
+    }
+
+    /// 创建带过期时间的 HMSET 命令，ttl 的单位是毫秒
+    pub fn new_hmset_with_ttl(table: impl Into<String>, pairs: Vec<KvPair>, ttl: u64) -> Self {
+        Self {
+            request_data: Some(RequestData::Hmset(Hmset {
+                table: table.into(),
+                pairs,
+                ttl,
+                ..Default::default()
+            })),
         }
     }
 
@@ -58,8 +92,8 @@ impl CommandRequest {
         Self {
             request_data: Some(RequestData::Hdel(Hdel {
                 table: table.into(),
-                key: key.into()
-            }))
+                key: key.into(),
+            })),
         }
     }
 
@@ -67,8 +101,8 @@ impl CommandRequest {
         Self {
             request_data: Some(RequestData::Hmdel(Hmdel {
                 table: table.into(),
-                keys
-            }))
+                keys,
+            })),
         }
     }
 
@@ -76,8 +110,8 @@ impl CommandRequest {
         Self {
             request_data: Some(RequestData::Hexist(Hexist {
                 table: table.into(),
-                key: key.into()
-            }))
+                key: key.into(),
+            })),
         }
     }
 
@@ -85,12 +119,476 @@ impl CommandRequest {
         Self {
             request_data: Some(RequestData::Hmexist(Hmexist {
                 table: table.into(),
-                keys
-            }))
+                keys,
+            })),
+        }
+    }
+
+    /// 创建 SAVE 命令
+    pub fn new_save() -> Self {
+        Self {
+            request_data: Some(RequestData::Save(Save {})),
+        }
+    }
+
+    /// 创建 EXPIRE 命令，ttl 的单位是毫秒
+    pub fn new_expire(table: impl Into<String>, key: impl Into<String>, ttl: u64) -> Self {
+        Self {
+            request_data: Some(RequestData::Expire(Expire {
+                table: table.into(),
+                key: key.into(),
+                ttl,
+                ..Default::default()
+            })),
+        }
+    }
+
+    /// 创建 PERSIST 命令
+    pub fn new_persist(table: impl Into<String>, key: impl Into<String>) -> Self {
+        Self {
+            request_data: Some(RequestData::Persist(Persist {
+                table: table.into(),
+                key: key.into(),
+            })),
+        }
+    }
+
+    /// 创建 TTL 命令
+    pub fn new_ttl(table: impl Into<String>, key: impl Into<String>) -> Self {
+        Self {
+            request_data: Some(RequestData::Ttl(Ttl {
+                table: table.into(),
+                key: key.into(),
+            })),
+        }
+    }
+
+    /// 创建 SUBSCRIBE 命令
+    pub fn new_subscribe(topic: impl Into<String>) -> Self {
+        Self {
+            request_data: Some(RequestData::Subscribe(Subscribe {
+                topic: topic.into(),
+            })),
+        }
+    }
+
+    /// 创建 UNSUBSCRIBE 命令
+    pub fn new_unsubscribe(topic: impl Into<String>, id: u32) -> Self {
+        Self {
+            request_data: Some(RequestData::Unsubscribe(Unsubscribe {
+                topic: topic.into(),
+                id,
+            })),
+        }
+    }
+
+    /// 创建 PUBLISH 命令
+    pub fn new_publish(topic: impl Into<String>, data: Vec<Value>) -> Self {
+        Self {
+            request_data: Some(RequestData::Publish(Publish {
+                topic: topic.into(),
+                data,
+            })),
+        }
+    }
+
+    /// 创建 WATCH 命令
+    pub fn new_watch(table: impl Into<String>) -> Self {
+        Self {
+            request_data: Some(RequestData::Watch(Watch {
+                table: table.into(),
+            })),
+        }
+    }
+
+    /// 创建 UNWATCH 命令
+    pub fn new_unwatch(table: impl Into<String>, id: u32) -> Self {
+        Self {
+            request_data: Some(RequestData::Unwatch(Unwatch {
+                table: table.into(),
+                id,
+            })),
+        }
+    }
+
+    /// 创建 HINCRBY 命令
+    pub fn new_hincrby(table: impl Into<String>, key: impl Into<String>, delta: i64) -> Self {
+        Self {
+            request_data: Some(RequestData::Hincrby(Hincrby {
+                table: table.into(),
+                key: key.into(),
+                delta,
+            })),
+        }
+    }
+
+    /// 创建 HINCRBYFLOAT 命令
+    pub fn new_hincrbyfloat(table: impl Into<String>, key: impl Into<String>, delta: f64) -> Self {
+        Self {
+            request_data: Some(RequestData::Hincrbyfloat(Hincrbyfloat {
+                table: table.into(),
+                key: key.into(),
+                delta,
+            })),
+        }
+    }
+
+    /// 创建 HSETNX 命令
+    pub fn new_hsetnx(table: impl Into<String>, key: impl Into<String>, value: Value) -> Self {
+        Self {
+            request_data: Some(RequestData::Hsetnx(Hsetnx {
+                table: table.into(),
+                pair: Some(KvPair::new(key, value)),
+            })),
+        }
+    }
+
+    /// 创建 HCAS 命令，key 当前的值等于 expected 时才设置
+    pub fn new_hcas(
+        table: impl Into<String>,
+        key: impl Into<String>,
+        expected: Value,
+        value: Value,
+    ) -> Self {
+        Self {
+            request_data: Some(RequestData::Hcas(Hcas {
+                table: table.into(),
+                key: key.into(),
+                value: Some(value),
+                expected: Some(expected),
+                version: 0,
+            })),
+        }
+    }
+
+    /// 创建 HCAS 命令，key 当前的版本号等于 version 时才设置
+    pub fn new_hcas_version(
+        table: impl Into<String>,
+        key: impl Into<String>,
+        version: u64,
+        value: Value,
+    ) -> Self {
+        Self {
+            request_data: Some(RequestData::Hcas(Hcas {
+                table: table.into(),
+                key: key.into(),
+                value: Some(value),
+                expected: None,
+                version,
+            })),
+        }
+    }
+
+    /// 创建事务，watched 中的 key 都没有变化时才执行 commands
+    pub fn new_transaction(watched: Vec<WatchedKey>, commands: Vec<CommandRequest>) -> Self {
+        Self {
+            request_data: Some(RequestData::Transaction(Transaction { watched, commands })),
+        }
+    }
+
+    /// 创建批量命令，stop_on_error 为 true 时遇到第一个失败的命令就停止
+    pub fn new_batch(requests: Vec<CommandRequest>, stop_on_error: bool) -> Self {
+        Self {
+            request_data: Some(RequestData::Batch(BatchRequest {
+                requests,
+                stop_on_error,
+            })),
+        }
+    }
+
+    /// 创建 LISTTABLES 命令
+    pub fn new_listtables() -> Self {
+        Self {
+            request_data: Some(RequestData::Listtables(Listtables {})),
+        }
+    }
+
+    /// 创建 DROPTABLE 命令
+    pub fn new_droptable(table: impl Into<String>) -> Self {
+        Self {
+            request_data: Some(RequestData::Droptable(Droptable {
+                table: table.into(),
+            })),
+        }
+    }
+
+    /// 创建 RENAMETABLE 命令
+    pub fn new_renametable(table: impl Into<String>, new_name: impl Into<String>) -> Self {
+        Self {
+            request_data: Some(RequestData::Renametable(Renametable {
+                table: table.into(),
+                new_name: new_name.into(),
+            })),
+        }
+    }
+
+    /// 创建 TRUNCATE 命令
+    pub fn new_truncate(table: impl Into<String>) -> Self {
+        Self {
+            request_data: Some(RequestData::Truncate(Truncate {
+                table: table.into(),
+            })),
+        }
+    }
+
+    /// 创建 HLEN 命令
+    pub fn new_hlen(table: impl Into<String>) -> Self {
+        Self {
+            request_data: Some(RequestData::Hlen(Hlen {
+                table: table.into(),
+            })),
+        }
+    }
+
+    /// 创建 HSCAN 命令，cursor 为空表示从头开始，pattern 为空表示不过滤
+    pub fn new_hscan(
+        table: impl Into<String>,
+        cursor: impl Into<String>,
+        count: u32,
+        pattern: impl Into<String>,
+    ) -> Self {
+        Self {
+            request_data: Some(RequestData::Hscan(Hscan {
+                table: table.into(),
+                cursor: cursor.into(),
+                count,
+                pattern: pattern.into(),
+            })),
+        }
+    }
+
+    /// 创建 CREATETABLE 命令
+    pub fn new_createtable(table: impl Into<String>, kind: TableKind) -> Self {
+        Self {
+            request_data: Some(RequestData::Createtable(Createtable {
+                table: table.into(),
+                kind: kind as i32,
+            })),
+        }
+    }
+
+    /// 创建 HRANGE 命令，返回 [start, end] 范围内所有的 kv pair，start / end 为空表示不限制。
+    /// 需要其它选项时用 Hrange::new 创建，再 into() 成 CommandRequest
+    pub fn new_hrange(
+        table: impl Into<String>,
+        start: impl Into<String>,
+        end: impl Into<String>,
+    ) -> Self {
+        Hrange::new(table, start, end).into()
+    }
+
+    /// 创建 HPREFIX 命令
+    pub fn new_hprefix(
+        table: impl Into<String>,
+        prefix: impl Into<String>,
+        reverse: bool,
+        limit: u32,
+    ) -> Self {
+        Self {
+            request_data: Some(RequestData::Hprefix(Hprefix {
+                table: table.into(),
+                prefix: prefix.into(),
+                reverse,
+                limit,
+            })),
+        }
+    }
+
+    /// 是否是会修改数据的命令，事务或者批量命令中有一个命令会修改数据，整个就算
+    pub fn is_mutating(&self) -> bool {
+        match &self.request_data {
+            Some(RequestData::Transaction(txn)) => {
+                return txn.commands.iter().any(|cmd| cmd.is_mutating())
+            }
+            Some(RequestData::Batch(batch)) => {
+                return batch.requests.iter().any(|cmd| cmd.is_mutating())
+            }
+            _ => {}
+        }
+        matches!(
+            self.request_data,
+            Some(
+                RequestData::Hset(_)
+                    | RequestData::Hmset(_)
+                    | RequestData::Hdel(_)
+                    | RequestData::Hmdel(_)
+                    | RequestData::Expire(_)
+                    | RequestData::Persist(_)
+                    | RequestData::Hincrby(_)
+                    | RequestData::Hincrbyfloat(_)
+                    | RequestData::Hsetnx(_)
+                    | RequestData::Hcas(_)
+                    | RequestData::Droptable(_)
+                    | RequestData::Renametable(_)
+                    | RequestData::Truncate(_)
+                    | RequestData::Createtable(_)
+            )
+        )
+    }
+
+    /// 是否是只读取数据的命令，重复执行不会有任何影响。事务或者批量命令中的每个命令都是才算
+    pub fn is_read_only(&self) -> bool {
+        match &self.request_data {
+            Some(RequestData::Transaction(txn)) => {
+                txn.commands.iter().all(|cmd| cmd.is_read_only())
+            }
+            Some(RequestData::Batch(batch)) => batch.requests.iter().all(|cmd| cmd.is_read_only()),
+            request_data => matches!(
+                request_data,
+                Some(
+                    RequestData::Hget(_)
+                        | RequestData::Hgetall(_)
+                        | RequestData::Hmget(_)
+                        | RequestData::Hexist(_)
+                        | RequestData::Hmexist(_)
+                        | RequestData::Ttl(_)
+                        | RequestData::Listtables(_)
+                        | RequestData::Hlen(_)
+                        | RequestData::Hscan(_)
+                        | RequestData::Hrange(_)
+                        | RequestData::Hprefix(_)
+                )
+            ),
+        }
+    }
+
+    /// 把命令中相对的 ttl 换算成绝对的过期时间。
+    /// 命令写入日志后再重放时，过期时间不会因为重放的时间不同而改变
+    pub fn resolve_expire_at(&mut self, now: u64) {
+        match &mut self.request_data {
+            Some(RequestData::Hset(Hset { ttl, expire_at, .. }))
+            | Some(RequestData::Hmset(Hmset { ttl, expire_at, .. }))
+            | Some(RequestData::Expire(Expire { ttl, expire_at, .. })) => {
+                *expire_at = resolve_expire_at(*ttl, *expire_at, now).unwrap_or_default();
+                *ttl = 0;
+            }
+            _ => {}
+        }
+    }
+
+    /// 条件写入（HSETNX / HCAS）成功之后的效果，等价于一个 HSET。其它命令返回 None
+    pub fn to_unconditional(&self) -> Option<CommandRequest> {
+        match &self.request_data {
+            Some(RequestData::Hsetnx(Hsetnx {
+                table,
+                pair: Some(pair),
+            })) => Some(Self::new_hset(
+                table.as_str(),
+                pair.key.as_str(),
+                pair.value.clone().unwrap_or_default(),
+            )),
+            Some(RequestData::Hcas(Hcas {
+                table, key, value, ..
+            })) => Some(Self::new_hset(
+                table.as_str(),
+                key.as_str(),
+                value.clone().unwrap_or_default(),
+            )),
+            _ => None,
+        }
+    }
+}
+
+impl From<Hrange> for CommandRequest {
+    fn from(hrange: Hrange) -> Self {
+        Self {
+            request_data: Some(RequestData::Hrange(hrange)),
         }
     }
 }
 
+impl Hrange {
+    /// 包含 start 和 end，start / end 为空表示不限制
+    pub fn new(table: impl Into<String>, start: impl Into<String>, end: impl Into<String>) -> Self {
+        Self {
+            table: table.into(),
+            start: start.into(),
+            end: end.into(),
+            ..Default::default()
+        }
+    }
+
+    /// 不包含 start
+    pub fn start_exclusive(mut self) -> Self {
+        self.start_exclusive = true;
+        self
+    }
+
+    /// 不包含 end
+    pub fn end_exclusive(mut self) -> Self {
+        self.end_exclusive = true;
+        self
+    }
+
+    /// 从大到小返回
+    pub fn reverse(mut self) -> Self {
+        self.reverse = true;
+        self
+    }
+
+    /// 最多返回 limit 个
+    pub fn limit(mut self, limit: u32) -> Self {
+        self.limit = limit;
+        self
+    }
+}
+
+/// 根据 ttl 和 expire_at 计算出过期时间，两者都是 0 时返回 None
+pub(crate) fn resolve_expire_at(ttl: u64, expire_at: u64, now: u64) -> Option<u64> {
+    match (ttl, expire_at) {
+        (0, 0) => None,
+        (ttl, 0) => Some(now.saturating_add(ttl)),
+        (_, at) => Some(at),
+    }
+}
+
+/*
+    CommandRequest 和 Value 都只有一个 oneof，JSON 中直接使用 oneof 的内容，不再多包一层：
+    CommandRequest 是 {"hget": {"table": "t", "key": "k"}}，Value 是 {"string": "hello"}，空的 Value 是 null。
+*/
+impl Serialize for CommandRequest {
+    fn serialize<S: Serializer>(&self, serializer: S) -> Result<S::Ok, S::Error> {
+        self.request_data.serialize(serializer)
+    }
+}
+
+impl<'de> Deserialize<'de> for CommandRequest {
+    fn deserialize<D: Deserializer<'de>>(deserializer: D) -> Result<Self, D::Error> {
+        Ok(Self {
+            request_data: Option::deserialize(deserializer)?,
+        })
+    }
+}
+
+impl Serialize for Value {
+    fn serialize<S: Serializer>(&self, serializer: S) -> Result<S::Ok, S::Error> {
+        self.value.serialize(serializer)
+    }
+}
+
+impl<'de> Deserialize<'de> for Value {
+    fn deserialize<D: Deserializer<'de>>(deserializer: D) -> Result<Self, D::Error> {
+        Ok(Self {
+            value: Option::deserialize(deserializer)?,
+        })
+    }
+}
+
+/// JSON 中 binary 类型的 value 使用 base64 编码
+pub(crate) mod base64_bytes {
+    use bytes::Bytes;
+    use serde::{de::Error, Deserialize, Deserializer, Serializer};
+
+    pub fn serialize<S: Serializer>(data: &Bytes, serializer: S) -> Result<S::Ok, S::Error> {
+        serializer.serialize_str(&base64::encode(data))
+    }
+
+    pub fn deserialize<'de, D: Deserializer<'de>>(deserializer: D) -> Result<Bytes, D::Error> {
+        let s = String::deserialize(deserializer)?;
+        base64::decode(s).map(Bytes::from).map_err(D::Error::custom)
+    }
+}
+
 impl KvPair {
     /// 创建一个新的 kv pair
     pub fn new(key: impl Into<String>, value: Value) -> Self {
@@ -101,11 +599,18 @@ impl KvPair {
     }
 }
 
+/// 从 (String, Value) 转换成 KvPair
+impl From<(String, Value)> for KvPair {
+    fn from(data: (String, Value)) -> Self {
+        KvPair::new(data.0, data.1)
+    }
+}
+
 /// 从 String 转换成 Value
 impl From<String> for Value {
     fn from(s: String) -> Self {
         Self {
-            value: Some(value::Value::String(s))
+            value: Some(value::Value::String(s)),
         }
     }
 }
@@ -114,7 +619,7 @@ impl From<String> for Value {
 impl From<&str> for Value {
     fn from(s: &str) -> Self {
         Self {
-            value: Some(value::Value::String(s.into()))
+            value: Some(value::Value::String(s.into())),
         }
     }
 }
@@ -122,7 +627,7 @@ impl From<&str> for Value {
 impl From<i64> for Value {
     fn from(i: i64) -> Self {
         Self {
-            value: Some(value::Value::Integer(i))
+            value: Some(value::Value::Integer(i)),
         }
     }
 }
@@ -131,13 +636,64 @@ impl From<i64> for Value {
 impl From<Value> for CommandResponse {
     fn from(v: Value) -> Self {
         Self {
-            status: StatusCode::OK.as_u16() as _,           // 新用法，这里status本来要接受u32，但StatusCode只要转成u16
+            status: StatusCode::OK.as_u16() as _, // 新用法，这里status本来要接受u32，但StatusCode只要转成u16
             values: vec![v],
             ..Default::default()
         }
     }
 }
 
+/// 从 Vec<KeyspaceEvent> 转换成 CommandResponse，WATCH 的订阅者收到的就是它
+impl From<Vec<KeyspaceEvent>> for CommandResponse {
+    fn from(events: Vec<KeyspaceEvent>) -> Self {
+        Self {
+            status: StatusCode::OK.as_u16() as _,
+            events,
+            ..Default::default()
+        }
+    }
+}
+
+impl WatchedKey {
+    /// key 当前的值等于 expected，expected 为空表示 key 不存在
+    pub fn new(table: impl Into<String>, key: impl Into<String>, expected: Value) -> Self {
+        Self {
+            table: table.into(),
+            key: key.into(),
+            expected: Some(expected),
+            version: 0,
+        }
+    }
+
+    /// key 当前的版本号等于 version
+    pub fn with_version(table: impl Into<String>, key: impl Into<String>, version: u64) -> Self {
+        Self {
+            table: table.into(),
+            key: key.into(),
+            expected: None,
+            version,
+        }
+    }
+}
+
+/// 从事务中每个命令的 response 转换成 CommandResponse
+impl From<Vec<CommandResponse>> for CommandResponse {
+    fn from(responses: Vec<CommandResponse>) -> Self {
+        Self {
+            status: StatusCode::OK.as_u16() as _,
+            responses,
+            ..Default::default()
+        }
+    }
+}
+
+/// 批量命令的结果放在 CommandResponse 的 responses 中返回
+impl From<BatchResponse> for CommandResponse {
+    fn from(batch: BatchResponse) -> Self {
+        batch.responses.into()
+    }
+}
+
 /// 从 Vec<Kvpair> 转换成 CommandResponse
 impl From<Vec<KvPair>> for CommandResponse {
     fn from(pairs: Vec<KvPair>) -> Self {
@@ -149,7 +705,6 @@ impl From<Vec<KvPair>> for CommandResponse {
     }
 }
 
-
 /// 从 KvError 转换成 CommandResponse
 impl From<KvError> for CommandResponse {
     fn from(e: KvError) -> Self {
@@ -160,8 +715,30 @@ impl From<KvError> for CommandResponse {
         };
 
         match e {
-            KvError::NotFound(_, _) => result.status = StatusCode::NOT_FOUND.as_u16() as _,
+            KvError::NotFound(_, _)
+            | KvError::TableNotFound(_)
+            | KvError::SubscriptionNotFound(_, _) => {
+                result.status = StatusCode::NOT_FOUND.as_u16() as _
+            }
             KvError::InvalidCommand(_) => result.status = StatusCode::BAD_REQUEST.as_u16() as _,
+            KvError::KeyExists(_, _) | KvError::TableExists(_) => {
+                result.status = StatusCode::CONFLICT.as_u16() as _
+            }
+            KvError::PreconditionFailed(_, _) => {
+                result.status = StatusCode::PRECONDITION_FAILED.as_u16() as _
+            }
+            KvError::OutOfMemory(_) | KvError::SlowSubscriber(_, _) => {
+                result.status = StatusCode::INSUFFICIENT_STORAGE.as_u16() as _
+            }
+            KvError::Timeout => result.status = StatusCode::GATEWAY_TIMEOUT.as_u16() as _,
+            KvError::FrameTooLarge(_) => {
+                result.status = StatusCode::PAYLOAD_TOO_LARGE.as_u16() as _
+            }
+            // 从服务器收到的错误，原样传递
+            KvError::ServerError(status, message) => {
+                result.status = status;
+                result.message = message;
+            }
             _ => {}
         }
 
@@ -182,7 +759,7 @@ impl From<Vec<Value>> for CommandResponse {
 impl From<f64> for Value {
     fn from(f: f64) -> Self {
         Self {
-            value: Some(value::Value::Float(f))
+            value: Some(value::Value::Float(f)),
         }
     }
 }
@@ -190,11 +767,32 @@ impl From<f64> for Value {
 impl From<bool> for Value {
     fn from(b: bool) -> Self {
         Self {
-            value: Some(value::Value::Bool(b))
+            value: Some(value::Value::Bool(b)),
         }
     }
 }
 
+/// 把 Value 编码成 protobuf 的字节，用于持久化存储
+impl TryFrom<&Value> for Vec<u8> {
+    type Error = KvError;
+
+    fn try_from(v: &Value) -> Result<Self, Self::Error> {
+        let mut buf = Vec::with_capacity(v.encoded_len());
+        v.encode(&mut buf)?;
+        Ok(buf)
+    }
+}
+
+/// 从 protobuf 的字节解码出 Value
+impl TryFrom<&[u8]> for Value {
+    type Error = KvError;
+
+    fn try_from(data: &[u8]) -> Result<Self, Self::Error> {
+        let msg = Value::decode(data)?;
+        Ok(msg)
+    }
+}
+
 // #[derive(Debug)]
 // struct KvVecString(Vec<String>);
 //
@@ -205,12 +803,10 @@ impl From<bool> for Value {
 // }
 
 #[derive(Debug)]
-pub struct StringWrapper(pub(crate) String);
+pub struct StringWrapper(pub String);
 
 impl From<Vec<String>> for StringWrapper {
     fn from(sw: Vec<String>) -> Self {
-        Self {
-            0: format!("{:?}", sw)
-        }
+        Self(format!("{:?}", sw))
     }
-}
\ No newline at end of file
+}
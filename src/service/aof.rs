@@ -0,0 +1,690 @@
+use std::convert::TryFrom;
+use std::fs::{self, File, OpenOptions};
+use std::io::{self, BufReader, BufWriter, ErrorKind, Read, Seek, SeekFrom, Write};
+use std::path::{Path, PathBuf};
+use std::sync::{Arc, Mutex, Weak};
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::thread;
+use std::time::Duration;
+use crc32fast::Hasher;
+use prost::Message;
+use serde::{Deserialize, Serialize};
+use tracing::{info, warn};
+use crate::*;
+use crate::command_request::RequestData;
+use crate::memory::MemTable;
+use crate::storage::now_ms;
+use crate::storage::snapshot::ChecksumWriter;
+
+/*
+    AOF（append-only file）的格式很简单：每条记录是 4 字节大端的长度，后面跟着 protobuf 编码的 CommandRequest。
+    只有会修改数据的命令（HSET / HMSET / HDEL / HMDEL 等）才会写入日志，并且是先修改 Storage，执行成功之后再写日志，
+    失败的命令（比如 HINCRBY 溢出、RENAMETABLE 的目标已经存在）不会留在日志里。
+    服务启动时，把日志从头到尾重放一遍，就能恢复出之前的数据。
+
+    日志从不截断，本身就包含了所有的修改，快照只是用来加快启动。SAVE 在日志的锁里保存快照，并在快照中记下当时
+    日志的长度和 checksum（AofMark）。启动时如果日志的这一段没有变过，就加载快照，只重放这之后的日志；
+    日志在快照之后被重写过的话，就忽略快照，只重放日志。这样快照里已经包含的命令不会被执行两次。
+*/
+
+// 重写时，每条 HMSET 最多带多少个 kv pair
+const REWRITE_BATCH_SIZE: usize = 1000;
+
+/// 什么时候把日志 fsync 到磁盘
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum FsyncPolicy {
+    /// 每写一条日志都 fsync，最安全，也最慢
+    Always,
+    /// 后台线程每秒 fsync 一次，最多丢失一秒的数据
+    EverySec,
+    /// 从不主动 fsync，交给操作系统决定
+    Never,
+}
+
+/// AOF 的配置
+#[derive(Clone, Debug)]
+pub struct AofConfig {
+    /// 日志文件的路径
+    pub path: PathBuf,
+    /// fsync 策略
+    pub fsync: FsyncPolicy,
+    /// 日志文件小于这个大小时，不会触发重写
+    pub rewrite_min_size: u64,
+    /// 日志文件比上一次重写后增长了这个百分比时，在后台触发重写
+    pub rewrite_percentage: u64,
+}
+
+impl AofConfig {
+    /// 使用缺省配置：每秒 fsync，日志超过 64MB 并且翻倍后重写
+    pub fn new(path: impl Into<PathBuf>) -> Self {
+        Self {
+            path: path.into(),
+            fsync: FsyncPolicy::EverySec,
+            rewrite_min_size: 64 * 1024 * 1024,
+            rewrite_percentage: 100,
+        }
+    }
+}
+
+/// Append-only 的命令日志，clone 是轻量级的
+#[derive(Clone)]
+pub struct Aof {
+    inner: Arc<AofInner>,
+}
+
+struct AofInner {
+    config: AofConfig,
+    log: Mutex<LogFile>,
+    rewriting: AtomicBool,
+}
+
+struct LogFile {
+    file: File,
+    // 当前日志文件的大小
+    size: u64,
+    // 上一次重写完成后日志文件的大小
+    base_size: u64,
+    // 日志文件中所有字节的 crc32
+    checksum: Hasher,
+}
+
+impl LogFile {
+    fn mark(&self) -> AofMark {
+        AofMark { offset: self.size, checksum: self.checksum.clone().finalize() }
+    }
+}
+
+impl Aof {
+    /// 打开日志文件，如果不存在则创建
+    pub fn open(config: AofConfig) -> Result<Self, KvError> {
+        let file = OpenOptions::new().create(true).append(true).open(&config.path)?;
+        let size = file.metadata()?.len();
+        let mut checksum = Hasher::new();
+        let valid = read_records(&config.path, 0, size, |record, _| checksum.update(record))?;
+        // 进程在写日志的过程中崩溃，会在文件末尾留下不完整的记录，直接截掉
+        if valid < size {
+            warn!("Truncating {} bytes of incomplete AOF tail", size - valid);
+            file.set_len(valid)?;
+        }
+        let fsync = config.fsync;
+        let inner = Arc::new(AofInner {
+            config,
+            log: Mutex::new(LogFile { file, size: valid, base_size: valid, checksum }),
+            rewriting: AtomicBool::new(false),
+        });
+
+        if fsync == FsyncPolicy::EverySec {
+            spawn_fsync_thread(Arc::downgrade(&inner));
+        }
+
+        Ok(Self { inner })
+    }
+
+    /// 把日志重放到 store 中，返回重放的命令条数。需要在服务处理请求之前调用
+    pub fn replay(&self, store: &impl Storage) -> Result<usize, KvError> {
+        let log = self.inner.log.lock().unwrap();
+        let count = replay_file(&self.inner.config.path, 0, log.size, store)?;
+        info!("Replayed {} commands from {:?}", count, self.inner.config.path);
+        Ok(count)
+    }
+
+    /// 从快照和日志中恢复出 MemTable。快照对应的那段日志没有变过时，加载快照并只重放它之后的日志；
+    /// 否则只重放日志。需要在服务处理请求之前调用
+    pub fn recover(&self, snapshot: Option<&Path>) -> Result<MemTable, KvError> {
+        let path = &self.inner.config.path;
+        let log = self.inner.log.lock().unwrap();
+        let (store, start) = match snapshot {
+            Some(snapshot) if snapshot.exists() => match MemTable::load_snapshot_with_mark(snapshot)? {
+                // 没有日志，或者快照不是在开启 AOF 时保存的，日志都是快照之后写的
+                (store, _) if log.size == 0 => (store, 0),
+                (store, None) => (store, 0),
+                (store, Some(mark)) if mark.offset <= log.size
+                    && checksum_prefix(path, mark.offset)? == mark.checksum => (store, mark.offset),
+                (_, Some(_)) => {
+                    warn!("AOF was rewritten after snapshot {:?} was saved, ignoring the snapshot", snapshot);
+                    (MemTable::new(), 0)
+                }
+            },
+            _ => (MemTable::new(), 0),
+        };
+        let count = replay_file(path, start, log.size, &store)?;
+        info!("Replayed {} commands from {:?} starting at {}", count, path, start);
+        Ok(store)
+    }
+
+    /// 在日志的锁里调用 save 保存快照，快照中记下当前日志的位置。保存期间所有写日志的命令都会等待
+    pub fn checkpoint(&self, save: impl FnOnce(AofMark) -> Result<(), KvError>) -> Result<(), KvError> {
+        let log = self.inner.log.lock().unwrap();
+        // 快照依赖这一段日志，先保证它已经在磁盘上了
+        log.file.sync_data()?;
+        save(log.mark())
+    }
+
+    /// 调用 apply 执行 cmd，执行成功之后再把 cmd 写入日志。
+    /// 执行的过程中一直持有日志的锁，保证日志里的顺序和命令真正执行的顺序一致。
+    /// 写日志失败时返回错误，但这时命令已经执行了
+    pub fn append(
+        &self,
+        mut cmd: CommandRequest,
+        apply: impl FnOnce(CommandRequest) -> CommandResponse,
+    ) -> Result<CommandResponse, KvError> {
+        cmd.resolve_expire_at(now_ms());
+        // 先编码好，cmd 的所有权要交给 apply
+        let data = encode_record(&cmd)?;
+        let mut log = self.inner.log.lock().unwrap();
+        let res = apply(cmd);
+        if res.status == 200 {
+            self.write_record(&mut log, &data)?;
+        }
+
+        if self.should_rewrite(&log) {
+            self.spawn_rewrite();
+        }
+        Ok(res)
+    }
+
+    /// 条件写入（HSETNX / HCAS）使用：先调用 apply 执行，成功之后再把 effect 写入日志。
+    /// effect 是等价的 HSET，版本号不会持久化，重放时没法再检查前提条件
+    pub fn append_applied(
+        &self,
+        effect: CommandRequest,
+        apply: impl FnOnce() -> CommandResponse,
+    ) -> Result<CommandResponse, KvError> {
+        let data = encode_record(&effect)?;
+        let mut log = self.inner.log.lock().unwrap();
+        let res = apply();
+        if res.status == 200 {
+            self.write_record(&mut log, &data)?;
+        }
+
+        if self.should_rewrite(&log) {
+            self.spawn_rewrite();
+        }
+        Ok(res)
+    }
+
+    /// 重写日志：把当前日志压缩成每个 key 一条记录。重写期间可以继续写日志
+    pub fn rewrite(&self) -> Result<(), KvError> {
+        rewrite(&self.inner)
+    }
+
+    /// 把日志刷到磁盘上
+    pub fn sync(&self) -> Result<(), KvError> {
+        self.inner.log.lock().unwrap().file.sync_data()?;
+        Ok(())
+    }
+
+    /// 当前日志文件的大小
+    pub fn size(&self) -> u64 {
+        self.inner.log.lock().unwrap().size
+    }
+
+    fn write_record(&self, log: &mut LogFile, data: &[u8]) -> Result<(), KvError> {
+        let result = log.file.write_all(data).and_then(|()| match self.inner.config.fsync {
+            FsyncPolicy::Always => log.file.sync_data(),
+            _ => Ok(()),
+        });
+        if let Err(e) = result {
+            // 写了一半的记录会让它之后的日志都没法重放，截回到写之前的长度
+            if let Err(e) = log.file.set_len(log.size) {
+                warn!("Failed to truncate torn AOF record: {:?}", e);
+            }
+            return Err(e.into());
+        }
+        log.size += data.len() as u64;
+        log.checksum.update(data);
+        Ok(())
+    }
+
+    fn should_rewrite(&self, log: &LogFile) -> bool {
+        let config = &self.inner.config;
+        log.size >= config.rewrite_min_size
+            && log.size >= log.base_size + log.base_size * config.rewrite_percentage / 100
+            && !self.inner.rewriting.load(Ordering::Acquire)
+    }
+
+    fn spawn_rewrite(&self) {
+        let inner = Arc::clone(&self.inner);
+        thread::spawn(move || {
+            if let Err(e) = rewrite(&inner) {
+                warn!("Failed to rewrite AOF: {:?}", e);
+            }
+        });
+    }
+}
+
+fn spawn_fsync_thread(inner: Weak<AofInner>) {
+    thread::spawn(move || loop {
+        thread::sleep(Duration::from_secs(1));
+        // Aof 被 drop 之后，线程也就退出了
+        let inner = match inner.upgrade() {
+            Some(inner) => inner,
+            None => break,
+        };
+        let log = inner.log.lock().unwrap();
+        if let Err(e) = log.file.sync_data() {
+            warn!("Failed to fsync AOF: {:?}", e);
+        }
+    });
+}
+
+fn rewrite(inner: &AofInner) -> Result<(), KvError> {
+    // 同一时间只允许一个重写
+    if inner.rewriting.swap(true, Ordering::AcqRel) {
+        return Ok(());
+    }
+    let result = do_rewrite(inner);
+    inner.rewriting.store(false, Ordering::Release);
+    result
+}
+
+fn do_rewrite(inner: &AofInner) -> Result<(), KvError> {
+    let path = &inner.config.path;
+    // 1. 记下当前日志的长度，之后只压缩这之前的部分，不阻塞写日志
+    let offset = inner.log.lock().unwrap().size;
+    let store = MemTable::new();
+    replay_file(path, 0, offset, &store)?;
+
+    let tmp_path = path.with_extension("rewrite");
+    let mut writer = ChecksumWriter::new(BufWriter::new(File::create(&tmp_path)?));
+    let now = now_ms();
+    for (name, table) in store.freeze() {
+        // 有序的 table 要在写入 key 之前创建，否则重放时会自动创建成 hash table
+        if table.kind() == TableKind::Ordered {
+            writer.write_all(&encode_record(&CommandRequest::new_createtable(name.as_str(), TableKind::Ordered))?)?;
+        }
+        let mut pairs = table
+            .data
+            .into_iter()
+            .filter(|(key, _)| !matches!(table.expires.get(key), Some(at) if *at <= now))
+            .map(|(key, entry)| KvPair::new(key, entry.value))
+            .peekable();
+        while pairs.peek().is_some() {
+            let batch = pairs.by_ref().take(REWRITE_BATCH_SIZE).collect();
+            writer.write_all(&encode_record(&CommandRequest::new_hmset(name.as_str(), batch))?)?;
+        }
+        // 过期时间用绝对时间写入
+        for (key, at) in table.expires.into_iter().filter(|(_, at)| *at > now) {
+            let mut cmd = CommandRequest::new_expire(name.as_str(), key, 0);
+            if let Some(RequestData::Expire(expire)) = &mut cmd.request_data {
+                expire.expire_at = at;
+            }
+            writer.write_all(&encode_record(&cmd)?)?;
+        }
+    }
+
+    // 2. 重写期间新写入的日志，原样追加到新文件后面，然后替换掉旧文件
+    let mut log = inner.log.lock().unwrap();
+    let mut old = File::open(path)?;
+    old.seek(SeekFrom::Start(offset))?;
+    io::copy(&mut old, &mut writer)?;
+    let (writer, checksum) = writer.finish();
+    let tmp = writer.into_inner().map_err(|e| KvError::from(e.into_error()))?;
+    tmp.sync_all()?;
+    fs::rename(&tmp_path, path)?;
+
+    let file = OpenOptions::new().append(true).open(path)?;
+    let size = file.metadata()?.len();
+    info!("Rewrote AOF {:?}: {} -> {} bytes", path, log.size, size);
+    *log = LogFile { file, size, base_size: size, checksum: Hasher::new_with_initial(checksum) };
+    Ok(())
+}
+
+/// 重放日志中 [start, limit) 范围内的记录，返回重放的命令条数
+fn replay_file(path: &Path, start: u64, limit: u64, store: &impl Storage) -> Result<usize, KvError> {
+    let mut count = 0;
+    read_records(path, start, limit, |_, cmd| {
+        dispatch(cmd, store);
+        count += 1;
+    })?;
+    Ok(count)
+}
+
+/// 依次读出日志中 [start, limit) 范围内的完整记录，对每条记录调用 f(记录的原始字节, 命令)。
+/// 遇到不完整或者损坏的记录就停下，返回最后一条完整记录结束的位置
+fn read_records(
+    path: &Path,
+    start: u64,
+    limit: u64,
+    mut f: impl FnMut(&[u8], CommandRequest),
+) -> Result<u64, KvError> {
+    let mut file = File::open(path)?;
+    file.seek(SeekFrom::Start(start))?;
+    let mut reader = BufReader::new(file.take(limit.saturating_sub(start)));
+    let mut valid = start;
+    loop {
+        let mut record = vec![0u8; 4];
+        if let Err(e) = reader.read_exact(&mut record) {
+            return match e.kind() {
+                ErrorKind::UnexpectedEof => Ok(valid),
+                _ => Err(e.into()),
+            };
+        }
+        let len = u32::from_be_bytes([record[0], record[1], record[2], record[3]]) as usize;
+        record.resize(4 + len, 0);
+        if let Err(e) = reader.read_exact(&mut record[4..]) {
+            return match e.kind() {
+                ErrorKind::UnexpectedEof => Ok(valid),
+                _ => Err(e.into()),
+            };
+        }
+        let cmd = match CommandRequest::decode(&record[4..]) {
+            Ok(cmd) => cmd,
+            Err(e) => {
+                warn!("Stop reading at corrupted AOF record: {:?}", e);
+                return Ok(valid);
+            }
+        };
+        f(&record, cmd);
+        valid += record.len() as u64;
+    }
+}
+
+/// 日志前 len 个字节的 crc32
+fn checksum_prefix(path: &Path, len: u64) -> Result<u32, KvError> {
+    let mut reader = File::open(path)?.take(len);
+    let mut hasher = Hasher::new();
+    let mut buf = vec![0u8; 64 * 1024];
+    loop {
+        match reader.read(&mut buf)? {
+            0 => return Ok(hasher.finalize()),
+            n => hasher.update(&buf[..n]),
+        }
+    }
+}
+
+/// 把 cmd 编码成一条日志记录
+fn encode_record(cmd: &CommandRequest) -> Result<Vec<u8>, KvError> {
+    let len = u32::try_from(cmd.encoded_len())
+        .map_err(|_| KvError::Internal("Command is too large".into()))?;
+    let mut buf = Vec::with_capacity(4 + len as usize);
+    buf.extend_from_slice(&len.to_be_bytes());
+    cmd.encode(&mut buf)?;
+    Ok(buf)
+}
+
+#[cfg(test)]
+mod tests {
+    use std::fs::OpenOptions;
+    use std::io::Write;
+    use tempfile::tempdir;
+    use super::*;
+
+    #[test]
+    fn aof_replay_should_restore_data() {
+        let dir = tempdir().unwrap();
+        let config = AofConfig::new(dir.path().join("kv.aof"));
+        {
+            let service: Service = ServiceInner::new(MemTable::new())
+                .aof(Aof::open(config.clone()).unwrap())
+                .into();
+            service.execute(CommandRequest::new_hset("t1", "k1", "v1".into()));
+            service.execute(CommandRequest::new_hset("t1", "k2", "v2".into()));
+            service.execute(CommandRequest::new_hdel("t1", "k1"));
+            // 读命令不会写日志
+            service.execute(CommandRequest::new_hget("t1", "k2"));
+        }
+
+        let aof = Aof::open(config).unwrap();
+        let store = MemTable::new();
+        assert_eq!(aof.replay(&store), Ok(3));
+        assert_eq!(store.get("t1", "k1"), Ok(None));
+        assert_eq!(store.get("t1", "k2"), Ok(Some("v2".into())));
+    }
+
+    #[test]
+    fn aof_replay_should_truncate_incomplete_tail() {
+        let dir = tempdir().unwrap();
+        let mut config = AofConfig::new(dir.path().join("kv.aof"));
+        config.fsync = FsyncPolicy::Always;
+        let aof = Aof::open(config.clone()).unwrap();
+        aof.append(CommandRequest::new_hset("t1", "k1", "v1".into()), ok).unwrap();
+        let size = aof.size();
+        drop(aof);
+
+        // 模拟写了一半就崩溃的记录
+        let mut file = OpenOptions::new().append(true).open(&config.path).unwrap();
+        file.write_all(&[0, 0, 0, 10, 1, 2]).unwrap();
+
+        let aof = Aof::open(config).unwrap();
+        let store = MemTable::new();
+        assert_eq!(aof.replay(&store), Ok(1));
+        assert_eq!(aof.size(), size);
+        assert_eq!(store.get("t1", "k1"), Ok(Some("v1".into())));
+    }
+
+    // 执行成功的命令
+    fn ok(_: CommandRequest) -> CommandResponse {
+        Value::from(true).into()
+    }
+
+    // 和 kvs 启动时一样，从快照和日志中恢复数据
+    fn restart(config: &AofConfig, snapshot: &Path) -> Service {
+        let aof = Aof::open(config.clone()).unwrap();
+        let store = aof.recover(Some(snapshot)).unwrap().snapshot_path(snapshot);
+        ServiceInner::new(store).aof(aof).into()
+    }
+
+    #[test]
+    fn recover_should_not_replay_commands_in_snapshot() {
+        let dir = tempdir().unwrap();
+        let config = AofConfig::new(dir.path().join("kv.aof"));
+        let snapshot = dir.path().join("dump.kvs");
+        {
+            let service = restart(&config, &snapshot);
+            service.execute(CommandRequest::new_hincrby("t1", "k1", 1));
+            assert_eq!(service.execute(CommandRequest::new_save()).status, 200);
+            service.execute(CommandRequest::new_hincrby("t1", "k1", 2));
+        }
+
+        let store = Aof::open(config.clone()).unwrap().recover(Some(&snapshot)).unwrap();
+        assert_eq!(store.get("t1", "k1"), Ok(Some(3.into())));
+
+        // 再重启一次，并且退出前 flush 写了新的快照，结果也一样
+        {
+            let service = restart(&config, &snapshot);
+            service.execute(CommandRequest::new_hincrby("t1", "k1", 4));
+            service.flush().unwrap();
+        }
+        let store = Aof::open(config).unwrap().recover(Some(&snapshot)).unwrap();
+        assert_eq!(store.get("t1", "k1"), Ok(Some(7.into())));
+    }
+
+    #[test]
+    fn recover_should_ignore_snapshot_after_rewrite() {
+        let dir = tempdir().unwrap();
+        let config = AofConfig::new(dir.path().join("kv.aof"));
+        let snapshot = dir.path().join("dump.kvs");
+        {
+            let service = restart(&config, &snapshot);
+            service.execute(CommandRequest::new_hincrby("t1", "k1", 1));
+            service.execute(CommandRequest::new_save());
+            service.execute(CommandRequest::new_hincrby("t1", "k1", 2));
+            // 重写之后快照记下的位置就对不上了，只能重放日志
+            Aof::open(config.clone()).unwrap().rewrite().unwrap();
+        }
+
+        let store = Aof::open(config).unwrap().recover(Some(&snapshot)).unwrap();
+        assert_eq!(store.get("t1", "k1"), Ok(Some(3.into())));
+    }
+
+    #[test]
+    fn recover_without_aof_records_should_load_snapshot() {
+        let dir = tempdir().unwrap();
+        let config = AofConfig::new(dir.path().join("kv.aof"));
+        let snapshot = dir.path().join("dump.kvs");
+        let store = MemTable::new();
+        store.set("t1", "k1".into(), "v1".into()).unwrap();
+        store.save_snapshot(&snapshot).unwrap();
+
+        let store = Aof::open(config).unwrap().recover(Some(&snapshot)).unwrap();
+        assert_eq!(store.get("t1", "k1"), Ok(Some("v1".into())));
+    }
+
+    #[test]
+    fn aof_rewrite_should_compact_log() {
+        let dir = tempdir().unwrap();
+        let config = AofConfig::new(dir.path().join("kv.aof"));
+        let aof = Aof::open(config.clone()).unwrap();
+        for i in 0..100 {
+            aof.append(CommandRequest::new_hset("t1", "k1", i.into()), ok).unwrap();
+        }
+        aof.append(CommandRequest::new_hset("t2", "k1", "v1".into()), ok).unwrap();
+        aof.append(CommandRequest::new_hdel("t2", "k1"), ok).unwrap();
+        let size = aof.size();
+
+        aof.rewrite().unwrap();
+        assert!(aof.size() < size);
+        // 重写之后还可以继续写日志
+        aof.append(CommandRequest::new_hset("t1", "k2", "v2".into()), ok).unwrap();
+        drop(aof);
+
+        let store = MemTable::new();
+        Aof::open(config).unwrap().replay(&store).unwrap();
+        assert_eq!(store.get("t1", "k1"), Ok(Some(99.into())));
+        assert_eq!(store.get("t1", "k2"), Ok(Some("v2".into())));
+        assert_eq!(store.get("t2", "k1"), Ok(None));
+    }
+
+    #[test]
+    fn aof_rewrite_should_keep_ordered_tables() {
+        let dir = tempdir().unwrap();
+        let config = AofConfig::new(dir.path().join("kv.aof"));
+        let aof = Aof::open(config.clone()).unwrap();
+        aof.append(CommandRequest::new_createtable("t1", TableKind::Ordered), ok).unwrap();
+        for key in ["k2", "k1", "k2"] {
+            aof.append(CommandRequest::new_hset("t1", key, key.into()), ok).unwrap();
+        }
+        aof.rewrite().unwrap();
+        drop(aof);
+
+        let store = MemTable::new();
+        Aof::open(config).unwrap().replay(&store).unwrap();
+        let keys: Vec<_> = store.range("t1", &KeyRange::all()).unwrap().into_iter().map(|pair| pair.key).collect();
+        assert_eq!(keys, vec!["k1", "k2"]);
+    }
+
+    #[test]
+    fn aof_should_keep_absolute_expire_at() {
+        let dir = tempdir().unwrap();
+        let config = AofConfig::new(dir.path().join("kv.aof"));
+        let aof = Aof::open(config.clone()).unwrap();
+        let store = MemTable::new();
+        let cmd = CommandRequest::new_hset_with_ttl("t1", "k1", "v1".into(), 60_000);
+        aof.append(cmd, |cmd| dispatch(cmd, &store)).unwrap();
+        aof.append(CommandRequest::new_hset("t1", "k2", "v2".into()), |cmd| dispatch(cmd, &store)).unwrap();
+        aof.append(CommandRequest::new_expire("t1", "k2", 1), |cmd| dispatch(cmd, &store)).unwrap();
+        let expire_at = store.get_expire_at("t1", "k1").unwrap();
+        thread::sleep(Duration::from_millis(5));
+
+        // 重放之后过期时间不变，已经过期的 key 不会复活
+        let replayed = MemTable::new();
+        Aof::open(config.clone()).unwrap().replay(&replayed).unwrap();
+        assert_eq!(replayed.get_expire_at("t1", "k1"), Ok(expire_at));
+        assert_eq!(replayed.get("t1", "k2"), Ok(None));
+
+        // 重写之后也一样
+        aof.rewrite().unwrap();
+        let rewritten = MemTable::new();
+        Aof::open(config).unwrap().replay(&rewritten).unwrap();
+        assert_eq!(rewritten.get_expire_at("t1", "k1"), Ok(expire_at));
+        assert_eq!(rewritten.get("t1", "k2"), Ok(None));
+    }
+
+    #[test]
+    fn aof_should_not_log_failed_commands() {
+        let dir = tempdir().unwrap();
+        let config = AofConfig::new(dir.path().join("kv.aof"));
+        let service: Service = ServiceInner::new(MemTable::new())
+            .aof(Aof::open(config.clone()).unwrap())
+            .into();
+        service.execute(CommandRequest::new_hset("t1", "k1", i64::MAX.into()));
+        service.execute(CommandRequest::new_hset("t2", "k1", "v1".into()));
+        // HINCRBY 溢出、RENAMETABLE 的目标已经存在，都执行失败
+        assert_eq!(service.execute(CommandRequest::new_hincrby("t1", "k1", 1)).status, 400);
+        assert_eq!(service.execute(CommandRequest::new_renametable("t1", "t2")).status, 409);
+        drop(service);
+
+        let store = MemTable::new();
+        assert_eq!(Aof::open(config).unwrap().replay(&store), Ok(2));
+    }
+
+    #[test]
+    fn aof_should_log_conditional_writes_as_hset() {
+        let dir = tempdir().unwrap();
+        let config = AofConfig::new(dir.path().join("kv.aof"));
+        let aof = Aof::open(config.clone()).unwrap();
+        let store = MemTable::new();
+        let append = |cmd: CommandRequest| {
+            let effect = cmd.to_unconditional().unwrap();
+            aof.append_applied(effect, || dispatch(cmd, &store)).unwrap()
+        };
+        let res = append(CommandRequest::new_hsetnx("t1", "k1", "v1".into()));
+        let version = match res.values[0].value {
+            Some(value::Value::Integer(v)) => v as u64,
+            _ => panic!("HSETNX should return the version"),
+        };
+        append(CommandRequest::new_hcas_version("t1", "k1", version, "v2".into()));
+        // 失败的条件写入不写日志
+        let size = aof.size();
+        let res = append(CommandRequest::new_hsetnx("t1", "k1", "v3".into()));
+        assert_eq!(res.status, 409);
+        assert_eq!(aof.size(), size);
+
+        // 重放时版本号已经不同了，但日志里是 HSET，结果不受影响
+        let replayed = MemTable::new();
+        assert_eq!(Aof::open(config).unwrap().replay(&replayed), Ok(2));
+        assert_eq!(replayed.get("t1", "k1"), Ok(Some("v2".into())));
+    }
+
+    #[test]
+    fn aof_should_log_batch_in_transaction() {
+        let dir = tempdir().unwrap();
+        let config = AofConfig::new(dir.path().join("kv.aof"));
+        let service: Service = ServiceInner::new(MemTable::new())
+            .aof(Aof::open(config.clone()).unwrap())
+            .into();
+        let txn = |commands| CommandRequest::new_transaction(vec![], vec![CommandRequest::new_batch(commands, false)]);
+        let res = service.execute(txn(vec![CommandRequest::new_hsetnx("t1", "k1", "v1".into())]));
+        let version = match res.responses[0].responses[0].values[0].value {
+            Some(value::Value::Integer(v)) => v as u64,
+            _ => panic!("HSETNX should return the version"),
+        };
+        service.execute(txn(vec![
+            CommandRequest::new_hcas_version("t1", "k1", version, "v2".into()),
+            CommandRequest::new_hset_with_ttl("t1", "k2", "v2".into(), 60_000),
+        ]));
+        let expire_at = service.inner.store.get_expire_at("t1", "k2").unwrap();
+        drop(service);
+        thread::sleep(Duration::from_millis(5));
+
+        // 批量命令中的条件写入以 HSET 记录，相对过期时间记录成绝对时间
+        let replayed = MemTable::new();
+        assert_eq!(Aof::open(config).unwrap().replay(&replayed), Ok(3));
+        assert_eq!(replayed.get("t1", "k1"), Ok(Some("v2".into())));
+        assert_eq!(replayed.get_expire_at("t1", "k2"), Ok(expire_at));
+    }
+
+    #[test]
+    fn aof_should_rewrite_in_background() {
+        let dir = tempdir().unwrap();
+        let mut config = AofConfig::new(dir.path().join("kv.aof"));
+        config.rewrite_min_size = 1024;
+        let aof = Aof::open(config).unwrap();
+        let mut written = 0;
+        for i in 0..200 {
+            let cmd = CommandRequest::new_hset("t1", "k1", i.into());
+            written += encode_record(&cmd).unwrap().len() as u64;
+            aof.append(cmd, ok).unwrap();
+        }
+        // 后台重写完成后，日志会比写入的总量小
+        for _ in 0..100 {
+            if aof.size() < written {
+                break;
+            }
+            thread::sleep(Duration::from_millis(10));
+        }
+        assert!(aof.size() < written);
+    }
+}
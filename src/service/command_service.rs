@@ -1,12 +1,15 @@
+use std::ops::Bound;
 use crate::*;
 use crate::errors::KvError;
-use pb::StringWrapper;
+use crate::pb::resolve_expire_at;
+use crate::storage::now_ms;
 
 
 impl CommandService for Hset {
     fn execute(self, store: &impl Storage) -> CommandResponse {
+        let expire_at = resolve_expire_at(self.ttl, self.expire_at, now_ms());
         match self.pair {
-            Some(v) => match store.set(&self.table, v.key, v.value.unwrap_or_default()) {
+            Some(v) => match store.set_with_expire(&self.table, v.key, v.value.unwrap_or_default(), expire_at) {
                 Ok(Some(v)) => v.into(),
                 Ok(None) => Value::default().into(),
                 Err(e) => e.into(),
@@ -28,8 +31,10 @@ impl CommandService for Hget {
 
 impl CommandService for Hgetall {
     fn execute(self, store: &impl Storage) -> CommandResponse {
-        match store.get_all(&self.table) {
-            Ok(v) =>  v.into(),
+        // get_iter 逐个 shard 读取，不会一直拿着整个 table 的锁。但 response 要一次带上所有的 pairs，
+        // 整个 table 还是会被收集到一个 Vec 里，大 table 请用 HSCAN 分页读取
+        match store.get_iter(&self.table) {
+            Ok(iter) => iter.collect::<Vec<_>>().into(),
             Err(e) => e.into(),
         }
     }
@@ -57,16 +62,12 @@ impl CommandService for Hmget {
 
 impl CommandService for Hmset {
     fn execute(self, store: &impl Storage) -> CommandResponse {
-        self.pairs.into_iter()
-            .map(|pair| {
-                let result = store.set(&self.table, pair.key, pair.value.unwrap());
-                match result {
-                    Ok(Some(v)) => v,
-                    _ => Value::default(),
-                }
-            })
-            .collect::<Vec<_>>()
-            .into()
+        let expire_at = resolve_expire_at(self.ttl, self.expire_at, now_ms());
+        // 所有的 key 一起写入，写入失败（比如内存不够）时一个 key 都没有写，直接返回错误
+        match store.set_many(&self.table, self.pairs, expire_at) {
+            Ok(values) => values.into_iter().map(|v| v.unwrap_or_default()).collect::<Vec<_>>().into(),
+            Err(e) => e.into(),
+        }
     }
 }
 
@@ -84,7 +85,7 @@ impl CommandService for Hmdel {
     fn execute(self, store: &impl Storage) -> CommandResponse {
         self.keys
             .iter()
-            .map(|key| match store.del(&self.table, &key) {
+            .map(|key| match store.del(&self.table, key) {
                 Ok(Some(v)) => v,
                 _ => Value::default(),
             })
@@ -116,6 +117,225 @@ impl CommandService for Hmexist {
 
 }
 
+impl CommandService for Save {
+    fn execute(self, store: &impl Storage) -> CommandResponse {
+        match store.save() {
+            Ok(()) => Value::from(true).into(),
+            Err(e) => e.into(),
+        }
+    }
+}
+impl CommandService for Expire {
+    fn execute(self, store: &impl Storage) -> CommandResponse {
+        let expire_at = match resolve_expire_at(self.ttl, self.expire_at, now_ms()) {
+            Some(at) => at,
+            None => return KvError::InvalidCommand("Expire needs ttl or expire_at".into()).into(),
+        };
+        match store.expire(&self.table, &self.key, Some(expire_at)) {
+            Ok(b) => Value::from(b).into(),
+            Err(e) => e.into(),
+        }
+    }
+}
+
+impl CommandService for Persist {
+    fn execute(self, store: &impl Storage) -> CommandResponse {
+        match store.expire(&self.table, &self.key, None) {
+            Ok(b) => Value::from(b).into(),
+            Err(e) => e.into(),
+        }
+    }
+}
+
+impl CommandService for Ttl {
+    fn execute(self, store: &impl Storage) -> CommandResponse {
+        // 和 Redis 一样，key 不存在返回 -2，没有过期时间返回 -1
+        let exists = match store.contains(&self.table, &self.key) {
+            Ok(b) => b,
+            Err(e) => return e.into(),
+        };
+        if !exists {
+            return Value::from(-2).into();
+        }
+        match store.get_expire_at(&self.table, &self.key) {
+            Ok(Some(at)) => Value::from(at.saturating_sub(now_ms()) as i64).into(),
+            Ok(None) => Value::from(-1).into(),
+            Err(e) => e.into(),
+        }
+    }
+}
+
+impl CommandService for Hincrby {
+    fn execute(self, store: &impl Storage) -> CommandResponse {
+        match store.incr(&self.table, self.key, self.delta.into()) {
+            Ok(v) => v.into(),
+            Err(e) => e.into(),
+        }
+    }
+}
+
+impl CommandService for Hincrbyfloat {
+    fn execute(self, store: &impl Storage) -> CommandResponse {
+        match store.incr(&self.table, self.key, self.delta.into()) {
+            Ok(v) => v.into(),
+            Err(e) => e.into(),
+        }
+    }
+}
+
+impl CommandService for Hsetnx {
+    fn execute(self, store: &impl Storage) -> CommandResponse {
+        let pair = match self.pair {
+            Some(pair) => pair,
+            None => return KvError::InvalidCommand("Hsetnx needs a key and value".into()).into(),
+        };
+        let value = pair.value.unwrap_or_default();
+        match store.set_if(&self.table, pair.key, value, Precondition::Absent) {
+            Ok(version) => Value::from(version as i64).into(),
+            Err(e) => e.into(),
+        }
+    }
+}
+
+impl CommandService for Hcas {
+    fn execute(self, store: &impl Storage) -> CommandResponse {
+        let precondition = Precondition::new(self.expected, self.version);
+        match store.set_if(&self.table, self.key, self.value.unwrap_or_default(), precondition) {
+            Ok(version) => Value::from(version as i64).into(),
+            // HCAS 的前提条件都用 412 表示，即使要求的是 key 不存在
+            Err(KvError::KeyExists(table, key)) => KvError::PreconditionFailed(table, key).into(),
+            Err(e) => e.into(),
+        }
+    }
+}
+
+impl CommandService for BatchRequest {
+    fn execute(self, store: &impl Storage) -> CommandResponse {
+        run_batch(self, |cmd| dispatch(cmd, store)).into()
+    }
+}
+
+impl CommandService for Listtables {
+    fn execute(self, store: &impl Storage) -> CommandResponse {
+        match store.list_tables() {
+            Ok(names) => names.into_iter().map(Value::from).collect::<Vec<_>>().into(),
+            Err(e) => e.into(),
+        }
+    }
+}
+
+impl CommandService for Droptable {
+    fn execute(self, store: &impl Storage) -> CommandResponse {
+        match store.drop_table(&self.table) {
+            Ok(b) => Value::from(b).into(),
+            Err(e) => e.into(),
+        }
+    }
+}
+
+impl CommandService for Renametable {
+    fn execute(self, store: &impl Storage) -> CommandResponse {
+        match store.rename_table(&self.table, &self.new_name) {
+            Ok(()) => Value::from(true).into(),
+            Err(e) => e.into(),
+        }
+    }
+}
+
+impl CommandService for Truncate {
+    fn execute(self, store: &impl Storage) -> CommandResponse {
+        match store.truncate(&self.table) {
+            Ok(n) => Value::from(n as i64).into(),
+            Err(e) => e.into(),
+        }
+    }
+}
+
+impl CommandService for Hlen {
+    fn execute(self, store: &impl Storage) -> CommandResponse {
+        match store.len(&self.table) {
+            Ok(n) => Value::from(n as i64).into(),
+            Err(e) => e.into(),
+        }
+    }
+}
+
+impl CommandService for Hscan {
+    fn execute(self, store: &impl Storage) -> CommandResponse {
+        let count = match self.count {
+            0 => 10,
+            n => n as usize,
+        };
+        let pattern = Some(self.pattern.as_str()).filter(|p| !p.is_empty());
+        match store.scan(&self.table, &self.cursor, count, pattern) {
+            Ok(page) => {
+                let mut res: CommandResponse = page.pairs.into();
+                res.cursor = page.cursor.unwrap_or_default();
+                res
+            }
+            Err(e) => e.into(),
+        }
+    }
+}
+
+impl CommandService for Createtable {
+    fn execute(self, store: &impl Storage) -> CommandResponse {
+        let kind = match TableKind::from_i32(self.kind) {
+            Some(kind) => kind,
+            None => return KvError::InvalidCommand(format!("Invalid table kind: {}", self.kind)).into(),
+        };
+        match store.create_table(&self.table, kind) {
+            Ok(()) => Value::from(true).into(),
+            Err(e) => e.into(),
+        }
+    }
+}
+
+impl CommandService for Hrange {
+    fn execute(self, store: &impl Storage) -> CommandResponse {
+        let bound = |key: String, exclusive: bool| match (key.is_empty(), exclusive) {
+            (true, _) => Bound::Unbounded,
+            (false, true) => Bound::Excluded(key),
+            (false, false) => Bound::Included(key),
+        };
+        let range = KeyRange {
+            start: bound(self.start, self.start_exclusive),
+            end: bound(self.end, self.end_exclusive),
+            reverse: self.reverse,
+            limit: self.limit as usize,
+        };
+        match store.range(&self.table, &range) {
+            Ok(pairs) => pairs.into(),
+            Err(e) => e.into(),
+        }
+    }
+}
+
+impl CommandService for Hprefix {
+    fn execute(self, store: &impl Storage) -> CommandResponse {
+        let range = KeyRange::prefix(&self.prefix)
+            .reverse(self.reverse)
+            .limit(self.limit as usize);
+        match store.range(&self.table, &range) {
+            Ok(pairs) => pairs.into(),
+            Err(e) => e.into(),
+        }
+    }
+}
+
+/// 依次执行批量命令中的每个命令，stop_on_error 时遇到第一个失败的命令就停止
+pub(crate) fn run_batch(batch: BatchRequest, mut execute: impl FnMut(CommandRequest) -> CommandResponse) -> BatchResponse {
+    let mut responses = Vec::with_capacity(batch.requests.len());
+    for cmd in batch.requests {
+        let res = execute(cmd);
+        let failed = res.status >= 400;
+        responses.push(res);
+        if failed && batch.stop_on_error {
+            break;
+        }
+    }
+    BatchResponse { responses }
+}
 
 /*  这些测试的作用就是验证产品需求，比如：HSET 成功返回上一次的值（这和 Redis 略有不同，Redis 返回表示多少 key 受影响的一个整数）
     HGET 返回 Value
@@ -123,7 +343,6 @@ impl CommandService for Hmexist {
  */
 #[cfg(test)]
 mod tests {
-    use crate::command_request::RequestData;
     use super::*;
     use crate::storage::memory::MemTable;       // 要先使memory可见，pub mod memory;
 
@@ -208,8 +427,6 @@ mod tests {
         // ]);
         // println!("{:?}", data);
 
-        let store = MemTable::new();
-
         set_key_pairs(
             "user",
             vec![("u1", "Tyr"), ("u2", "Lindsey"), ("u3", "Rosie")],
@@ -298,6 +515,229 @@ mod tests {
     }
 
 
+    #[test]
+    fn hset_with_ttl_should_work() {
+        let store = MemTable::new();
+        let cmd = CommandRequest::new_hset_with_ttl("t1", "k1", "v1".into(), 60_000);
+        dispatch(cmd, &store);
+        let res = dispatch(CommandRequest::new_ttl("t1", "k1"), &store);
+        assert_eq!(res.status, 200);
+        match &res.values[0].value {
+            Some(value::Value::Integer(ttl)) => assert!(*ttl > 0 && *ttl <= 60_000),
+            v => panic!("unexpected ttl: {:?}", v),
+        }
+    }
+
+    #[test]
+    fn hmset_with_ttl_should_expire() {
+        let store = MemTable::new();
+        let pairs = vec![KvPair::new("k1", "v1".into()), KvPair::new("k2", "v2".into())];
+        let cmd = CommandRequest::new_hmset_with_ttl("t1", pairs, 1);
+        dispatch(cmd, &store);
+        std::thread::sleep(std::time::Duration::from_millis(5));
+        let res = dispatch(CommandRequest::new_hgetall("t1"), &store);
+        assert_res_ok(res, &[], &[]);
+    }
+
+    #[test]
+    fn expire_persist_ttl_should_work() {
+        let store = MemTable::new();
+        set_key_pairs("t1", vec![("k1", "v1")], &store);
+
+        let res = dispatch(CommandRequest::new_ttl("t1", "k1"), &store);
+        assert_res_ok(res, &[(-1).into()], &[]);
+        let res = dispatch(CommandRequest::new_ttl("t1", "k2"), &store);
+        assert_res_ok(res, &[(-2).into()], &[]);
+
+        let res = dispatch(CommandRequest::new_expire("t1", "k1", 60_000), &store);
+        assert_res_ok(res, &[true.into()], &[]);
+        let res = dispatch(CommandRequest::new_expire("t1", "k2", 60_000), &store);
+        assert_res_ok(res, &[false.into()], &[]);
+
+        let res = dispatch(CommandRequest::new_persist("t1", "k1"), &store);
+        assert_res_ok(res, &[true.into()], &[]);
+        let res = dispatch(CommandRequest::new_ttl("t1", "k1"), &store);
+        assert_res_ok(res, &[(-1).into()], &[]);
+    }
+
+    #[test]
+    fn expire_without_ttl_should_return_400() {
+        let store = MemTable::new();
+        set_key_pairs("t1", vec![("k1", "v1")], &store);
+        let res = dispatch(CommandRequest::new_expire("t1", "k1", 0), &store);
+        assert_res_error(res, 400, "Expire needs ttl or expire_at");
+    }
+
+    #[test]
+    fn hincrby_and_hincrbyfloat_should_work() {
+        let store = MemTable::new();
+        set_key_pairs("t1", vec![("name", "tyr")], &store);
+
+        let res = dispatch(CommandRequest::new_hincrby("t1", "count", 5), &store);
+        assert_res_ok(res, &[5.into()], &[]);
+        let res = dispatch(CommandRequest::new_hincrby("t1", "count", -2), &store);
+        assert_res_ok(res, &[3.into()], &[]);
+        let res = dispatch(CommandRequest::new_hincrbyfloat("t1", "count", 0.5), &store);
+        assert_res_ok(res, &[3.5.into()], &[]);
+
+        let res = dispatch(CommandRequest::new_hincrby("t1", "name", 1), &store);
+        assert_res_error(res, 400, "Value is not an integer");
+        set_key_pairs("t1", vec![("max", i64::MAX)], &store);
+        let res = dispatch(CommandRequest::new_hincrby("t1", "max", 1), &store);
+        assert_res_error(res, 400, "overflow");
+    }
+
+    #[test]
+    fn hsetnx_and_hcas_should_work() {
+        let store = MemTable::new();
+        let res = dispatch(CommandRequest::new_hsetnx("t1", "k1", "v1".into()), &store);
+        assert_eq!(res.status, 200);
+        let res = dispatch(CommandRequest::new_hsetnx("t1", "k1", "v2".into()), &store);
+        assert_res_error(res, 409, "Key already exists");
+
+        let res = dispatch(CommandRequest::new_hcas("t1", "k1", "v0".into(), "v2".into()), &store);
+        assert_res_error(res, 412, "Precondition failed");
+        let res = dispatch(CommandRequest::new_hcas("t1", "k1", "v1".into(), "v2".into()), &store);
+        let version = match res.values[0].value {
+            Some(value::Value::Integer(v)) => v as u64,
+            _ => panic!("HCAS should return the version"),
+        };
+        let res = dispatch(CommandRequest::new_hcas_version("t1", "k1", version + 1, "v3".into()), &store);
+        assert_res_error(res, 412, "Precondition failed");
+        let res = dispatch(CommandRequest::new_hcas_version("t1", "k1", version, "v3".into()), &store);
+        assert_eq!(res.status, 200);
+
+        // expected 为空表示 key 不存在，失败时也是 412
+        let res = dispatch(CommandRequest::new_hcas("t1", "k1", Value::default(), "v4".into()), &store);
+        assert_res_error(res, 412, "Precondition failed");
+        let res = dispatch(CommandRequest::new_hcas("t1", "k2", Value::default(), "v4".into()), &store);
+        assert_eq!(res.status, 200);
+        let res = dispatch(CommandRequest::new_hmget("t1", vec!["k1".into(), "k2".into()]), &store);
+        assert_res_ok(res, &["v3".into(), "v4".into()], &[]);
+    }
+
+    #[test]
+    fn batch_should_work() {
+        let store = MemTable::new();
+        let requests = vec![
+            CommandRequest::new_hset("t1", "k1", "v1".into()),
+            CommandRequest::new_hincrby("t1", "k1", 1),
+            CommandRequest::new_hset("t2", "k2", "v2".into()),
+        ];
+        let res = dispatch(CommandRequest::new_batch(requests.clone(), false), &store);
+        assert_eq!(res.status, 200);
+        let statuses: Vec<_> = res.responses.iter().map(|res| res.status).collect();
+        assert_eq!(statuses, vec![200, 400, 200]);
+
+        let store = MemTable::new();
+        let res = dispatch(CommandRequest::new_batch(requests, true), &store);
+        assert_eq!(res.responses.len(), 2);
+        let res = dispatch(CommandRequest::new_hget("t2", "k2"), &store);
+        assert_res_error(res, 404, "Not found");
+    }
+
+    #[test]
+    fn table_commands_should_work() {
+        let store = MemTable::new();
+        set_key_pairs("t1", vec![("k1", "v1"), ("k2", "v2")], &store);
+        set_key_pairs("t2", vec![("k1", "v1")], &store);
+
+        let res = dispatch(CommandRequest::new_listtables(), &store);
+        assert_res_ok(res, &["t1".into(), "t2".into()], &[]);
+        let res = dispatch(CommandRequest::new_hlen("t1"), &store);
+        assert_res_ok(res, &[2.into()], &[]);
+
+        let res = dispatch(CommandRequest::new_renametable("t1", "t2"), &store);
+        assert_res_error(res, 409, "Table already exists");
+        let res = dispatch(CommandRequest::new_renametable("t9", "t3"), &store);
+        assert_res_error(res, 404, "Table not found");
+        let res = dispatch(CommandRequest::new_renametable("t1", "t3"), &store);
+        assert_res_ok(res, &[true.into()], &[]);
+
+        let res = dispatch(CommandRequest::new_truncate("t3"), &store);
+        assert_res_ok(res, &[2.into()], &[]);
+        let res = dispatch(CommandRequest::new_droptable("t2"), &store);
+        assert_res_ok(res, &[true.into()], &[]);
+        let res = dispatch(CommandRequest::new_listtables(), &store);
+        assert_res_ok(res, &["t3".into()], &[]);
+    }
+
+    #[test]
+    fn hscan_should_return_next_cursor() {
+        let store = MemTable::new();
+        set_key_pairs("t1", vec![("u1", 1), ("u2", 2), ("o1", 3)], &store);
+        let mut keys = Vec::new();
+        let mut cursor = String::new();
+        loop {
+            let res = dispatch(CommandRequest::new_hscan("t1", cursor, 1, "u*"), &store);
+            assert_eq!(res.status, 200);
+            keys.extend(res.pairs.into_iter().map(|pair| pair.key));
+            if res.cursor.is_empty() {
+                break;
+            }
+            cursor = res.cursor;
+        }
+        keys.sort();
+        assert_eq!(keys, vec!["u1", "u2"]);
+
+        let res = dispatch(CommandRequest::new_hscan("t1", "bad", 0, ""), &store);
+        assert_res_error(res, 400, "Invalid cursor");
+    }
+
+    #[test]
+    fn hrange_and_hprefix_should_work() {
+        let store = MemTable::new();
+        let res = dispatch(CommandRequest::new_createtable("t1", TableKind::Ordered), &store);
+        assert_res_ok(res, &[true.into()], &[]);
+        let res = dispatch(CommandRequest::new_createtable("t1", TableKind::Hash), &store);
+        assert_res_error(res, 409, "Table already exists");
+        set_key_pairs("t1", vec![("a:1", 1), ("a:2", 2), ("a:3", 3), ("b:1", 4)], &store);
+
+        let res = dispatch(CommandRequest::new_hrange("t1", "a:2", ""), &store);
+        let keys: Vec<_> = res.pairs.iter().map(|pair| pair.key.as_str()).collect();
+        assert_eq!(keys, vec!["a:2", "a:3", "b:1"]);
+        let cmd = Hrange::new("t1", "a:1", "b:1").start_exclusive().end_exclusive().reverse().limit(1);
+        let res = dispatch(cmd.into(), &store);
+        assert_eq!(res.pairs, vec![KvPair::new("a:3", 3.into())]);
+
+        let res = dispatch(CommandRequest::new_hprefix("t1", "a:", true, 0), &store);
+        let keys: Vec<_> = res.pairs.iter().map(|pair| pair.key.as_str()).collect();
+        assert_eq!(keys, vec!["a:3", "a:2", "a:1"]);
+
+        set_key_pairs("t2", vec![("a:1", 1)], &store);
+        let res = dispatch(CommandRequest::new_hprefix("t2", "a:", false, 0), &store);
+        assert_res_error(res, 400, "ordered table");
+    }
+
+    #[test]
+    fn hset_without_eviction_should_return_507_when_out_of_memory() {
+        let store = MemTable::new().max_memory(16);
+        let res = dispatch(CommandRequest::new_hset("t1", "k1", "v1".into()), &store);
+        assert_res_ok(res, &[Value::default()], &[]);
+
+        let res = dispatch(CommandRequest::new_hset("t1", "k2", "a long value".into()), &store);
+        assert_res_error(res, 507, "Out of memory");
+
+        let pairs = vec![KvPair::new("k3", "v3".into()), KvPair::new("k4", "a long value".into())];
+        let res = dispatch(CommandRequest::new_hmset("t1", pairs), &store);
+        assert_res_error(res, 507, "Out of memory");
+    }
+
+    #[test]
+    fn hmset_should_not_write_any_key_when_out_of_memory() {
+        let store = MemTable::new().max_memory(16);
+        dispatch(CommandRequest::new_hset("t1", "k1", "v1".into()), &store);
+        let used = store.used_memory();
+
+        // 第一个 key 放得下，第二个放不下，整个 HMSET 都不写入
+        let pairs = vec![KvPair::new("k1", "v2".into()), KvPair::new("k2", "v2".into()), KvPair::new("k3", "a long value".into())];
+        let res = dispatch(CommandRequest::new_hmset("t1", pairs), &store);
+        assert_res_error(res, 507, "Out of memory");
+        assert_eq!(store.get("t1", "k1"), Ok(Some("v1".into())));
+        assert_eq!(store.get("t1", "k2"), Ok(None));
+        assert_eq!(store.used_memory(), used);
+    }
+
     // 测试成功返回的结果
     fn assert_res_ok(mut res: CommandResponse, values: &[Value], pairs: &[KvPair]) {
         res.pairs.sort_by(|a, b| a.partial_cmp(b).unwrap());
@@ -0,0 +1,274 @@
+use std::sync::{Arc, Mutex, MutexGuard};
+use crate::*;
+use crate::command_request::RequestData;
+use crate::topic::{Broadcaster, Subscription};
+
+/*
+    WATCH 的 keyspace 通知：每个 table 是一个 topic，订阅者是 WATCH 这个 table 的连接。
+
+    通知由 Service::execute 驱动：执行命令之前，如果有人 WATCH 命令涉及的 table，先记下命令要修改哪些 key 和新的值；
+    命令成功执行后，response 中正好带着这些 key 之前的值，两者合起来就是事件。
+    DROPTABLE / TRUNCATE / RENAMETABLE 一次改变整个 table，只产生一个 key 为空的 table 级别的事件，不逐个列出 key。
+    没有人 WATCH 的 table 不需要做任何额外的工作。
+
+    Storage 只对单个 key 加锁，两个命令各自执行完再分发事件的话，后执行的命令的事件可能先发出去。
+    所以 pending 在有人 WATCH 时拿一把锁，PendingChanges 一直持有它，直到 notify 把事件发出去，
+    这样被 WATCH 的写操作从执行到分发事件是串行的，订阅者看到的顺序就是执行的顺序。
+*/
+/// 管理 WATCH 的订阅，生成并分发 keyspace 事件
+pub struct KeyspaceNotifier {
+    broadcaster: Arc<Broadcaster>,
+    // 保证事件按照命令执行的顺序分发
+    ordering: Mutex<()>,
+}
+
+/// 一个命令将要修改的 key
+pub(crate) struct PendingChanges<'a> {
+    _guard: MutexGuard<'a, ()>,
+    /// 执行之前就能确定的部分，缺少的部分从 response 中补上
+    events: Vec<KeyspaceEvent>,
+    returned: Returned,
+}
+
+/// 命令成功时 response 的 values 是什么
+enum Returned {
+    /// 每个 key 之前的值（HSET / HDEL 等）
+    OldValues,
+    /// key 新的值（HINCRBY / HINCRBYFLOAT），事件中没有之前的值
+    NewValue,
+    /// 事件需要的值在执行之前都知道了（HSETNX / HCAS）
+    Nothing,
+    /// 整个 table 的变化，response 是 true 或者删除的 key 的个数，false 或者 0 表示什么都没变
+    TableChanged,
+}
+
+impl KeyspaceNotifier {
+    /// 每个 WATCH 的订阅者最多缓存 buffer 个事件
+    pub fn new(buffer: usize) -> Self {
+        Self {
+            broadcaster: Arc::new(Broadcaster::new(buffer)),
+            ordering: Mutex::new(()),
+        }
+    }
+
+    /// 监听 table 中 key 的变化
+    pub fn watch(&self, table: impl Into<String>) -> Subscription {
+        self.broadcaster.subscribe(table)
+    }
+
+    /// 取消监听，订阅不存在时返回错误
+    pub fn unwatch(&self, table: &str, id: u32) -> Result<(), KvError> {
+        self.broadcaster.unsubscribe(table, id)
+    }
+
+    /// 命令执行之前调用，返回命令将要修改的 key。没有人 WATCH 命令涉及的 table 时返回 None。
+    /// 返回的 PendingChanges 被 notify 消费之前，其它被 WATCH 的写操作都要等待
+    pub(crate) fn pending(&self, cmd: &CommandRequest) -> Option<PendingChanges<'_>> {
+        use KeyspaceOp::{Del, Set, TableDropped, TableRenamed, TableTruncated};
+        let (table, returned, changes) = match &cmd.request_data {
+            Some(RequestData::Hset(Hset { table, pair: Some(pair), .. })) => {
+                (table, Returned::OldValues, vec![(Set, &pair.key, None, Some(value_of(pair)))])
+            }
+            Some(RequestData::Hmset(Hmset { table, pairs, .. })) => (
+                table,
+                Returned::OldValues,
+                pairs.iter().map(|pair| (Set, &pair.key, None, Some(value_of(pair)))).collect(),
+            ),
+            Some(RequestData::Hdel(Hdel { table, key })) => (table, Returned::OldValues, vec![(Del, key, None, None)]),
+            Some(RequestData::Hmdel(Hmdel { table, keys })) => {
+                (table, Returned::OldValues, keys.iter().map(|key| (Del, key, None, None)).collect())
+            }
+            Some(RequestData::Hincrby(Hincrby { table, key, .. }))
+            | Some(RequestData::Hincrbyfloat(Hincrbyfloat { table, key, .. })) => {
+                (table, Returned::NewValue, vec![(Set, key, None, None)])
+            }
+            Some(RequestData::Hsetnx(Hsetnx { table, pair: Some(pair) })) => {
+                (table, Returned::Nothing, vec![(Set, &pair.key, None, Some(value_of(pair)))])
+            }
+            // 按版本号比较时不知道之前的值
+            Some(RequestData::Hcas(Hcas { table, key, value, expected, .. })) => {
+                let old_value = expected.clone().filter(|v| v.value.is_some());
+                (table, Returned::Nothing, vec![(Set, key, old_value, Some(value.clone().unwrap_or_default()))])
+            }
+            Some(RequestData::Droptable(Droptable { table })) => {
+                return self.table_changed([table], |table| table_event(table, TableDropped, None, None));
+            }
+            Some(RequestData::Truncate(Truncate { table })) => {
+                return self.table_changed([table], |table| table_event(table, TableTruncated, None, None));
+            }
+            // 原来的 table 和新的 table 都变了，两边 WATCH 的一方都要收到事件
+            Some(RequestData::Renametable(Renametable { table, new_name })) => {
+                let (from, to) = (Value::from(table.as_str()), Value::from(new_name.as_str()));
+                return self.table_changed([table, new_name], |table| {
+                    table_event(table, TableRenamed, Some(from.clone()), Some(to.clone()))
+                });
+            }
+            _ => return None,
+        };
+        if !self.broadcaster.has_subscribers(table) {
+            return None;
+        }
+        let events = changes
+            .into_iter()
+            .map(|(op, key, old_value, new_value)| KeyspaceEvent {
+                table: table.clone(),
+                key: key.clone(),
+                op: op as i32,
+                old_value,
+                new_value,
+            })
+            .collect();
+        Some(self.lock_pending(events, returned))
+    }
+
+    /// 整个 table 的变化，给 tables 中有人 WATCH 的 table 各生成一个事件
+    fn table_changed<const N: usize>(
+        &self,
+        tables: [&String; N],
+        event: impl Fn(&String) -> KeyspaceEvent,
+    ) -> Option<PendingChanges<'_>> {
+        let events: Vec<_> = tables
+            .into_iter()
+            .filter(|table| self.broadcaster.has_subscribers(table))
+            .map(event)
+            .collect();
+        (!events.is_empty()).then(|| self.lock_pending(events, Returned::TableChanged))
+    }
+
+    fn lock_pending(&self, events: Vec<KeyspaceEvent>, returned: Returned) -> PendingChanges<'_> {
+        PendingChanges {
+            _guard: self.ordering.lock().unwrap(),
+            events,
+            returned,
+        }
+    }
+
+    /// 命令执行之后调用，根据 response 补全事件并分发。DEL 一个不存在的 key 不算变化
+    pub(crate) fn notify(&self, changes: PendingChanges<'_>, res: &CommandResponse) {
+        if res.status != 200 {
+            return;
+        }
+        let PendingChanges { _guard, mut events, returned } = changes;
+        match returned {
+            Returned::OldValues => {
+                for (event, old_value) in events.iter_mut().zip(res.values.iter()) {
+                    event.old_value = Some(old_value.clone()).filter(|v| v.value.is_some());
+                }
+                events.retain(|event| event.op != KeyspaceOp::Del as i32 || event.old_value.is_some());
+            }
+            Returned::NewValue => {
+                for (event, new_value) in events.iter_mut().zip(res.values.iter()) {
+                    event.new_value = Some(new_value.clone());
+                }
+            }
+            Returned::Nothing => {}
+            Returned::TableChanged => {
+                let changed = match res.values.first().and_then(|v| v.value.as_ref()) {
+                    Some(value::Value::Bool(b)) => *b,
+                    Some(value::Value::Integer(n)) => *n > 0,
+                    _ => false,
+                };
+                if !changed {
+                    return;
+                }
+            }
+        }
+        // RENAMETABLE 的事件属于两个 table，分别发给各自的订阅者
+        for events in events.chunk_by(|a, b| a.table == b.table) {
+            self.broadcaster.broadcast(&events[0].table, events.to_vec().into());
+        }
+    }
+}
+
+fn value_of(pair: &KvPair) -> Value {
+    pair.value.clone().unwrap_or_default()
+}
+
+fn table_event(table: &str, op: KeyspaceOp, old_value: Option<Value>, new_value: Option<Value>) -> KeyspaceEvent {
+    KeyspaceEvent {
+        table: table.into(),
+        key: String::new(),
+        op: op as i32,
+        old_value,
+        new_value,
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use futures::StreamExt;
+    use super::*;
+
+    #[tokio::test]
+    async fn notifier_should_emit_events_for_watched_table() {
+        let notifier = KeyspaceNotifier::new(16);
+        // 没有人 WATCH 时不需要记录
+        assert!(notifier.pending(&CommandRequest::new_hset("t1", "k1", "v1".into())).is_none());
+
+        let mut watch = notifier.watch("t1");
+        let changes = notifier.pending(&CommandRequest::new_hset("t1", "k1", "v2".into())).unwrap();
+        notifier.notify(changes, &Value::from("v1").into());
+        let changes = notifier.pending(&CommandRequest::new_hmdel("t1", vec!["k1".into(), "k2".into()])).unwrap();
+        notifier.notify(changes, &vec![Value::from("v2"), Value::default()].into());
+        assert!(notifier.pending(&CommandRequest::new_hset("t2", "k1", "v1".into())).is_none());
+        assert!(notifier.pending(&CommandRequest::new_hget("t1", "k1")).is_none());
+
+        let event = watch.next().await.unwrap().events.remove(0);
+        assert_eq!(event.table, "t1");
+        assert_eq!(event.key, "k1");
+        assert_eq!(event.op, KeyspaceOp::Set as i32);
+        assert_eq!(event.old_value, Some("v1".into()));
+        assert_eq!(event.new_value, Some("v2".into()));
+        // k2 不存在，删除它不算变化
+        let mut events = watch.next().await.unwrap().events;
+        assert_eq!(events.len(), 1);
+        let event = events.remove(0);
+        assert_eq!((event.key.as_str(), event.op), ("k1", KeyspaceOp::Del as i32));
+        assert_eq!((event.old_value, event.new_value), (Some("v2".into()), None));
+
+        // HINCRBY 的 response 是新的值
+        let changes = notifier.pending(&CommandRequest::new_hincrby("t1", "n", 2)).unwrap();
+        notifier.notify(changes, &Value::from(7).into());
+        let event = watch.next().await.unwrap().events.remove(0);
+        assert_eq!((event.key.as_str(), event.op), ("n", KeyspaceOp::Set as i32));
+        assert_eq!((event.old_value, event.new_value), (None, Some(7.into())));
+
+        // HCAS 的事件在执行之前就确定了
+        let changes = notifier.pending(&CommandRequest::new_hcas("t1", "n", 7.into(), 8.into())).unwrap();
+        notifier.notify(changes, &Value::from(42).into());
+        let event = watch.next().await.unwrap().events.remove(0);
+        assert_eq!((event.old_value, event.new_value), (Some(7.into()), Some(8.into())));
+
+        notifier.unwatch("t1", watch.id()).unwrap();
+        assert!(watch.next().await.is_none());
+    }
+
+    #[tokio::test]
+    async fn notifier_should_emit_table_events() {
+        let notifier = KeyspaceNotifier::new(16);
+        let mut t1 = notifier.watch("t1");
+        let mut t2 = notifier.watch("t2");
+
+        // 没有删除任何 key 的 TRUNCATE 不算变化
+        let changes = notifier.pending(&CommandRequest::new_truncate("t1")).unwrap();
+        notifier.notify(changes, &Value::from(0).into());
+        let changes = notifier.pending(&CommandRequest::new_truncate("t1")).unwrap();
+        notifier.notify(changes, &Value::from(3).into());
+        let event = t1.next().await.unwrap().events.remove(0);
+        assert_eq!((event.key.as_str(), event.op), ("", KeyspaceOp::TableTruncated as i32));
+
+        let changes = notifier.pending(&CommandRequest::new_renametable("t1", "t2")).unwrap();
+        notifier.notify(changes, &Value::from(true).into());
+        for watch in [&mut t1, &mut t2] {
+            let event = watch.next().await.unwrap().events.remove(0);
+            assert_eq!(event.op, KeyspaceOp::TableRenamed as i32);
+            assert_eq!((event.old_value, event.new_value), (Some("t1".into()), Some("t2".into())));
+        }
+
+        let changes = notifier.pending(&CommandRequest::new_droptable("t2")).unwrap();
+        notifier.notify(changes, &Value::from(true).into());
+        let event = t2.next().await.unwrap().events.remove(0);
+        assert_eq!((event.table.as_str(), event.op), ("t2", KeyspaceOp::TableDropped as i32));
+        assert!(notifier.pending(&CommandRequest::new_droptable("t3")).is_none());
+    }
+}
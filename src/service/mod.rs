@@ -1,12 +1,21 @@
 mod command_service;
+pub mod aof;
+pub mod keyspace;
+pub mod topic;
 
-use std::sync::Arc;
-use tracing::debug;
+use std::sync::{Arc, RwLock};
+use std::thread;
+use std::time::Duration;
+use tracing::{debug, warn};
 use crate::*;
+use crate::aof::Aof;
+use crate::service::command_service::run_batch;
 use crate::command_request::RequestData;
 use crate::errors::KvError;
 use crate::memory::MemTable;
+use crate::keyspace::KeyspaceNotifier;
 use crate::storage::Storage;
+use crate::topic::{Broadcaster, Subscription, DEFAULT_SUBSCRIPTION_BUFFER};
 
 // 未来我们支持新命令时，只需要做两件事：为命令实现 CommandService、在 dispatch 方法中添加新命令的支持
 
@@ -35,8 +44,37 @@ pub fn dispatch(cmd: CommandRequest, store: &impl Storage) -> CommandResponse {
         Some(RequestData::Hmdel(param)) => param.execute(store),
         Some(RequestData::Hexist(param)) => param.execute(store),
         Some(RequestData::Hmexist(param)) => param.execute(store),
+        Some(RequestData::Save(param)) => param.execute(store),
+        Some(RequestData::Expire(param)) => param.execute(store),
+        Some(RequestData::Persist(param)) => param.execute(store),
+        Some(RequestData::Ttl(param)) => param.execute(store),
+        Some(RequestData::Hincrby(param)) => param.execute(store),
+        Some(RequestData::Hincrbyfloat(param)) => param.execute(store),
+        Some(RequestData::Hsetnx(param)) => param.execute(store),
+        Some(RequestData::Hcas(param)) => param.execute(store),
+        Some(RequestData::Batch(param)) => param.execute(store),
+        Some(RequestData::Listtables(param)) => param.execute(store),
+        Some(RequestData::Droptable(param)) => param.execute(store),
+        Some(RequestData::Renametable(param)) => param.execute(store),
+        Some(RequestData::Truncate(param)) => param.execute(store),
+        Some(RequestData::Hlen(param)) => param.execute(store),
+        Some(RequestData::Hscan(param)) => param.execute(store),
+        Some(RequestData::Createtable(param)) => param.execute(store),
+        Some(RequestData::Hrange(param)) => param.execute(store),
+        Some(RequestData::Hprefix(param)) => param.execute(store),
+        // 事务需要和其它写操作互斥，由 Service 处理
+        Some(RequestData::Transaction(_)) => {
+            KvError::InvalidCommand("Transactions must be executed by Service".into()).into()
+        }
+        // Pub/Sub 和 WATCH 的命令和存储无关，由 Service 处理
+        Some(
+            RequestData::Subscribe(_)
+            | RequestData::Unsubscribe(_)
+            | RequestData::Publish(_)
+            | RequestData::Watch(_)
+            | RequestData::Unwatch(_),
+        ) => KvError::InvalidCommand("Pub/Sub commands must be executed by Service".into()).into(),
         None => KvError::InvalidCommand("Request has no data".into()).into(),
-        _ => KvError::Internal("Not implemented".into()).into()
     }
 }
 
@@ -54,35 +92,316 @@ impl<Store> Clone for Service<Store> {
     }
 }
 
+/*
+    事件通知：外部可以在 ServiceInner 上注册一串回调，Service 在处理命令的各个阶段依次调用它们。
+    - on_received：收到命令时调用，可以检查命令，返回错误会拒绝执行这个命令
+    - on_executed：命令执行完调用，可以检查和修改 response
+    - on_before_send：发送 response 之前调用，可以修改 response
+    - on_after_send：发送 response 之后调用
+    后两个事件由网络层在发送 response 前后通过 Service::before_send / Service::after_send 触发。
+*/
+type ReceivedHook = Box<dyn Fn(&CommandRequest) -> Result<(), KvError> + Send + Sync>;
+type ResponseHook = Box<dyn Fn(&mut CommandResponse) + Send + Sync>;
+type SentHook = Box<dyn Fn() + Send + Sync>;
+
 /// Service 内部数据结构
 pub struct ServiceInner<Store> {
-    store: Store
+    store: Store,
+    aof: Option<Aof>,
+    broadcaster: Arc<Broadcaster>,
+    keyspace: KeyspaceNotifier,
+    // 事务拿写锁，其它写操作拿读锁
+    txn_lock: RwLock<()>,
+    on_received: Vec<ReceivedHook>,
+    on_executed: Vec<ResponseHook>,
+    on_before_send: Vec<ResponseHook>,
+    on_after_send: Vec<SentHook>,
 }
 
-impl<Store: Storage> Service<Store> {
+impl<Store: Storage> ServiceInner<Store> {
     pub fn new(store: Store) -> Self {
         Self {
-            inner: Arc::new(ServiceInner { store })
+            store,
+            aof: None,
+            broadcaster: Arc::new(Broadcaster::default()),
+            keyspace: KeyspaceNotifier::new(DEFAULT_SUBSCRIPTION_BUFFER),
+            txn_lock: RwLock::new(()),
+            on_received: Vec::new(),
+            on_executed: Vec::new(),
+            on_before_send: Vec::new(),
+            on_after_send: Vec::new(),
+        }
+    }
+
+    /// 会修改数据的命令执行成功之后，把命令写入 aof
+    pub fn aof(mut self, aof: Aof) -> Self {
+        self.aof = Some(aof);
+        self
+    }
+
+    /// SUBSCRIBE 和 WATCH 的订阅者最多缓存多少条消息，超过之后订阅者会被踢掉
+    pub fn subscription_buffer(mut self, n: usize) -> Self {
+        self.broadcaster = Arc::new(Broadcaster::new(n));
+        self.keyspace = KeyspaceNotifier::new(n);
+        self
+    }
+
+    /// 注册收到命令时的回调，返回错误会拒绝执行这个命令
+    pub fn fn_received(mut self, f: impl Fn(&CommandRequest) -> Result<(), KvError> + Send + Sync + 'static) -> Self {
+        self.on_received.push(Box::new(f));
+        self
+    }
+
+    /// 注册命令执行完后的回调，可以修改 response
+    pub fn fn_executed(mut self, f: impl Fn(&mut CommandResponse) + Send + Sync + 'static) -> Self {
+        self.on_executed.push(Box::new(f));
+        self
+    }
+
+    /// 注册发送 response 之前的回调，可以修改 response
+    pub fn fn_before_send(mut self, f: impl Fn(&mut CommandResponse) + Send + Sync + 'static) -> Self {
+        self.on_before_send.push(Box::new(f));
+        self
+    }
+
+    /// 注册发送 response 之后的回调
+    pub fn fn_after_send(mut self, f: impl Fn() + Send + Sync + 'static) -> Self {
+        self.on_after_send.push(Box::new(f));
+        self
+    }
+}
+
+/*
+    execute 是同步的：写 AOF（fsync = always 时每个命令都要等磁盘）和 SAVE 都可能阻塞很久，
+    直接在 tokio 的 worker 上执行会卡住这个 worker 上的所有连接。网络层用 execute_async 执行命令，
+    会写磁盘的命令放到 blocking 线程池中执行，只读的命令很快，仍然直接执行。
+*/
+impl<Store: Storage + Send + Sync + 'static> Service<Store> {
+    /// 在异步环境中执行命令，不会阻塞 tokio 的 worker
+    pub async fn execute_async(&self, cmd: CommandRequest) -> CommandResponse {
+        if !cmd.is_mutating() && !matches!(cmd.request_data, Some(RequestData::Save(_))) {
+            return self.execute(cmd);
+        }
+        let service = self.clone();
+        match tokio::task::spawn_blocking(move || service.execute(cmd)).await {
+            Ok(res) => res,
+            Err(e) => KvError::Internal(format!("Failed to execute command: {}", e)).into(),
+        }
+    }
+}
+
+impl<Store: Storage> From<ServiceInner<Store>> for Service<Store> {
+    fn from(inner: ServiceInner<Store>) -> Self {
+        Self {
+            inner: Arc::new(inner)
         }
     }
+}
+
+impl<Store: Storage> Service<Store> {
+    pub fn new(store: Store) -> Self {
+        ServiceInner::new(store).into()
+    }
 
     pub fn execute(&self, cmd: CommandRequest) -> CommandResponse {
         debug!("Got request: {:?}", cmd);
-        // TODO: 发送 on_received 事件
-        let res = dispatch(cmd, &self.inner.store);
+        // 任何一个回调拒绝了这个命令，就不再执行，直接返回错误
+        if let Err(e) = self.inner.on_received.iter().try_for_each(|f| f(&cmd)) {
+            debug!("Request rejected: {:?}", e);
+            return e.into();
+        }
+
+        let mut res = match cmd.request_data {
+            // Pub/Sub 的命令不经过存储，也不写入 aof
+            Some(RequestData::Publish(param)) => {
+                let n = self.inner.broadcaster.publish(&param.topic, param.data);
+                Value::from(n as i64).into()
+            }
+            // 订阅的结果是一个 stream，只能由网络层调用 Service::subscribe / Service::watch。
+            // 订阅属于创建它的连接，取消订阅也只能由网络层在这个连接上调用 Service::unsubscribe / Service::unwatch
+            Some(
+                RequestData::Subscribe(_)
+                | RequestData::Watch(_)
+                | RequestData::Unsubscribe(_)
+                | RequestData::Unwatch(_),
+            ) => KvError::InvalidCommand(
+                "SUBSCRIBE, UNSUBSCRIBE, WATCH and UNWATCH are only supported on streaming connections".into(),
+            )
+            .into(),
+            Some(RequestData::Transaction(txn)) => self.transaction(txn).unwrap_or_else(|e| e.into()),
+            Some(RequestData::Batch(batch)) => self.execute_batch(batch).into(),
+            request_data => {
+                let cmd = CommandRequest { request_data };
+                // 事务执行期间不能有其它写操作，SAVE 要等正在执行的事务结束，快照里不会只有事务的一部分
+                let _read = cmd.is_mutating().then(|| self.inner.txn_lock.read().unwrap());
+                let _write = matches!(cmd.request_data, Some(RequestData::Save(_))).then(|| self.inner.txn_lock.write().unwrap());
+                self.apply(cmd)
+            }
+        };
         debug!("Executed response: {:?}", res);
 
-        // TODO: 发送 on_executed 事件
+        self.inner.on_executed.iter().for_each(|f| f(&mut res));
+        res
+    }
+
+    /// 依次执行批量命令中的每个命令，每个命令和单独发送时一样经过回调、aof 和 WATCH 的处理
+    pub fn execute_batch(&self, batch: BatchRequest) -> BatchResponse {
+        run_batch(batch, |cmd| self.execute(cmd))
+    }
 
+    /// 执行和存储有关的命令：执行，成功后写 aof，然后给 WATCH 的订阅者发送事件
+    fn apply(&self, cmd: CommandRequest) -> CommandResponse {
+        // 有人 WATCH 命令涉及的 table 时，先记下要修改的 key，执行成功后生成事件
+        let changes = self.inner.keyspace.pending(&cmd);
+        let res = match &self.inner.aof {
+            Some(aof) if cmd.is_mutating() => match cmd.to_unconditional() {
+                // 条件写入先执行，成功之后再把等价的 HSET 写入日志
+                Some(effect) => aof.append_applied(effect, || dispatch(cmd, &self.inner.store)),
+                None => aof.append(cmd, |cmd| dispatch(cmd, &self.inner.store)),
+            }
+            .unwrap_or_else(|e| e.into()),
+            // 快照中要记下对应的日志位置，重启时快照里已有的命令就不会再重放一遍
+            Some(aof) if matches!(cmd.request_data, Some(RequestData::Save(_))) => {
+                match aof.checkpoint(|mark| self.inner.store.save_with_mark(mark)) {
+                    Ok(()) => Value::from(true).into(),
+                    Err(e) => e.into(),
+                }
+            }
+            _ => dispatch(cmd, &self.inner.store),
+        };
+        if let Some(changes) = changes {
+            self.inner.keyspace.notify(changes, &res);
+        }
         res
     }
+
+    /*
+        事务拿着 txn_lock 的写锁执行，其它写操作都要先拿读锁，所以检查 watched 和执行 commands 的过程中不会有别的写操作。
+        事务中的每个命令（包括批量命令中的每个命令）和单独执行时一样写 aof、产生 WATCH 事件；进程在事务执行到一半时崩溃，重放后只有前面的命令生效。
+    */
+    /// 执行事务，watched 中有 key 变化了返回 PreconditionFailed
+    fn transaction(&self, txn: Transaction) -> Result<CommandResponse, KvError> {
+        // 回调要检查事务中的每个命令，任何一个被拒绝，整个事务都不执行
+        self.check_txn_commands(&txn.commands)?;
+
+        let _guard = self.inner.txn_lock.write().unwrap();
+        for watched in txn.watched {
+            let precondition = Precondition::new(watched.expected, watched.version);
+            if !precondition.check(&self.inner.store, &watched.table, &watched.key)? {
+                return Err(KvError::PreconditionFailed(watched.table, watched.key));
+            }
+        }
+        let responses: Vec<_> = txn
+            .commands
+            .into_iter()
+            .map(|cmd| match cmd.request_data {
+                Some(RequestData::Batch(batch)) => run_batch(batch, |cmd| self.apply(cmd)).into(),
+                _ => self.apply(cmd),
+            })
+            .collect();
+        Ok(responses.into())
+    }
+
+    /// 用回调检查事务中的命令，批量命令中的每个命令也要检查
+    fn check_txn_commands(&self, commands: &[CommandRequest]) -> Result<(), KvError> {
+        for cmd in commands {
+            if let Some(RequestData::Batch(batch)) = &cmd.request_data {
+                self.check_txn_commands(&batch.requests)?;
+            }
+            self.inner.on_received.iter().try_for_each(|f| f(cmd))?;
+        }
+        Ok(())
+    }
+
+    /// 订阅 topic，返回收到的消息组成的 stream，stream 被 drop 时自动取消订阅。
+    /// 对 on_received 来说它和 SUBSCRIBE 命令一样，回调可以拒绝订阅
+    pub fn subscribe(&self, topic: &str) -> Result<Subscription, KvError> {
+        let cmd = CommandRequest::new_subscribe(topic);
+        self.inner.on_received.iter().try_for_each(|f| f(&cmd))?;
+        Ok(self.inner.broadcaster.subscribe(topic))
+    }
+
+    /// 取消订阅，订阅不存在时返回错误。调用者要保证这是它自己的订阅，网络层只允许连接取消它自己创建的订阅
+    pub fn unsubscribe(&self, topic: &str, id: u32) -> Result<(), KvError> {
+        let cmd = CommandRequest::new_unsubscribe(topic, id);
+        self.inner.on_received.iter().try_for_each(|f| f(&cmd))?;
+        self.inner.broadcaster.unsubscribe(topic, id)
+    }
+
+    /// 监听 table 中 key 的变化，返回事件组成的 stream，stream 被 drop 时自动取消监听。
+    /// 对 on_received 来说它和 WATCH 命令一样，回调可以拒绝监听
+    pub fn watch(&self, table: &str) -> Result<Subscription, KvError> {
+        let cmd = CommandRequest::new_watch(table);
+        self.inner.on_received.iter().try_for_each(|f| f(&cmd))?;
+        Ok(self.inner.keyspace.watch(table))
+    }
+
+    /// 取消监听，和 unsubscribe 一样只能取消自己的监听
+    pub fn unwatch(&self, table: &str, id: u32) -> Result<(), KvError> {
+        let cmd = CommandRequest::new_unwatch(table, id);
+        self.inner.on_received.iter().try_for_each(|f| f(&cmd))?;
+        self.inner.keyspace.unwatch(table, id)
+    }
+
+    /// 遍历 table 中以 prefix 开头的 kv pair，prefix 为空时返回所有的 kv pair。
+    /// 对 on_received 来说它和 HGETALL 一样，回调可以用同样的规则拒绝它
+    pub fn scan(&self, table: &str, prefix: &str) -> Result<impl Iterator<Item = KvPair>, KvError> {
+        let cmd = CommandRequest::new_hgetall(table);
+        self.inner.on_received.iter().try_for_each(|f| f(&cmd))?;
+        let prefix = prefix.to_string();
+        Ok(self.inner.store.get_iter(table)?.filter(move |pair| pair.key.starts_with(&prefix)))
+    }
+
+    /// 网络层发送 response 之前调用
+    pub fn before_send(&self, res: &mut CommandResponse) {
+        self.inner.on_before_send.iter().for_each(|f| f(res));
+    }
+
+    /// 网络层发送 response 之后调用
+    pub fn after_send(&self) {
+        self.inner.on_after_send.iter().for_each(|f| f());
+    }
+
+    /// 服务退出前调用，把 aof 和 store 中还没落盘的数据写到磁盘上
+    pub fn flush(&self) -> Result<(), KvError> {
+        match &self.inner.aof {
+            Some(aof) => aof.checkpoint(|mark| self.inner.store.flush_with_mark(mark)),
+            None => self.inner.store.flush(),
+        }
+    }
+}
+
+impl<Store: Storage + Send + Sync + 'static> Service<Store> {
+    /// 启动一个后台线程，每隔 interval 清理一次过期的 key。Service 被 drop 之后线程自动退出
+    pub fn spawn_expire_sweeper(&self, interval: Duration) {
+        let inner = Arc::downgrade(&self.inner);
+        thread::spawn(move || loop {
+            thread::sleep(interval);
+            let inner = match inner.upgrade() {
+                Some(inner) => inner,
+                None => break,
+            };
+            match inner.store.purge_expired() {
+                Ok(0) => {}
+                Ok(n) => debug!("Purged {} expired keys", n),
+                Err(e) => warn!("Failed to purge expired keys: {:?}", e),
+            }
+        });
+    }
 }
 
 #[cfg(test)]
 mod tests {
     use std::thread;
-    use crate::{CommandRequest, CommandResponse, KvPair, Service, Value};
+    use std::time::Duration;
+    use crate::storage::Storage;
+    use std::sync::Arc;
+    use std::sync::atomic::{AtomicUsize, Ordering};
+    use http::StatusCode;
+    use crate::{CommandRequest, CommandResponse, KeyspaceOp, KvError, KvPair, Service, ServiceInner, Value, WatchedKey};
+    use crate::{BatchRequest, command_request::RequestData};
     use crate::memory::MemTable;
+    use crate::sleddb::SledDb;
 
     #[test]
     fn servicek_should_works() {
@@ -104,6 +423,311 @@ mod tests {
         assert_res_ok(res, &["v1".into()], &[]);
     }
 
+    #[test]
+    fn service_should_work_with_sleddb() {
+        let dir = tempfile::tempdir().unwrap();
+        let service = Service::new(SledDb::new(dir.path()).unwrap());
+        service.execute(CommandRequest::new_hset("t1", "k1", "v1".into()));
+        let res = service.execute(CommandRequest::new_hget("t1", "k1"));
+        assert_res_ok(res, &["v1".into()], &[]);
+    }
+
+    #[test]
+    fn transaction_should_work() {
+        let service: Service = Service::new(MemTable::default());
+        service.execute(CommandRequest::new_hset("t1", "k1", "v1".into()));
+
+        let commands = vec![
+            CommandRequest::new_hset("t1", "k2", "v2".into()),
+            CommandRequest::new_hincrby("t1", "k1", 1),
+            CommandRequest::new_hget("t1", "k2"),
+        ];
+        let watched = vec![WatchedKey::new("t1", "k1", "v1".into()), WatchedKey::new("t1", "k2", Value::default())];
+        let res = service.execute(CommandRequest::new_transaction(watched, commands.clone()));
+        assert_eq!(res.status, 200);
+        // 失败的命令不影响后面的命令
+        let statuses: Vec<_> = res.responses.iter().map(|res| res.status).collect();
+        assert_eq!(statuses, vec![200, 400, 200]);
+        assert_eq!(res.responses[2].values, vec!["v2".into()]);
+
+        // k2 已经存在了，整个事务都不执行
+        let watched = vec![WatchedKey::new("t1", "k2", Value::default())];
+        let commands = vec![CommandRequest::new_hset("t1", "k3", "v3".into())];
+        let res = service.execute(CommandRequest::new_transaction(watched, commands));
+        assert_res_error(res, 412, "Precondition failed for table: t1, key: k2");
+        let res = service.execute(CommandRequest::new_hget("t1", "k3"));
+        assert_res_error(res, 404, "Not found");
+
+        // 事务不能嵌套
+        let inner = CommandRequest::new_transaction(vec![], vec![]);
+        let res = service.execute(CommandRequest::new_transaction(vec![], vec![inner]));
+        assert_eq!(res.responses[0].status, 400);
+    }
+
+    #[test]
+    fn transaction_should_be_isolated_from_concurrent_writes() {
+        let service: Service = Service::new(MemTable::default());
+        service.execute(CommandRequest::new_hset("t1", "a", 0.into()));
+        service.execute(CommandRequest::new_hset("t1", "b", 0.into()));
+
+        // 一个线程不停地在事务中同时修改 a 和 b，另一个线程单独修改 a 和 b，a 和 b 最后的差值只取决于单独修改的次数
+        let cloned = service.clone();
+        let handle = thread::spawn(move || {
+            for _ in 0..200 {
+                let commands = vec![CommandRequest::new_hincrby("t1", "a", 1), CommandRequest::new_hincrby("t1", "b", 1)];
+                cloned.execute(CommandRequest::new_transaction(vec![], commands));
+            }
+        });
+        for _ in 0..200 {
+            service.execute(CommandRequest::new_hincrby("t1", "a", 1));
+        }
+        handle.join().unwrap();
+        let res = service.execute(CommandRequest::new_hmget("t1", vec!["a".into(), "b".into()]));
+        assert_res_ok(res, &[400.into(), 200.into()], &[]);
+
+        // 用版本号 WATCH：别人修改了 key 之后事务被放弃
+        let version = match service.inner.store.version("t1", "a") {
+            Ok(Some(v)) => v,
+            v => panic!("unexpected version: {:?}", v),
+        };
+        let watched = vec![WatchedKey::with_version("t1", "a", version)];
+        let commands = vec![CommandRequest::new_hset("t1", "a", 0.into())];
+        service.execute(CommandRequest::new_hincrby("t1", "a", 1));
+        let res = service.execute(CommandRequest::new_transaction(watched, commands));
+        assert_eq!(res.status, 412);
+    }
+
+    #[test]
+    fn save_should_not_capture_part_of_transaction() {
+        let dir = tempfile::tempdir().unwrap();
+        let path = dir.path().join("dump.kvs");
+        let service: Service = Service::new(MemTable::new().snapshot_path(&path));
+
+        // 事务同时修改两个 table，快照里两个 table 的值总是一样的
+        let cloned = service.clone();
+        let handle = thread::spawn(move || {
+            for i in 0..200 {
+                let commands = vec![CommandRequest::new_hset("t1", "k", i.into()), CommandRequest::new_hset("t2", "k", i.into())];
+                cloned.execute(CommandRequest::new_transaction(vec![], commands));
+            }
+        });
+        for _ in 0..20 {
+            assert_eq!(service.execute(CommandRequest::new_save()).status, 200);
+            let store = MemTable::load_snapshot(&path).unwrap();
+            assert_eq!(store.get("t1", "k"), store.get("t2", "k"));
+        }
+        handle.join().unwrap();
+    }
+
+    #[test]
+    fn batch_should_go_through_hooks() {
+        let service: Service = ServiceInner::new(MemTable::default())
+            .fn_received(|cmd: &CommandRequest| match cmd.request_data {
+                Some(RequestData::Hdel(_)) => Err(KvError::InvalidCommand("HDEL is not allowed".into())),
+                _ => Ok(()),
+            })
+            .into();
+        let requests = vec![
+            CommandRequest::new_hset("t1", "k1", "v1".into()),
+            CommandRequest::new_hdel("t1", "k1"),
+            CommandRequest::new_publish("news", vec!["hello".into()]),
+        ];
+        let res = service.execute_batch(BatchRequest { requests, stop_on_error: false });
+        let statuses: Vec<_> = res.responses.iter().map(|res| res.status).collect();
+        assert_eq!(statuses, vec![200, 400, 200]);
+        let res = service.execute(CommandRequest::new_hget("t1", "k1"));
+        assert_res_ok(res, &["v1".into()], &[]);
+    }
+
+    #[test]
+    fn expire_sweeper_should_purge_expired_keys() {
+        let service: Service = Service::new(MemTable::default());
+        service.execute(CommandRequest::new_hset_with_ttl("t1", "k1", "v1".into(), 1));
+        service.spawn_expire_sweeper(Duration::from_millis(5));
+        thread::sleep(Duration::from_millis(50));
+        // 后台线程已经删掉了过期的 key，不需要再清理了
+        assert_eq!(service.inner.store.purge_expired(), Ok(0));
+    }
+
+    #[test]
+    fn event_registration_should_work() {
+        fn d(res: &mut CommandResponse) {
+            res.status = StatusCode::CREATED.as_u16() as _;
+        }
+
+        let received = Arc::new(AtomicUsize::new(0));
+        let sent = Arc::new(AtomicUsize::new(0));
+        let (r, s) = (received.clone(), sent.clone());
+        let service: Service = ServiceInner::new(MemTable::default())
+            .fn_received(|_: &CommandRequest| Ok(()))
+            .fn_received(move |cmd: &CommandRequest| {
+                assert!(matches!(cmd.request_data, Some(RequestData::Hset(_))));
+                r.fetch_add(1, Ordering::SeqCst);
+                Ok(())
+            })
+            .fn_executed(|res: &mut CommandResponse| res.message = "executed".into())
+            .fn_before_send(d)
+            .fn_after_send(move || {
+                s.fetch_add(1, Ordering::SeqCst);
+            })
+            .into();
+
+        let mut res = service.execute(CommandRequest::new_hset("t1", "k1", "v1".into()));
+        assert_eq!(received.load(Ordering::SeqCst), 1);
+        assert_eq!(res.status, StatusCode::OK.as_u16() as u32);
+        assert_eq!(res.message, "executed");
+        assert_eq!(sent.load(Ordering::SeqCst), 0);
+        service.before_send(&mut res);
+        assert_eq!(res.status, StatusCode::CREATED.as_u16() as u32);
+        service.after_send();
+        assert_eq!(sent.load(Ordering::SeqCst), 1);
+    }
+
+    #[test]
+    fn on_received_should_reject_command() {
+        let service: Service = ServiceInner::new(MemTable::default())
+            .fn_received(|cmd: &CommandRequest| match cmd.is_mutating() {
+                true => Err(KvError::InvalidCommand("Service is read-only".into())),
+                false => Ok(()),
+            })
+            .into();
+
+        let res = service.execute(CommandRequest::new_hset("t1", "k1", "v1".into()));
+        assert_res_error(res, 400, "Service is read-only");
+        let commands = vec![CommandRequest::new_hget("t1", "k1"), CommandRequest::new_hset("t1", "k1", "v1".into())];
+        let res = service.execute(CommandRequest::new_transaction(vec![], commands));
+        assert_res_error(res, 400, "Service is read-only");
+        // 被拒绝的命令不会执行
+        let res = service.execute(CommandRequest::new_hget("t1", "k1"));
+        assert_res_error(res, 404, "Not found");
+    }
+
+    #[test]
+    fn on_received_should_check_commands_nested_in_transaction() {
+        let service: Service = ServiceInner::new(MemTable::default())
+            .fn_received(|cmd: &CommandRequest| match cmd.request_data {
+                Some(RequestData::Hdel(_)) => Err(KvError::InvalidCommand("HDEL is not allowed".into())),
+                _ => Ok(()),
+            })
+            .into();
+        service.execute(CommandRequest::new_hset("t1", "k1", "v1".into()));
+
+        // 批量命令中的 HDEL 也要经过回调
+        let batch = CommandRequest::new_batch(vec![CommandRequest::new_hdel("t1", "k1")], false);
+        let res = service.execute(CommandRequest::new_transaction(vec![], vec![batch]));
+        assert_res_error(res, 400, "HDEL is not allowed");
+        let res = service.execute(CommandRequest::new_hget("t1", "k1"));
+        assert_res_ok(res, &["v1".into()], &[]);
+    }
+
+    #[test]
+    fn on_executed_should_modify_response_in_order() {
+        let service: Service = ServiceInner::new(MemTable::default())
+            .fn_executed(|res: &mut CommandResponse| res.message = "first".into())
+            .fn_executed(|res: &mut CommandResponse| res.message.push_str(" second"))
+            .into();
+
+        let res = service.execute(CommandRequest::new_hget("t1", "k1"));
+        assert_eq!(res.status, 404);
+        assert_eq!(res.message, "first second");
+    }
+
+    #[tokio::test]
+    async fn execute_async_should_not_block_runtime() {
+        let service: Service = ServiceInner::new(MemTable::default())
+            .fn_received(|cmd: &CommandRequest| {
+                // 模拟 fsync 很慢的写操作
+                if cmd.is_mutating() {
+                    thread::sleep(Duration::from_millis(300));
+                }
+                Ok(())
+            })
+            .into();
+        let write = tokio::spawn({
+            let service = service.clone();
+            async move { service.execute_async(CommandRequest::new_hset("t1", "k1", "v1".into())).await }
+        });
+        // tokio::test 的 runtime 只有一个线程，写操作在这个线程上执行的话，sleep 要等它执行完才能返回
+        let start = std::time::Instant::now();
+        tokio::time::sleep(Duration::from_millis(10)).await;
+        assert!(start.elapsed() < Duration::from_millis(200));
+        assert_eq!(write.await.unwrap().status, 200);
+        assert_eq!(service.execute_async(CommandRequest::new_hget("t1", "k1")).await.values, vec!["v1".into()]);
+    }
+
+    #[tokio::test]
+    async fn service_pubsub_should_work() {
+        use futures::StreamExt;
+
+        let service: Service = Service::new(MemTable::default());
+        let mut sub = service.subscribe("news").unwrap();
+        let res = service.execute(CommandRequest::new_publish("news", vec!["hello".into()]));
+        assert_res_ok(res, &[1.into()], &[]);
+        assert_eq!(sub.next().await.unwrap().values, vec!["hello".into()]);
+
+        // UNSUBSCRIBE 不能通过 execute 执行，否则任何人都可以取消别人的订阅
+        let res = service.execute(CommandRequest::new_unsubscribe("news", sub.id()));
+        assert_res_error(res, 400, "streaming connections");
+        service.unsubscribe("news", sub.id()).unwrap();
+        assert!(sub.next().await.is_none());
+        assert_eq!(service.unsubscribe("news", sub.id()), Err(KvError::SubscriptionNotFound("news".into(), sub.id())));
+
+        // SUBSCRIBE 返回的是 stream，不能通过 execute 执行
+        let res = service.execute(CommandRequest::new_subscribe("news"));
+        assert_res_error(res, 400, "streaming connections");
+    }
+
+    #[tokio::test]
+    async fn watch_should_receive_keyspace_events() {
+        use futures::StreamExt;
+
+        let service: Service = Service::new(MemTable::default());
+        let mut watch = service.watch("t1").unwrap();
+        service.execute(CommandRequest::new_hset("t1", "k1", "v1".into()));
+        service.execute(CommandRequest::new_hset("t2", "k1", "v1".into()));
+        service.execute(CommandRequest::new_hdel("t1", "k1"));
+
+        let event = watch.next().await.unwrap().events.remove(0);
+        assert_eq!((event.op, event.old_value, event.new_value), (KeyspaceOp::Set as i32, None, Some("v1".into())));
+        let event = watch.next().await.unwrap().events.remove(0);
+        assert_eq!((event.op, event.old_value, event.new_value), (KeyspaceOp::Del as i32, Some("v1".into()), None));
+
+        service.unwatch("t1", watch.id()).unwrap();
+        assert!(watch.next().await.is_none());
+    }
+
+    #[tokio::test]
+    async fn watch_events_should_follow_execution_order() {
+        use futures::StreamExt;
+
+        let service: Service = ServiceInner::new(MemTable::default()).subscription_buffer(1000).into();
+        let mut watch = service.watch("t1").unwrap();
+        let handles: Vec<_> = (0..4)
+            .map(|_| {
+                let service = service.clone();
+                thread::spawn(move || {
+                    for _ in 0..100 {
+                        service.execute(CommandRequest::new_hincrby("t1", "k1", 1));
+                    }
+                })
+            })
+            .collect();
+        handles.into_iter().for_each(|h| h.join().unwrap());
+
+        // 多个线程同时修改同一个 key，事件中的新值也必须是严格递增的
+        for i in 1..=400 {
+            let event = watch.next().await.unwrap().events.remove(0);
+            assert_eq!(event.new_value, Some(i.into()));
+        }
+    }
+
+    #[test]
+    fn service_should_return_400_for_empty_request() {
+        let service: Service = Service::new(MemTable::default());
+        let res = service.execute(CommandRequest::default());
+        assert_res_error(res, 400, "Request has no data");
+    }
+
 
     // 测试成功返回的结果
     fn assert_res_ok(mut res: CommandResponse, values: &[Value], pairs: &[KvPair]) {
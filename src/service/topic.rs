@@ -0,0 +1,222 @@
+use std::collections::HashMap;
+use std::pin::Pin;
+use std::sync::atomic::{AtomicBool, AtomicU32, Ordering};
+use std::sync::Arc;
+use std::task::{Context, Poll};
+use dashmap::DashMap;
+use futures::Stream;
+use tokio::sync::mpsc;
+use tracing::{debug, warn};
+use crate::*;
+
+/*
+    Pub/Sub 的消息分发：每个订阅有一个有上限的 channel，PUBLISH 时把同一条消息（Arc）放进所有订阅者的 channel。
+    发布者不会等待订阅者：某个订阅者的 channel 满了，说明它处理得太慢，直接把它踢掉，
+    它的 stream 在收完已经缓存的消息之后会收到一个 SlowSubscriber 错误，然后结束。
+*/
+/// 每个订阅者最多缓存多少条还没发出去的消息
+pub const DEFAULT_SUBSCRIPTION_BUFFER: usize = 128;
+
+/// topic 的订阅者
+struct Subscriber {
+    tx: mpsc::Sender<Arc<CommandResponse>>,
+    /// 因为太慢被踢掉时设置，Subscription 据此在最后返回一个错误
+    lagged: Arc<AtomicBool>,
+}
+
+/// 把消息分发给 topic 的所有订阅者，PUBLISH 和 WATCH 都使用它
+pub struct Broadcaster {
+    next_id: AtomicU32,
+    buffer: usize,
+    topics: DashMap<String, HashMap<u32, Subscriber>>,
+}
+
+impl Default for Broadcaster {
+    fn default() -> Self {
+        Self::new(DEFAULT_SUBSCRIPTION_BUFFER)
+    }
+}
+
+impl Broadcaster {
+    /// 每个订阅者最多缓存 buffer 条消息
+    pub fn new(buffer: usize) -> Self {
+        Self {
+            next_id: AtomicU32::new(1),
+            buffer: buffer.max(1),
+            topics: DashMap::new(),
+        }
+    }
+
+    /// 订阅 topic，返回的 Subscription 被 drop 时自动取消订阅
+    pub fn subscribe(self: &Arc<Self>, topic: impl Into<String>) -> Subscription {
+        let topic = topic.into();
+        let id = self.next_id.fetch_add(1, Ordering::Relaxed);
+        let (tx, rx) = mpsc::channel(self.buffer);
+        let lagged = Arc::new(AtomicBool::new(false));
+        let subscriber = Subscriber {
+            tx,
+            lagged: Arc::clone(&lagged),
+        };
+        self.topics.entry(topic.clone()).or_default().insert(id, subscriber);
+        debug!("Subscription {} added to topic {}", id, topic);
+        Subscription {
+            id,
+            topic,
+            rx,
+            lagged,
+            broadcaster: Arc::clone(self),
+        }
+    }
+
+    /// 取消订阅，订阅不存在时返回错误
+    pub fn unsubscribe(&self, topic: &str, id: u32) -> Result<(), KvError> {
+        let removed = match self.topics.get_mut(topic) {
+            Some(mut subscribers) => subscribers.remove(&id).is_some(),
+            None => false,
+        };
+        self.topics.remove_if(topic, |_, subscribers| subscribers.is_empty());
+        match removed {
+            true => Ok(()),
+            false => Err(KvError::SubscriptionNotFound(topic.into(), id)),
+        }
+    }
+
+    /// 向 topic 发布一条消息，返回收到这条消息的订阅者数量
+    pub fn publish(&self, topic: &str, data: Vec<Value>) -> usize {
+        self.broadcast(topic, data.into())
+    }
+
+    /// topic 是否有订阅者
+    pub fn has_subscribers(&self, topic: &str) -> bool {
+        self.topics.contains_key(topic)
+    }
+
+    /// 把 msg 原样发给 topic 的所有订阅者，返回收到的订阅者数量
+    pub fn broadcast(&self, topic: &str, msg: CommandResponse) -> usize {
+        let msg = Arc::new(msg);
+        let mut delivered = 0;
+        if let Some(mut subscribers) = self.topics.get_mut(topic) {
+            subscribers.retain(|id, subscriber| match subscriber.tx.try_send(Arc::clone(&msg)) {
+                Ok(()) => {
+                    delivered += 1;
+                    true
+                }
+                Err(mpsc::error::TrySendError::Full(_)) => {
+                    warn!("Subscription {} of topic {} is too slow, dropped", id, topic);
+                    subscriber.lagged.store(true, Ordering::Release);
+                    false
+                }
+                // Subscription 已经 drop 了
+                Err(mpsc::error::TrySendError::Closed(_)) => false,
+            });
+        }
+        self.topics.remove_if(topic, |_, subscribers| subscribers.is_empty());
+        delivered
+    }
+}
+
+/// 一个订阅收到的消息组成的 stream，每条消息是一个 CommandResponse，消息的内容在 values 中
+pub struct Subscription {
+    id: u32,
+    topic: String,
+    rx: mpsc::Receiver<Arc<CommandResponse>>,
+    lagged: Arc<AtomicBool>,
+    broadcaster: Arc<Broadcaster>,
+}
+
+impl Subscription {
+    /// 订阅的 id，UNSUBSCRIBE 时使用
+    pub fn id(&self) -> u32 {
+        self.id
+    }
+
+    pub fn topic(&self) -> &str {
+        &self.topic
+    }
+}
+
+impl Stream for Subscription {
+    type Item = CommandResponse;
+
+    fn poll_next(mut self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<Option<Self::Item>> {
+        match self.rx.poll_recv(cx) {
+            Poll::Ready(Some(msg)) => Poll::Ready(Some(msg.as_ref().clone())),
+            // 被取消订阅或者被踢掉了，被踢掉时最后返回一个错误
+            Poll::Ready(None) => match self.lagged.swap(false, Ordering::AcqRel) {
+                true => {
+                    let e = KvError::SlowSubscriber(self.topic.clone(), self.broadcaster.buffer);
+                    Poll::Ready(Some(e.into()))
+                }
+                false => Poll::Ready(None),
+            },
+            Poll::Pending => Poll::Pending,
+        }
+    }
+}
+
+impl Drop for Subscription {
+    fn drop(&mut self) {
+        // 已经取消订阅或者被踢掉时，这里什么也不做
+        let _ = self.broadcaster.unsubscribe(&self.topic, self.id);
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use futures::StreamExt;
+    use super::*;
+
+    #[tokio::test]
+    async fn publish_should_reach_all_subscribers() {
+        let broadcaster = Arc::new(Broadcaster::default());
+        let mut sub1 = broadcaster.subscribe("news");
+        let mut sub2 = broadcaster.subscribe("news");
+        let _other = broadcaster.subscribe("sports");
+        assert_ne!(sub1.id(), sub2.id());
+
+        assert_eq!(broadcaster.publish("news", vec!["hello".into()]), 2);
+        assert_eq!(broadcaster.publish("nobody", vec!["hello".into()]), 0);
+        for sub in [&mut sub1, &mut sub2] {
+            let res = sub.next().await.unwrap();
+            assert_eq!(res.status, 200);
+            assert_eq!(res.values, vec!["hello".into()]);
+        }
+
+        // 取消订阅之后 stream 结束，不会再收到消息
+        broadcaster.unsubscribe("news", sub1.id()).unwrap();
+        assert!(sub1.next().await.is_none());
+        assert_eq!(broadcaster.publish("news", vec!["again".into()]), 1);
+        assert_eq!(
+            broadcaster.unsubscribe("news", sub1.id()),
+            Err(KvError::SubscriptionNotFound("news".into(), sub1.id()))
+        );
+    }
+
+    #[tokio::test]
+    async fn slow_subscriber_should_be_dropped_with_error() {
+        let broadcaster = Arc::new(Broadcaster::new(2));
+        let mut slow = broadcaster.subscribe("t");
+        assert_eq!(broadcaster.publish("t", vec![1.into()]), 1);
+        assert_eq!(broadcaster.publish("t", vec![2.into()]), 1);
+        // channel 满了，订阅者被踢掉
+        assert_eq!(broadcaster.publish("t", vec![3.into()]), 0);
+        assert_eq!(broadcaster.publish("t", vec![4.into()]), 0);
+
+        // 已经缓存的消息仍然能收到，然后是一个错误
+        assert_eq!(slow.next().await.unwrap().values, vec![1.into()]);
+        assert_eq!(slow.next().await.unwrap().values, vec![2.into()]);
+        let res = slow.next().await.unwrap();
+        assert_eq!(res.status, 507);
+        assert!(res.message.contains("too slow"));
+        assert!(slow.next().await.is_none());
+    }
+
+    #[test]
+    fn dropped_subscription_should_unsubscribe() {
+        let broadcaster = Arc::new(Broadcaster::default());
+        let sub = broadcaster.subscribe("t");
+        drop(sub);
+        assert!(broadcaster.topics.is_empty());
+        assert_eq!(broadcaster.publish("t", vec![1.into()]), 0);
+    }
+}
@@ -1,11 +1,241 @@
-
-use dashmap::{DashMap, mapref::one::Ref};
-use crate::{KvPair, Storage, Value};
+use std::collections::BTreeSet;
+use std::ops::Bound;
+use std::path::PathBuf;
+use std::sync::{Arc, RwLock, RwLockReadGuard};
+use std::sync::atomic::{AtomicU64, Ordering};
+use dashmap::{DashMap, mapref::{entry::Entry as MapEntry, one::Ref}};
+use prost::Message;
+use serde::{Deserialize, Serialize};
+use crate::{AofMark, KeyRange, KvPair, Precondition, ScanPage, Storage, StorageIter, TableKind, Value};
 use crate::errors::KvError;
+use crate::storage::{glob_match, incr_value, is_empty_range, now_ms, ScanCursor};
+
+// 淘汰 key 时，每次采样多少个 key，从中挑一个最合适的淘汰
+const EVICTION_SAMPLES: usize = 5;
+// LFU 的访问次数每空闲这么久（毫秒）减半，避免以前很热、现在不用的 key 一直留着
+const LFU_DECAY_MS: u64 = 60_000;
+
+// 所有 MemTable 共用的版本号，每次写入 key 时分配一个新的，所以删除后重建的 key 也不会和之前的版本号相同
+static NEXT_VERSION: AtomicU64 = AtomicU64::new(1);
+
+fn next_version() -> u64 {
+    NEXT_VERSION.fetch_add(1, Ordering::Relaxed)
+}
+
+/// 内存超过上限时，如何淘汰 key。配置文件中使用和 redis 一样的名字
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq, Serialize, Deserialize)]
+pub enum EvictionPolicy {
+    /// 不淘汰，写操作直接返回错误
+    #[default]
+    #[serde(rename = "noeviction")]
+    NoEviction,
+    /// 在所有 key 中淘汰最久没有访问的
+    #[serde(rename = "allkeys-lru")]
+    AllKeysLru,
+    /// 在所有 key 中淘汰访问最不频繁的
+    #[serde(rename = "allkeys-lfu")]
+    AllKeysLfu,
+    /// 在设置了过期时间的 key 中淘汰最快过期的
+    #[serde(rename = "volatile-ttl")]
+    VolatileTtl,
+}
+
+/// table 中存放的一个 value，以及它的访问统计
+#[derive(Debug, Default)]
+pub(crate) struct Entry {
+    pub(crate) value: Value,
+    // 版本号，HCAS 用它判断 value 有没有被别人修改过
+    version: u64,
+    // 最近一次访问的时间（unix 时间戳，毫秒），用于 LRU
+    last_access: AtomicU64,
+    // 访问的次数，用于 LFU
+    hits: AtomicU64,
+}
+
+impl Entry {
+    pub(crate) fn new(value: Value) -> Self {
+        Self {
+            value,
+            version: next_version(),
+            last_access: AtomicU64::new(now_ms()),
+            hits: AtomicU64::new(1),
+        }
+    }
+
+    /// 记录一次访问。读操作只拿了读锁，所以用原子变量
+    fn touch(&self, now: u64) {
+        self.last_access.store(now, Ordering::Relaxed);
+        self.hits.fetch_add(1, Ordering::Relaxed);
+    }
+
+    /// LFU 的分数，越小越先被淘汰
+    fn lfu_score(&self, now: u64) -> u64 {
+        let idle = now.saturating_sub(self.last_access.load(Ordering::Relaxed)) / LFU_DECAY_MS;
+        self.hits.load(Ordering::Relaxed) >> idle.min(63)
+    }
+}
+
+impl Clone for Entry {
+    fn clone(&self) -> Self {
+        Self {
+            value: self.value.clone(),
+            version: self.version,
+            last_access: AtomicU64::new(self.last_access.load(Ordering::Relaxed)),
+            hits: AtomicU64::new(self.hits.load(Ordering::Relaxed)),
+        }
+    }
+}
+
+/// 一个 key / value 占用的内存，按 key 的长度加上 value 编码后的长度计算
+fn entry_size(key: &str, value: &Value) -> u64 {
+    (key.len() + value.encoded_len()) as u64
+}
+
+/*
+    和 Redis 类似，过期时间单独存放在 expires 里，只有设置了过期时间的 key 才会出现在 expires 中，
+    这样后台清理过期 key 的时候，只需要遍历 expires。
+
+    同时访问 data 和 expires 时，一定是先拿 data 的锁，再拿 expires 的锁，避免死锁。
+
+    数据都放在 data 里，另外用 index 按顺序记录所有的 key。有序的 table 用一个 BTreeSet 保存所有的 key，HRANGE 按它的顺序读取；
+    hash table 不需要全局的顺序，data 的每个 shard 配一个 BTreeSet，只保存这个 shard 中的 key，写不同 shard 的操作不会互相等待。
+    HSCAN 在这些 BTreeSet 上从 cursor 往后取 key，一页的代价只和 count 有关，和 table 的大小无关。
+
+    读写单个 key 的逻辑完全不变，只有新增和删除 key 时要同时修改 index，并且一定是在拿着 data 中这个 key 的锁的时候修改，
+    所以 index 和 data 中有哪些 key 总是一致的。按顺序读取时先从 index 中取出一批 key，释放 index 的锁之后再去 data 中读 value，
+    任何时候都不会拿着 index 的锁再去拿 data 的锁。
+*/
+/// 一个 hash table
+#[derive(Debug)]
+pub(crate) struct Table {
+    pub(crate) data: DashMap<String, Entry>,
+    // key 的过期时间（unix 时间戳，毫秒）
+    pub(crate) expires: DashMap<String, u64>,
+    // 按顺序保存 data 中所有的 key
+    index: KeyIndex,
+}
+
+#[derive(Debug)]
+enum KeyIndex {
+    /// 有序的 table，所有的 key 在一起
+    Ordered(RwLock<BTreeSet<String>>),
+    /// hash table，和 data 的 shard 一一对应
+    Sharded(Vec<RwLock<BTreeSet<String>>>),
+}
+
+impl Default for Table {
+    fn default() -> Self {
+        Self::new(TableKind::Hash)
+    }
+}
+
+impl Table {
+    /// 创建一个指定类型的空 table
+    pub(crate) fn new(kind: TableKind) -> Self {
+        let data = DashMap::new();
+        let index = match kind {
+            TableKind::Ordered => KeyIndex::Ordered(Default::default()),
+            TableKind::Hash => KeyIndex::Sharded(data.shards().iter().map(|_| Default::default()).collect()),
+        };
+        Self { data, expires: DashMap::new(), index }
+    }
+
+    pub(crate) fn kind(&self) -> TableKind {
+        match self.index {
+            KeyIndex::Ordered(_) => TableKind::Ordered,
+            KeyIndex::Sharded(_) => TableKind::Hash,
+        }
+    }
+
+    /// HSCAN 依次遍历的 index，有序的 table 只有一个
+    fn scan_indexes(&self) -> &[RwLock<BTreeSet<String>>] {
+        match &self.index {
+            KeyIndex::Ordered(index) => std::slice::from_ref(index),
+            KeyIndex::Sharded(indexes) => indexes,
+        }
+    }
+
+    /// key 所在的 index
+    fn index_of(&self, key: &str) -> &RwLock<BTreeSet<String>> {
+        match &self.index {
+            KeyIndex::Ordered(index) => index,
+            KeyIndex::Sharded(indexes) => &indexes[self.data.determine_map(key)],
+        }
+    }
+
+    /// key 是否已经过期
+    fn is_expired(&self, key: &str, now: u64) -> bool {
+        matches!(self.expires.get(key), Some(at) if *at <= now)
+    }
+
+    /// data 中新增了 key，调用者要拿着 data 中这个 key 的锁
+    pub(crate) fn index_insert(&self, key: &str) {
+        self.index_of(key).write().unwrap().insert(key.into());
+    }
 
-#[derive(Clone, Default, Debug)]
+    /// data 中删除了 key，调用者要拿着 data 中这个 key 的锁
+    fn index_remove(&self, key: &str) {
+        self.index_of(key).write().unwrap().remove(key);
+    }
+
+    /// data 被清空之后，把 index 也清空
+    fn index_clear(&self) {
+        self.scan_indexes().iter().for_each(|index| index.write().unwrap().clear());
+    }
+}
+
+impl Clone for Table {
+    fn clone(&self) -> Self {
+        let copy = |index: &RwLock<BTreeSet<String>>| RwLock::new(index.read().unwrap().clone());
+        Self {
+            data: self.data.clone(),
+            expires: self.expires.clone(),
+            index: match &self.index {
+                KeyIndex::Ordered(index) => KeyIndex::Ordered(copy(index)),
+                KeyIndex::Sharded(indexes) => KeyIndex::Sharded(indexes.iter().map(copy).collect()),
+            },
+        }
+    }
+}
+
+// 每个 table 用 Arc 包一层，这样 get_iter 返回的 iterator 可以持有 table 的所有权，而不用先把整个 table 复制一份
+type TableRef = Arc<Table>;
+
+/// 使用 DashMap 的内存存储。clone 会把所有数据复制一份，要和原来的共享同一份数据请用 MemTable::fork
+#[derive(Default, Debug)]
 pub struct MemTable {
-    tables: DashMap<String, DashMap<String, Value>>,
+    tables: Arc<DashMap<String, TableRef>>,
+    // 所有写操作拿读锁，做快照和 DROPTABLE / RENAMETABLE / TRUNCATE 时拿写锁
+    write_gate: Arc<RwLock<()>>,
+    // SAVE 命令把快照写到这个文件
+    snapshot_path: Option<PathBuf>,
+    // 内存上限（字节），None 表示不限制
+    max_memory: Option<u64>,
+    eviction_policy: EvictionPolicy,
+    // 当前所有 key / value 占用的内存
+    used_memory: Arc<AtomicU64>,
+    // 淘汰 key 时用来生成随机数
+    random: Arc<AtomicU64>,
+}
+
+impl Clone for MemTable {
+    fn clone(&self) -> Self {
+        let tables: DashMap<String, TableRef> =
+            self.freeze().into_iter().map(|(name, table)| (name, Arc::new(table))).collect();
+        let used_memory = tables
+            .iter()
+            .map(|t| t.value().data.iter().map(|e| entry_size(e.key(), &e.value().value)).sum::<u64>())
+            .sum();
+        Self {
+            tables: Arc::new(tables),
+            write_gate: Default::default(),
+            snapshot_path: self.snapshot_path.clone(),
+            max_memory: self.max_memory,
+            eviction_policy: self.eviction_policy,
+            used_memory: Arc::new(AtomicU64::new(used_memory)),
+            random: Arc::new(AtomicU64::new(self.random.load(Ordering::Relaxed))),
+        }
+    }
 }
 
 impl MemTable {
@@ -14,9 +244,261 @@ impl MemTable {
         Self::default()
     }
 
-    /// 如果名为 name 的 hash table 不存在，则创建，否则返回
+    /// 设置 SAVE 命令写快照的文件
+    pub fn snapshot_path(mut self, path: impl Into<PathBuf>) -> Self {
+        self.snapshot_path = Some(path.into());
+        self
+    }
+
+    /// 设置内存上限（字节）
+    pub fn max_memory(mut self, bytes: u64) -> Self {
+        self.max_memory = Some(bytes);
+        self
+    }
+
+    /// 设置超过内存上限后的淘汰策略
+    pub fn eviction_policy(mut self, policy: EvictionPolicy) -> Self {
+        self.eviction_policy = policy;
+        self
+    }
+
+    /// 当前所有 key / value 占用的内存（字节）
+    pub fn used_memory(&self) -> u64 {
+        self.used_memory.load(Ordering::Relaxed)
+    }
+
+    /// 返回所有 table 的名字
+    pub fn table_names(&self) -> Vec<String> {
+        self.tables.iter().map(|t| t.key().clone()).collect()
+    }
+
+    /// 返回一个和它共享同一份数据的 MemTable，在一个上面写入，另一个上面也能读到
+    pub fn fork(&self) -> Self {
+        Self {
+            tables: self.tables.clone(),
+            write_gate: self.write_gate.clone(),
+            snapshot_path: self.snapshot_path.clone(),
+            max_memory: self.max_memory,
+            eviction_policy: self.eviction_policy,
+            used_memory: self.used_memory.clone(),
+            random: self.random.clone(),
+        }
+    }
+
+    /// 在同一时刻把所有 table 复制一份，复制期间所有写操作都会等待
+    pub(crate) fn freeze(&self) -> Vec<(String, Table)> {
+        let _guard = self.write_gate.write().unwrap();
+        self.tables
+            .iter()
+            .map(|t| (t.key().clone(), t.value().as_ref().clone()))
+            .collect()
+    }
+
+    /// 插入一个 table，用于从快照中恢复
+    pub(crate) fn insert_table(&self, name: String, table: Table) {
+        let size: u64 = table.data.iter().map(|e| entry_size(e.key(), &e.value().value)).sum();
+        self.used_memory.fetch_add(size, Ordering::Relaxed);
+        if let Some(old) = self.tables.insert(name, Arc::new(table)) {
+            self.release_table(&old);
+        }
+    }
+
+    /// 所有的写操作都要先拿到这个 guard
+    fn write_guard(&self) -> RwLockReadGuard<'_, ()> {
+        self.write_gate.read().unwrap()
+    }
+
+    /// 读操作发现 key 过期时，顺便把它删掉（惰性删除）
+    fn expire_on_read(&self, table: &Table, key: &str) {
+        let now = now_ms();
+        if table.is_expired(key, now) {
+            let _guard = self.write_guard();
+            self.remove_if_expired(table, key, now);
+        }
+    }
+
+    /// 写入 key 需要额外占用的内存，覆盖已有的 key 时只算多出来的部分
+    fn extra_size(&self, table: &Table, key: &str, value: &Value) -> u64 {
+        let old_size = table.data.get(key).map(|e| entry_size(key, &e.value)).unwrap_or_default();
+        entry_size(key, value).saturating_sub(old_size)
+    }
+
+    /// 写入 key，返回之前的值。调用者要拿着 write_guard，并且已经腾出了空间
+    fn insert(&self, table: &Table, key: String, value: Value, expire_at: Option<u64>) -> Option<Value> {
+        // 和 Redis 的 SET 一样，重新设置 value 会清除之前的过期时间。新的过期时间在 entry 的锁里一起写入
+        let set_expire = |key: &String| match expire_at {
+            Some(at) => {
+                table.expires.insert(key.clone(), at);
+            }
+            None => {
+                table.expires.remove(key);
+            }
+        };
+        self.used_memory.fetch_add(entry_size(&key, &value), Ordering::Relaxed);
+        match table.data.entry(key) {
+            MapEntry::Occupied(mut entry) => {
+                set_expire(entry.key());
+                let old = entry.insert(Entry::new(value));
+                self.release(entry.key(), &old.value);
+                Some(old.value)
+            }
+            MapEntry::Vacant(entry) => {
+                set_expire(entry.key());
+                table.index_insert(entry.key());
+                entry.insert(Entry::new(value));
+                None
+            }
+        }
+    }
+
+    /// 如果 key 已经过期，就删除它，返回是否删除了
+    fn remove_if_expired(&self, table: &Table, key: &str, now: u64) -> bool {
+        if !table.is_expired(key, now) {
+            return false;
+        }
+        // 在 data 的锁里再检查一次，避免删掉刚刚被重新 set 的值
+        let removed = table.data.remove_if(key, |_, _| {
+            let expired = table.expires.remove_if(key, |_, at| *at <= now).is_some();
+            if expired {
+                table.index_remove(key);
+            }
+            expired
+        });
+        match removed {
+            Some((k, entry)) => {
+                self.release(&k, &entry.value);
+                true
+            }
+            None => false,
+        }
+    }
+
+    /// 删除一个 key，返回它之前的值
+    fn remove(&self, table: &Table, key: &str) -> Option<Value> {
+        match table.data.entry(key.into()) {
+            MapEntry::Occupied(entry) => {
+                table.expires.remove(key);
+                table.index_remove(key);
+                let (k, entry) = entry.remove_entry();
+                self.release(&k, &entry.value);
+                Some(entry.value)
+            }
+            MapEntry::Vacant(_) => None,
+        }
+    }
+
+    fn release(&self, key: &str, value: &Value) {
+        self.used_memory.fetch_sub(entry_size(key, value), Ordering::Relaxed);
+    }
+
+    fn release_table(&self, table: &Table) {
+        for e in table.data.iter() {
+            self.release(e.key(), &e.value().value);
+        }
+    }
+
+    /// 写入 size 字节之前，确保内存足够，不够时按照淘汰策略淘汰 key
+    fn reserve(&self, size: u64) -> Result<(), KvError> {
+        let max = match self.max_memory {
+            Some(max) => max,
+            None => return Ok(()),
+        };
+        while self.used_memory() + size > max {
+            if !self.evict_one() {
+                return Err(KvError::OutOfMemory(format!(
+                    "used {} + {} bytes exceeds max memory {} bytes",
+                    self.used_memory(),
+                    size,
+                    max
+                )));
+            }
+        }
+        Ok(())
+    }
+
+    /*
+        和 Redis 一样，淘汰时并不维护精确的 LRU / LFU 顺序，而是随机采样几个 key，淘汰其中分数最低的。
+        这样不需要额外的数据结构，读操作也只需要更新 entry 中的原子变量。
+    */
+    /// 按照淘汰策略淘汰一个 key，没有可以淘汰的 key 时返回 false
+    fn evict_one(&self) -> bool {
+        let now = now_ms();
+        let tables: Vec<TableRef> = self.tables.iter().map(|t| t.value().clone()).collect();
+        if tables.is_empty() {
+            return false;
+        }
+
+        // 从随机的 table 开始采样，直到采够 EVICTION_SAMPLES 个 key
+        let start = self.random_index(tables.len());
+        let mut samples: Vec<(usize, String, u64)> = Vec::with_capacity(EVICTION_SAMPLES);
+        for i in 0..tables.len() {
+            let idx = (start + i) % tables.len();
+            let table = &tables[idx];
+            let want = EVICTION_SAMPLES - samples.len();
+            let sampled = match self.eviction_policy {
+                EvictionPolicy::NoEviction => return false,
+                EvictionPolicy::AllKeysLru => self.sample(&table.data, want, |e| e.last_access.load(Ordering::Relaxed)),
+                EvictionPolicy::AllKeysLfu => self.sample(&table.data, want, |e| e.lfu_score(now)),
+                EvictionPolicy::VolatileTtl => self.sample(&table.expires, want, |at| *at),
+            };
+            samples.extend(sampled.into_iter().map(|(key, score)| (idx, key, score)));
+            if samples.len() >= EVICTION_SAMPLES {
+                break;
+            }
+        }
+
+        match samples.into_iter().min_by_key(|(_, _, score)| *score) {
+            Some((idx, key, _)) => {
+                self.remove(&tables[idx], &key);
+                true
+            }
+            None => false,
+        }
+    }
+
+    /// 从 map 中随机采样最多 count 个 key，返回 key 和它的分数
+    fn sample<V>(&self, map: &DashMap<String, V>, count: usize, score: impl Fn(&V) -> u64) -> Vec<(String, u64)> {
+        let shards = map.shards();
+        let start = self.random_index(shards.len());
+        let mut result = Vec::with_capacity(count);
+        for i in 0..shards.len() {
+            let shard = shards[(start + i) % shards.len()].read();
+            if shard.is_empty() {
+                continue;
+            }
+            let skip = self.random_index(shard.len());
+            result.extend(
+                shard
+                    .iter()
+                    .cycle()
+                    .skip(skip)
+                    .take((count - result.len()).min(shard.len()))
+                    .map(|(k, v)| (k.clone(), score(v.get()))),
+            );
+            if result.len() >= count {
+                break;
+            }
+        }
+        result
+    }
+
+    /// 用 splitmix64 生成 [0, len) 的随机数，不需要很强的随机性
+    fn random_index(&self, len: usize) -> usize {
+        let mut x = self.random.fetch_add(0x9E37_79B9_7F4A_7C15, Ordering::Relaxed);
+        x = (x ^ (x >> 30)).wrapping_mul(0xBF58_476D_1CE4_E5B9);
+        x = (x ^ (x >> 27)).wrapping_mul(0x94D0_49BB_1331_11EB);
+        x ^= x >> 31;
+        (x % len.max(1) as u64) as usize
+    }
+
+    /// 返回名为 name 的 hash table，不存在时返回 None。读操作使用它，不会创建空的 table
+    fn get_table(&self, name: &str) -> Option<Ref<'_, String, TableRef>> {
+        self.tables.get(name)
+    }
+
+    /// 如果名为 name 的 hash table 不存在，则创建，否则返回。只有写入 key 的操作才使用它
     // Ref<String, DashMap<String, Value>>，具体是干什么的，要靠猜啊，官方文档也没有详细说明
-    fn get_or_create_table(&self, name: &str) -> Ref<String, DashMap<String, Value>> {
+    fn get_or_create_table(&self, name: &str) -> Ref<'_, String, TableRef> {
         match self.tables.get(name) {
             Some(table) => table,
             None => {
@@ -29,37 +511,391 @@ impl MemTable {
 
 impl Storage for MemTable {
     fn get(&self, table: &str, key: &str) -> Result<Option<Value>, KvError> {
-        let table = self.get_or_create_table(table);
-        Ok(table.get(key).map(|v| v.value().clone()))       // Value没有实现`Copy` trait，只能用clone()
+        let table = match self.get_table(table) {
+            Some(table) => table,
+            None => return Ok(None),
+        };
+        self.expire_on_read(&table, key);
+        Ok(table.data.get(key).map(|e| {
+            e.touch(now_ms());
+            e.value.clone()       // Value没有实现`Copy` trait，只能用clone()
+        }))
     }
 
     fn set(&self, table: &str, key: String, value: Value) -> Result<Option<Value>, KvError> {
+        self.set_with_expire(table, key, value, None)
+    }
+
+    fn set_with_expire(&self, table: &str, key: String, value: Value, expire_at: Option<u64>) -> Result<Option<Value>, KvError> {
+        let _guard = self.write_guard();
         let table = self.get_or_create_table(table);
-        Ok(table.insert(key, value))
+        self.remove_if_expired(&table, &key, now_ms());
+
+        // 覆盖已有的 key 时，只需要为多出来的部分腾出空间
+        self.reserve(self.extra_size(&table, &key, &value))?;
+        Ok(self.insert(&table, key, value, expire_at))
     }
 
-    fn contains(&self, table: &str, key: &str) -> Result<bool, KvError> {
+    fn set_many(&self, table: &str, pairs: Vec<KvPair>, expire_at: Option<u64>) -> Result<Vec<Option<Value>>, KvError> {
+        let _guard = self.write_guard();
         let table = self.get_or_create_table(table);
-        Ok(table.contains_key(key))
+        let now = now_ms();
+        let pairs: Vec<_> = pairs.into_iter().map(|pair| (pair.key, pair.value.unwrap_or_default())).collect();
+        pairs.iter().for_each(|(key, _)| {
+            self.remove_if_expired(&table, key, now);
+        });
+
+        // 先为所有的 key 腾出空间，空间不够时一个 key 都不写
+        let size = pairs.iter().map(|(key, value)| self.extra_size(&table, key, value)).sum();
+        self.reserve(size)?;
+        Ok(pairs.into_iter().map(|(key, value)| self.insert(&table, key, value, expire_at)).collect())
     }
 
-    fn del(&self, table: &str, key: &str) -> Result<Option<Value>, KvError> {
+    fn incr(&self, table: &str, key: String, delta: Value) -> Result<Value, KvError> {
+        let _guard = self.write_guard();
         let table = self.get_or_create_table(table);
-        // 调用remove后，得到Option<(K, V)>，这里注意Option里面是一个元组的(K, V)，然后只返回value，所以是map(|(_k, v)| v)
-        Ok(table.remove(key).map(|(_k, v)| v))
+        self.remove_if_expired(&table, &key, now_ms());
+
+        // 拿着 entry 的锁时不能淘汰 key（可能在同一个 shard 上），所以先按数字最大的编码长度腾出空间
+        self.reserve(entry_size(&key, &Value::from(i64::MIN)).max(entry_size(&key, &Value::from(f64::MAX))))?;
+
+        // 读取、计算和写回都在 entry 的锁里完成，并发的 incr 不会丢失更新。过期时间保持不变
+        let (new, old_size, new_size) = match table.data.entry(key) {
+            MapEntry::Occupied(mut entry) => {
+                let new = incr_value(Some(&entry.get().value), &delta)?;
+                let sizes = (entry_size(entry.key(), &entry.get().value), entry_size(entry.key(), &new));
+                let e = entry.get_mut();
+                e.value = new.clone();
+                e.version = next_version();
+                e.touch(now_ms());
+                (new, sizes.0, sizes.1)
+            }
+            MapEntry::Vacant(entry) => {
+                let new = incr_value(None, &delta)?;
+                let size = entry_size(entry.key(), &new);
+                table.index_insert(entry.key());
+                entry.insert(Entry::new(new.clone()));
+                (new, 0, size)
+            }
+        };
+        self.used_memory.fetch_add(new_size, Ordering::Relaxed);
+        self.used_memory.fetch_sub(old_size, Ordering::Relaxed);
+        Ok(new)
+    }
+
+    fn set_if(&self, table: &str, key: String, value: Value, precondition: Precondition) -> Result<u64, KvError> {
+        let _guard = self.write_guard();
+        let name = table;
+        let table = self.get_or_create_table(name);
+        self.remove_if_expired(&table, &key, now_ms());
+
+        // 和 set 一样先腾出空间，拿着 entry 的锁时不能淘汰 key
+        let size = entry_size(&key, &value);
+        let old_size = table.data.get(&key).map(|e| entry_size(&key, &e.value)).unwrap_or_default();
+        self.reserve(size.saturating_sub(old_size))?;
+
+        // 检查和写入都在 entry 的锁里完成
+        let entry = Entry::new(value);
+        let version = entry.version;
+        match table.data.entry(key) {
+            MapEntry::Occupied(mut e) => {
+                let matched = match &precondition {
+                    Precondition::Absent => false,
+                    Precondition::Equals(v) => &e.get().value == v,
+                    Precondition::Version(v) => e.get().version == *v,
+                };
+                if !matched {
+                    return Err(precondition.failed(name, e.key()));
+                }
+                table.expires.remove(e.key());
+                self.used_memory.fetch_add(size, Ordering::Relaxed);
+                let old = e.insert(entry);
+                self.release(e.key(), &old.value);
+            }
+            MapEntry::Vacant(e) => {
+                if precondition != Precondition::Absent {
+                    return Err(precondition.failed(name, e.key()));
+                }
+                table.expires.remove(e.key());
+                table.index_insert(e.key());
+                self.used_memory.fetch_add(size, Ordering::Relaxed);
+                e.insert(entry);
+            }
+        }
+        Ok(version)
+    }
+
+    fn contains(&self, table: &str, key: &str) -> Result<bool, KvError> {
+        let table = match self.get_table(table) {
+            Some(table) => table,
+            None => return Ok(false),
+        };
+        self.expire_on_read(&table, key);
+        Ok(table.data.contains_key(key))
+    }
+
+    fn del(&self, table: &str, key: &str) -> Result<Option<Value>, KvError> {
+        let _guard = self.write_guard();
+        let table = match self.get_table(table) {
+            Some(table) => table,
+            None => return Ok(None),
+        };
+        self.remove_if_expired(&table, key, now_ms());
+        Ok(self.remove(&table, key))
     }
 
     fn get_all(&self, table: &str) -> Result<Vec<KvPair>, KvError> {
-        let table = self.get_or_create_table(table);
+        let table = match self.get_table(table) {
+            Some(table) => table,
+            None => return Ok(Vec::new()),
+        };
+        // 有序的 table 按 key 的顺序返回
+        if table.kind() == TableKind::Ordered {
+            return Ok(OrderedIter::new(table.clone(), KeyRange::all()).map(KvPair::from).collect());
+        }
+        let now = now_ms();
         Ok(table
+            .data
             .iter()
-            .map(|pair| KvPair::new(pair.key(), pair.value().clone()))
+            .filter(|pair| !table.is_expired(pair.key(), now))
+            .map(|pair| KvPair::new(pair.key(), pair.value().value.clone()))
             .collect()
         )
     }
 
     fn get_iter(&self, table: &str) -> Result<Box<dyn Iterator<Item=KvPair>>, KvError> {
-        todo!()
+        // 这里只 clone 了 Arc，外层 tables 的锁在这一行结束时就释放了
+        let table = match self.get_table(table) {
+            Some(table) => table.clone(),
+            None => return Ok(Box::new(std::iter::empty())),
+        };
+        if table.kind() == TableKind::Ordered {
+            return Ok(Box::new(StorageIter::new(OrderedIter::new(table, KeyRange::all()))));
+        }
+        Ok(Box::new(StorageIter::new(ShardIter::new(table))))
+    }
+
+    /*
+        依次遍历 table 的每个 index（hash table 是每个 shard 一个，有序的 table 只有一个），
+        cursor 记下 index 的序号和上一页最后的 key。每一页只在从 index 中取 key 的时候拿它的读锁，
+        取出 count 个 key 之后马上释放，再去 data 中读 value，所以一页的代价是 O(log n + count)。
+    */
+    fn scan(&self, table: &str, cursor: &str, count: usize, pattern: Option<&str>) -> Result<ScanPage, KvError> {
+        let ScanCursor { mut shard, key: mut after } = ScanCursor::parse(cursor)?;
+        let table = match self.get_table(table) {
+            Some(table) => table.clone(),
+            None => return Ok(ScanPage::default()),
+        };
+        let indexes = table.scan_indexes();
+        let now = now_ms();
+        let mut pairs = Vec::new();
+        let mut remaining = count.max(1);
+        while remaining > 0 && shard < indexes.len() {
+            // 多取一个，用来判断这个 index 是不是已经遍历完了
+            let mut keys: Vec<String> = {
+                let index = indexes[shard].read().unwrap();
+                let start = after.map_or(Bound::Unbounded, Bound::Excluded);
+                index.range::<String, _>((start, Bound::Unbounded)).take(remaining + 1).cloned().collect()
+            };
+            let finished = keys.len() <= remaining;
+            keys.truncate(remaining);
+            remaining -= keys.len();
+            after = match finished {
+                true => None,
+                false => keys.last().cloned(),
+            };
+            pairs.extend(
+                keys.into_iter()
+                    .filter(|k| pattern.is_none_or(|pattern| glob_match(pattern, k)))
+                    .filter(|k| !table.is_expired(k, now))
+                    .filter_map(|k| {
+                        let value = table.data.get(&k).map(|e| e.value.clone())?;
+                        Some(KvPair::new(k, value))
+                    }),
+            );
+            if finished {
+                shard += 1;
+            }
+        }
+        let cursor = (shard < indexes.len()).then(|| ScanCursor { shard, key: after }.to_string());
+        Ok(ScanPage { pairs, cursor })
+    }
+
+    fn save(&self) -> Result<(), KvError> {
+        match &self.snapshot_path {
+            Some(path) => self.save_snapshot(path),
+            None => Err(KvError::InvalidCommand("Snapshot path is not configured".into())),
+        }
+    }
+
+    fn flush(&self) -> Result<(), KvError> {
+        // 只有配置了快照路径才需要落盘
+        match &self.snapshot_path {
+            Some(path) => self.save_snapshot(path),
+            None => Ok(()),
+        }
+    }
+
+    fn save_with_mark(&self, mark: AofMark) -> Result<(), KvError> {
+        match &self.snapshot_path {
+            Some(path) => self.save_snapshot_with_mark(path, mark),
+            None => Err(KvError::InvalidCommand("Snapshot path is not configured".into())),
+        }
+    }
+
+    fn flush_with_mark(&self, mark: AofMark) -> Result<(), KvError> {
+        match &self.snapshot_path {
+            Some(path) => self.save_snapshot_with_mark(path, mark),
+            None => Ok(()),
+        }
+    }
+
+    fn expire(&self, table: &str, key: &str, expire_at: Option<u64>) -> Result<bool, KvError> {
+        let _guard = self.write_guard();
+        let table = match self.get_table(table) {
+            Some(table) => table,
+            None => return Ok(false),
+        };
+        self.remove_if_expired(&table, key, now_ms());
+        // 持有 data 中这个 key 的锁，再修改 expires
+        let _entry = match table.data.get_mut(key) {
+            Some(entry) => entry,
+            None => return Ok(false),
+        };
+        match expire_at {
+            Some(at) => {
+                table.expires.insert(key.into(), at);
+            }
+            None => {
+                table.expires.remove(key);
+            }
+        }
+        Ok(true)
+    }
+
+    fn get_expire_at(&self, table: &str, key: &str) -> Result<Option<u64>, KvError> {
+        let table = match self.get_table(table) {
+            Some(table) => table,
+            None => return Ok(None),
+        };
+        self.expire_on_read(&table, key);
+        Ok(table.expires.get(key).map(|at| *at))
+    }
+
+    fn version(&self, table: &str, key: &str) -> Result<Option<u64>, KvError> {
+        let table = match self.get_table(table) {
+            Some(table) => table,
+            None => return Ok(None),
+        };
+        self.expire_on_read(&table, key);
+        Ok(table.data.get(key).map(|e| e.version))
+    }
+
+    fn create_table(&self, table: &str, kind: TableKind) -> Result<(), KvError> {
+        let _guard = self.write_guard();
+        match self.tables.entry(table.into()) {
+            MapEntry::Occupied(_) => Err(KvError::TableExists(table.into())),
+            MapEntry::Vacant(entry) => {
+                entry.insert(Arc::new(Table::new(kind)));
+                Ok(())
+            }
+        }
+    }
+
+    fn range(&self, table: &str, range: &KeyRange) -> Result<Vec<KvPair>, KvError> {
+        let table = match self.get_table(table) {
+            Some(table) => table.clone(),
+            None => return Ok(Vec::new()),
+        };
+        if table.kind() != TableKind::Ordered {
+            return Err(KvError::InvalidCommand("Range queries need an ordered table".into()));
+        }
+        let limit = match range.limit {
+            0 => usize::MAX,
+            n => n,
+        };
+        Ok(OrderedIter::new(table, range.clone()).take(limit).map(KvPair::from).collect())
+    }
+
+    fn list_tables(&self) -> Result<Vec<String>, KvError> {
+        let mut names = self.table_names();
+        names.sort();
+        Ok(names)
+    }
+
+    /*
+        DROPTABLE / RENAMETABLE / TRUNCATE 拿 write_gate 的写锁，和做快照一样，执行期间所有写操作都会等待，
+        所以不会有写操作写到一个刚被删掉或者改了名的 table 里。
+    */
+    fn drop_table(&self, table: &str) -> Result<bool, KvError> {
+        let _guard = self.write_gate.write().unwrap();
+        match self.tables.remove(table) {
+            Some((_, table)) => {
+                self.release_table(&table);
+                Ok(true)
+            }
+            None => Ok(false),
+        }
+    }
+
+    fn rename_table(&self, from: &str, to: &str) -> Result<(), KvError> {
+        let _guard = self.write_gate.write().unwrap();
+        if self.tables.contains_key(to) {
+            return Err(KvError::TableExists(to.into()));
+        }
+        match self.tables.remove(from) {
+            Some((_, table)) => {
+                self.tables.insert(to.into(), table);
+                Ok(())
+            }
+            None => Err(KvError::TableNotFound(from.into())),
+        }
+    }
+
+    fn truncate(&self, table: &str) -> Result<usize, KvError> {
+        let _guard = self.write_gate.write().unwrap();
+        let table = match self.get_table(table) {
+            Some(table) => table,
+            None => return Ok(0),
+        };
+        let count = table.data.len();
+        self.release_table(&table);
+        table.data.clear();
+        table.expires.clear();
+        table.index_clear();
+        Ok(count)
+    }
+
+    fn len(&self, table: &str) -> Result<usize, KvError> {
+        let table = match self.get_table(table) {
+            Some(table) => table,
+            None => return Ok(0),
+        };
+        // 已经过期、还没来得及删除的 key 不算
+        let now = now_ms();
+        let expired = table.expires.iter().filter(|at| *at.value() <= now).count();
+        Ok(table.data.len().saturating_sub(expired))
+    }
+
+    fn purge_expired(&self) -> Result<usize, KvError> {
+        let now = now_ms();
+        let tables: Vec<TableRef> = self.tables.iter().map(|t| t.value().clone()).collect();
+        let mut count = 0;
+        for table in tables {
+            // 先把过期的 key 复制出来，不能在遍历 expires 的同时去拿 data 的锁
+            let expired: Vec<String> = table
+                .expires
+                .iter()
+                .filter(|at| *at.value() <= now)
+                .map(|at| at.key().clone())
+                .collect();
+            let _guard = self.write_guard();
+            count += expired
+                .iter()
+                .filter(|key| self.remove_if_expired(&table, key, now))
+                .count();
+        }
+        Ok(count)
     }
 
     // fn m_get(&self, table: &str, keys: Vec<String>) -> Result<Option<Vec<Value>>, KvError> {
@@ -68,4 +904,131 @@ impl Storage for MemTable {
     //         .map(|key| table.get(key).map(|v| v.value().clone()).unwrap()).collect::<Vec<_>>();
     //     Ok(Some(values))
     // }
-}
\ No newline at end of file
+}
+
+/// 有序的 table 每次从 index 中取出多少个 key
+const ORDERED_BATCH: usize = 128;
+
+/*
+    和 ShardIter 类似，OrderedIter 每次从 index 中取出一批 key，马上释放 index 的锁，再去 data 中读取它们的 value。
+    读取 value 的时候 key 可能已经被删除了，这样的 key 直接跳过。
+*/
+/// 按 key 的顺序遍历有序 table 中某个范围的 iterator
+struct OrderedIter {
+    table: TableRef,
+    // 还没有遍历的范围，每取出一批 key 就把范围缩小到这批 key 之后
+    start: Bound<String>,
+    end: Bound<String>,
+    reverse: bool,
+    buf: std::vec::IntoIter<(String, Value)>,
+    done: bool,
+}
+
+impl OrderedIter {
+    fn new(table: TableRef, range: KeyRange) -> Self {
+        Self {
+            done: range.is_empty(),
+            table,
+            start: range.start,
+            end: range.end,
+            reverse: range.reverse,
+            buf: Vec::new().into_iter(),
+        }
+    }
+
+    /// 从 index 中取出下一批 key，并且把还没有遍历的范围缩小到这批 key 之后
+    fn next_keys(&mut self) -> Vec<String> {
+        let index = match &self.table.index {
+            KeyIndex::Ordered(index) => index.read().unwrap(),
+            KeyIndex::Sharded(_) => return Vec::new(),
+        };
+        let range = index.range::<String, _>((self.start.clone(), self.end.clone()));
+        let keys: Vec<String> = match self.reverse {
+            true => range.rev().take(ORDERED_BATCH).cloned().collect(),
+            false => range.take(ORDERED_BATCH).cloned().collect(),
+        };
+        if let Some(last) = keys.last() {
+            match self.reverse {
+                true => self.end = Bound::Excluded(last.clone()),
+                false => self.start = Bound::Excluded(last.clone()),
+            }
+        }
+        keys
+    }
+}
+
+impl Iterator for OrderedIter {
+    type Item = (String, Value);
+
+    fn next(&mut self) -> Option<Self::Item> {
+        loop {
+            if let Some(item) = self.buf.next() {
+                return Some(item);
+            }
+            if self.done {
+                return None;
+            }
+
+            let keys = self.next_keys();
+            self.done = keys.len() < ORDERED_BATCH || is_empty_range(&self.start, &self.end);
+            let now = now_ms();
+            let table = &self.table;
+            self.buf = keys
+                .into_iter()
+                .filter(|k| !table.is_expired(k, now))
+                .filter_map(|k| {
+                    let value = table.data.get(&k).map(|e| e.value.clone())?;
+                    Some((k, value))
+                })
+                .collect::<Vec<_>>()
+                .into_iter();
+        }
+    }
+}
+
+/*
+    DashMap 内部是若干个加了读写锁的 shard。ShardIter 每次只把一个 shard 里的数据复制到 buffer 中，
+    复制完就释放这个 shard 的读锁。这样内存里最多只多出一个 shard 的数据，
+    而且调用者在两次 next() 之间不持有任何锁，遍历的同时往同一个 table 里写数据也不会死锁。
+*/
+/// 按 shard 逐个遍历 table 的 iterator
+struct ShardIter {
+    table: TableRef,
+    shard: usize,
+    buf: std::vec::IntoIter<(String, Value)>,
+}
+
+impl ShardIter {
+    fn new(table: TableRef) -> Self {
+        Self {
+            table,
+            shard: 0,
+            buf: Vec::new().into_iter(),
+        }
+    }
+}
+
+impl Iterator for ShardIter {
+    type Item = (String, Value);
+
+    fn next(&mut self) -> Option<Self::Item> {
+        loop {
+            if let Some(item) = self.buf.next() {
+                return Some(item);
+            }
+
+            // 所有 shard 都遍历完了，返回 None
+            let shard = self.table.data.shards().get(self.shard)?;
+            self.shard += 1;
+            let now = now_ms();
+            let table = &self.table;
+            self.buf = shard
+                .read()
+                .iter()
+                .filter(|(k, _)| !table.is_expired(k, now))
+                .map(|(k, v)| (k.clone(), v.get().value.clone()))
+                .collect::<Vec<_>>()
+                .into_iter();
+        }
+    }
+}
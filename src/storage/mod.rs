@@ -1,7 +1,11 @@
 pub mod memory;
+pub mod sleddb;
+pub(crate) mod snapshot;
 
+use std::ops::Bound;
+use std::time::{SystemTime, UNIX_EPOCH};
 use crate::errors::KvError;
-use crate::{KvPair, Value};
+use crate::{value, KvPair, TableKind, Value};
 
 /// 对存储的抽象，我们不关心数据存在哪儿，但需要定义外界如何和存储打交道
 pub trait Storage {
@@ -22,6 +26,15 @@ pub trait Storage {
     /// 查看 HashTable 中是否有 key
     fn contains(&self, table: &str, key: &str) -> Result<bool, KvError>;
 
+    /// 把 key 的值原子地加上 delta，key 不存在时当作 0，返回新的值。
+    /// delta 是 integer 时旧值必须是 integer；delta 是 float 时旧值可以是 integer 或者 float，结果是 float
+    fn incr(&self, table: &str, key: String, delta: Value) -> Result<Value, KvError>;
+
+    /// key 当前的状态满足 precondition 时才设置 value，检查和设置是原子的，和 set 一样会清除过期时间。
+    /// 返回新的版本号，不支持版本号的 Storage 返回 0。
+    /// 不满足时返回错误：要求 key 不存在时是 KvError::KeyExists，其它是 KvError::PreconditionFailed
+    fn set_if(&self, table: &str, key: String, value: Value, precondition: Precondition) -> Result<u64, KvError>;
+
     /// 从 HashTable 中删除一个 key
     fn del(&self, table: &str, key: &str) -> Result<Option<Value>, KvError>;
 
@@ -36,6 +49,98 @@ pub trait Storage {
     /// 遍历 HashTable，返回 kv pair 的 Iterator
     fn get_iter(&self, table: &str) -> Result<Box<dyn Iterator<Item = KvPair>>, KvError>;
 
+    /*
+        HSCAN 的 cursor 只是一个字符串，记录遍历到了哪里，两次调用之间 Storage 不保存任何状态，也不持有任何锁。
+        在整个遍历过程中一直存在的 key 保证返回且只返回一次，遍历期间新增或者删除的 key 可能返回也可能不返回。
+    */
+    /// 从 cursor 开始遍历 table，最多检查 count 个 key，返回其中匹配 pattern（glob 格式）的 kv pair 和下一次的 cursor。
+    /// cursor 为空表示从头开始；table 不存在时返回空的结果
+    fn scan(&self, table: &str, cursor: &str, count: usize, pattern: Option<&str>) -> Result<ScanPage, KvError>;
+
+    /// 创建指定类型的 table，table 已经存在时返回 KvError::TableExists。
+    /// 写入一个不存在的 table 时会自动创建 TableKind::Hash 类型的 table
+    fn create_table(&self, table: &str, kind: TableKind) -> Result<(), KvError>;
+
+    /// 按 key 的顺序返回 table 中在 range 范围内的 kv pair。table 不是有序的时返回 KvError::InvalidCommand，
+    /// table 不存在时返回空的结果
+    fn range(&self, table: &str, range: &KeyRange) -> Result<Vec<KvPair>, KvError>;
+
+    /// 返回所有 table 的名字，按名字排序
+    fn list_tables(&self) -> Result<Vec<String>, KvError>;
+
+    /// 删除整个 table，返回 table 之前是否存在
+    fn drop_table(&self, table: &str) -> Result<bool, KvError>;
+
+    /// 重命名 table。from 不存在时返回 KvError::TableNotFound，to 已经存在时返回 KvError::TableExists
+    fn rename_table(&self, from: &str, to: &str) -> Result<(), KvError>;
+
+    /// 删除 table 中所有的 key，但保留 table，返回删除的 key 的个数。table 不存在时返回 0
+    fn truncate(&self, table: &str) -> Result<usize, KvError>;
+
+    /// table 中 key 的个数，table 不存在时返回 0
+    fn len(&self, table: &str) -> Result<usize, KvError>;
+
+    /// 把数据保存到磁盘上
+    fn save(&self) -> Result<(), KvError>;
+
+    /// 服务退出前调用，把还没落盘的数据写到磁盘上。纯内存的 Storage 什么都不用做
+    fn flush(&self) -> Result<(), KvError> {
+        Ok(())
+    }
+
+    /// 和 save 一样，同时在快照中记下它对应的 AOF 位置，重启时只需要重放这之后的日志。
+    /// 快照不依赖 AOF 的 Storage 直接忽略 mark
+    fn save_with_mark(&self, _mark: AofMark) -> Result<(), KvError> {
+        self.save()
+    }
+
+    /// 和 flush 一样，同时在快照中记下它对应的 AOF 位置
+    fn flush_with_mark(&self, _mark: AofMark) -> Result<(), KvError> {
+        self.flush()
+    }
+
+    /*
+        过期时间是可选的能力，不支持过期的 Storage 可以直接使用下面的缺省实现：
+        没有 key 会过期，设置过期时间会返回错误。
+    */
+    /// 设置 key 的过期时间（unix 时间戳，毫秒），None 表示去掉过期时间。key 不存在时返回 false
+    fn expire(&self, _table: &str, _key: &str, _expire_at: Option<u64>) -> Result<bool, KvError> {
+        Err(KvError::InvalidCommand("Expiration is not supported by this storage".into()))
+    }
+
+    /// 设置 value，同时设置过期时间，两者是一起生效的。expire_at 为 None 时和 set 一样。
+    /// 不支持过期的 Storage 在写入之前就返回错误
+    fn set_with_expire(&self, table: &str, key: String, value: Value, expire_at: Option<u64>) -> Result<Option<Value>, KvError> {
+        match expire_at {
+            None => self.set(table, key, value),
+            Some(_) => Err(KvError::InvalidCommand("Expiration is not supported by this storage".into())),
+        }
+    }
+
+    /// 一次设置多个 key，返回它们之前的值。出错时（比如内存不够）一个 key 都不会写入。
+    /// 缺省实现逐个调用 set_with_expire，写入可能中途失败的 Storage 要自己实现
+    fn set_many(&self, table: &str, pairs: Vec<KvPair>, expire_at: Option<u64>) -> Result<Vec<Option<Value>>, KvError> {
+        pairs
+            .into_iter()
+            .map(|pair| self.set_with_expire(table, pair.key, pair.value.unwrap_or_default(), expire_at))
+            .collect()
+    }
+
+    /// 获取 key 的过期时间（unix 时间戳，毫秒），key 不存在或者没有过期时间时返回 None
+    fn get_expire_at(&self, _table: &str, _key: &str) -> Result<Option<u64>, KvError> {
+        Ok(None)
+    }
+
+    /// 删除所有已经过期的 key，返回删除的个数
+    fn purge_expired(&self) -> Result<usize, KvError> {
+        Ok(0)
+    }
+
+    /// 获取 key 当前的版本号，key 不存在时返回 None。不支持版本号的 Storage 返回错误
+    fn version(&self, _table: &str, _key: &str) -> Result<Option<u64>, KvError> {
+        Err(KvError::InvalidCommand("Versions are not supported by this storage".into()))
+    }
+
     // ----------------------
 
     // 实现HMGET、HMSET、HDEL、HMDEL、HEXIST、HMEXIST，只需利用上面的命令即可实现
@@ -52,9 +157,319 @@ pub trait Storage {
     // fn hm_exist(&self, table: &str, keys: Vec<String>) -> Result<Option<Vec<String>>, KvError>;
 }
 
+/// 快照对应的 AOF 位置：快照包含了日志前 offset 个字节中的所有修改。
+/// checksum 是这部分日志的 crc32，日志在快照之后被重写过的话就对不上了
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq)]
+pub struct AofMark {
+    pub offset: u64,
+    pub checksum: u32,
+}
+
+/// HRANGE / HPREFIX 查询的 key 的范围
+#[derive(Clone, Debug, PartialEq)]
+pub struct KeyRange {
+    pub start: Bound<String>,
+    pub end: Bound<String>,
+    /// 是否从大到小返回
+    pub reverse: bool,
+    /// 最多返回多少个，0 表示不限制
+    pub limit: usize,
+}
+
+impl KeyRange {
+    /// 所有的 key
+    pub fn all() -> Self {
+        Self {
+            start: Bound::Unbounded,
+            end: Bound::Unbounded,
+            reverse: false,
+            limit: 0,
+        }
+    }
+
+    /// 以 prefix 开头的所有 key
+    pub fn prefix(prefix: &str) -> Self {
+        Self {
+            start: Bound::Included(prefix.into()),
+            end: prefix_end(prefix).map_or(Bound::Unbounded, Bound::Excluded),
+            ..Self::all()
+        }
+    }
+
+    /// 从大到小返回
+    pub fn reverse(mut self, reverse: bool) -> Self {
+        self.reverse = reverse;
+        self
+    }
+
+    /// 最多返回 limit 个，0 表示不限制
+    pub fn limit(mut self, limit: usize) -> Self {
+        self.limit = limit;
+        self
+    }
+
+    /// 范围内是否一个 key 都不可能有，比如 start 比 end 大。BTreeMap::range 遇到这样的范围会 panic
+    pub(crate) fn is_empty(&self) -> bool {
+        is_empty_range(&self.start, &self.end)
+    }
+}
+
+pub(crate) fn is_empty_range(start: &Bound<String>, end: &Bound<String>) -> bool {
+    match (start, end) {
+        (Bound::Included(s), Bound::Included(e)) => s > e,
+        (Bound::Included(s), Bound::Excluded(e))
+        | (Bound::Excluded(s), Bound::Included(e))
+        | (Bound::Excluded(s), Bound::Excluded(e)) => s >= e,
+        _ => false,
+    }
+}
+
+/// 比所有以 prefix 开头的字符串都大的最小的字符串，不存在（prefix 为空或者都是 char::MAX）时返回 None
+fn prefix_end(prefix: &str) -> Option<String> {
+    let mut chars: Vec<char> = prefix.chars().collect();
+    while let Some(c) = chars.pop() {
+        // 跳过 UTF-16 代理区，它们不是合法的 char
+        let next = match c {
+            '\u{d7ff}' => Some('\u{e000}'),
+            c => char::from_u32(c as u32 + 1),
+        };
+        if let Some(next) = next {
+            chars.push(next);
+            return Some(chars.into_iter().collect());
+        }
+    }
+    None
+}
+
+/// HSCAN 返回的一页数据
+#[derive(Clone, Debug, Default, PartialEq)]
+pub struct ScanPage {
+    pub pairs: Vec<KvPair>,
+    /// 下一次遍历使用的 cursor，None 表示遍历结束
+    pub cursor: Option<String>,
+}
+
+/*
+    cursor 的格式是 "{shard}" 或者 "{shard}:{key}"：前者表示从第 shard 个 shard 的开头开始，
+    后者表示从这个 shard 中比 key 大的第一个 key 开始。没有 shard 的 Storage（比如 sled）shard 总是 0。
+*/
+/// HSCAN 遍历到的位置
+#[derive(Clone, Debug, Default, PartialEq)]
+pub(crate) struct ScanCursor {
+    pub(crate) shard: usize,
+    /// 上一页最后检查的 key
+    pub(crate) key: Option<String>,
+}
+
+impl ScanCursor {
+    /// 解析 cursor，空字符串表示从头开始
+    pub(crate) fn parse(cursor: &str) -> Result<Self, KvError> {
+        if cursor.is_empty() {
+            return Ok(Self::default());
+        }
+        let (shard, key) = match cursor.split_once(':') {
+            Some((shard, key)) => (shard, Some(key.to_string())),
+            None => (cursor, None),
+        };
+        let shard = shard
+            .parse()
+            .map_err(|_| KvError::InvalidCommand(format!("Invalid cursor: {}", cursor)))?;
+        Ok(Self { shard, key })
+    }
+}
+
+impl std::fmt::Display for ScanCursor {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match &self.key {
+            Some(key) => write!(f, "{}:{}", self.shard, key),
+            None => write!(f, "{}", self.shard),
+        }
+    }
+}
+
+/// 和 Redis 一样的 glob 匹配：* 匹配任意个字符，? 匹配一个字符，[abc] / [a-z] / [^a] 匹配字符集合，\ 转义
+pub(crate) fn glob_match(pattern: &str, key: &str) -> bool {
+    let pattern: Vec<char> = pattern.chars().collect();
+    let key: Vec<char> = key.chars().collect();
+    let (mut p, mut k) = (0, 0);
+    // 最近的一个 * 之后的位置，以及这个 * 当前匹配到 key 的哪里，匹配失败时让 * 多匹配一个字符再试
+    let mut star: Option<(usize, usize)> = None;
+    while k < key.len() {
+        if p < pattern.len() {
+            if pattern[p] == '*' {
+                p += 1;
+                star = Some((p, k));
+                continue;
+            }
+            if let Some(next) = match_char(&pattern, p, key[k]) {
+                p = next;
+                k += 1;
+                continue;
+            }
+        }
+        match star {
+            Some((sp, sk)) => {
+                p = sp;
+                k = sk + 1;
+                star = Some((sp, k));
+            }
+            None => return false,
+        }
+    }
+    pattern[p..].iter().all(|c| *c == '*')
+}
+
+/// pattern[p] 开始的一个元素（不是 *）是否匹配字符 c，匹配时返回下一个元素的位置
+fn match_char(pattern: &[char], p: usize, c: char) -> Option<usize> {
+    match pattern[p] {
+        '?' => Some(p + 1),
+        '\\' if p + 1 < pattern.len() => (pattern[p + 1] == c).then(|| p + 2),
+        '[' => match pattern[p + 1..].iter().position(|ch| *ch == ']') {
+            // 没有配对的 ]，[ 当作普通字符
+            None => (c == '[').then(|| p + 1),
+            Some(len) => {
+                let end = p + 1 + len;
+                let mut class = &pattern[p + 1..end];
+                let negate = class.first() == Some(&'^');
+                if negate {
+                    class = &class[1..];
+                }
+                let mut matched = false;
+                let mut i = 0;
+                while i < class.len() {
+                    if i + 2 < class.len() && class[i + 1] == '-' {
+                        matched |= class[i] <= c && c <= class[i + 2];
+                        i += 3;
+                    } else {
+                        matched |= class[i] == c;
+                        i += 1;
+                    }
+                }
+                (matched != negate).then(|| end + 1)
+            }
+        },
+        ch => (ch == c).then(|| p + 1),
+    }
+}
+
+/// 条件写入（HSETNX / HCAS）对 key 当前状态的要求
+#[derive(Clone, Debug, PartialEq)]
+pub enum Precondition {
+    /// key 不存在
+    Absent,
+    /// key 当前的值等于它
+    Equals(Value),
+    /// key 当前的版本号等于它。版本号在每次写入 key 时改变，不会持久化，重启之后需要重新获取
+    Version(u64),
+}
+
+impl Precondition {
+    /// 根据 HCAS / WATCH 的参数得到前提条件：version 不为 0 时比较版本号，否则比较值，值为空表示 key 不存在
+    pub fn new(expected: Option<Value>, version: u64) -> Self {
+        match (version, expected) {
+            (0, Some(v)) if v.value.is_some() => Precondition::Equals(v),
+            (0, _) => Precondition::Absent,
+            (version, _) => Precondition::Version(version),
+        }
+    }
+
+    /// 检查 key 当前的状态是否满足前提条件。只是检查，调用者需要自己保证检查之后 key 不会被修改
+    pub fn check(&self, store: &impl Storage, table: &str, key: &str) -> Result<bool, KvError> {
+        Ok(match self {
+            Precondition::Absent => !store.contains(table, key)?,
+            Precondition::Equals(v) => store.get(table, key)?.as_ref() == Some(v),
+            Precondition::Version(v) => store.version(table, key)? == Some(*v),
+        })
+    }
+
+    /// 不满足时返回的错误
+    pub(crate) fn failed(&self, table: &str, key: &str) -> KvError {
+        match self {
+            Precondition::Absent => KvError::KeyExists(table.into(), key.into()),
+            _ => KvError::PreconditionFailed(table.into(), key.into()),
+        }
+    }
+}
+
+/// 当前的 unix 时间戳，毫秒
+pub(crate) fn now_ms() -> u64 {
+    SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .map(|d| d.as_millis() as u64)
+        .unwrap_or_default()
+}
+
+/// HINCRBY / HINCRBYFLOAT 的计算：old 加上 delta，old 为 None 时当作 0。
+/// 旧值不是数字，或者结果溢出、不是有限的浮点数时返回错误
+pub(crate) fn incr_value(old: Option<&Value>, delta: &Value) -> Result<Value, KvError> {
+    // 通过 RESP 写入的值都是字符串，和 Redis 一样，能解析成数字的字符串也可以加
+    let parsed = match old.and_then(|v| v.value.as_ref()) {
+        Some(value::Value::String(s)) => match (s.parse::<i64>(), s.parse::<f64>()) {
+            (Ok(i), _) => Some(value::Value::Integer(i)),
+            (_, Ok(f)) if f.is_finite() => Some(value::Value::Float(f)),
+            _ => None,
+        },
+        _ => None,
+    };
+    let old = parsed.as_ref().or_else(|| old.and_then(|v| v.value.as_ref()));
+    let invalid = |msg: &str| Err(KvError::InvalidCommand(msg.into()));
+    match (old, &delta.value) {
+        (None, Some(value::Value::Integer(d))) => Ok((*d).into()),
+        (Some(value::Value::Integer(o)), Some(value::Value::Integer(d))) => match o.checked_add(*d) {
+            Some(v) => Ok(v.into()),
+            None => invalid("Increment or decrement would overflow"),
+        },
+        (Some(_), Some(value::Value::Integer(_))) => invalid("Value is not an integer"),
+        (old, Some(value::Value::Float(d))) => {
+            let old = match old {
+                None => 0.0,
+                Some(value::Value::Integer(o)) => *o as f64,
+                Some(value::Value::Float(o)) => *o,
+                Some(_) => return invalid("Value is not a valid float"),
+            };
+            match old + d {
+                v if v.is_finite() => Ok(v.into()),
+                _ => invalid("Increment would produce NaN or Infinity"),
+            }
+        }
+        _ => invalid("Increment must be an integer or a float"),
+    }
+}
+
+/*
+    不同的 Storage 实现内部遍历出来的数据类型可能不一样，比如 MemTable 里是 (String, Value)。
+    只要这个数据类型能转换成 KvPair，就可以用 StorageIter 包一层，统一成 Iterator<Item = KvPair>。
+*/
+/// 提供 Storage iterator，这样 trait 的实现者只需要把它们的 iterator 提供给 StorageIter，
+/// 然后保证 next() 传出的类型实现了 Into<KvPair> 即可
+pub struct StorageIter<T> {
+    data: T,
+}
+
+impl<T> StorageIter<T> {
+    pub fn new(data: T) -> Self {
+        Self { data }
+    }
+}
+
+impl<T> Iterator for StorageIter<T>
+where
+    T: Iterator,
+    T::Item: Into<KvPair>,
+{
+    type Item = KvPair;
+
+    fn next(&mut self) -> Option<Self::Item> {
+        self.data.next().map(|v| v.into())
+    }
+}
+
 #[cfg(test)]
 mod tests {
-    use crate::storage::memory::MemTable;
+    use std::ops::Bound;
+    use tempfile::tempdir;
+    use crate::storage::memory::{EvictionPolicy, MemTable};
+    use crate::storage::sleddb::SledDb;
     use super::*;
 
     #[test]
@@ -69,11 +484,641 @@ mod tests {
         test_get_all(store);
     }
 
-    // #[test]
-    // fn memtable_iter_should_work() {
-    //     let store = MemTable::new();
-    //     test_get_iter(store);
-    // }
+    #[test]
+    fn memtable_iter_should_work() {
+        let store = MemTable::new();
+        test_get_iter(store);
+    }
+
+    #[test]
+    fn memtable_iter_should_not_block_writes() {
+        let store = MemTable::new();
+        store.set("table1", "k1".into(), "v1".into()).unwrap();
+        let mut iter = store.get_iter("table1").unwrap();
+        // 遍历过程中不持有 table 的锁，所以可以同时写同一个 table
+        store.set("table1", "k2".into(), "v2".into()).unwrap();
+        assert!(iter.next().is_some());
+        store.del("table1", "k1").unwrap();
+        let _ = iter.count();
+    }
+
+    #[test]
+    fn sleddb_basic_interface_should_work() {
+        let dir = tempdir().unwrap();
+        let store = SledDb::new(dir.path()).unwrap();
+        test_basic_interface(store);
+    }
+
+    #[test]
+    fn sleddb_get_all_should_work() {
+        let dir = tempdir().unwrap();
+        let store = SledDb::new(dir.path()).unwrap();
+        test_get_all(store);
+    }
+
+    #[test]
+    fn sleddb_iter_should_work() {
+        let dir = tempdir().unwrap();
+        let store = SledDb::new(dir.path()).unwrap();
+        test_get_iter(store);
+    }
+
+    #[test]
+    fn sleddb_should_keep_data_after_reopen() {
+        let dir = tempdir().unwrap();
+        {
+            let store = SledDb::new(dir.path()).unwrap();
+            store.set("table1", "hello".into(), "world".into()).unwrap();
+            store.create_table("table2", TableKind::Hash).unwrap();
+            store.create_table("table3", TableKind::Hash).unwrap();
+            store.drop_table("table3").unwrap();
+        }
+        let store = SledDb::new(dir.path()).unwrap();
+        assert_eq!(store.get("table1", "hello"), Ok(Some("world".into())));
+        // 重新打开之后，已有的 table 都还在
+        assert_eq!(store.list_tables(), Ok(vec!["table1".to_string(), "table2".to_string()]));
+        assert_eq!(store.create_table("table2", TableKind::Hash), Err(KvError::TableExists("table2".into())));
+    }
+
+    #[test]
+    fn memtable_expire_should_work() {
+        let store = MemTable::new();
+        test_expire(store);
+    }
+
+    #[test]
+    fn memtable_purge_expired_should_work() {
+        let store = MemTable::new();
+        store.set("t1", "k1".into(), "v1".into()).unwrap();
+        store.set("t1", "k2".into(), "v2".into()).unwrap();
+        store.expire("t1", "k1", Some(now_ms() - 1)).unwrap();
+        store.expire("t1", "k2", Some(now_ms() + 60_000)).unwrap();
+        assert_eq!(store.purge_expired(), Ok(1));
+        assert_eq!(store.purge_expired(), Ok(0));
+        assert_eq!(store.get_all("t1"), Ok(vec![KvPair::new("k2", "v2".into())]));
+    }
+
+    #[test]
+    fn memtable_should_track_used_memory() {
+        let store = MemTable::new();
+        store.set("t1", "k1".into(), "v1".into()).unwrap();
+        let size = store.used_memory();
+        assert!(size > 0);
+        store.set("t1", "k2".into(), "v2".into()).unwrap();
+        assert_eq!(store.used_memory(), size * 2);
+        // 覆盖同样大小的值，内存不变
+        store.set("t1", "k1".into(), "v3".into()).unwrap();
+        assert_eq!(store.used_memory(), size * 2);
+        store.del("t1", "k1").unwrap();
+        store.expire("t1", "k2", Some(now_ms() - 1)).unwrap();
+        store.purge_expired().unwrap();
+        assert_eq!(store.used_memory(), 0);
+    }
+
+    #[test]
+    fn memtable_clone_should_copy_data() {
+        let store = MemTable::new();
+        store.set("t1", "k1".into(), "v1".into()).unwrap();
+        let cloned = store.clone();
+        assert_eq!(cloned.used_memory(), store.used_memory());
+        cloned.set("t1", "k1".into(), "v2".into()).unwrap();
+        cloned.set("t1", "k2".into(), "v2".into()).unwrap();
+        assert_eq!(store.get("t1", "k1"), Ok(Some("v1".into())));
+        assert_eq!(store.get("t1", "k2"), Ok(None));
+    }
+
+    #[test]
+    fn memtable_fork_should_share_data() {
+        let store = MemTable::new();
+        let forked = store.fork();
+        forked.set("t1", "k1".into(), "v1".into()).unwrap();
+        assert_eq!(store.get("t1", "k1"), Ok(Some("v1".into())));
+        assert_eq!(store.used_memory(), forked.used_memory());
+        store.rename_table("t1", "t2").unwrap();
+        assert_eq!(forked.list_tables(), Ok(vec!["t2".to_string()]));
+    }
+
+    #[test]
+    fn memtable_noeviction_should_reject_writes() {
+        let store = MemTable::new().max_memory(20);
+        store.set("t1", "k1".into(), "v1".into()).unwrap();
+        let res = store.set("t1", "k2".into(), "a long value".into());
+        assert!(matches!(res, Err(KvError::OutOfMemory(_))));
+        assert_eq!(store.get("t1", "k1"), Ok(Some("v1".into())));
+    }
+
+    #[test]
+    fn memtable_lru_should_evict_least_recently_used() {
+        let store = MemTable::new().eviction_policy(EvictionPolicy::AllKeysLru);
+        store.set("t1", "k1".into(), "v1".into()).unwrap();
+        std::thread::sleep(std::time::Duration::from_millis(2));
+        store.set("t1", "k2".into(), "v2".into()).unwrap();
+        std::thread::sleep(std::time::Duration::from_millis(2));
+        // 访问 k1，k2 就成了最久没有访问的 key
+        store.get("t1", "k1").unwrap();
+        let used = store.used_memory();
+        let store = store.max_memory(used);
+        store.set("t2", "k3".into(), "v3".into()).unwrap();
+        assert_eq!(store.get("t1", "k2"), Ok(None));
+        assert_eq!(store.get("t1", "k1"), Ok(Some("v1".into())));
+        assert_eq!(store.get("t2", "k3"), Ok(Some("v3".into())));
+    }
+
+    #[test]
+    fn memtable_lfu_should_evict_least_frequently_used() {
+        let store = MemTable::new().eviction_policy(EvictionPolicy::AllKeysLfu);
+        store.set("t1", "k1".into(), "v1".into()).unwrap();
+        store.set("t1", "k2".into(), "v2".into()).unwrap();
+        for _ in 0..10 {
+            store.get("t1", "k2").unwrap();
+        }
+        let used = store.used_memory();
+        let store = store.max_memory(used);
+        store.set("t1", "k3".into(), "v3".into()).unwrap();
+        assert_eq!(store.get("t1", "k1"), Ok(None));
+        assert_eq!(store.get("t1", "k2"), Ok(Some("v2".into())));
+    }
+
+    #[test]
+    fn memtable_volatile_ttl_should_evict_keys_with_nearest_expiration() {
+        let store = MemTable::new().eviction_policy(EvictionPolicy::VolatileTtl);
+        store.set("t1", "k1".into(), "v1".into()).unwrap();
+        store.set("t1", "k2".into(), "v2".into()).unwrap();
+        store.set("t1", "k3".into(), "v3".into()).unwrap();
+        store.expire("t1", "k2", Some(now_ms() + 10_000)).unwrap();
+        store.expire("t1", "k3", Some(now_ms() + 60_000)).unwrap();
+        let used = store.used_memory();
+        let store = store.max_memory(used);
+        store.set("t1", "k4".into(), "v4".into()).unwrap();
+        assert_eq!(store.get("t1", "k2"), Ok(None));
+        assert_eq!(store.contains("t1", "k1"), Ok(true));
+        assert_eq!(store.contains("t1", "k3"), Ok(true));
+
+        // 没有设置过期时间的 key 不会被淘汰
+        store.del("t1", "k3").unwrap();
+        store.set("t1", "k5".into(), "v5".into()).unwrap();
+        let res = store.set("t1", "k6".into(), "a long value".into());
+        assert!(matches!(res, Err(KvError::OutOfMemory(_))));
+    }
+
+    #[test]
+    fn sleddb_should_not_support_expire() {
+        let dir = tempdir().unwrap();
+        let store = SledDb::new(dir.path()).unwrap();
+        store.set("t1", "k1".into(), "v1".into()).unwrap();
+        assert!(store.expire("t1", "k1", Some(now_ms())).is_err());
+        assert_eq!(store.get_expire_at("t1", "k1"), Ok(None));
+        // 带过期时间的 set 在写入之前就失败，原来的值不变
+        assert!(store.set_with_expire("t1", "k1".into(), "v2".into(), Some(now_ms() + 60_000)).is_err());
+        assert_eq!(store.get("t1", "k1"), Ok(Some("v1".into())));
+    }
+
+    #[test]
+    fn memtable_incr_should_work() {
+        let store = MemTable::new();
+        test_incr(store);
+    }
+
+    #[test]
+    fn sleddb_incr_should_work() {
+        let dir = tempdir().unwrap();
+        let store = SledDb::new(dir.path()).unwrap();
+        test_incr(store);
+    }
+
+    #[test]
+    fn memtable_incr_should_keep_expiration_and_track_memory() {
+        let store = MemTable::new();
+        let at = now_ms() + 60_000;
+        store.incr("t1", "k1".into(), 1.into()).unwrap();
+        store.expire("t1", "k1", Some(at)).unwrap();
+        store.incr("t1", "k1".into(), i64::MAX.into()).unwrap_err();
+        store.incr("t1", "k1".into(), 1_000_000_000.into()).unwrap();
+        assert_eq!(store.get_expire_at("t1", "k1"), Ok(Some(at)));
+        // 占用的内存和直接写入同样的值一样
+        let expected = MemTable::new();
+        expected.set("t1", "k1".into(), 1_000_000_001.into()).unwrap();
+        assert_eq!(store.used_memory(), expected.used_memory());
+    }
+
+    #[test]
+    fn concurrent_incr_should_not_lose_updates() {
+        let store = std::sync::Arc::new(MemTable::new());
+        let handles: Vec<_> = (0..8)
+            .map(|_| {
+                let store = store.clone();
+                std::thread::spawn(move || {
+                    for _ in 0..1000 {
+                        store.incr("t1", "counter".into(), 1.into()).unwrap();
+                    }
+                })
+            })
+            .collect();
+        for handle in handles {
+            handle.join().unwrap();
+        }
+        assert_eq!(store.get("t1", "counter"), Ok(Some(8000.into())));
+    }
+
+    #[test]
+    fn memtable_set_if_should_work() {
+        let store = MemTable::new();
+        test_set_if(&store);
+
+        // 每次写入都会得到新的版本号，旧的版本号不再匹配
+        let v1 = store.set_if("t1", "k9".into(), "v1".into(), Precondition::Absent).unwrap();
+        let v2 = store.set_if("t1", "k9".into(), "v2".into(), Precondition::Version(v1)).unwrap();
+        assert_ne!(v1, v2);
+        let err = store.set_if("t1", "k9".into(), "v3".into(), Precondition::Version(v1)).unwrap_err();
+        assert_eq!(err, KvError::PreconditionFailed("t1".into(), "k9".into()));
+        store.set("t1", "k9".into(), "v3".into()).unwrap();
+        assert!(store.set_if("t1", "k9".into(), "v4".into(), Precondition::Version(v2)).is_err());
+        assert_eq!(store.get("t1", "k9"), Ok(Some("v3".into())));
+    }
+
+    #[test]
+    fn sleddb_set_if_should_work() {
+        let dir = tempdir().unwrap();
+        let store = SledDb::new(dir.path()).unwrap();
+        test_set_if(&store);
+        assert!(matches!(
+            store.set_if("t1", "k1".into(), "v1".into(), Precondition::Version(1)),
+            Err(KvError::InvalidCommand(_))
+        ));
+    }
+
+    #[test]
+    fn concurrent_set_if_should_have_one_winner() {
+        let store = std::sync::Arc::new(MemTable::new());
+        let handles: Vec<_> = (0..8)
+            .map(|i| {
+                let store = store.clone();
+                std::thread::spawn(move || store.set_if("t1", "lock".into(), i.into(), Precondition::Absent).is_ok())
+            })
+            .collect();
+        let winners = handles.into_iter().map(|h| h.join().unwrap()).filter(|ok| *ok).count();
+        assert_eq!(winners, 1);
+    }
+
+    #[test]
+    fn memtable_table_management_should_work() {
+        let store = MemTable::new();
+        test_table_management(&store);
+        assert_eq!(store.used_memory(), 0);
+    }
+
+    #[test]
+    fn sleddb_table_management_should_work() {
+        let dir = tempdir().unwrap();
+        let store = SledDb::new(dir.path()).unwrap();
+        test_table_management(&store);
+    }
+
+    #[test]
+    fn sleddb_rename_table_should_not_lose_concurrent_writes() {
+        let dir = tempdir().unwrap();
+        let store = SledDb::new(dir.path()).unwrap();
+        store.set("t1", "k0".into(), 0.into()).unwrap();
+        let cloned = store.clone();
+        let handle = std::thread::spawn(move || {
+            for i in 1..500 {
+                cloned.set("t1", format!("k{}", i), i.into()).unwrap();
+            }
+        });
+        // 写入要么在改名之前，搬到了 t2 里，要么在改名之后，写到了新建的 t1 里
+        store.rename_table("t1", "t2").unwrap();
+        handle.join().unwrap();
+        assert_eq!(store.len("t1").unwrap() + store.len("t2").unwrap(), 500);
+
+        // 重新打开之后 table 和数据都还在
+        drop(store);
+        let store = SledDb::new(dir.path()).unwrap();
+        assert_eq!(store.len("t1").unwrap() + store.len("t2").unwrap(), 500);
+    }
+
+    #[test]
+    fn memtable_len_should_skip_expired_keys() {
+        let store = MemTable::new();
+        store.set("t1", "k1".into(), "v1".into()).unwrap();
+        store.set("t1", "k2".into(), "v2".into()).unwrap();
+        store.expire("t1", "k1", Some(now_ms() - 1)).unwrap();
+        assert_eq!(store.len("t1"), Ok(1));
+    }
+
+    fn test_table_management(store: &impl Storage) {
+        // 读操作不会创建 table
+        assert_eq!(store.get("t1", "k1"), Ok(None));
+        assert_eq!(store.contains("t1", "k1"), Ok(false));
+        assert_eq!(store.get_all("t1"), Ok(vec![]));
+        assert_eq!(store.get_iter("t1").unwrap().count(), 0);
+        assert_eq!(store.del("t1", "k1"), Ok(None));
+        assert_eq!(store.len("t1"), Ok(0));
+        assert_eq!(store.list_tables(), Ok(vec![]));
+
+        store.set("t2", "k1".into(), "v1".into()).unwrap();
+        store.set("t2", "k2".into(), "v2".into()).unwrap();
+        store.set("t1", "k1".into(), "v1".into()).unwrap();
+        assert_eq!(store.list_tables(), Ok(vec!["t1".into(), "t2".into()]));
+        assert_eq!(store.len("t2"), Ok(2));
+
+        // 重命名
+        assert_eq!(store.rename_table("t9", "t3"), Err(KvError::TableNotFound("t9".into())));
+        assert_eq!(store.rename_table("t2", "t1"), Err(KvError::TableExists("t1".into())));
+        store.rename_table("t2", "t3").unwrap();
+        assert_eq!(store.list_tables(), Ok(vec!["t1".into(), "t3".into()]));
+        assert_eq!(store.get("t3", "k2"), Ok(Some("v2".into())));
+        assert_eq!(store.get("t2", "k2"), Ok(None));
+
+        // 清空，table 还在
+        assert_eq!(store.truncate("t3"), Ok(2));
+        assert_eq!(store.len("t3"), Ok(0));
+        assert_eq!(store.list_tables(), Ok(vec!["t1".into(), "t3".into()]));
+
+        // 删除
+        assert_eq!(store.drop_table("t1"), Ok(true));
+        assert_eq!(store.drop_table("t1"), Ok(false));
+        assert_eq!(store.get("t1", "k1"), Ok(None));
+        assert_eq!(store.drop_table("t3"), Ok(true));
+        assert_eq!(store.list_tables(), Ok(vec![]));
+    }
+
+    #[test]
+    fn glob_match_should_work() {
+        assert!(glob_match("*", ""));
+        assert!(glob_match("user:*", "user:123:2026"));
+        assert!(!glob_match("user:*", "order:1"));
+        assert!(glob_match("*:2026*", "user:123:2026-10-17"));
+        assert!(glob_match("k?", "k1"));
+        assert!(!glob_match("k?", "k10"));
+        assert!(glob_match("k[0-2]", "k1"));
+        assert!(!glob_match("k[^0-2]", "k1"));
+        assert!(glob_match("k[ab]", "kb"));
+        assert!(glob_match("a\\*b", "a*b"));
+        assert!(!glob_match("a\\*b", "axb"));
+        assert!(glob_match("[", "["));
+    }
+
+    #[test]
+    fn scan_cursor_should_round_trip() {
+        for cursor in ["3", "0:k1", "12:a:b", "5:"] {
+            assert_eq!(ScanCursor::parse(cursor).unwrap().to_string(), cursor);
+        }
+        assert_eq!(ScanCursor::parse(""), Ok(ScanCursor::default()));
+        assert!(ScanCursor::parse("x:k1").is_err());
+    }
+
+    #[test]
+    fn memtable_scan_should_work() {
+        test_scan(MemTable::new());
+    }
+
+    #[test]
+    fn sleddb_scan_should_work() {
+        let dir = tempdir().unwrap();
+        test_scan(SledDb::new(dir.path()).unwrap());
+    }
+
+    #[test]
+    fn memtable_scan_should_be_stable_under_concurrent_writes() {
+        let store = MemTable::new();
+        for i in 0..500 {
+            store.set("t1", format!("k{}", i), i.into()).unwrap();
+        }
+        let mut seen = std::collections::HashSet::new();
+        let mut cursor = String::new();
+        let mut round = 0;
+        loop {
+            let page = store.scan("t1", &cursor, 7, None).unwrap();
+            for pair in page.pairs {
+                assert!(seen.insert(pair.key), "key returned twice");
+            }
+            // 遍历期间插入和删除别的 key，不影响原来的 key
+            store.set("t1", format!("new{}", round), round.into()).unwrap();
+            store.del("t1", &format!("new{}", round / 2)).unwrap();
+            round += 1;
+            match page.cursor {
+                Some(next) => cursor = next,
+                None => break,
+            }
+        }
+        assert!((0..500).all(|i| seen.contains(&format!("k{}", i))));
+    }
+
+    #[test]
+    fn memtable_scan_should_follow_index() {
+        fn scan_all(store: &MemTable, table: &str) -> Vec<String> {
+            let mut keys = Vec::new();
+            let mut cursor = String::new();
+            loop {
+                let page = store.scan(table, &cursor, 8, None).unwrap();
+                keys.extend(page.pairs.into_iter().map(|pair| pair.key));
+                match page.cursor {
+                    Some(next) => cursor = next,
+                    None => return keys,
+                }
+            }
+        }
+
+        let store = MemTable::new();
+        store.create_table("t2", TableKind::Ordered).unwrap();
+        for i in 0..50 {
+            store.set("t1", format!("k{:02}", i), i.into()).unwrap();
+            store.set("t2", format!("k{:02}", i), i.into()).unwrap();
+        }
+        for i in 0..25 {
+            store.del("t1", &format!("k{:02}", i)).unwrap();
+        }
+        let mut keys = scan_all(&store, "t1");
+        keys.sort();
+        assert_eq!(keys, (25..50).map(|i| format!("k{:02}", i)).collect::<Vec<_>>());
+        // 有序的 table 只有一个 index，按 key 的顺序返回
+        assert_eq!(scan_all(&store, "t2"), (0..50).map(|i| format!("k{:02}", i)).collect::<Vec<_>>());
+
+        store.truncate("t1").unwrap();
+        assert!(scan_all(&store, "t1").is_empty());
+    }
+
+    fn test_scan(store: impl Storage) {
+        assert_eq!(store.scan("t1", "", 10, None), Ok(ScanPage::default()));
+        for i in 0..100 {
+            store.set("t1", format!("user:{}", i), i.into()).unwrap();
+            store.set("t1", format!("order:{}", i), i.into()).unwrap();
+        }
+        let mut users = Vec::new();
+        let mut all = 0;
+        let mut cursor = String::new();
+        loop {
+            let page = store.scan("t1", &cursor, 10, Some("user:*")).unwrap();
+            // 每次最多检查 10 个 key
+            assert!(page.pairs.len() <= 10);
+            all += 1;
+            users.extend(page.pairs.into_iter().map(|pair| pair.key));
+            match page.cursor {
+                Some(next) => cursor = next,
+                None => break,
+            }
+        }
+        assert!(all >= 20);
+        users.sort();
+        let mut expected: Vec<_> = (0..100).map(|i| format!("user:{}", i)).collect();
+        expected.sort();
+        assert_eq!(users, expected);
+        assert!(store.scan("t1", "bad", 10, None).is_err());
+    }
+
+    #[test]
+    fn memtable_ordered_table_should_work() {
+        let store = MemTable::new();
+        test_ordered_table(&store);
+
+        // 自动创建的是 hash table，不支持按范围读取
+        store.set("t2", "k1".into(), "v1".into()).unwrap();
+        assert!(matches!(store.range("t2", &KeyRange::all()), Err(KvError::InvalidCommand(_))));
+
+        // 过期和删除的 key 同时从 index 中去掉
+        store.expire("t1", "user:1:2026-10-17", Some(now_ms() - 1)).unwrap();
+        store.purge_expired().unwrap();
+        assert_eq!(store.range("t1", &KeyRange::prefix("user:1:")).unwrap().len(), 1);
+        store.truncate("t1").unwrap();
+        assert_eq!(store.range("t1", &KeyRange::all()), Ok(vec![]));
+    }
+
+    #[test]
+    fn sleddb_ordered_table_should_work() {
+        let dir = tempdir().unwrap();
+        let store = SledDb::new(dir.path()).unwrap();
+        test_ordered_table(&store);
+    }
+
+    #[test]
+    fn memtable_ordered_range_should_cross_batches() {
+        let store = MemTable::new();
+        store.create_table("t1", TableKind::Ordered).unwrap();
+        for i in 0..1000 {
+            store.set("t1", format!("k{:04}", i), i.into()).unwrap();
+        }
+        let all = store.get_all("t1").unwrap();
+        assert_eq!(all.len(), 1000);
+        assert!(all.windows(2).all(|w| w[0].key < w[1].key));
+        let range = KeyRange {
+            start: Bound::Excluded("k0100".into()),
+            end: Bound::Included("k0899".into()),
+            reverse: true,
+            limit: 0,
+        };
+        let pairs = store.range("t1", &range).unwrap();
+        assert_eq!(pairs.len(), 799);
+        assert_eq!(pairs[0].key, "k0899");
+        assert_eq!(pairs[798].key, "k0101");
+    }
+
+    #[test]
+    fn key_range_prefix_should_work() {
+        let range = KeyRange::prefix("user:");
+        assert_eq!(range.start, Bound::Included("user:".into()));
+        assert_eq!(range.end, Bound::Excluded("user;".into()));
+        assert_eq!(KeyRange::prefix("").end, Bound::Unbounded);
+        assert_eq!(KeyRange::prefix("a\u{10ffff}").end, Bound::Excluded("b".into()));
+    }
+
+    fn test_ordered_table(store: &impl Storage) {
+        store.create_table("t1", TableKind::Ordered).unwrap();
+        assert_eq!(store.create_table("t1", TableKind::Ordered), Err(KvError::TableExists("t1".into())));
+        for key in ["user:2:2026-10-17", "user:1:2026-10-18", "user:1:2026-10-17", "order:1", "user:10:2026-10-17"] {
+            store.set("t1", key.into(), key.into()).unwrap();
+        }
+        let keys = |pairs: Vec<KvPair>| pairs.into_iter().map(|pair| pair.key).collect::<Vec<_>>();
+
+        // 不需要排序，get_all 就是按 key 的顺序返回的
+        assert_eq!(
+            keys(store.get_all("t1").unwrap()),
+            vec!["order:1", "user:10:2026-10-17", "user:1:2026-10-17", "user:1:2026-10-18", "user:2:2026-10-17"]
+        );
+        assert_eq!(
+            keys(store.range("t1", &KeyRange::prefix("user:1:")).unwrap()),
+            vec!["user:1:2026-10-17", "user:1:2026-10-18"]
+        );
+        assert_eq!(
+            keys(store.range("t1", &KeyRange::prefix("user:").reverse(true).limit(2)).unwrap()),
+            vec!["user:2:2026-10-17", "user:1:2026-10-18"]
+        );
+        let range = KeyRange {
+            start: Bound::Included("user:1:2026-10-17".into()),
+            end: Bound::Excluded("user:2".into()),
+            reverse: false,
+            limit: 0,
+        };
+        assert_eq!(
+            keys(store.range("t1", &range).unwrap()),
+            vec!["user:1:2026-10-17", "user:1:2026-10-18"]
+        );
+        let range = KeyRange {
+            start: Bound::Excluded("user:1:2026-10-17".into()),
+            ..range
+        };
+        assert_eq!(keys(store.range("t1", &range).unwrap()), vec!["user:1:2026-10-18"]);
+        // start 比 end 大时没有结果
+        let range = KeyRange {
+            start: Bound::Included("z".into()),
+            end: Bound::Included("a".into()),
+            ..KeyRange::all()
+        };
+        assert_eq!(store.range("t1", &range), Ok(vec![]));
+
+        // 删除的 key 不再返回
+        store.del("t1", "order:1").unwrap();
+        assert_eq!(store.range("t1", &KeyRange::prefix("order:")), Ok(vec![]));
+        assert_eq!(store.range("t9", &KeyRange::all()), Ok(vec![]));
+    }
+
+    fn test_set_if(store: &impl Storage) {
+        // key 不存在时才能 Absent
+        assert!(store.set_if("t1", "k1".into(), "v1".into(), Precondition::Absent).is_ok());
+        let err = store.set_if("t1", "k1".into(), "v2".into(), Precondition::Absent).unwrap_err();
+        assert_eq!(err, KvError::KeyExists("t1".into(), "k1".into()));
+
+        // 值相等时才能替换
+        let err = store.set_if("t1", "k1".into(), "v2".into(), Precondition::Equals("v0".into())).unwrap_err();
+        assert_eq!(err, KvError::PreconditionFailed("t1".into(), "k1".into()));
+        assert_eq!(store.get("t1", "k1"), Ok(Some("v1".into())));
+        assert!(store.set_if("t1", "k1".into(), "v2".into(), Precondition::Equals("v1".into())).is_ok());
+        assert_eq!(store.get("t1", "k1"), Ok(Some("v2".into())));
+        // 不存在的 key 和任何值都不相等
+        let err = store.set_if("t1", "k2".into(), "v2".into(), Precondition::Equals("v1".into())).unwrap_err();
+        assert_eq!(err, KvError::PreconditionFailed("t1".into(), "k2".into()));
+    }
+
+    fn test_incr(store: impl Storage) {
+        // key 不存在时当作 0
+        assert_eq!(store.incr("t1", "k1".into(), 5.into()), Ok(5.into()));
+        assert_eq!(store.incr("t1", "k1".into(), (-7).into()), Ok((-2).into()));
+        // integer 可以加上 float，结果是 float
+        assert_eq!(store.incr("t1", "k1".into(), 0.5.into()), Ok((-1.5).into()));
+        assert_eq!(store.incr("t1", "k2".into(), 0.25.into()), Ok(0.25.into()));
+
+        // float 不能用 HINCRBY
+        let err = store.incr("t1", "k1".into(), 1.into()).unwrap_err();
+        assert_eq!(err, KvError::InvalidCommand("Value is not an integer".into()));
+        // 不是数字的值不能加
+        store.set("t1", "s".into(), "hello".into()).unwrap();
+        assert!(matches!(store.incr("t1", "s".into(), 1.into()), Err(KvError::InvalidCommand(_))));
+        assert!(matches!(store.incr("t1", "s".into(), 1.0.into()), Err(KvError::InvalidCommand(_))));
+        assert!(matches!(store.incr("t1", "s".into(), "1".into()), Err(KvError::InvalidCommand(_))));
+        // 能解析成数字的字符串可以加
+        store.set("t1", "n".into(), "10".into()).unwrap();
+        assert_eq!(store.incr("t1", "n".into(), 1.into()), Ok(11.into()));
+        store.set("t1", "f".into(), "1.5".into()).unwrap();
+        assert_eq!(store.incr("t1", "f".into(), 1.0.into()), Ok(2.5.into()));
+
+        // 溢出时返回错误，原来的值不变
+        store.set("t1", "max".into(), i64::MAX.into()).unwrap();
+        let err = store.incr("t1", "max".into(), 1.into()).unwrap_err();
+        assert_eq!(err, KvError::InvalidCommand("Increment or decrement would overflow".into()));
+        assert_eq!(store.get("t1", "max"), Ok(Some(i64::MAX.into())));
+        store.set("t1", "big".into(), f64::MAX.into()).unwrap();
+        assert!(matches!(store.incr("t1", "big".into(), f64::MAX.into()), Err(KvError::InvalidCommand(_))));
+        assert_eq!(store.get("t1", "big"), Ok(Some(f64::MAX.into())));
+    }
 
     fn test_basic_interface(store: impl Storage) {
         // 第一次 set 会创建 table，插入 key 并返回 None（之前没值）
@@ -103,6 +1148,12 @@ mod tests {
         // del 不存在的 key 或 table 返回 None
         assert_eq!(Ok(None), store.del("table1", "hello1"));
         assert_eq!(Ok(None), store.del("table2", "hello"));
+
+        // set_many 返回每个 key 之前的值
+        store.set("table1", "k1".into(), "v1".into()).unwrap();
+        let pairs = vec![KvPair::new("k1", "v2".into()), KvPair::new("k2", "v2".into())];
+        assert_eq!(store.set_many("table1", pairs, None), Ok(vec![Some("v1".into()), None]));
+        assert_eq!(store.get("table1", "k2"), Ok(Some("v2".into())));
     }
 
     fn test_get_all(store: impl Storage) {
@@ -117,6 +1168,45 @@ mod tests {
         ]);
     }
 
+    fn test_expire(store: impl Storage) {
+        store.set("t1", "k1".into(), "v1".into()).unwrap();
+        store.set("t1", "k2".into(), "v2".into()).unwrap();
+
+        // 不存在的 key 不能设置过期时间
+        assert_eq!(store.expire("t1", "k3", Some(now_ms() + 60_000)), Ok(false));
+
+        // 没有过期的 key 可以正常读写
+        let at = now_ms() + 60_000;
+        assert_eq!(store.expire("t1", "k1", Some(at)), Ok(true));
+        assert_eq!(store.get_expire_at("t1", "k1"), Ok(Some(at)));
+        assert_eq!(store.get("t1", "k1"), Ok(Some("v1".into())));
+
+        // 去掉过期时间
+        assert_eq!(store.expire("t1", "k1", None), Ok(true));
+        assert_eq!(store.get_expire_at("t1", "k1"), Ok(None));
+
+        // 过期的 key 读不到，也不会出现在遍历的结果里
+        store.expire("t1", "k2", Some(now_ms() - 1)).unwrap();
+        assert_eq!(store.get("t1", "k2"), Ok(None));
+        assert_eq!(store.contains("t1", "k2"), Ok(false));
+        let data: Vec<_> = store.get_iter("t1").unwrap().collect();
+        assert_eq!(data, vec![KvPair::new("k1", "v1".into())]);
+
+        // 重新 set 会清除过期时间，并且过期的旧值不会被返回
+        store.set("t1", "k1".into(), "v1".into()).unwrap();
+        store.expire("t1", "k1", Some(now_ms() - 1)).unwrap();
+        assert_eq!(store.set("t1", "k1".into(), "v2".into()), Ok(None));
+        assert_eq!(store.get_expire_at("t1", "k1"), Ok(None));
+        assert_eq!(store.get("t1", "k1"), Ok(Some("v2".into())));
+
+        // set_with_expire 同时写入 value 和过期时间
+        let at = now_ms() + 60_000;
+        assert_eq!(store.set_with_expire("t1", "k1".into(), "v3".into(), Some(at)), Ok(Some("v2".into())));
+        assert_eq!(store.get_expire_at("t1", "k1"), Ok(Some(at)));
+        assert_eq!(store.set_with_expire("t1", "k1".into(), "v4".into(), None), Ok(Some("v3".into())));
+        assert_eq!(store.get_expire_at("t1", "k1"), Ok(None));
+    }
+
     fn test_get_iter(storage: impl Storage) {
         storage.set("table2", "k1".into(), "v1".into()).unwrap();
         storage.set("table2", "k2".into(), "v2".into()).unwrap();
@@ -0,0 +1,351 @@
+use std::convert::{TryFrom, TryInto};
+use std::ops::Bound;
+use std::path::Path;
+use std::sync::{Arc, RwLock, RwLockReadGuard};
+use dashmap::{DashMap, mapref::entry::Entry};
+use sled::{Db, IVec, Tree};
+use sled::transaction::{ConflictableTransactionError, TransactionError, Transactional};
+use tracing::warn;
+use crate::{KeyRange, KvPair, Precondition, ScanPage, Storage, StorageIter, TableKind, Value};
+use crate::errors::KvError;
+use crate::storage::{glob_match, incr_value, ScanCursor};
+
+// sled 自己会用到 "__sled__default" 这样的 tree，给 table 加上前缀，避免和 sled 内部的 tree 重名
+const TABLE_PREFIX: &str = "table:";
+
+/*
+    sled 的 open_tree 在 tree 不存在时会创建它，读操作不能直接用，而每次都用 tree_names 检查又要把所有 tree 的名字复制一遍。
+    所以打开数据库时把已有的 table 都记在 tables 里，之后创建和删除 table 时同步修改，读操作只需要查一下 tables。
+    创建和删除同一个 table 时都拿着 tables 中这个 table 的锁，不会把一个刚被删掉的 tree 又放回 tables 里。
+
+    和 MemTable 一样，写 key 的操作拿 write_gate 的读锁，DROPTABLE / RENAMETABLE / TRUNCATE 拿写锁，
+    执行期间不会有写操作写到一个正在被删掉或者改名的 table 里。
+*/
+/// 基于 sled 的持久化存储，每个 table 对应 sled 中的一个 Tree
+#[derive(Clone, Debug)]
+pub struct SledDb {
+    db: Db,
+    tables: Arc<DashMap<String, Tree>>,
+    write_gate: Arc<RwLock<()>>,
+}
+
+impl SledDb {
+    /// 打开 path 目录下的数据库，如果不存在则创建
+    pub fn new(path: impl AsRef<Path>) -> Result<Self, KvError> {
+        let db = sled::open(path)?;
+        let tables = DashMap::new();
+        for name in table_names(&db) {
+            let tree = db.open_tree(tree_name(&name))?;
+            tables.insert(name, tree);
+        }
+        Ok(Self { db, tables: Arc::new(tables), write_gate: Default::default() })
+    }
+
+    /// 如果名为 name 的 table 不存在，则创建，否则返回。只有写入 key 的操作才使用它
+    fn get_or_create_table(&self, name: &str) -> Result<Tree, KvError> {
+        if let Some(tree) = self.get_table(name) {
+            return Ok(tree);
+        }
+        match self.tables.entry(name.into()) {
+            Entry::Occupied(entry) => Ok(entry.get().clone()),
+            Entry::Vacant(entry) => {
+                let tree = self.db.open_tree(tree_name(name))?;
+                Ok(entry.insert(tree).value().clone())
+            }
+        }
+    }
+
+    /// 所有写 key 的操作都要先拿到这个 guard
+    fn write_guard(&self) -> RwLockReadGuard<'_, ()> {
+        self.write_gate.read().unwrap()
+    }
+
+    /// 返回名为 name 的 table，不存在时返回 None
+    fn get_table(&self, name: &str) -> Option<Tree> {
+        self.tables.get(name).map(|t| t.value().clone())
+    }
+
+    /// 删除名为 name 的 table，返回它之前是否存在
+    fn remove_table(&self, name: &str) -> Result<bool, KvError> {
+        match self.tables.entry(name.into()) {
+            Entry::Occupied(entry) => {
+                self.db.drop_tree(tree_name(name))?;
+                entry.remove();
+                Ok(true)
+            }
+            Entry::Vacant(_) => Ok(false),
+        }
+    }
+}
+
+impl Storage for SledDb {
+    fn get(&self, table: &str, key: &str) -> Result<Option<Value>, KvError> {
+        let table = match self.get_table(table) {
+            Some(table) => table,
+            None => return Ok(None),
+        };
+        // Option<Result<Value, KvError>> 需要 transpose 成 Result<Option<Value>, KvError>
+        table.get(key)?.map(|v| v.as_ref().try_into()).transpose()
+    }
+
+    fn set(&self, table: &str, key: String, value: Value) -> Result<Option<Value>, KvError> {
+        let _guard = self.write_guard();
+        let table = self.get_or_create_table(table)?;
+        let data = Vec::<u8>::try_from(&value)?;
+        table.insert(key, data)?.map(|v| v.as_ref().try_into()).transpose()
+    }
+
+    fn set_many(&self, table: &str, pairs: Vec<KvPair>, expire_at: Option<u64>) -> Result<Vec<Option<Value>>, KvError> {
+        if expire_at.is_some() {
+            return Err(KvError::InvalidCommand("Expiration is not supported by this storage".into()));
+        }
+        let _guard = self.write_guard();
+        let table = self.get_or_create_table(table)?;
+        let pairs = pairs
+            .into_iter()
+            .map(|pair| Ok((pair.key, Vec::<u8>::try_from(&pair.value.unwrap_or_default())?)))
+            .collect::<Result<Vec<_>, KvError>>()?;
+        // 在一个 sled 事务中写入，要么全部写入，要么一个都不写
+        let result = table.transaction(|tx| {
+            let mut olds = Vec::with_capacity(pairs.len());
+            for (key, data) in &pairs {
+                olds.push(tx.insert(key.as_bytes(), data.clone())?);
+            }
+            Ok::<_, ConflictableTransactionError<KvError>>(olds)
+        });
+        let olds = result.map_err(|e| match e {
+            TransactionError::Abort(e) => e,
+            TransactionError::Storage(e) => e.into(),
+        })?;
+        olds.into_iter().map(|v| v.map(|v| v.as_ref().try_into()).transpose()).collect()
+    }
+
+    fn contains(&self, table: &str, key: &str) -> Result<bool, KvError> {
+        let table = match self.get_table(table) {
+            Some(table) => table,
+            None => return Ok(false),
+        };
+        Ok(table.contains_key(key)?)
+    }
+
+    fn incr(&self, table: &str, key: String, delta: Value) -> Result<Value, KvError> {
+        let _guard = self.write_guard();
+        let table = self.get_or_create_table(table)?;
+        // 在 sled 的事务中读取、计算和写回，并发修改同一个 key 时事务会自动重试
+        let result = table.transaction(|tx| {
+            let old = tx
+                .get(key.as_bytes())?
+                .map(|v| Value::try_from(v.as_ref()))
+                .transpose()
+                .map_err(ConflictableTransactionError::Abort)?;
+            let new = incr_value(old.as_ref(), &delta).map_err(ConflictableTransactionError::Abort)?;
+            let data = Vec::<u8>::try_from(&new).map_err(ConflictableTransactionError::Abort)?;
+            tx.insert(key.as_bytes(), data)?;
+            Ok(new)
+        });
+        result.map_err(|e| match e {
+            TransactionError::Abort(e) => e,
+            TransactionError::Storage(e) => e.into(),
+        })
+    }
+
+    fn set_if(&self, table: &str, key: String, value: Value, precondition: Precondition) -> Result<u64, KvError> {
+        let _guard = self.write_guard();
+        let tree = self.get_or_create_table(table)?;
+        // 同一个 Value 编码的结果是一样的，所以可以直接用 sled 的 compare_and_swap 比较编码后的字节
+        let expected = match &precondition {
+            Precondition::Absent => None,
+            Precondition::Equals(v) => Some(Vec::<u8>::try_from(v)?),
+            // 没有版本号，缺省的 version() 会返回不支持的错误
+            Precondition::Version(_) => return self.version(table, &key).map(|_| 0),
+        };
+        let data = Vec::<u8>::try_from(&value)?;
+        match tree.compare_and_swap(key.as_bytes(), expected, Some(data))? {
+            Ok(()) => Ok(0),
+            Err(_) => Err(precondition.failed(table, &key)),
+        }
+    }
+
+    fn del(&self, table: &str, key: &str) -> Result<Option<Value>, KvError> {
+        let _guard = self.write_guard();
+        let table = match self.get_table(table) {
+            Some(table) => table,
+            None => return Ok(None),
+        };
+        table.remove(key)?.map(|v| v.as_ref().try_into()).transpose()
+    }
+
+    fn get_all(&self, table: &str) -> Result<Vec<KvPair>, KvError> {
+        let table = match self.get_table(table) {
+            Some(table) => table,
+            None => return Ok(Vec::new()),
+        };
+        table.iter().map(decode_pair).collect()
+    }
+
+    fn get_iter(&self, table: &str) -> Result<Box<dyn Iterator<Item=KvPair>>, KvError> {
+        let table = match self.get_table(table) {
+            Some(table) => table,
+            None => return Ok(Box::new(std::iter::empty())),
+        };
+        // sled 的 Iter 本身就是惰性的，并且不借用 Tree，可以直接返回
+        let iter = table.iter().filter_map(|item| match decode_pair(item) {
+            Ok(pair) => Some(pair),
+            Err(e) => {
+                warn!("Failed to read pair from sled: {:?}", e);
+                None
+            }
+        });
+        Ok(Box::new(StorageIter::new(iter)))
+    }
+
+    fn scan(&self, table: &str, cursor: &str, count: usize, pattern: Option<&str>) -> Result<ScanPage, KvError> {
+        let cursor = ScanCursor::parse(cursor)?;
+        let tree = match self.get_table(table) {
+            Some(tree) => tree,
+            None => return Ok(ScanPage::default()),
+        };
+        // sled 中的 key 本身就是有序的，从上一页最后的 key 之后接着读就可以了
+        let iter = match &cursor.key {
+            Some(key) => tree.range::<&[u8], _>((Bound::Excluded(key.as_bytes()), Bound::Unbounded)),
+            None => tree.iter(),
+        };
+        let mut pairs = Vec::new();
+        let mut examined = 0;
+        let mut last = None;
+        for item in iter.take(count.max(1)) {
+            let pair = decode_pair(item)?;
+            examined += 1;
+            last = Some(pair.key.clone());
+            if pattern.is_none_or(|pattern| glob_match(pattern, &pair.key)) {
+                pairs.push(pair);
+            }
+        }
+        // 读满了 count 个 key 才可能还有剩下的
+        let cursor = match last {
+            Some(key) if examined == count.max(1) => Some(ScanCursor { shard: 0, key: Some(key) }.to_string()),
+            _ => None,
+        };
+        Ok(ScanPage { pairs, cursor })
+    }
+
+    // sled 中的 tree 本身就是按 key 排序的，所以不管 kind 是什么，table 都是有序的
+    fn create_table(&self, table: &str, _kind: TableKind) -> Result<(), KvError> {
+        let _guard = self.write_guard();
+        match self.tables.entry(table.into()) {
+            Entry::Occupied(_) => Err(KvError::TableExists(table.into())),
+            Entry::Vacant(entry) => {
+                entry.insert(self.db.open_tree(tree_name(table))?);
+                Ok(())
+            }
+        }
+    }
+
+    fn range(&self, table: &str, range: &KeyRange) -> Result<Vec<KvPair>, KvError> {
+        let tree = match self.get_table(table) {
+            Some(tree) => tree,
+            None => return Ok(Vec::new()),
+        };
+        if range.is_empty() {
+            return Ok(Vec::new());
+        }
+        let start = range.start.as_ref().map(|k| k.as_bytes());
+        let end = range.end.as_ref().map(|k| k.as_bytes());
+        let iter = tree.range::<&[u8], _>((start, end));
+        let limit = match range.limit {
+            0 => usize::MAX,
+            n => n,
+        };
+        match range.reverse {
+            true => iter.rev().take(limit).map(decode_pair).collect(),
+            false => iter.take(limit).map(decode_pair).collect(),
+        }
+    }
+
+    fn list_tables(&self) -> Result<Vec<String>, KvError> {
+        let mut names: Vec<String> = self.tables.iter().map(|t| t.key().clone()).collect();
+        names.sort();
+        Ok(names)
+    }
+
+    fn drop_table(&self, table: &str) -> Result<bool, KvError> {
+        let _guard = self.write_gate.write().unwrap();
+        self.remove_table(table)
+    }
+
+    /*
+        sled 不支持重命名 tree，只能把数据搬到新的 tree 里再删掉旧的。搬数据在一个跨两个 tree 的 sled 事务里完成，
+        新 tree 中写入所有的 key 和旧 tree 中删除所有的 key 一起生效，崩溃之后不会出现两个 table 中都有数据的情况，
+        最多留下一个空的 table。整个过程拿着 write_gate 的写锁，不会有写操作写到旧的 table 里。
+    */
+    fn rename_table(&self, from: &str, to: &str) -> Result<(), KvError> {
+        let _guard = self.write_gate.write().unwrap();
+        let old = self.get_table(from).ok_or_else(|| KvError::TableNotFound(from.into()))?;
+        if self.get_table(to).is_some() {
+            return Err(KvError::TableExists(to.into()));
+        }
+        let new = self.get_or_create_table(to)?;
+        let entries = old.iter().collect::<Result<Vec<_>, _>>()?;
+        let result = (&old, &new).transaction(|(old, new)| {
+            for (k, v) in &entries {
+                new.insert(k, v.clone())?;
+                old.remove(k)?;
+            }
+            Ok::<_, ConflictableTransactionError<KvError>>(())
+        });
+        result.map_err(|e| match e {
+            TransactionError::Abort(e) => e,
+            TransactionError::Storage(e) => e.into(),
+        })?;
+        self.remove_table(from)?;
+        Ok(())
+    }
+
+    fn truncate(&self, table: &str) -> Result<usize, KvError> {
+        let _guard = self.write_gate.write().unwrap();
+        let table = match self.get_table(table) {
+            Some(table) => table,
+            None => return Ok(0),
+        };
+        let count = table.len();
+        table.clear()?;
+        Ok(count)
+    }
+
+    fn len(&self, table: &str) -> Result<usize, KvError> {
+        Ok(self.get_table(table).map(|t| t.len()).unwrap_or(0))
+    }
+
+    fn save(&self) -> Result<(), KvError> {
+        // sled 本身就是持久化的，只需要把缓存中的数据刷到磁盘上
+        self.db.flush()?;
+        Ok(())
+    }
+
+    fn flush(&self) -> Result<(), KvError> {
+        self.save()
+    }
+}
+
+/// 数据库中已有的 table，去掉 sled 内部的 tree
+fn table_names(db: &Db) -> Vec<String> {
+    db.tree_names()
+        .into_iter()
+        .filter_map(|n| n.strip_prefix(TABLE_PREFIX.as_bytes()).map(|n| n.to_vec()))
+        .filter_map(|n| String::from_utf8(n).ok())
+        .collect()
+}
+
+/// table 在 sled 中对应的 tree 的名字
+fn tree_name(table: &str) -> String {
+    format!("{}{}", TABLE_PREFIX, table)
+}
+
+/// 把 sled 中的一条记录解码成 KvPair
+fn decode_pair(item: sled::Result<(IVec, IVec)>) -> Result<KvPair, KvError> {
+    let (k, v) = item?;
+    let key = String::from_utf8(k.to_vec())
+        .map_err(|e| KvError::Internal(format!("Invalid utf8 key: {}", e)))?;
+    let value = v.as_ref().try_into()?;
+    Ok(KvPair::new(key, value))
+}
@@ -0,0 +1,364 @@
+use std::convert::TryFrom;
+use std::fs::{self, File};
+use std::io::{self, BufReader, BufWriter, Read, Write};
+use std::path::Path;
+use crc32fast::Hasher;
+use prost::Message;
+use crate::{AofMark, KvPair, TableKind};
+use crate::errors::KvError;
+use crate::memory::{Entry, MemTable, Table};
+use crate::storage::now_ms;
+
+/*
+    快照文件的格式（所有整数都是大端）：
+
+    | magic "KVSS" | version: u32 | 是否有 AOF 位置: u8 | AOF offset: u64 | AOF checksum: u32 | table 数量: u32 |
+    | table name 长度: u32 | table name | table 类型: u8 | pair 数量: u64 | pair 长度: u32 | protobuf 编码的 KvPair | 过期时间: u64 | ... |
+    | ... 下一个 table ... |
+    | crc32: u32 |
+
+    过期时间是 unix 时间戳（毫秒），0 表示不过期；version 1 的快照里没有过期时间。
+    table 类型是 TableKind 的值；version 3 之前的快照里没有 table 类型，都是 hash table。
+    AOF 位置见 AofMark，没有开启 AOF 时三个字段都是 0；version 4 之前的快照里没有 AOF 位置。
+    最后的 crc32 是对它之前所有字节计算的 checksum。
+*/
+const MAGIC: &[u8; 4] = b"KVSS";
+const VERSION: u32 = 4;
+
+impl MemTable {
+    /// 把所有 table 在同一时刻的数据写入 path 指向的快照文件
+    pub fn save_snapshot(&self, path: impl AsRef<Path>) -> Result<(), KvError> {
+        self.write_snapshot(path.as_ref(), None)
+    }
+
+    /// 和 save_snapshot 一样，同时在快照中记下它对应的 AOF 位置
+    pub fn save_snapshot_with_mark(&self, path: impl AsRef<Path>, mark: AofMark) -> Result<(), KvError> {
+        self.write_snapshot(path.as_ref(), Some(mark))
+    }
+
+    fn write_snapshot(&self, path: &Path, mark: Option<AofMark>) -> Result<(), KvError> {
+        let tables = self.freeze();
+
+        // 先写到临时文件，写完再 rename，这样不会因为写到一半出错而破坏之前的快照
+        let tmp_path = path.with_extension("tmp");
+        let mut writer = ChecksumWriter::new(BufWriter::new(File::create(&tmp_path)?));
+        writer.write_all(MAGIC)?;
+        writer.write_all(&VERSION.to_be_bytes())?;
+        let AofMark { offset, checksum } = mark.unwrap_or_default();
+        writer.write_all(&[mark.is_some() as u8])?;
+        writer.write_all(&offset.to_be_bytes())?;
+        writer.write_all(&checksum.to_be_bytes())?;
+        writer.write_all(&len_u32(tables.len())?.to_be_bytes())?;
+        let now = now_ms();
+        for (name, table) in tables {
+            let kind = table.kind();
+            // 已经过期的 key 不写入快照
+            let pairs: Vec<_> = table
+                .data
+                .into_iter()
+                .map(|(key, entry)| {
+                    let expire_at = table.expires.get(&key).map(|at| *at).unwrap_or_default();
+                    (key, entry.value, expire_at)
+                })
+                .filter(|(_, _, expire_at)| *expire_at == 0 || *expire_at > now)
+                .collect();
+            write_section(&mut writer, name.as_bytes())?;
+            writer.write_all(&[kind as u8])?;
+            writer.write_all(&(pairs.len() as u64).to_be_bytes())?;
+            for (key, value, expire_at) in pairs {
+                write_section(&mut writer, &KvPair::new(key, value).encode_to_vec())?;
+                writer.write_all(&expire_at.to_be_bytes())?;
+            }
+        }
+
+        let (mut inner, checksum) = writer.finish();
+        inner.write_all(&checksum.to_be_bytes())?;
+        let file = inner.into_inner().map_err(|e| KvError::from(e.into_error()))?;
+        file.sync_all()?;
+        fs::rename(&tmp_path, path)?;
+        Ok(())
+    }
+
+    /// 从 path 指向的快照文件中恢复出一个 MemTable
+    pub fn load_snapshot(path: impl AsRef<Path>) -> Result<Self, KvError> {
+        Ok(Self::load_snapshot_with_mark(path)?.0)
+    }
+
+    /// 从快照文件中恢复出 MemTable，同时返回快照对应的 AOF 位置，快照里没有记录时是 None
+    pub fn load_snapshot_with_mark(path: impl AsRef<Path>) -> Result<(Self, Option<AofMark>), KvError> {
+        let mut reader = ChecksumReader::new(BufReader::new(File::open(path)?));
+
+        let mut magic = [0u8; 4];
+        reader.read_exact(&mut magic)?;
+        if &magic != MAGIC {
+            return Err(KvError::InvalidSnapshot("bad magic".into()));
+        }
+        let version = read_u32(&mut reader)?;
+        if version == 0 || version > VERSION {
+            return Err(KvError::InvalidSnapshot(format!("unsupported version {}", version)));
+        }
+
+        let mut mark = None;
+        if version >= 4 {
+            let mut has_mark = [0u8; 1];
+            reader.read_exact(&mut has_mark)?;
+            let offset = read_u64(&mut reader)?;
+            let checksum = read_u32(&mut reader)?;
+            if has_mark[0] != 0 {
+                mark = Some(AofMark { offset, checksum });
+            }
+        }
+
+        let store = MemTable::new();
+        let table_count = read_u32(&mut reader)?;
+        for _ in 0..table_count {
+            let name = String::from_utf8(read_section(&mut reader)?)
+                .map_err(|_| KvError::InvalidSnapshot("table name is not utf8".into()))?;
+            let kind = if version >= 3 {
+                let mut kind = [0u8; 1];
+                reader.read_exact(&mut kind)?;
+                TableKind::from_i32(kind[0] as i32)
+                    .ok_or_else(|| KvError::InvalidSnapshot(format!("unknown table kind {}", kind[0])))?
+            } else {
+                TableKind::Hash
+            };
+            let pair_count = read_u64(&mut reader)?;
+            let table = Table::new(kind);
+            for _ in 0..pair_count {
+                let pair = KvPair::decode(read_section(&mut reader)?.as_slice())?;
+                let expire_at = if version >= 2 { read_u64(&mut reader)? } else { 0 };
+                if expire_at > 0 {
+                    table.expires.insert(pair.key.clone(), expire_at);
+                }
+                table.index_insert(&pair.key);
+                table.data.insert(pair.key, Entry::new(pair.value.unwrap_or_default()));
+            }
+            store.insert_table(name, table);
+        }
+
+        let (mut inner, checksum) = reader.finish();
+        if read_u32(&mut inner)? != checksum {
+            return Err(KvError::InvalidSnapshot("checksum mismatch".into()));
+        }
+        Ok((store, mark))
+    }
+}
+
+fn len_u32(len: usize) -> Result<u32, KvError> {
+    u32::try_from(len).map_err(|_| KvError::Internal("Snapshot section is too large".into()))
+}
+
+/// 写入 u32 长度 + 数据
+fn write_section(writer: &mut impl Write, data: &[u8]) -> Result<(), KvError> {
+    writer.write_all(&len_u32(data.len())?.to_be_bytes())?;
+    writer.write_all(data)?;
+    Ok(())
+}
+
+/// 读出 u32 长度 + 数据
+fn read_section(reader: &mut impl Read) -> Result<Vec<u8>, KvError> {
+    let len = read_u32(reader)? as u64;
+    // 不直接按 len 分配内存，文件损坏时 len 可能非常大
+    let mut buf = Vec::new();
+    reader.take(len).read_to_end(&mut buf)?;
+    if buf.len() as u64 != len {
+        return Err(KvError::InvalidSnapshot("unexpected end of file".into()));
+    }
+    Ok(buf)
+}
+
+fn read_u32(reader: &mut impl Read) -> Result<u32, KvError> {
+    let mut buf = [0u8; 4];
+    reader.read_exact(&mut buf)?;
+    Ok(u32::from_be_bytes(buf))
+}
+
+fn read_u64(reader: &mut impl Read) -> Result<u64, KvError> {
+    let mut buf = [0u8; 8];
+    reader.read_exact(&mut buf)?;
+    Ok(u64::from_be_bytes(buf))
+}
+
+/// 写入数据的同时计算 crc32
+pub(crate) struct ChecksumWriter<W> {
+    inner: W,
+    hasher: Hasher,
+}
+
+impl<W: Write> ChecksumWriter<W> {
+    pub(crate) fn new(inner: W) -> Self {
+        Self { inner, hasher: Hasher::new() }
+    }
+
+    pub(crate) fn finish(self) -> (W, u32) {
+        (self.inner, self.hasher.finalize())
+    }
+}
+
+impl<W: Write> Write for ChecksumWriter<W> {
+    fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+        let n = self.inner.write(buf)?;
+        self.hasher.update(&buf[..n]);
+        Ok(n)
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        self.inner.flush()
+    }
+}
+
+/// 读取数据的同时计算 crc32
+struct ChecksumReader<R> {
+    inner: R,
+    hasher: Hasher,
+}
+
+impl<R: Read> ChecksumReader<R> {
+    fn new(inner: R) -> Self {
+        Self { inner, hasher: Hasher::new() }
+    }
+
+    fn finish(self) -> (R, u32) {
+        (self.inner, self.hasher.finalize())
+    }
+}
+
+impl<R: Read> Read for ChecksumReader<R> {
+    fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+        let n = self.inner.read(buf)?;
+        self.hasher.update(&buf[..n]);
+        Ok(n)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use tempfile::tempdir;
+    use crate::{dispatch, CommandRequest, Storage};
+    use super::*;
+
+    #[test]
+    fn snapshot_should_roundtrip() {
+        let dir = tempdir().unwrap();
+        let path = dir.path().join("dump.kvs");
+        let store = MemTable::new();
+        store.set("t1", "k1".into(), "v1".into()).unwrap();
+        store.set("t1", "k2".into(), 10.into()).unwrap();
+        store.set("t2", "k1".into(), true.into()).unwrap();
+        store.save_snapshot(&path).unwrap();
+
+        let store = MemTable::load_snapshot(&path).unwrap();
+        assert_eq!(store.get("t1", "k1"), Ok(Some("v1".into())));
+        assert_eq!(store.get("t1", "k2"), Ok(Some(10.into())));
+        assert_eq!(store.get("t2", "k1"), Ok(Some(true.into())));
+    }
+
+    #[test]
+    fn snapshot_should_keep_expire_at() {
+        let dir = tempdir().unwrap();
+        let path = dir.path().join("dump.kvs");
+        let store = MemTable::new();
+        let at = now_ms() + 60_000;
+        store.set("t1", "k1".into(), "v1".into()).unwrap();
+        store.set("t1", "k2".into(), "v2".into()).unwrap();
+        store.expire("t1", "k1", Some(at)).unwrap();
+        store.expire("t1", "k2", Some(now_ms() - 1)).unwrap();
+        store.save_snapshot(&path).unwrap();
+
+        let store = MemTable::load_snapshot(&path).unwrap();
+        assert_eq!(store.get_expire_at("t1", "k1"), Ok(Some(at)));
+        assert_eq!(store.get("t1", "k2"), Ok(None));
+    }
+
+    #[test]
+    fn snapshot_should_keep_ordered_tables() {
+        let dir = tempdir().unwrap();
+        let path = dir.path().join("dump.kvs");
+        let store = MemTable::new();
+        store.create_table("t1", TableKind::Ordered).unwrap();
+        store.set("t1", "k2".into(), "v2".into()).unwrap();
+        store.set("t1", "k1".into(), "v1".into()).unwrap();
+        store.save_snapshot(&path).unwrap();
+
+        let store = MemTable::load_snapshot(&path).unwrap();
+        let keys: Vec<_> = store.get_all("t1").unwrap().into_iter().map(|pair| pair.key).collect();
+        assert_eq!(keys, vec!["k1", "k2"]);
+        assert!(store.range("t1", &crate::KeyRange::prefix("k")).is_ok());
+    }
+
+    #[test]
+    fn snapshot_should_keep_aof_mark() {
+        let dir = tempdir().unwrap();
+        let path = dir.path().join("dump.kvs");
+        let store = MemTable::new();
+        store.set("t1", "k1".into(), "v1".into()).unwrap();
+        store.save_snapshot(&path).unwrap();
+        assert_eq!(MemTable::load_snapshot_with_mark(&path).unwrap().1, None);
+
+        let mark = AofMark { offset: 42, checksum: 0xdead_beef };
+        store.save_snapshot_with_mark(&path, mark).unwrap();
+        let (store, loaded) = MemTable::load_snapshot_with_mark(&path).unwrap();
+        assert_eq!(loaded, Some(mark));
+        assert_eq!(store.get("t1", "k1"), Ok(Some("v1".into())));
+    }
+
+    #[test]
+    fn version_1_snapshot_should_load() {
+        let dir = tempdir().unwrap();
+        let path = dir.path().join("dump.kvs");
+        let mut writer = ChecksumWriter::new(Vec::new());
+        writer.write_all(MAGIC).unwrap();
+        writer.write_all(&1u32.to_be_bytes()).unwrap();
+        writer.write_all(&1u32.to_be_bytes()).unwrap();
+        write_section(&mut writer, b"t1").unwrap();
+        writer.write_all(&1u64.to_be_bytes()).unwrap();
+        write_section(&mut writer, &KvPair::new("k1", "v1".into()).encode_to_vec()).unwrap();
+        let (mut data, checksum) = writer.finish();
+        data.extend_from_slice(&checksum.to_be_bytes());
+        fs::write(&path, data).unwrap();
+
+        let store = MemTable::load_snapshot(&path).unwrap();
+        assert_eq!(store.get("t1", "k1"), Ok(Some("v1".into())));
+    }
+
+    #[test]
+    fn corrupted_snapshot_should_be_rejected() {
+        let dir = tempdir().unwrap();
+        let path = dir.path().join("dump.kvs");
+        let store = MemTable::new();
+        store.set("t1", "k1".into(), "v1".into()).unwrap();
+        store.save_snapshot(&path).unwrap();
+
+        let mut data = fs::read(&path).unwrap();
+        let last = data.len() - 1;
+        data[last] ^= 0xff;
+        fs::write(&path, &data).unwrap();
+        let err = MemTable::load_snapshot(&path).unwrap_err();
+        assert_eq!(err, KvError::InvalidSnapshot("checksum mismatch".into()));
+
+        fs::write(&path, b"NOPE").unwrap();
+        let err = MemTable::load_snapshot(&path).unwrap_err();
+        assert_eq!(err, KvError::InvalidSnapshot("bad magic".into()));
+    }
+
+    #[test]
+    fn save_command_should_write_snapshot() {
+        let dir = tempdir().unwrap();
+        let path = dir.path().join("dump.kvs");
+        let store = MemTable::new().snapshot_path(&path);
+        dispatch(CommandRequest::new_hset("t1", "k1", "v1".into()), &store);
+        let res = dispatch(CommandRequest::new_save(), &store);
+        assert_eq!(res.status, 200);
+
+        let store = MemTable::load_snapshot(&path).unwrap();
+        assert_eq!(store.get("t1", "k1"), Ok(Some("v1".into())));
+    }
+
+    #[test]
+    fn save_command_without_snapshot_path_should_fail() {
+        let store = MemTable::new();
+        let res = dispatch(CommandRequest::new_save(), &store);
+        assert_eq!(res.status, 400);
+        assert!(res.message.contains("Snapshot path is not configured"));
+    }
+}